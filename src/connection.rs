@@ -1,39 +1,112 @@
 use core::str;
-use std::{sync::Arc, time::Duration};
-use bytes::Bytes;
+use std::{sync::{atomic::{AtomicU64, Ordering}, Arc}, time::Duration};
 use tokio::net::TcpStream;
-use crate::{context::Context, parser::{RespParser, RespRequest, RespValue}};
+use crate::{context::Context, error::Error, parser::{ParserLimits, RespEncoder, RespParser, RespRequest, RespValue, RESP2}};
 use anyhow::Result;
 
 
+const FLUSH_THRESHOLD: usize = 64 * 1024;
+
+static NEXT_CLIENT_ID: AtomicU64 = AtomicU64::new(1);
+
+// error codes that messages may start with, any other message is sent as a generic ERR like redis does
+const ERROR_CODES: [&str; 5] = ["ERR", "WRONGTYPE", "NOGROUP", "BUSYGROUP", "INVALIDOBJ"];
+
+fn error_reply(e: &anyhow::Error) -> RespValue {
+    let message = e.to_string();
+    let code = message.split(' ').next().unwrap_or_default();
+    if ERROR_CODES.contains(&code) {
+        return RespValue::Error(message.into());
+    }
+    RespValue::Error(format!("ERR {}", message).into())
+}
+
 pub struct Connection {
+    id: u64,
+    // RESP version negotiated through HELLO
+    protocol: u8,
     writer: tokio::io::WriteHalf<TcpStream>,
-    reader: tokio::io::ReadHalf<TcpStream>,
+    // responses waiting for the next flush, the buffer is reused for the whole connection
+    encoder: RespEncoder,
+    // lives as long as the connection, bytes buffered from a pipelined batch are kept for the next request
+    parser: RespParser<tokio::io::ReadHalf<TcpStream>>,
+    // the client went away while a blocking command was waiting
+    closed: bool,
 }
 
 impl Connection {
     pub fn new(stream: TcpStream) -> Self {
+        Self::with_limits(stream, ParserLimits::default())
+    }
+
+    pub fn with_limits(stream: TcpStream, limits: ParserLimits) -> Self {
         let (reader, writer) = tokio::io::split(stream);
         Connection {
+            id: NEXT_CLIENT_ID.fetch_add(1, Ordering::Relaxed),
+            protocol: RESP2,
             writer,
-            reader,
+            encoder: RespEncoder::new(),
+            parser: RespParser::with_limits(reader, limits),
+            closed: false,
         }
     }
 
+    /// Serves requests of a pipelined batch from the buffer, responses are only flushed
+    /// once no complete request is left and the socket has to be read again
     pub async fn read_request(&mut self) -> Result<RespRequest> {
-        let mut parser = RespParser::new(&mut self.reader);
-        parser.parse_request().await
+        if let Some(request) = self.parser.try_parse_request()? {
+            return Ok(request);
+        }
+        self.flush().await?;
+        self.parser.parse_request().await
+    }
+
+    pub async fn flush(&mut self) -> Result<()> {
+        self.encoder.write_to(&mut self.writer).await?;
+        Ok(())
     }
 
-    pub async fn write_response(&mut self, response: RespValue) -> Result<()> {
-        response.write(&mut self.writer).await
+    /// Queues the response, see `read_request` for when it is flushed
+    pub fn write_response(&mut self, response: RespValue) {
+        self.encoder.encode(&response.into_protocol(self.protocol));
     }
 
     async fn process(&mut self, context: Arc<Context>, req: RespRequest) -> Result<RespValue> {
-        println!("Processing request: {:?}", req);
-        let command = str::from_utf8(req.command.as_ref())?;
-        let handler = crate::command_table::get_handler(command)?;
-        tokio::time::timeout(context.timeout.unwrap_or(Duration::from_secs(5)), handler(context, req)).await?
+        let command = str::from_utf8(req.command.as_ref())?.to_ascii_uppercase();
+        let handler = crate::command_table::get_handler(&command)?;
+        let mut response = handler(context.clone(), req);
+        let mut parked = false;
+        loop {
+            let deadline = context.deadline();
+            let expired = async {
+                match deadline {
+                    Some(deadline) => tokio::time::sleep_until(deadline.into()).await,
+                    None => std::future::pending().await,
+                }
+            };
+            tokio::select! {
+                result = &mut response => return result,
+                // responses of the pipelined requests before must not wait for a blocking command
+                _ = context.blocked() => {
+                    parked = true;
+                    self.flush().await?;
+                }
+                // a parked client is watched for a disconnection, dropping the command unregisters it so that
+                // it cannot take a value no one would receive; what it sends meanwhile waits in the buffer
+                result = self.parser.fill_buffer(), if parked => {
+                    if let Err(e) = result {
+                        self.closed = true;
+                        return Err(e);
+                    }
+                }
+                _ = expired => {
+                    // unless a blocking command moved the deadline meanwhile
+                    if context.deadline() == deadline {
+                        return Err(anyhow::anyhow!("deadline has elapsed"));
+                    }
+                }
+            }
+        }
     }
 
     pub async fn serve_loop(&mut self) {
@@ -42,22 +115,128 @@ impl Connection {
                 Ok(req) => req,
                 Err(e) => {
                     eprintln!("Error reading request: {}", e);
+                    // tell the client why before closing, the rest of its input cannot be trusted
+                    if let Some(Error::Protocol(_)) = e.downcast_ref::<Error>() {
+                        self.write_response(error_reply(&e));
+                    }
+                    let _ = self.flush().await;
                     return;
                 }
             };
 
-            let context = Arc::new(Context::new(Some(Duration::from_secs(5)), 3));
-            let result = self.process(context, req).await;
+            let context = Arc::new(Context::new(Some(Duration::from_secs(5)), 3).with_client(self.id, self.protocol));
+            let result = self.process(context.clone(), req).await;
+            self.protocol = context.protocol();
+            if self.closed {
+                return;
+            }
 
             let response = match result {
                 Ok(response) => response,
-                Err(e) => RespValue::Error(Bytes::from(e.to_string()))
+                Err(e) => error_reply(&e),
             };
 
-            if let Err(e) = self.write_response(response).await {
-                eprintln!("Error writing response: {}", e);
-                return;
+            self.write_response(response);
+            // a long pipelined batch should not pile up its responses in memory
+            if self.encoder.pending() >= FLUSH_THRESHOLD {
+                if let Err(e) = self.flush().await {
+                    eprintln!("Error writing response: {}", e);
+                    return;
+                }
             }
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+    #[tokio::test]
+    async fn pipelined_requests() -> Result<()> {
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await?;
+        let addr = listener.local_addr()?;
+        tokio::spawn(async move {
+            let (socket, _) = listener.accept().await.unwrap();
+            Connection::new(socket).serve_loop().await;
+        });
+
+        let mut client = TcpStream::connect(addr).await?;
+        let batch = b"*1\r\n$4\r\nPING\r\n*3\r\n$3\r\nSET\r\n$9\r\npipe:key1\r\n$1\r\nv\r\n*2\r\n$3\r\nGET\r\n$9\r\npipe:key1\r\n";
+        client.write_all(batch).await?;
+
+        let expected = b"+PONG\r\n+OK\r\n$1\r\nv\r\n";
+        let mut response = vec![0; expected.len()];
+        client.read_exact(&mut response).await?;
+        assert_eq!(response, expected);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn errors_carry_a_code() -> Result<()> {
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await?;
+        let addr = listener.local_addr()?;
+        tokio::spawn(async move {
+            let (socket, _) = listener.accept().await.unwrap();
+            Connection::new(socket).serve_loop().await;
+        });
+
+        let mut client = TcpStream::connect(addr).await?;
+        client.write_all(b"SET conn:s abc\r\nINCR conn:s\r\nGET\r\nLPUSH conn:s x\r\n").await?;
+        let expected: &[u8] = b"+OK\r\n\
+            -ERR value is not an integer or out of range\r\n\
+            -ERR wrong number of arguments for 'get' command\r\n\
+            -WRONGTYPE Operation against a key holding the wrong kind of value\r\n";
+        let mut response = vec![0; expected.len()];
+        client.read_exact(&mut response).await?;
+        assert_eq!(String::from_utf8_lossy(&response), String::from_utf8_lossy(expected));
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn disconnected_waiter_is_not_served() -> Result<()> {
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await?;
+        let addr = listener.local_addr()?;
+        tokio::spawn(async move {
+            loop {
+                let (socket, _) = listener.accept().await.unwrap();
+                tokio::spawn(async move { Connection::new(socket).serve_loop().await });
+            }
+        });
+        let settle = || tokio::time::sleep(Duration::from_millis(50));
+
+        let mut waiter = TcpStream::connect(addr).await?;
+        waiter.write_all(b"*3\r\n$5\r\nBLPOP\r\n$9\r\nconn:jobs\r\n$1\r\n0\r\n").await?;
+        settle().await;
+        drop(waiter);
+        settle().await;
+
+        let mut client = TcpStream::connect(addr).await?;
+        client.write_all(b"*3\r\n$5\r\nLPUSH\r\n$9\r\nconn:jobs\r\n$3\r\njob\r\n*2\r\n$4\r\nLLEN\r\n$9\r\nconn:jobs\r\n").await?;
+        let expected = b":1\r\n:1\r\n";
+        let mut response = vec![0; expected.len()];
+        client.read_exact(&mut response).await?;
+        assert_eq!(response, expected);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn oversized_bulk_closes_connection() -> Result<()> {
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await?;
+        let addr = listener.local_addr()?;
+        tokio::spawn(async move {
+            let (socket, _) = listener.accept().await.unwrap();
+            let limits = ParserLimits { max_bulk_len: 16, ..Default::default() };
+            Connection::with_limits(socket, limits).serve_loop().await;
+        });
+
+        let mut client = TcpStream::connect(addr).await?;
+        client.write_all(b"*2\r\n$3\r\nGET\r\n$5000000000\r\n").await?;
+
+        let mut response = Vec::new();
+        client.read_to_end(&mut response).await?;
+        assert_eq!(response, b"-ERR Protocol error: invalid bulk length\r\n");
+        Ok(())
+    }
+}
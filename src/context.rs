@@ -1,26 +1,72 @@
-use std::{error::Error, sync::atomic::AtomicIsize, time::Duration};
+use std::{error::Error, sync::{atomic::{AtomicIsize, AtomicU8}, Mutex}, time::{Duration, Instant}};
+use tokio::sync::Notify;
+use crate::parser::RESP2;
 
 #[derive(Debug)]
 pub struct Context {
     pub timeout: Option<Duration>,
     pub retries: AtomicIsize,
     pub start_time: std::time::Instant,
+    pub client_id: u64,
+    // protocol of the connection, commands such as HELLO may switch it
+    pub protocol: AtomicU8,
+    // the request fails once it is reached, blocking commands push it back
+    deadline: Mutex<Option<Instant>>,
+    blocked: Notify,
 }
 
 impl Context {
     pub fn new(timeout: Option<Duration>, retries: isize) -> Self {
+        let start_time = Instant::now();
         Context {
             timeout,
             retries: retries.into(),
-            start_time: std::time::Instant::now(),
+            start_time,
+            client_id: 0,
+            protocol: RESP2.into(),
+            deadline: Mutex::new(timeout.map(|timeout| start_time + timeout)),
+            blocked: Notify::new(),
         }
     }
 
+    pub fn with_client(mut self, client_id: u64, protocol: u8) -> Self {
+        self.client_id = client_id;
+        self.protocol = protocol.into();
+        self
+    }
+
+    pub fn protocol(&self) -> u8 {
+        self.protocol.load(std::sync::atomic::Ordering::Relaxed)
+    }
+
+    pub fn set_protocol(&self, protocol: u8) {
+        self.protocol.store(protocol, std::sync::atomic::Ordering::Relaxed);
+    }
+
+    pub fn deadline(&self) -> Option<Instant> {
+        *self.deadline.lock().unwrap()
+    }
+
+    /// Lifts the deadline, for a command that must run to its end once started such as SAVE
+    pub fn clear_deadline(&self) {
+        *self.deadline.lock().unwrap() = None;
+    }
+
+    /// Called by a command about to wait for `timeout`, or forever if None: the deadline moves past
+    /// the wait by the usual timeout, and whoever awaits `blocked` learns that the client is parked
+    pub fn block_for(&self, timeout: Option<Duration>) {
+        let deadline = timeout.zip(self.timeout).map(|(timeout, budget)| Instant::now() + timeout + budget);
+        *self.deadline.lock().unwrap() = deadline;
+        self.blocked.notify_one();
+    }
+
+    pub async fn blocked(&self) {
+        self.blocked.notified().await
+    }
+
     pub fn is_timeout(&self) -> Result<(), Box<dyn Error>> {
-        if let Some(timeout) = self.timeout {
-            if self.start_time.elapsed() > timeout {
-                return Err("Timeout".into());
-            }
+        if self.deadline().is_some_and(|deadline| Instant::now() > deadline) {
+            return Err("Timeout".into());
         }
         Ok(())
     }
@@ -0,0 +1,506 @@
+// Append only file: every successful write command is appended in RESP form and replayed on startup
+use std::{fs::{File, OpenOptions}, io::{BufWriter, Write}, path::{Path, PathBuf}, sync::{atomic::{AtomicBool, Ordering}, Arc, Mutex}, time::Duration};
+use anyhow::{anyhow, bail, Context as _, Result};
+use bytes::{Bytes, BytesMut};
+use crate::{command_table, context::Context, parser::{ParserLimits, RespDecoder, RespEncoder, RespValue}, redis_types::format_f64};
+use super::{Entry, LogManager, Stream, SyncJob, Value, DB};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, clap::ValueEnum)]
+pub enum FsyncPolicy {
+    /// fsync after every write command
+    Always,
+    /// fsync at most once per second
+    Everysec,
+    /// leave flushing to the operating system
+    No,
+}
+
+pub struct AofLog {
+    path: PathBuf,
+    file: File,
+    policy: FsyncPolicy,
+    size: u64,
+    base_size: u64,
+    dirty: bool,
+    // records appended while a rewrite is writing the new baseline
+    rewrite_buffer: Option<Vec<u8>>,
+}
+
+impl AofLog {
+    pub fn open(path: impl AsRef<Path>, policy: FsyncPolicy) -> Result<Self> {
+        let path = path.as_ref().to_path_buf();
+        let file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&path)
+            .with_context(|| format!("open append only file {}", path.display()))?;
+        let size = file.metadata()?.len();
+        Ok(AofLog {
+            path,
+            file,
+            policy,
+            size,
+            base_size: size,
+            dirty: false,
+            rewrite_buffer: None,
+        })
+    }
+
+    fn rewrite_path(&self) -> PathBuf {
+        let mut name = self.path.file_name().unwrap_or_default().to_os_string();
+        name.push(".rewrite");
+        self.path.with_file_name(name)
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    pub fn policy(&self) -> FsyncPolicy {
+        self.policy
+    }
+}
+
+impl LogManager for AofLog {
+    fn append(&mut self, record: &[u8]) -> Result<()> {
+        self.file.write_all(record)?;
+        self.size += record.len() as u64;
+        if let Some(buffer) = self.rewrite_buffer.as_mut() {
+            buffer.extend_from_slice(record);
+        }
+        self.dirty = true;
+        // everysec is left to the timer of `open`, which syncs away from the database lock
+        if self.policy == FsyncPolicy::Always {
+            self.file.sync_data()?;
+            self.dirty = false;
+        }
+        Ok(())
+    }
+
+    fn sync(&mut self) -> Result<SyncJob> {
+        if !self.dirty || self.policy == FsyncPolicy::No {
+            return Ok(Box::new(|| Ok(())));
+        }
+        // a handle of its own, so that the log can be appended to while the job runs
+        let file = self.file.try_clone()?;
+        self.dirty = false;
+        Ok(Box::new(move || Ok(file.sync_data()?)))
+    }
+
+    fn size(&self) -> u64 {
+        self.size
+    }
+
+    fn base_size(&self) -> u64 {
+        self.base_size
+    }
+
+    fn start_rewrite(&mut self) -> Result<Box<dyn Write + Send>> {
+        if self.rewrite_buffer.is_some() {
+            bail!("rewrite already in progress");
+        }
+        let file = File::create(self.rewrite_path())?;
+        self.rewrite_buffer = Some(Vec::new());
+        Ok(Box::new(BufWriter::new(file)))
+    }
+
+    fn flush_rewrite(&mut self) -> Result<SyncJob> {
+        let buffer = self.rewrite_buffer.as_mut().ok_or(anyhow!("no rewrite in progress"))?;
+        let records = std::mem::take(buffer);
+        let rewrite_path = self.rewrite_path();
+        Ok(Box::new(move || {
+            let mut file = OpenOptions::new().append(true).open(&rewrite_path)?;
+            file.write_all(&records)?;
+            file.sync_all()?;
+            Ok(())
+        }))
+    }
+
+    fn finish_rewrite(&mut self) -> Result<SyncJob> {
+        let buffer = self.rewrite_buffer.take().ok_or(anyhow!("no rewrite in progress"))?;
+        let rewrite_path = self.rewrite_path();
+
+        let mut file = OpenOptions::new().append(true).open(&rewrite_path)?;
+        file.write_all(&buffer)?;
+        std::fs::rename(&rewrite_path, &self.path)?;
+
+        self.size = file.metadata()?.len();
+        self.base_size = self.size;
+        self.file = file;
+        self.dirty = false;
+
+        let file = self.file.try_clone()?;
+        let dir = self.path.parent().filter(|dir| !dir.as_os_str().is_empty()).unwrap_or(Path::new(".")).to_path_buf();
+        Ok(Box::new(move || {
+            file.sync_data()?;
+            // the rename itself only survives a crash once the directory is synced
+            File::open(dir)?.sync_all()?;
+            Ok(())
+        }))
+    }
+
+    fn abort_rewrite(&mut self) {
+        self.rewrite_buffer = None;
+        let _ = std::fs::remove_file(self.rewrite_path());
+    }
+}
+
+// elements per command when rebuilding a collection, like AOF_REWRITE_ITEMS_PER_CMD of redis
+const ITEMS_PER_COMMAND: usize = 64;
+
+/// `command key item...` repeated for every `ITEMS_PER_COMMAND` items
+fn batched(command: &'static str, key: &Bytes, items: impl Iterator<Item = Bytes>) -> Vec<RespValue> {
+    let items: Vec<Bytes> = items.collect();
+    items
+        .chunks(ITEMS_PER_COMMAND)
+        .map(|chunk| {
+            let head = [Bytes::from_static(command.as_bytes()), key.clone()];
+            RespValue::bulk_array(head.into_iter().chain(chunk.iter().cloned()))
+        })
+        .collect()
+}
+
+/// Commands that rebuild `entry` from scratch
+pub fn rewrite_commands(key: &Bytes, entry: &Entry) -> Vec<RespValue> {
+    let deadline = entry.expire_at.map(|t| Bytes::from(t.timestamp_millis().to_string()));
+    let mut commands = match &entry.value {
+        Value::String(value) => {
+            let mut command = vec!["SET".into(), key.clone(), value.clone()];
+            if let Some(deadline) = deadline {
+                command.extend(["PXAT".into(), deadline]);
+            }
+            return vec![RespValue::bulk_array(command)];
+        }
+        Value::List(list) => batched("RPUSH", key, list.iter().cloned()),
+        Value::Hash(hash) => {
+            let now = chrono::Utc::now();
+            let live: Vec<_> = hash.entries().filter(|(_, _, deadline)| deadline.is_none_or(|t| t > now)).collect();
+            let mut commands = batched("HSET", key, live.iter().flat_map(|(field, value, _)| [(*field).clone(), (*value).clone()]));
+            for (field, _, deadline) in &live {
+                if let Some(deadline) = deadline {
+                    let deadline = Bytes::from(deadline.timestamp_millis().to_string());
+                    let command = ["HPEXPIREAT".into(), key.clone(), deadline, "FIELDS".into(), "1".into(), (*field).clone()];
+                    commands.push(RespValue::bulk_array(command));
+                }
+            }
+            commands
+        }
+        Value::Set(set) => batched("SADD", key, set.iter()),
+        Value::ZSet(zset) => batched("ZADD", key, zset.iter().flat_map(|(member, score)| [Bytes::from(format_f64(score)), member.clone()])),
+        Value::Stream(stream) => stream_commands(key, stream),
+    };
+    // the deadline goes last, once the key exists again
+    if let Some(deadline) = deadline {
+        commands.push(RespValue::bulk_array(["PEXPIREAT".into(), key.clone(), deadline]));
+    }
+    commands
+}
+
+fn stream_commands(key: &Bytes, stream: &Stream) -> Vec<RespValue> {
+    let mut commands = Vec::new();
+    for (id, fields) in &stream.entries {
+        let head = ["XADD".into(), key.clone(), id.to_bytes()];
+        let fields = fields.iter().flat_map(|(field, value)| [field.clone(), value.clone()]);
+        commands.push(RespValue::bulk_array(head.into_iter().chain(fields)));
+    }
+    if stream.is_empty() {
+        // an empty stream still needs a command creating it, XSETID then puts its last ID back, even 0-0
+        commands.push(RespValue::bulk_array(["XADD".into(), key.clone(), "MAXLEN".into(), "0".into(), "0-1".into(), "x".into(), "y".into()]));
+    }
+    commands.push(RespValue::bulk_array([
+        "XSETID".into(), key.clone(), stream.last_id.to_bytes(),
+        "ENTRIESADDED".into(), stream.entries_added.to_string().into(),
+        "MAXDELETEDID".into(), stream.max_deleted_id.to_bytes(),
+    ]));
+
+    for (name, group) in &stream.groups {
+        // -1 keeps an unknown count unknown
+        let read = group.entries_read.map_or("-1".into(), |read| read.to_string());
+        let create = ["XGROUP".into(), "CREATE".into(), key.clone(), name.clone(), group.last_id.to_bytes(), "ENTRIESREAD".into(), read.into()];
+        commands.push(RespValue::bulk_array(create));
+        for consumer in group.consumers.keys() {
+            commands.push(RespValue::bulk_array(["XGROUP".into(), "CREATECONSUMER".into(), key.clone(), name.clone(), consumer.clone()]));
+        }
+        for (id, pending) in &group.pending {
+            commands.push(RespValue::bulk_array([
+                "XCLAIM".into(), key.clone(), name.clone(), pending.consumer.clone(), "0".into(), id.to_bytes(),
+                "TIME".into(), pending.delivered_at.to_string().into(),
+                "RETRYCOUNT".into(), pending.deliveries.to_string().into(),
+                "FORCE".into(), "JUSTID".into(),
+            ]));
+        }
+    }
+    commands
+}
+
+/// Writes the commands rebuilding `entries`, blocking the thread until the baseline is on its way to disk
+fn write_baseline(mut writer: Box<dyn Write + Send>, entries: Vec<(Bytes, Entry)>) -> Result<()> {
+    let now = chrono::Utc::now();
+    let mut encoder = RespEncoder::new();
+    for (key, entry) in entries.iter().filter(|(_, entry)| !entry.is_expired(now)) {
+        for command in rewrite_commands(key, entry) {
+            encoder.encode(&command);
+        }
+        if encoder.pending() >= 64 * 1024 {
+            writer.write_all(&encoder.take())?;
+        }
+    }
+    writer.write_all(&encoder.take())?;
+    writer.flush()?;
+    Ok(())
+}
+
+static REWRITE_IN_PROGRESS: AtomicBool = AtomicBool::new(false);
+
+pub fn rewrite_in_progress() -> bool {
+    REWRITE_IN_PROGRESS.load(Ordering::Acquire)
+}
+
+async fn run_blocking(job: SyncJob) -> Result<()> {
+    tokio::task::spawn_blocking(job).await.map_err(anyhow::Error::from).and_then(|result| result)
+}
+
+/// Compacts the log into the commands rebuilding the current state, clients keep being served meanwhile
+pub async fn bgrewrite() -> Result<tokio::task::JoinHandle<()>> {
+    if REWRITE_IN_PROGRESS.swap(true, Ordering::AcqRel) {
+        bail!("Background append only file rewriting already in progress");
+    }
+
+    let started = {
+        let mut db = DB.write().await;
+        // the copy has to match the point the log is split at, readers may go on while it is taken
+        db.start_rewrite().map(|writer| (writer, db.downgrade().engine().snapshot()))
+    };
+    let (writer, entries) = match started {
+        Ok(started) => started,
+        Err(e) => {
+            REWRITE_IN_PROGRESS.store(false, Ordering::Release);
+            return Err(e);
+        }
+    };
+
+    Ok(tokio::spawn(async move {
+        // the database is only locked to hand over the records kept aside and to swap the files
+        let result = async {
+            run_blocking(Box::new(move || write_baseline(writer, entries))).await?;
+            let flush = DB.write().await.flush_rewrite()?;
+            run_blocking(flush).await?;
+            let finish = DB.write().await.finish_rewrite()?;
+            run_blocking(finish).await
+        };
+        if let Err(e) = result.await {
+            eprintln!("Background append only file rewriting failed: {}", e);
+            DB.write().await.abort_rewrite();
+        }
+        REWRITE_IN_PROGRESS.store(false, Ordering::Release);
+    }))
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct AutoRewrite {
+    /// growth over the base size, in percent, that triggers a rewrite; 0 disables it
+    pub percentage: u64,
+    pub min_size: u64,
+}
+
+static AUTO_REWRITE: Mutex<AutoRewrite> = Mutex::new(AutoRewrite { percentage: 100, min_size: 64 * 1024 * 1024 });
+
+pub fn set_auto_rewrite(config: AutoRewrite) {
+    *AUTO_REWRITE.lock().unwrap() = config;
+}
+
+impl AutoRewrite {
+    fn should_rewrite(&self, size: u64, base_size: u64) -> bool {
+        if self.percentage == 0 || size < self.min_size {
+            return false;
+        }
+        let base_size = base_size.max(1);
+        size.saturating_sub(base_size) * 100 / base_size >= self.percentage
+    }
+}
+
+/// Replays every command of `data` through the command table, returns the number of valid bytes.
+/// A command cut off by a crash at the end of the file is ignored, anything else that does not parse is an error.
+pub async fn replay(data: &[u8]) -> Result<u64> {
+    let mut decoder = RespDecoder::new(ParserLimits::default());
+    let mut buf = BytesMut::from(data);
+    let mut count = 0usize;
+
+    loop {
+        let offset = data.len() - buf.len();
+        let request = match decoder.decode_request(&mut buf) {
+            Ok(Some(request)) => request,
+            Ok(None) => break,
+            Err(e) => bail!("Bad file format reading the append only file at offset {}: {}", offset, e),
+        };
+
+        let command = std::str::from_utf8(&request.command)?.to_ascii_uppercase();
+        let handler = command_table::get_handler(&command)?;
+        let context = Arc::new(Context::new(None, 0));
+        handler(context, request)
+            .await
+            .map_err(|e| anyhow!("replay command {} at offset {} failed: {}", command, offset, e))?;
+        count += 1;
+    }
+
+    let valid = (data.len() - buf.len()) as u64;
+    if valid < data.len() as u64 {
+        eprintln!("Append only file truncated at offset {}: the last command is incomplete", valid);
+    }
+    println!("Replayed {} commands from append only file", count);
+    Ok(valid)
+}
+
+/// Loads an existing append only file into the database and starts logging new writes to it
+pub async fn open(path: impl AsRef<Path>, policy: FsyncPolicy) -> Result<()> {
+    let path = path.as_ref();
+    if path.exists() {
+        let data = std::fs::read(path)?;
+        let valid = replay(&data).await?;
+        if valid < data.len() as u64 {
+            OpenOptions::new().write(true).open(path)?.set_len(valid)?;
+        }
+    }
+
+    let log = AofLog::open(path, policy)?;
+    DB.write().await.set_log(Some(Box::new(log)));
+
+    tokio::spawn(async move {
+        let mut interval = tokio::time::interval(Duration::from_secs(1));
+        loop {
+            interval.tick().await;
+            if policy == FsyncPolicy::Everysec {
+                let sync = DB.write().await.sync_log();
+                if let Err(e) = async { run_blocking(sync?).await }.await {
+                    eprintln!("Error syncing append only file: {}", e);
+                }
+            }
+
+            let auto_rewrite = *AUTO_REWRITE.lock().unwrap();
+            if auto_rewrite.percentage == 0 || rewrite_in_progress() {
+                continue;
+            }
+            let sizes = DB.read().await.log().map(|log| (log.size(), log.base_size()));
+            if let Some((size, base_size)) = sizes {
+                if auto_rewrite.should_rewrite(size, base_size) {
+                    println!("Starting automatic rewriting of append only file at {} bytes", size);
+                    if let Err(e) = bgrewrite().await {
+                        eprintln!("Error starting append only file rewriting: {}", e);
+                    }
+                }
+            }
+        }
+    });
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::parser::RespValue;
+
+    #[tokio::test]
+    async fn append_and_replay() -> Result<()> {
+        let path = std::env::temp_dir().join(format!("kv-aof-test-{}.aof", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+
+        let mut log = AofLog::open(&path, FsyncPolicy::Always)?;
+        for (key, value) in [("aof:k1", "v1"), ("aof:k2", "v2"), ("aof:k1", "v3")] {
+            let record = RespValue::bulk_array(["SET".into(), key.into(), value.into()]);
+            let mut buf = Vec::new();
+            record.write(&mut buf).await?;
+            log.append(&buf)?;
+        }
+        // a command cut off by a crash
+        let partial = b"*3\r\n$3\r\nSET\r\n$6\r\naof:k2";
+        log.append(partial)?;
+        let size = log.size();
+        drop(log);
+
+        let data = std::fs::read(&path)?;
+        assert_eq!(data.len() as u64, size);
+        let valid = replay(&data).await?;
+        assert_eq!(valid, size - partial.len() as u64);
+
+        let db = DB.read().await;
+        assert_eq!(db.get(b"aof:k1").unwrap().as_string()?, &bytes::Bytes::from("v3"));
+        assert_eq!(db.get(b"aof:k2").unwrap().as_string()?, &bytes::Bytes::from("v2"));
+        drop(db);
+
+        std::fs::remove_file(&path)?;
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn corrupt_record_stops_replay() -> Result<()> {
+        let valid = b"*3\r\n$3\r\nSET\r\n$7\r\naof:bad\r\n$1\r\n1\r\n";
+        let mut data = valid.to_vec();
+        data.extend_from_slice(b"*3\r\n$3\r\nSET\r\n$x\r\n");
+        data.extend_from_slice(valid);
+        let err = replay(&data).await.unwrap_err();
+        assert!(err.to_string().contains(&format!("offset {}", valid.len())), "{}", err);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn rewrite_keeps_records_appended_meanwhile() -> Result<()> {
+        let path = std::env::temp_dir().join(format!("kv-aof-rewrite-test-{}.aof", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        let encode = |key: &str, value: &str| {
+            let mut command = b"*3\r\n$3\r\nSET\r\n".to_vec();
+            for arg in [key, value] {
+                command.extend(format!("${}\r\n{}\r\n", arg.len(), arg).as_bytes());
+            }
+            command
+        };
+
+        let mut log = AofLog::open(&path, FsyncPolicy::No)?;
+        for i in 0..10 {
+            log.append(&encode("rewrite:k", &i.to_string()))?;
+        }
+        let writer = log.start_rewrite()?;
+        log.append(&encode("rewrite:k2", "after"))?;
+
+        let entries = vec![(Bytes::from("rewrite:k"), Entry::new(Value::String("9".into())))];
+        write_baseline(writer, entries)?;
+        log.flush_rewrite()?()?;
+        log.append(&encode("rewrite:k2", "last"))?;
+        log.finish_rewrite()?()?;
+        log.append(&encode("rewrite:k3", "tail"))?;
+        drop(log);
+
+        let mut expected = encode("rewrite:k", "9");
+        expected.extend(encode("rewrite:k2", "after"));
+        expected.extend(encode("rewrite:k2", "last"));
+        expected.extend(encode("rewrite:k3", "tail"));
+        assert_eq!(std::fs::read(&path)?, expected);
+
+        std::fs::remove_file(&path)?;
+        Ok(())
+    }
+
+    #[test]
+    fn collections_are_rebuilt_in_batches() {
+        let list = (0..100).map(|i| Bytes::from(i.to_string())).collect();
+        let commands = rewrite_commands(&Bytes::from("k"), &Entry::new(Value::List(list)));
+        assert_eq!(commands.len(), 2);
+        let RespValue::Array(last) = &commands[1] else { panic!("not an array") };
+        assert_eq!(last.len(), 2 + 100 - ITEMS_PER_COMMAND);
+        assert_eq!(last[0], RespValue::BulkString(Some("RPUSH".into())));
+
+        let mut entry = Entry::new(Value::Set(["a".into()].into_iter().collect()));
+        entry.expire_at = chrono::DateTime::from_timestamp_millis(4_102_444_800_000);
+        let commands = rewrite_commands(&Bytes::from("k"), &entry);
+        assert_eq!(commands.last(), Some(&RespValue::bulk_array(["PEXPIREAT".into(), "k".into(), "4102444800000".into()])));
+    }
+
+    #[test]
+    fn auto_rewrite_trigger() {
+        let config = AutoRewrite { percentage: 100, min_size: 1000 };
+        assert!(!config.should_rewrite(900, 100));
+        assert!(!config.should_rewrite(1500, 1000));
+        assert!(config.should_rewrite(2000, 1000));
+        assert!(config.should_rewrite(1000, 0));
+        assert!(!AutoRewrite { percentage: 0, min_size: 0 }.should_rewrite(2000, 1));
+    }
+}
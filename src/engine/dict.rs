@@ -0,0 +1,403 @@
+use std::borrow::Borrow;
+use std::collections::hash_map::RandomState;
+use std::fmt;
+use std::hash::{BuildHasher, Hash};
+use rand::Rng;
+
+const INITIAL_SIZE: usize = 4;
+// a table shrinks once less than this percentage of its buckets would be used
+const MIN_FILL_PERCENT: usize = 10;
+// empty buckets a rehash step may go through before it gives up
+const EMPTY_VISITS: usize = 10;
+
+type Bucket<K, V> = Vec<(u64, K, V)>;
+
+#[derive(Clone)]
+struct Table<K, V> {
+    buckets: Vec<Bucket<K, V>>,
+    used: usize,
+}
+
+impl<K, V> Table<K, V> {
+    fn with_size(size: usize) -> Self {
+        Table { buckets: std::iter::repeat_with(Vec::new).take(size).collect(), used: 0 }
+    }
+
+    fn empty() -> Self {
+        Table { buckets: vec![], used: 0 }
+    }
+
+    fn mask(&self) -> u64 {
+        (self.buckets.len() as u64).wrapping_sub(1)
+    }
+
+    fn bucket(&self, hash: u64) -> &Bucket<K, V> {
+        &self.buckets[(hash & self.mask()) as usize]
+    }
+
+    fn bucket_mut(&mut self, hash: u64) -> &mut Bucket<K, V> {
+        let mask = self.mask();
+        &mut self.buckets[(hash & mask) as usize]
+    }
+}
+
+/// A chained hash table that grows and shrinks by powers of two and rehashes incrementally, like dict.c of redis:
+/// while a resize is in progress both tables are live and every write moves a bucket from the old to the new one.
+/// This is what makes `scan` cursors stable across resizes.
+#[derive(Clone)]
+pub struct Dict<K, V> {
+    tables: [Table<K, V>; 2],
+    // next bucket of the first table to move to the second one, while rehashing
+    rehash_index: Option<usize>,
+    hasher: RandomState,
+}
+
+impl<K, V> Default for Dict<K, V> {
+    fn default() -> Self {
+        Dict { tables: [Table::empty(), Table::empty()], rehash_index: None, hasher: RandomState::new() }
+    }
+}
+
+impl<K: Hash + Eq, V> Dict<K, V> {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn len(&self) -> usize {
+        self.tables[0].used + self.tables[1].used
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    pub fn is_rehashing(&self) -> bool {
+        self.rehash_index.is_some()
+    }
+
+    fn live_tables(&self) -> usize {
+        if self.is_rehashing() { 2 } else { 1 }
+    }
+
+    /// Table and position in its bucket of `key`
+    fn find<Q>(&self, key: &Q) -> Option<(usize, u64, usize)>
+    where
+        K: Borrow<Q>,
+        Q: Hash + Eq + ?Sized,
+    {
+        if self.is_empty() {
+            return None;
+        }
+        let hash = self.hasher.hash_one(key);
+        (0..self.live_tables()).find_map(|table| {
+            let bucket = self.tables[table].bucket(hash);
+            let position = bucket.iter().position(|(h, k, _)| *h == hash && k.borrow() == key)?;
+            Some((table, hash, position))
+        })
+    }
+
+    pub fn get<Q>(&self, key: &Q) -> Option<&V>
+    where
+        K: Borrow<Q>,
+        Q: Hash + Eq + ?Sized,
+    {
+        let (table, hash, position) = self.find(key)?;
+        Some(&self.tables[table].bucket(hash)[position].2)
+    }
+
+    pub fn get_mut<Q>(&mut self, key: &Q) -> Option<&mut V>
+    where
+        K: Borrow<Q>,
+        Q: Hash + Eq + ?Sized,
+    {
+        self.rehash_step();
+        let (table, hash, position) = self.find(key)?;
+        Some(&mut self.tables[table].bucket_mut(hash)[position].2)
+    }
+
+    pub fn contains_key<Q>(&self, key: &Q) -> bool
+    where
+        K: Borrow<Q>,
+        Q: Hash + Eq + ?Sized,
+    {
+        self.find(key).is_some()
+    }
+
+    /// Sets the value of `key`, returns the one it replaced
+    pub fn insert(&mut self, key: K, value: V) -> Option<V> {
+        self.rehash_step();
+        if let Some((table, hash, position)) = self.find(&key) {
+            return Some(std::mem::replace(&mut self.tables[table].bucket_mut(hash)[position].2, value));
+        }
+        self.expand_if_needed();
+        let hash = self.hasher.hash_one(&key);
+        // new keys go to the new table while rehashing, so that the old one only ever empties
+        let table = &mut self.tables[self.live_tables() - 1];
+        table.bucket_mut(hash).push((hash, key, value));
+        table.used += 1;
+        None
+    }
+
+    pub fn remove<Q>(&mut self, key: &Q) -> Option<V>
+    where
+        K: Borrow<Q>,
+        Q: Hash + Eq + ?Sized,
+    {
+        self.rehash_step();
+        let (table, hash, position) = self.find(key)?;
+        let (_, _, value) = self.tables[table].bucket_mut(hash).swap_remove(position);
+        self.tables[table].used -= 1;
+        self.shrink_if_needed();
+        Some(value)
+    }
+
+    pub fn clear(&mut self) {
+        self.tables = [Table::empty(), Table::empty()];
+        self.rehash_index = None;
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = (&K, &V)> {
+        self.tables.iter().flat_map(|table| table.buckets.iter().flatten()).map(|(_, key, value)| (key, value))
+    }
+
+    pub fn keys(&self) -> impl Iterator<Item = &K> {
+        self.iter().map(|(key, _)| key)
+    }
+
+    pub fn values(&self) -> impl Iterator<Item = &V> {
+        self.iter().map(|(_, value)| value)
+    }
+
+    /// Visits the entries of the bucket at `cursor`, returns the cursor of the next bucket or 0 once done.
+    ///
+    /// The high bits of the cursor are incremented rather than the low ones: the buckets a bucket splits into
+    /// when the table grows, or merges with when it shrinks, come right after it in that order. So starting
+    /// over from 0 and passing along the returned cursor visits every entry present for the whole iteration,
+    /// whatever resizes happen between calls, although some entries may be visited more than once.
+    pub fn scan(&self, cursor: u64, mut visit: impl FnMut(&K, &V)) -> u64 {
+        if self.is_empty() {
+            return 0;
+        }
+        let mut visit_bucket = |table: &Table<K, V>, cursor: u64| {
+            for (_, key, value) in table.bucket(cursor) {
+                visit(key, value);
+            }
+        };
+        let next = |cursor: u64, mask: u64| (cursor | !mask).reverse_bits().wrapping_add(1).reverse_bits();
+
+        if !self.is_rehashing() {
+            let table = &self.tables[0];
+            visit_bucket(table, cursor);
+            return next(cursor, table.mask());
+        }
+        // the buckets of the larger table that the bucket of the smaller one expands to
+        let (small, large) = match self.tables[0].buckets.len() <= self.tables[1].buckets.len() {
+            true => (&self.tables[0], &self.tables[1]),
+            false => (&self.tables[1], &self.tables[0]),
+        };
+        visit_bucket(small, cursor);
+        let mut cursor = cursor;
+        loop {
+            visit_bucket(large, cursor);
+            cursor = next(cursor, large.mask());
+            if cursor & (small.mask() ^ large.mask()) == 0 {
+                return cursor;
+            }
+        }
+    }
+
+    /// A random entry, like dictGetRandomKey of redis: random buckets are tried until a non empty one comes up,
+    /// then one entry of its chain is picked. The table is kept at least a tenth full and chains stay short,
+    /// so this takes a few tries at most, although entries sharing a bucket are a bit less likely.
+    pub fn random(&self, rng: &mut impl Rng) -> Option<(&K, &V)> {
+        if self.is_empty() {
+            return None;
+        }
+        // the buckets of the old table before `rehash_index` were moved already
+        let start = self.rehash_index.unwrap_or(0);
+        let first = self.tables[0].buckets.len();
+        let total = first + self.tables[1].buckets.len();
+        loop {
+            let index = rng.gen_range(start..total);
+            let bucket = match index < first {
+                true => &self.tables[0].buckets[index],
+                false => &self.tables[1].buckets[index - first],
+            };
+            if !bucket.is_empty() {
+                let (_, key, value) = &bucket[rng.gen_range(0..bucket.len())];
+                return Some((key, value));
+            }
+        }
+    }
+
+    /// Moves one bucket of the old table to the new one
+    fn rehash_step(&mut self) {
+        let Some(mut index) = self.rehash_index else {
+            return;
+        };
+        let mut empty_visits = EMPTY_VISITS;
+        while self.tables[0].used > 0 && index < self.tables[0].buckets.len() {
+            let bucket = std::mem::take(&mut self.tables[0].buckets[index]);
+            index += 1;
+            if bucket.is_empty() {
+                empty_visits -= 1;
+                if empty_visits == 0 {
+                    break;
+                }
+                continue;
+            }
+            self.tables[0].used -= bucket.len();
+            self.tables[1].used += bucket.len();
+            for entry in bucket {
+                self.tables[1].bucket_mut(entry.0).push(entry);
+            }
+            break;
+        }
+        if self.tables[0].used == 0 {
+            self.tables[0] = std::mem::replace(&mut self.tables[1], Table::empty());
+            self.rehash_index = None;
+        } else {
+            self.rehash_index = Some(index);
+        }
+    }
+
+    fn resize(&mut self, size: usize) {
+        self.tables[1] = Table::with_size(size);
+        self.rehash_index = Some(0);
+    }
+
+    fn expand_if_needed(&mut self) {
+        if self.is_rehashing() {
+            return;
+        }
+        let table = &self.tables[0];
+        if table.buckets.is_empty() {
+            self.tables[0] = Table::with_size(INITIAL_SIZE);
+        } else if table.used >= table.buckets.len() {
+            self.resize((table.used + 1).next_power_of_two());
+        }
+    }
+
+    fn shrink_if_needed(&mut self) {
+        let table = &self.tables[0];
+        if self.is_rehashing() || table.buckets.len() <= INITIAL_SIZE || table.used * 100 / table.buckets.len() >= MIN_FILL_PERCENT {
+            return;
+        }
+        self.resize(table.used.next_power_of_two().max(INITIAL_SIZE));
+    }
+}
+
+impl<K: Hash + Eq, V: PartialEq> PartialEq for Dict<K, V> {
+    fn eq(&self, other: &Self) -> bool {
+        self.len() == other.len() && self.iter().all(|(key, value)| other.get(key) == Some(value))
+    }
+}
+
+impl<K: Hash + Eq + fmt::Debug, V: fmt::Debug> fmt::Debug for Dict<K, V> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_map().entries(self.iter()).finish()
+    }
+}
+
+impl<K: Hash + Eq, V> FromIterator<(K, V)> for Dict<K, V> {
+    fn from_iter<I: IntoIterator<Item = (K, V)>>(iter: I) -> Self {
+        let mut dict = Dict::new();
+        for (key, value) in iter {
+            dict.insert(key, value);
+        }
+        dict
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::HashSet;
+
+    /// Scans `dict` to the end, calling `between` on it after each step
+    fn scan_all(dict: &mut Dict<u32, ()>, mut between: impl FnMut(&mut Dict<u32, ()>)) -> HashSet<u32> {
+        let mut seen = HashSet::new();
+        let mut cursor = 0;
+        loop {
+            cursor = dict.scan(cursor, |key, _| {
+                seen.insert(*key);
+            });
+            if cursor == 0 {
+                return seen;
+            }
+            between(dict);
+        }
+    }
+
+    #[test]
+    fn grows_shrinks_and_keeps_entries() {
+        let mut dict: Dict<u32, u32> = (0..1000).map(|i| (i, i * 2)).collect();
+        assert_eq!(dict.len(), 1000);
+        assert_eq!(dict.get(&500), Some(&1000));
+        assert_eq!(dict.insert(500, 1), Some(1000));
+        *dict.get_mut(&501).unwrap() = 7;
+        assert_eq!(dict.get(&501), Some(&7));
+        for i in 0..990 {
+            assert!(dict.remove(&i).is_some());
+        }
+        assert_eq!(dict.remove(&0), None);
+        assert_eq!(dict.len(), 10);
+        assert_eq!(dict.iter().count(), 10);
+        // writes keep the rehashing going until the table is small again
+        for i in 0..100 {
+            dict.insert(2000 + i, 0);
+            dict.remove(&(2000 + i));
+        }
+        assert!(dict.tables[0].buckets.len() <= 16 && !dict.is_rehashing());
+        assert_eq!(dict, (990..1000).map(|i| (i, i * 2)).collect());
+    }
+
+    #[test]
+    fn random_picks_any_entry() {
+        let mut rng = rand::thread_rng();
+        assert!(Dict::<u32, ()>::new().random(&mut rng).is_none());
+        let mut dict: Dict<u32, ()> = (0..2000).map(|i| (i, ())).collect();
+        // half way through a shrink, both tables hold entries
+        for i in 0..1900 {
+            dict.remove(&i);
+        }
+        assert!(dict.is_rehashing());
+        let picked: HashSet<u32> = (0..5000).map(|_| *dict.random(&mut rng).unwrap().0).collect();
+        assert_eq!(picked, (1900..2000).collect());
+    }
+
+    #[test]
+    fn scan_survives_resizes() {
+        let mut dict: Dict<u32, ()> = (0..500).map(|i| (i, ())).collect();
+        assert_eq!(scan_all(&mut dict, |_| {}), (0..500).collect());
+        assert_eq!(Dict::<u32, ()>::new().scan(0, |_, _| {}), 0);
+
+        // the keys present from start to end are all returned while the table grows underneath
+        let mut dict: Dict<u32, ()> = (0..100).map(|i| (i, ())).collect();
+        let mut next = 100;
+        let seen = scan_all(&mut dict, |dict| {
+            for _ in 0..20 {
+                if next < 3000 {
+                    dict.insert(next, ());
+                    next += 1;
+                }
+            }
+        });
+        assert_eq!(dict.len(), 3000);
+        assert!((0..100).all(|key| seen.contains(&key)));
+
+        // and while it shrinks
+        let mut dict: Dict<u32, ()> = (0..5000).map(|i| (i, ())).collect();
+        let mut next = 100;
+        let seen = scan_all(&mut dict, |dict| {
+            for _ in 0..50 {
+                if next < 5000 {
+                    dict.remove(&next);
+                    next += 1;
+                }
+            }
+        });
+        assert!(dict.len() < 1000);
+        assert!((0..100).all(|key| seen.contains(&key)));
+    }
+}
@@ -0,0 +1,108 @@
+// Active expiry, like activeExpireCycle of redis: reads only hide expired keys, so a background task walks the
+// keys that have a deadline and deletes the expired ones, working harder while it keeps finding many of them
+use std::time::{Duration, Instant};
+use bytes::Bytes;
+use crate::parser::RespValue;
+use super::{Database, DB};
+
+// how often a cycle runs, the default hz of redis
+const CYCLE_PERIOD: Duration = Duration::from_millis(100);
+// how long a cycle may hold the database, a quarter of the period like ACTIVE_EXPIRE_CYCLE_SLOW_TIME_PERC
+const CYCLE_BUDGET: Duration = Duration::from_millis(25);
+// keys looked at per round, like ACTIVE_EXPIRE_CYCLE_KEYS_PER_LOOP
+const KEYS_PER_ROUND: usize = 20;
+// buckets a round may go through to find them, when the table is sparse
+const BUCKETS_PER_ROUND: usize = KEYS_PER_ROUND * 20;
+// another round follows while more than this percentage of the keys looked at had expired
+const ACCEPTABLE_STALE_PERCENT: usize = 10;
+
+impl Database {
+    /// Deletes expired keys round after round, until a round finds few of them or `budget` is spent.
+    /// Returns the keys it deleted.
+    pub fn expire_cycle(&mut self, budget: Duration) -> Vec<Bytes> {
+        let start = Instant::now();
+        let now = chrono::Utc::now();
+        let mut deleted = vec![];
+        while !self.expires.is_empty() {
+            let mut sampled = 0;
+            let mut expired = vec![];
+            for _ in 0..BUCKETS_PER_ROUND {
+                self.expire_cursor = self.expires.scan(self.expire_cursor, |key, deadline| {
+                    sampled += 1;
+                    if *deadline <= now {
+                        expired.push(key.clone());
+                    }
+                });
+                if sampled >= KEYS_PER_ROUND || self.expire_cursor == 0 {
+                    break;
+                }
+            }
+            for key in &expired {
+                self.delete(key);
+            }
+            let stale = expired.len() * 100 > sampled * ACCEPTABLE_STALE_PERCENT;
+            deleted.extend(expired);
+            if !stale || start.elapsed() >= budget {
+                break;
+            }
+        }
+        deleted
+    }
+}
+
+/// Runs an expiry cycle every `CYCLE_PERIOD`, logging a DEL for every key it reclaims
+pub fn start_active_expire() {
+    tokio::spawn(async move {
+        let mut interval = tokio::time::interval(CYCLE_PERIOD);
+        loop {
+            interval.tick().await;
+            if DB.read().await.volatile_len() == 0 {
+                continue;
+            }
+            let mut db = DB.write().await;
+            for key in db.expire_cycle(CYCLE_BUDGET) {
+                if let Err(e) = db.append_log(&RespValue::bulk_array(["DEL".into(), key])).await {
+                    eprintln!("Error logging an expired key: {}", e);
+                }
+            }
+        }
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::engine::{Entry, MemoryEngine, Value};
+
+    #[test]
+    fn cycles_reclaim_expired_keys() {
+        let mut db = Database::new(Box::new(MemoryEngine::new()));
+        let past = chrono::Utc::now() - chrono::Duration::seconds(1);
+        let future = chrono::Utc::now() + chrono::Duration::seconds(100);
+        for i in 0..1000 {
+            let mut entry = Entry::new(Value::String("v".into()));
+            entry.expire_at = Some(if i % 10 == 0 { future } else { past });
+            db.put(Bytes::from(i.to_string()), entry);
+        }
+        db.put("persistent".into(), Entry::new(Value::String("v".into())));
+        assert_eq!(db.volatile_len(), 1000);
+
+        // most sampled keys are expired, so a single cycle goes on for many rounds
+        let deleted = db.expire_cycle(Duration::from_secs(10));
+        assert!(deleted.len() > 500, "{}", deleted.len());
+        // later cycles resume from where the previous ones stopped
+        for _ in 0..100 {
+            db.expire_cycle(Duration::from_secs(10));
+        }
+        assert_eq!(db.volatile_len(), 100);
+        assert_eq!(db.engine().len(), 101);
+
+        assert!(db.set_expire(&"persistent".into(), Some(past)));
+        // a write finds it expired and deletes it
+        assert!(db.get_mut(b"persistent").is_none());
+        assert_eq!(db.engine().len(), 100);
+        assert!(!db.set_expire(&"missing".into(), None));
+        db.flush();
+        assert_eq!(db.volatile_len(), 0);
+    }
+}
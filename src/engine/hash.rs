@@ -0,0 +1,139 @@
+use std::collections::HashMap;
+use bytes::Bytes;
+use super::{Dict, Timestamp};
+
+/// Fields of a hash, each of them may expire on its own.
+/// Expired fields stay until the next write purges them, reads skip them.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Hash {
+    fields: Dict<Bytes, Bytes>,
+    expires: HashMap<Bytes, Timestamp>,
+}
+
+impl Hash {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    fn is_expired(&self, field: &[u8], now: Timestamp) -> bool {
+        self.expires.get(field).is_some_and(|deadline| *deadline <= now)
+    }
+
+    pub fn get(&self, field: &[u8]) -> Option<&Bytes> {
+        let now = chrono::Utc::now();
+        self.fields.get(field).filter(|_| !self.is_expired(field, now))
+    }
+
+    pub fn contains(&self, field: &[u8]) -> bool {
+        self.get(field).is_some()
+    }
+
+    /// Sets the value of `field` and clears its expiration, returns whether it is a new field
+    pub fn insert(&mut self, field: Bytes, value: Bytes) -> bool {
+        self.expires.remove(&field);
+        self.fields.insert(field, value).is_none()
+    }
+
+    /// Sets the value of `field`, keeping its expiration
+    pub fn update(&mut self, field: Bytes, value: Bytes) {
+        self.fields.insert(field, value);
+    }
+
+    pub fn remove(&mut self, field: &[u8]) -> bool {
+        self.expires.remove(field);
+        self.fields.remove(field).is_some()
+    }
+
+    pub fn len(&self) -> usize {
+        let now = chrono::Utc::now();
+        let expired = self.expires.values().filter(|deadline| **deadline <= now).count();
+        self.fields.len() - expired
+    }
+
+    pub fn is_empty(&self) -> bool {
+        // a field without expiration is enough to keep the hash alive
+        if self.fields.len() > self.expires.len() {
+            return false;
+        }
+        let now = chrono::Utc::now();
+        self.expires.values().all(|deadline| *deadline <= now)
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = (&Bytes, &Bytes)> {
+        let now = chrono::Utc::now();
+        self.fields.iter().filter(move |(field, _)| !self.is_expired(field, now))
+    }
+
+    /// Visits the fields of one bucket that did not expire, see `Dict::scan` for the cursor
+    pub fn scan(&self, cursor: u64, mut visit: impl FnMut(&Bytes, &Bytes)) -> u64 {
+        let now = chrono::Utc::now();
+        self.fields.scan(cursor, |field, value| {
+            if !self.is_expired(field, now) {
+                visit(field, value);
+            }
+        })
+    }
+
+    /// Every field with its expiration, including the expired ones
+    pub fn entries(&self) -> impl Iterator<Item = (&Bytes, &Bytes, Option<Timestamp>)> {
+        self.fields.iter().map(|(field, value)| (field, value, self.expires.get(field).copied()))
+    }
+
+    pub fn expire_at(&self, field: &[u8]) -> Option<Timestamp> {
+        self.expires.get(field).copied()
+    }
+
+    pub fn set_expire(&mut self, field: &Bytes, deadline: Option<Timestamp>) {
+        match deadline {
+            Some(deadline) => self.expires.insert(field.clone(), deadline),
+            None => self.expires.remove(field),
+        };
+    }
+
+    /// Drops the expired fields, returns how many there were
+    pub fn purge_expired(&mut self) -> usize {
+        if self.expires.is_empty() {
+            return 0;
+        }
+        let now = chrono::Utc::now();
+        let expired: Vec<Bytes> = self.expires.iter().filter(|(_, deadline)| **deadline <= now).map(|(field, _)| field.clone()).collect();
+        for field in &expired {
+            self.remove(field);
+        }
+        expired.len()
+    }
+}
+
+impl FromIterator<(Bytes, Bytes, Option<Timestamp>)> for Hash {
+    fn from_iter<I: IntoIterator<Item = (Bytes, Bytes, Option<Timestamp>)>>(iter: I) -> Self {
+        let mut hash = Hash::new();
+        for (field, value, deadline) in iter {
+            hash.set_expire(&field, deadline);
+            hash.fields.insert(field, value);
+        }
+        hash
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn expired_fields_are_hidden() {
+        let mut hash = Hash::new();
+        assert!(hash.insert("a".into(), "1".into()));
+        assert!(hash.insert("b".into(), "2".into()));
+        assert!(!hash.insert("a".into(), "3".into()));
+
+        hash.set_expire(&"b".into(), Some(chrono::Utc::now() - chrono::Duration::seconds(1)));
+        assert_eq!(hash.len(), 1);
+        assert!(!hash.contains(b"b"));
+        assert_eq!(hash.iter().count(), 1);
+        assert_eq!(hash.entries().count(), 2);
+
+        assert_eq!(hash.purge_expired(), 1);
+        assert_eq!(hash.entries().count(), 1);
+        assert_eq!(hash.get(b"a"), Some(&Bytes::from("3")));
+    }
+}
@@ -0,0 +1,417 @@
+// HyperLogLog kept in a string value with the same layout as hyperloglog.c of redis, so that it survives
+// persistence as a plain string:
+// "HYLL" | encoding u8 | 3 unused bytes | cached cardinality u64 little endian, the top bit set when stale | registers
+// Dense registers pack 16384 counters of 6 bits, sparse ones are runs of opcodes:
+// ZERO 00xxxxxx (1 to 64 zeros), XZERO 01xxxxxx yyyyyyyy (1 to 16384 zeros), VAL 1vvvvvxx (1 to 4 times 1 to 32)
+use crate::error::{Error, Result};
+use crate::utils::murmur_hash64a;
+
+const P: u32 = 14;
+const REGISTERS: usize = 1 << P;
+// bits of a hash left to count the run of zeros in
+const Q: u32 = 64 - P;
+const BITS: usize = 6;
+const REGISTER_MAX: u8 = (1 << BITS) - 1;
+const HEADER_SIZE: usize = 16;
+// size of a dense HyperLogLog, 12 KB of registers after the header
+const DENSE_SIZE: usize = HEADER_SIZE + (REGISTERS * BITS).div_ceil(8);
+// a sparse HyperLogLog turns dense once it would take more bytes than this, like hll-sparse-max-bytes of redis
+const SPARSE_MAX_BYTES: usize = 3000;
+const MAGIC: &[u8] = b"HYLL";
+const DENSE: u8 = 0;
+const SPARSE: u8 = 1;
+const SEED: u64 = 0xadc8_3b19;
+const ALPHA_INF: f64 = 0.721_347_520_444_481_7;
+
+const VAL_MAX_VALUE: u8 = 32;
+const VAL_MAX_LEN: usize = 4;
+const ZERO_MAX_LEN: usize = 64;
+const XZERO_MAX_LEN: usize = 16384;
+
+fn invalid() -> Error {
+    Error::Other("WRONGTYPE Key is not a valid HyperLogLog string value.".into())
+}
+
+fn corrupted() -> Error {
+    Error::Other("INVALIDOBJ Corrupted HLL object detected".into())
+}
+
+/// The register an element falls in and the value it proposes: the length of the run of zeros of its hash plus one
+fn hash_element(element: &[u8]) -> (usize, u8) {
+    let hash = murmur_hash64a(element, SEED);
+    let index = (hash & (REGISTERS as u64 - 1)) as usize;
+    // the sentinel bit bounds the run at Q zeros
+    let rest = (hash >> P) | (1 << Q);
+    (index, rest.trailing_zeros() as u8 + 1)
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct HyperLogLog {
+    bytes: Vec<u8>,
+}
+
+impl Default for HyperLogLog {
+    fn default() -> Self {
+        let mut bytes = Self::header(SPARSE);
+        push_zeros(&mut bytes, REGISTERS);
+        HyperLogLog { bytes }
+    }
+}
+
+impl HyperLogLog {
+    pub const REGISTERS: usize = REGISTERS;
+    pub const DENSE_SIZE: usize = DENSE_SIZE;
+
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    fn header(encoding: u8) -> Vec<u8> {
+        let mut bytes = Vec::with_capacity(HEADER_SIZE);
+        bytes.extend_from_slice(MAGIC);
+        bytes.extend_from_slice(&[encoding, 0, 0, 0]);
+        bytes.extend_from_slice(&[0; 8]);
+        bytes
+    }
+
+    /// Checks that a string value holds a HyperLogLog
+    pub fn from_bytes(bytes: &[u8]) -> Result<Self> {
+        if bytes.len() < HEADER_SIZE || &bytes[..4] != MAGIC {
+            return Err(invalid());
+        }
+        let hll = HyperLogLog { bytes: bytes.to_vec() };
+        match bytes[4] {
+            DENSE if bytes.len() == DENSE_SIZE => Ok(hll),
+            SPARSE => {
+                hll.sparse_runs().ok_or_else(corrupted)?;
+                Ok(hll)
+            }
+            _ => Err(invalid()),
+        }
+    }
+
+    /// Builds a HyperLogLog out of registers, sparse if allowed and small enough
+    pub fn from_registers(registers: &[u8], sparse: bool) -> Self {
+        if sparse {
+            if let Some(hll) = Self::encode_sparse(registers) {
+                return hll;
+            }
+        }
+        let mut bytes = Self::header(DENSE);
+        bytes.resize(DENSE_SIZE, 0);
+        for (index, &value) in registers.iter().enumerate() {
+            set_dense(&mut bytes[HEADER_SIZE..], index, value);
+        }
+        let mut hll = HyperLogLog { bytes };
+        hll.invalidate_cache();
+        hll
+    }
+
+    pub fn into_bytes(self) -> Vec<u8> {
+        self.bytes
+    }
+
+    pub fn is_sparse(&self) -> bool {
+        self.bytes[4] == SPARSE
+    }
+
+    /// Runs of (value, length) of a sparse HyperLogLog, None if they do not cover exactly every register
+    fn sparse_runs(&self) -> Option<Vec<(u8, usize)>> {
+        let data = &self.bytes[HEADER_SIZE..];
+        let mut runs = Vec::new();
+        let (mut i, mut covered) = (0, 0);
+        while i < data.len() {
+            let op = data[i];
+            let run = match op >> 6 {
+                0b00 => (0, (op & 0x3f) as usize + 1),
+                0b01 => {
+                    let low = *data.get(i + 1)?;
+                    i += 1;
+                    (0, (((op & 0x3f) as usize) << 8 | low as usize) + 1)
+                }
+                _ => (((op >> 2) & 0x1f) + 1, (op & 0x3) as usize + 1),
+            };
+            i += 1;
+            covered += run.1;
+            runs.push(run);
+        }
+        (covered == REGISTERS).then_some(runs)
+    }
+
+    /// Every register, whatever the encoding
+    pub fn registers(&self) -> Vec<u8> {
+        if !self.is_sparse() {
+            let data = &self.bytes[HEADER_SIZE..];
+            return (0..REGISTERS).map(|index| get_dense(data, index)).collect();
+        }
+        let mut registers = Vec::with_capacity(REGISTERS);
+        for (value, len) in self.sparse_runs().expect("checked when loaded") {
+            registers.resize(registers.len() + len, value);
+        }
+        registers
+    }
+
+    /// Raises every register of `max` that is lower than the same one here
+    pub fn merge_into(&self, max: &mut [u8]) {
+        for (max, value) in max.iter_mut().zip(self.registers()) {
+            *max = (*max).max(value);
+        }
+    }
+
+    /// Sparse encoding of `registers`, None if it cannot hold them or takes more than `SPARSE_MAX_BYTES`
+    fn encode_sparse(registers: &[u8]) -> Option<Self> {
+        let mut bytes = Self::header(SPARSE);
+        let mut i = 0;
+        while i < registers.len() {
+            let value = registers[i];
+            let len = registers[i..].iter().take_while(|&&other| other == value).count();
+            if value == 0 {
+                push_zeros(&mut bytes, len);
+            } else if value > VAL_MAX_VALUE {
+                return None;
+            } else {
+                for chunk in (0..len).step_by(VAL_MAX_LEN) {
+                    let chunk = (len - chunk).min(VAL_MAX_LEN);
+                    bytes.push(0x80 | ((value - 1) << 2) | (chunk as u8 - 1));
+                }
+            }
+            if bytes.len() > SPARSE_MAX_BYTES {
+                return None;
+            }
+            i += len;
+        }
+        let mut hll = HyperLogLog { bytes };
+        hll.invalidate_cache();
+        Some(hll)
+    }
+
+    /// Switches to the dense encoding, false if it already was
+    pub fn to_dense(&mut self) -> bool {
+        if !self.is_sparse() {
+            return false;
+        }
+        let cache = self.bytes[8..HEADER_SIZE].to_vec();
+        *self = Self::from_registers(&self.registers(), false);
+        self.bytes[8..HEADER_SIZE].copy_from_slice(&cache);
+        true
+    }
+
+    /// Adds elements, true if that changed a register
+    pub fn add<'a>(&mut self, elements: impl IntoIterator<Item = &'a [u8]>) -> bool {
+        let mut changed = false;
+        if !self.is_sparse() {
+            let data = &mut self.bytes[HEADER_SIZE..];
+            for (index, count) in elements.into_iter().map(hash_element) {
+                if get_dense(data, index) < count {
+                    set_dense(data, index, count);
+                    changed = true;
+                }
+            }
+        } else {
+            // the sparse encoding is rewritten once for all the elements
+            let mut registers = self.registers();
+            for (index, count) in elements.into_iter().map(hash_element) {
+                if registers[index] < count {
+                    registers[index] = count;
+                    changed = true;
+                }
+            }
+            if changed {
+                // too large a value or too many runs for the sparse encoding makes it dense for good
+                *self = Self::from_registers(&registers, true);
+            }
+        }
+        if changed {
+            self.invalidate_cache();
+        }
+        changed
+    }
+
+    fn invalidate_cache(&mut self) {
+        self.bytes[15] |= 0x80;
+    }
+
+    /// The cardinality computed last time, None if the registers changed since
+    pub fn cached_count(&self) -> Option<u64> {
+        let card = u64::from_le_bytes(self.bytes[8..HEADER_SIZE].try_into().unwrap());
+        (card >> 63 == 0).then_some(card)
+    }
+
+    /// Estimates the cardinality and caches it
+    pub fn count(&mut self) -> u64 {
+        if let Some(count) = self.cached_count() {
+            return count;
+        }
+        let count = Self::estimate(&self.registers());
+        self.bytes[8..HEADER_SIZE].copy_from_slice(&count.to_le_bytes());
+        count
+    }
+
+    /// Cardinality estimated from registers, with the improved estimator redis uses
+    pub fn estimate(registers: &[u8]) -> u64 {
+        let mut histogram = [0u32; 64];
+        for &value in registers {
+            histogram[value as usize] += 1;
+        }
+        let m = REGISTERS as f64;
+        let mut z = m * tau((m - histogram[Q as usize + 1] as f64) / m);
+        for j in (1..=Q as usize).rev() {
+            z += histogram[j] as f64;
+            z *= 0.5;
+        }
+        z += m * sigma(histogram[0] as f64 / m);
+        (ALPHA_INF * m * m / z).round() as u64
+    }
+
+    /// The opcodes of a sparse HyperLogLog as PFDEBUG DECODE prints them
+    pub fn decode(&self) -> Option<String> {
+        if !self.is_sparse() {
+            return None;
+        }
+        let data = &self.bytes[HEADER_SIZE..];
+        let mut ops = Vec::new();
+        let mut i = 0;
+        while i < data.len() {
+            let op = data[i];
+            ops.push(match op >> 6 {
+                0b00 => format!("z:{}", (op & 0x3f) as usize + 1),
+                0b01 => {
+                    i += 1;
+                    format!("Z:{}", (((op & 0x3f) as usize) << 8 | data[i] as usize) + 1)
+                }
+                _ => format!("v:{},{}", ((op >> 2) & 0x1f) + 1, (op & 0x3) + 1),
+            });
+            i += 1;
+        }
+        Some(ops.join(" "))
+    }
+}
+
+/// Appends the ZERO and XZERO opcodes for `len` registers at zero
+fn push_zeros(bytes: &mut Vec<u8>, mut len: usize) {
+    while len > 0 {
+        let run = len.min(XZERO_MAX_LEN);
+        if run > ZERO_MAX_LEN {
+            bytes.push(0x40 | ((run - 1) >> 8) as u8);
+            bytes.push(((run - 1) & 0xff) as u8);
+        } else {
+            bytes.push((run - 1) as u8);
+        }
+        len -= run;
+    }
+}
+
+/// Register `index` of dense registers, which starts at bit index * 6 counting from the least significant bits
+fn get_dense(data: &[u8], index: usize) -> u8 {
+    let (byte, shift) = (index * BITS / 8, index * BITS % 8);
+    let mut value = data[byte] >> shift;
+    // the register spills over the next byte
+    if shift > 8 - BITS {
+        value |= data[byte + 1] << (8 - shift);
+    }
+    value & REGISTER_MAX
+}
+
+fn set_dense(data: &mut [u8], index: usize, value: u8) {
+    let (byte, shift) = (index * BITS / 8, index * BITS % 8);
+    data[byte] &= !(REGISTER_MAX << shift);
+    data[byte] |= value << shift;
+    // the register spills over the next byte
+    if shift > 8 - BITS {
+        data[byte + 1] &= !(REGISTER_MAX >> (8 - shift));
+        data[byte + 1] |= value >> (8 - shift);
+    }
+}
+
+/// sigma of "New cardinality estimation algorithms for HyperLogLog sketches" by Otmar Ertl
+fn sigma(mut x: f64) -> f64 {
+    if x == 1.0 {
+        return f64::INFINITY;
+    }
+    let (mut y, mut z) = (1.0, x);
+    loop {
+        x *= x;
+        let previous = z;
+        z += x * y;
+        y += y;
+        if z == previous {
+            return z;
+        }
+    }
+}
+
+/// tau of the same paper
+fn tau(mut x: f64) -> f64 {
+    if x == 0.0 || x == 1.0 {
+        return 0.0;
+    }
+    let (mut y, mut z) = (1.0, 1.0 - x);
+    loop {
+        x = x.sqrt();
+        let previous = z;
+        y *= 0.5;
+        z -= (1.0 - x).powi(2) * y;
+        if z == previous {
+            return z / 3.0;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn dense_registers_round_trip() {
+        let mut data = vec![0u8; DENSE_SIZE - HEADER_SIZE];
+        for index in 0..REGISTERS {
+            set_dense(&mut data, index, (index % 64) as u8);
+        }
+        assert!((0..REGISTERS).all(|index| get_dense(&data, index) == (index % 64) as u8));
+        set_dense(&mut data, 1, 0);
+        assert_eq!((get_dense(&data, 0), get_dense(&data, 1), get_dense(&data, 2)), (0, 0, 2));
+    }
+
+    #[test]
+    fn sparse_turns_dense_as_it_grows() -> Result<()> {
+        let mut hll = HyperLogLog::new();
+        assert_eq!(hll.decode().as_deref(), Some("Z:16384"));
+        assert_eq!(hll.count(), 0);
+        for i in 0..100 {
+            hll.add([format!("element:{}", i).as_bytes()]);
+        }
+        assert!(hll.is_sparse());
+        assert_eq!(hll.cached_count(), None);
+        let count = hll.count();
+        assert!((98..=102).contains(&count), "{}", count);
+        assert_eq!(HyperLogLog::from_bytes(&hll.clone().into_bytes())?, hll);
+
+        for i in 100..5000 {
+            hll.add([format!("element:{}", i).as_bytes()]);
+        }
+        assert!(!hll.is_sparse());
+        assert_eq!(hll.clone().into_bytes().len(), DENSE_SIZE);
+        let registers = hll.registers();
+        assert_eq!(HyperLogLog::from_registers(&registers, false).registers(), registers);
+        Ok(())
+    }
+
+    #[test]
+    fn standard_error() {
+        let mut hll = HyperLogLog::new();
+        for i in 0..200_000 {
+            hll.add([i.to_string().as_bytes()]);
+        }
+        let error = (hll.count() as f64 - 200_000.0).abs() / 200_000.0;
+        // three times the 0.81% standard error
+        assert!(error < 0.0243, "{}", error);
+    }
+
+    #[test]
+    fn rejects_other_strings() {
+        assert!(HyperLogLog::from_bytes(b"hello").is_err());
+        let mut bytes = HyperLogLog::new().into_bytes();
+        bytes.push(0);
+        assert_eq!(HyperLogLog::from_bytes(&bytes).unwrap_err().to_string(), "INVALIDOBJ Corrupted HLL object detected");
+    }
+}
@@ -0,0 +1,178 @@
+use std::sync::{Arc, Mutex};
+use anyhow::{anyhow, bail, Result};
+use bytes::Bytes;
+use super::{Dict, Engine, Entry, LogManager, SyncJob};
+
+#[derive(Debug, Default, Clone)]
+pub struct MemoryEngine {
+    map: Dict<Bytes, Entry>,
+}
+
+impl MemoryEngine {
+    pub fn new() -> Self {
+        Self::default()
+    }
+}
+
+impl Engine for MemoryEngine {
+    fn get(&self, key: &[u8]) -> Option<&Entry> {
+        self.map.get(key)
+    }
+
+    fn get_mut(&mut self, key: &[u8]) -> Option<&mut Entry> {
+        self.map.get_mut(key)
+    }
+
+    fn put(&mut self, key: Bytes, entry: Entry) -> Option<Entry> {
+        self.map.insert(key, entry)
+    }
+
+    fn delete(&mut self, key: &[u8]) -> Option<Entry> {
+        self.map.remove(key)
+    }
+
+    fn scan(&self) -> Box<dyn Iterator<Item = (&Bytes, &Entry)> + '_> {
+        Box::new(self.map.iter())
+    }
+
+    fn scan_from(&self, cursor: u64, visit: &mut dyn FnMut(&Bytes, &Entry)) -> u64 {
+        self.map.scan(cursor, visit)
+    }
+
+    fn random(&self) -> Option<(&Bytes, &Entry)> {
+        self.map.random(&mut rand::thread_rng())
+    }
+
+    fn snapshot(&self) -> Vec<(Bytes, Entry)> {
+        self.map.iter().map(|(k, v)| (k.clone(), v.clone())).collect()
+    }
+
+    fn len(&self) -> usize {
+        self.map.len()
+    }
+
+    fn clear(&mut self) {
+        self.map.clear();
+    }
+
+    fn take(&mut self) -> Box<dyn Engine> {
+        Box::new(std::mem::take(self))
+    }
+}
+
+#[derive(Debug, Default)]
+pub struct MemoryLog {
+    records: Vec<Bytes>,
+    size: u64,
+    base_size: u64,
+    // the baseline being written and the index of the first record appended after it was started
+    rewrite: Option<(Arc<Mutex<Vec<u8>>>, usize)>,
+}
+
+struct SharedWriter(Arc<Mutex<Vec<u8>>>);
+
+impl std::io::Write for SharedWriter {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        self.0.lock().unwrap().extend_from_slice(buf);
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        Ok(())
+    }
+}
+
+impl MemoryLog {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn records(&self) -> &[Bytes] {
+        &self.records
+    }
+}
+
+impl LogManager for MemoryLog {
+    fn append(&mut self, record: &[u8]) -> Result<()> {
+        self.size += record.len() as u64;
+        self.records.push(Bytes::copy_from_slice(record));
+        Ok(())
+    }
+
+    fn sync(&mut self) -> Result<SyncJob> {
+        Ok(Box::new(|| Ok(())))
+    }
+
+    fn size(&self) -> u64 {
+        self.size
+    }
+
+    fn base_size(&self) -> u64 {
+        self.base_size
+    }
+
+    fn start_rewrite(&mut self) -> Result<Box<dyn std::io::Write + Send>> {
+        if self.rewrite.is_some() {
+            bail!("rewrite already in progress");
+        }
+        let baseline = Arc::new(Mutex::new(Vec::new()));
+        self.rewrite = Some((baseline.clone(), self.records.len()));
+        Ok(Box::new(SharedWriter(baseline)))
+    }
+
+    fn flush_rewrite(&mut self) -> Result<SyncJob> {
+        Ok(Box::new(|| Ok(())))
+    }
+
+    fn finish_rewrite(&mut self) -> Result<SyncJob> {
+        let (baseline, start) = self.rewrite.take().ok_or(anyhow!("no rewrite in progress"))?;
+        let baseline = Bytes::from(std::mem::take(&mut *baseline.lock().unwrap()));
+        let tail = self.records.split_off(start);
+
+        self.base_size = baseline.len() as u64;
+        self.records = vec![baseline];
+        self.records.extend(tail);
+        self.size = self.records.iter().map(|r| r.len() as u64).sum();
+        Ok(Box::new(|| Ok(())))
+    }
+
+    fn abort_rewrite(&mut self) {
+        self.rewrite = None;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::engine::{Database, Value};
+    use crate::parser::RespValue;
+
+    #[test]
+    fn put_get_delete() {
+        let mut engine = MemoryEngine::new();
+        assert!(engine.put("k".into(), Entry::new(Value::String("v".into()))).is_none());
+        assert_eq!(engine.get(b"k"), Some(&Entry::new(Value::String("v".into()))));
+        assert_eq!(engine.snapshot().len(), 1);
+        assert_eq!(engine.random().map(|(key, _)| key.clone()), Some(Bytes::from("k")));
+        assert!(engine.delete(b"k").is_some());
+        assert!(engine.is_empty());
+        assert!(engine.random().is_none());
+    }
+
+    #[tokio::test]
+    async fn database_hides_expired_entries_and_logs_writes() {
+        let mut db = Database::new(Box::new(MemoryEngine::new()));
+        db.set_log(Some(Box::new(MemoryLog::new())));
+
+        let mut entry = Entry::new(Value::String("v".into()));
+        entry.expire_at = Some(chrono::Utc::now() - chrono::Duration::seconds(1));
+        db.put("k".into(), entry);
+        assert!(db.get(b"k").is_none());
+        assert_eq!(db.engine().len(), 1);
+
+        let record = RespValue::bulk_array(["SET".into(), "k".into(), "v".into()]);
+        db.append_log(&record).await.unwrap();
+        let log = db.set_log(None).unwrap();
+        assert_eq!(log.size(), record.encoded_len() as u64);
+    }
+}
@@ -1,3 +1,356 @@
 // We defines the traits here
 // 首先, 这里需要两个层级, 可用来读取基线数据的engine, 以及一个支持灵活插入操作日志的日志管理层
-// 
\ No newline at end of file
+pub mod aof;
+mod dict;
+pub mod expire;
+mod hash;
+mod hyperloglog;
+mod memory;
+mod set;
+mod skiplist;
+pub mod snapshot;
+mod stream;
+mod zset;
+
+use std::{collections::VecDeque, sync::LazyLock};
+use anyhow::Result;
+use bytes::Bytes;
+use tokio::sync::RwLock;
+use crate::{error::Error, parser::RespValue};
+
+pub use dict::Dict;
+pub use hash::Hash;
+pub use hyperloglog::HyperLogLog;
+pub use memory::{MemoryEngine, MemoryLog};
+pub use set::{IntSet, Set, MAX_INTSET_ENTRIES};
+pub use skiplist::{LexBound, LexRange, ScoreBound, ScoreRange};
+pub use stream::{Consumer, ConsumerGroup, Fields, PendingEntry, Stream, StreamId};
+pub use zset::ZSet;
+
+pub type Timestamp = chrono::DateTime<chrono::Utc>;
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Value {
+    String(Bytes),
+    List(VecDeque<Bytes>),
+    Hash(Hash),
+    Set(Set),
+    ZSet(ZSet),
+    Stream(Stream),
+}
+
+impl Value {
+    /// The name TYPE replies with
+    pub fn type_name(&self) -> &'static str {
+        match self {
+            Value::String(_) => "string",
+            Value::List(_) => "list",
+            Value::Hash(_) => "hash",
+            Value::Set(_) => "set",
+            Value::ZSet(_) => "zset",
+            Value::Stream(_) => "stream",
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Entry {
+    pub value: Value,
+    pub expire_at: Option<Timestamp>,
+}
+
+impl Entry {
+    pub fn new(value: Value) -> Self {
+        Entry {
+            value,
+            expire_at: None,
+        }
+    }
+
+    /// Whether the deadline passed, or for a hash whether every field expired, either way the key is gone
+    pub fn is_expired(&self, now: Timestamp) -> bool {
+        self.expire_at.is_some_and(|deadline| deadline <= now) || matches!(&self.value, Value::Hash(hash) if hash.is_empty())
+    }
+
+    pub fn as_string(&self) -> crate::error::Result<&Bytes> {
+        match &self.value {
+            Value::String(s) => Ok(s),
+            _ => Err(Error::WrongType),
+        }
+    }
+
+    pub fn as_string_mut(&mut self) -> crate::error::Result<&mut Bytes> {
+        match &mut self.value {
+            Value::String(s) => Ok(s),
+            _ => Err(Error::WrongType),
+        }
+    }
+
+    pub fn as_list(&self) -> crate::error::Result<&VecDeque<Bytes>> {
+        match &self.value {
+            Value::List(list) => Ok(list),
+            _ => Err(Error::WrongType),
+        }
+    }
+
+    pub fn as_list_mut(&mut self) -> crate::error::Result<&mut VecDeque<Bytes>> {
+        match &mut self.value {
+            Value::List(list) => Ok(list),
+            _ => Err(Error::WrongType),
+        }
+    }
+
+    pub fn as_hash(&self) -> crate::error::Result<&Hash> {
+        match &self.value {
+            Value::Hash(hash) => Ok(hash),
+            _ => Err(Error::WrongType),
+        }
+    }
+
+    pub fn as_hash_mut(&mut self) -> crate::error::Result<&mut Hash> {
+        match &mut self.value {
+            Value::Hash(hash) => Ok(hash),
+            _ => Err(Error::WrongType),
+        }
+    }
+
+    pub fn as_set(&self) -> crate::error::Result<&Set> {
+        match &self.value {
+            Value::Set(set) => Ok(set),
+            _ => Err(Error::WrongType),
+        }
+    }
+
+    pub fn as_set_mut(&mut self) -> crate::error::Result<&mut Set> {
+        match &mut self.value {
+            Value::Set(set) => Ok(set),
+            _ => Err(Error::WrongType),
+        }
+    }
+
+    pub fn as_zset(&self) -> crate::error::Result<&ZSet> {
+        match &self.value {
+            Value::ZSet(zset) => Ok(zset),
+            _ => Err(Error::WrongType),
+        }
+    }
+
+    pub fn as_zset_mut(&mut self) -> crate::error::Result<&mut ZSet> {
+        match &mut self.value {
+            Value::ZSet(zset) => Ok(zset),
+            _ => Err(Error::WrongType),
+        }
+    }
+
+    pub fn as_stream(&self) -> crate::error::Result<&Stream> {
+        match &self.value {
+            Value::Stream(stream) => Ok(stream),
+            _ => Err(Error::WrongType),
+        }
+    }
+
+    pub fn as_stream_mut(&mut self) -> crate::error::Result<&mut Stream> {
+        match &mut self.value {
+            Value::Stream(stream) => Ok(stream),
+            _ => Err(Error::WrongType),
+        }
+    }
+}
+
+/// The baseline layer: a keyspace that can be read, modified and copied.
+pub trait Engine: Send + Sync {
+    fn get(&self, key: &[u8]) -> Option<&Entry>;
+    fn get_mut(&mut self, key: &[u8]) -> Option<&mut Entry>;
+    fn put(&mut self, key: Bytes, entry: Entry) -> Option<Entry>;
+    fn delete(&mut self, key: &[u8]) -> Option<Entry>;
+    fn scan(&self) -> Box<dyn Iterator<Item = (&Bytes, &Entry)> + '_>;
+    /// Visits the entries of one bucket of the keyspace, see `Dict::scan` for the cursor
+    fn scan_from(&self, cursor: u64, visit: &mut dyn FnMut(&Bytes, &Entry)) -> u64;
+    /// A random entry, expired ones included, `None` only if the keyspace is empty
+    fn random(&self) -> Option<(&Bytes, &Entry)>;
+    /// A point-in-time copy of every entry, detached from later writes
+    fn snapshot(&self) -> Vec<(Bytes, Entry)>;
+    fn len(&self) -> usize;
+    fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+    fn clear(&mut self);
+    /// Empties the keyspace and hands over what it held, so that it can be dropped elsewhere
+    fn take(&mut self) -> Box<dyn Engine>;
+}
+
+/// Slow work of the log, such as an fsync, handed out to run on a blocking thread without the database lock
+pub type SyncJob = Box<dyn FnOnce() -> Result<()> + Send>;
+
+/// The log layer: an ordered list of write operations, each one the RESP encoded array of a command.
+pub trait LogManager: Send + Sync {
+    fn append(&mut self, record: &[u8]) -> Result<()>;
+    /// Makes what was appended so far durable, once the job ran
+    fn sync(&mut self) -> Result<SyncJob>;
+    /// Size of the log in bytes
+    fn size(&self) -> u64;
+    /// Size of the log right after it was opened or last rewritten
+    fn base_size(&self) -> u64;
+
+    /// Starts folding the log into a new baseline. The caller writes commands rebuilding the current
+    /// state into the returned writer, while records appended meanwhile are kept aside.
+    fn start_rewrite(&mut self) -> Result<Box<dyn std::io::Write + Send>>;
+    /// Hands the records kept aside so far to the job, which writes them after the baseline and syncs it all.
+    /// Records appended meanwhile are still kept aside, so that only the last few are left for `finish_rewrite`.
+    fn flush_rewrite(&mut self) -> Result<SyncJob>;
+    /// Replaces the log with the new baseline followed by the records still kept aside, the job syncs the result
+    fn finish_rewrite(&mut self) -> Result<SyncJob>;
+    fn abort_rewrite(&mut self);
+}
+
+pub struct Database {
+    engine: Box<dyn Engine>,
+    // deadline of every key that has one, what active expiry walks through
+    expires: Dict<Bytes, Timestamp>,
+    // where active expiry resumes walking `expires`
+    expire_cursor: u64,
+    log: Option<Box<dyn LogManager>>,
+    // number of writes since the last snapshot
+    dirty: u64,
+}
+
+pub static DB: LazyLock<RwLock<Database>> = LazyLock::new(|| {
+    RwLock::new(Database::new(Box::new(MemoryEngine::new())))
+});
+
+impl Database {
+    pub fn new(engine: Box<dyn Engine>) -> Self {
+        Database {
+            engine,
+            expires: Dict::new(),
+            expire_cursor: 0,
+            log: None,
+            dirty: 0,
+        }
+    }
+
+    pub fn set_log(&mut self, log: Option<Box<dyn LogManager>>) -> Option<Box<dyn LogManager>> {
+        std::mem::replace(&mut self.log, log)
+    }
+
+    pub fn engine(&self) -> &dyn Engine {
+        self.engine.as_ref()
+    }
+
+    pub fn engine_mut(&mut self) -> &mut dyn Engine {
+        self.engine.as_mut()
+    }
+
+    /// Returns the entry of `key` unless it is missing or expired
+    pub fn get(&self, key: &[u8]) -> Option<&Entry> {
+        let now = chrono::Utc::now();
+        self.engine.get(key).filter(|entry| !entry.is_expired(now))
+    }
+
+    /// Same as `get` for writing, an expired entry gets deleted on the way.
+    /// Reads only hide expired entries since they cannot write, active expiry reclaims those.
+    pub fn get_mut(&mut self, key: &[u8]) -> Option<&mut Entry> {
+        let now = chrono::Utc::now();
+        if self.engine.get(key).is_some_and(|entry| entry.is_expired(now)) {
+            self.delete(key);
+            return None;
+        }
+        self.engine.get_mut(key)
+    }
+
+    pub fn put(&mut self, key: Bytes, entry: Entry) -> Option<Entry> {
+        match entry.expire_at {
+            Some(deadline) => self.expires.insert(key.clone(), deadline),
+            None => self.expires.remove(&key),
+        };
+        self.engine.put(key, entry)
+    }
+
+    pub fn delete(&mut self, key: &[u8]) -> Option<Entry> {
+        self.expires.remove(key);
+        self.engine.delete(key)
+    }
+
+    /// Sets or clears the deadline of `key`, returns whether the key exists
+    pub fn set_expire(&mut self, key: &Bytes, deadline: Option<Timestamp>) -> bool {
+        let Some(entry) = self.get_mut(key) else {
+            return false;
+        };
+        entry.expire_at = deadline;
+        match deadline {
+            Some(deadline) => self.expires.insert(key.clone(), deadline),
+            None => self.expires.remove(key),
+        };
+        true
+    }
+
+    /// Number of keys with a deadline, expired ones included until they are reclaimed
+    pub fn volatile_len(&self) -> usize {
+        self.expires.len()
+    }
+
+    /// Empties the keyspace, handing over what it held so that it can be dropped elsewhere
+    pub fn flush(&mut self) -> Box<dyn Engine> {
+        self.expires.clear();
+        self.expire_cursor = 0;
+        self.engine.take()
+    }
+
+    /// Records a successful write so that it can be replayed later
+    pub async fn append_log(&mut self, record: &RespValue) -> Result<()> {
+        self.dirty += 1;
+        if let Some(log) = self.log.as_mut() {
+            let mut buf = Vec::with_capacity(record.encoded_len());
+            record.write(&mut buf).await?;
+            log.append(&buf)?;
+        }
+        Ok(())
+    }
+
+    pub fn dirty(&self) -> u64 {
+        self.dirty
+    }
+
+    /// Forgets the `saved` writes that a finished snapshot already contains
+    pub fn mark_saved(&mut self, saved: u64) {
+        self.dirty = self.dirty.saturating_sub(saved);
+    }
+
+    pub fn log(&self) -> Option<&dyn LogManager> {
+        self.log.as_deref()
+    }
+
+    pub fn start_rewrite(&mut self) -> Result<Box<dyn std::io::Write + Send>> {
+        match self.log.as_mut() {
+            Some(log) => log.start_rewrite(),
+            None => Err(anyhow::anyhow!("append only file is not enabled")),
+        }
+    }
+
+    pub fn flush_rewrite(&mut self) -> Result<SyncJob> {
+        match self.log.as_mut() {
+            Some(log) => log.flush_rewrite(),
+            None => Err(anyhow::anyhow!("append only file is not enabled")),
+        }
+    }
+
+    pub fn finish_rewrite(&mut self) -> Result<SyncJob> {
+        match self.log.as_mut() {
+            Some(log) => log.finish_rewrite(),
+            None => Err(anyhow::anyhow!("append only file is not enabled")),
+        }
+    }
+
+    pub fn abort_rewrite(&mut self) {
+        if let Some(log) = self.log.as_mut() {
+            log.abort_rewrite();
+        }
+    }
+
+    pub fn sync_log(&mut self) -> Result<SyncJob> {
+        match self.log.as_mut() {
+            Some(log) => log.sync(),
+            None => Ok(Box::new(|| Ok(()))),
+        }
+    }
+}
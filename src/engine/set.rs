@@ -0,0 +1,275 @@
+use bytes::Bytes;
+use rand::Rng;
+use super::dict::Dict;
+
+/// Sets made of integers only stay an `IntSet` up to this many members, like set-max-intset-entries of redis
+pub const MAX_INTSET_ENTRIES: usize = 512;
+
+/// Sorted integers packed with the smallest width that fits them all: 2, 4 or 8 bytes, like intset.c of redis
+#[derive(Debug, Clone, PartialEq)]
+pub struct IntSet {
+    width: usize,
+    data: Vec<u8>,
+}
+
+impl Default for IntSet {
+    fn default() -> Self {
+        IntSet { width: 2, data: Vec::new() }
+    }
+}
+
+fn width_of(v: i64) -> usize {
+    if i16::try_from(v).is_ok() {
+        2
+    } else if i32::try_from(v).is_ok() {
+        4
+    } else {
+        8
+    }
+}
+
+impl IntSet {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// The integer a member stands for, only if it is written the canonical way: "12" but neither "012" nor "+12"
+    pub fn parse(member: &[u8]) -> Option<i64> {
+        let v = std::str::from_utf8(member).ok()?.parse::<i64>().ok()?;
+        (itoa::Buffer::new().format(v).as_bytes() == member).then_some(v)
+    }
+
+    pub fn len(&self) -> usize {
+        self.data.len() / self.width
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.data.is_empty()
+    }
+
+    pub fn width(&self) -> usize {
+        self.width
+    }
+
+    pub fn get(&self, index: usize) -> i64 {
+        let bytes = &self.data[index * self.width..(index + 1) * self.width];
+        match self.width {
+            2 => i16::from_le_bytes(bytes.try_into().unwrap()) as i64,
+            4 => i32::from_le_bytes(bytes.try_into().unwrap()) as i64,
+            _ => i64::from_le_bytes(bytes.try_into().unwrap()),
+        }
+    }
+
+    fn encode(v: i64, width: usize) -> Vec<u8> {
+        match width {
+            2 => (v as i16).to_le_bytes().to_vec(),
+            4 => (v as i32).to_le_bytes().to_vec(),
+            _ => v.to_le_bytes().to_vec(),
+        }
+    }
+
+    fn search(&self, v: i64) -> std::result::Result<usize, usize> {
+        if width_of(v) > self.width {
+            // out of the range of every member: before all of them or after all of them
+            return Err(if v < 0 { 0 } else { self.len() });
+        }
+        let (mut low, mut high) = (0, self.len());
+        while low < high {
+            let mid = (low + high) / 2;
+            match self.get(mid).cmp(&v) {
+                std::cmp::Ordering::Less => low = mid + 1,
+                std::cmp::Ordering::Greater => high = mid,
+                std::cmp::Ordering::Equal => return Ok(mid),
+            }
+        }
+        Err(low)
+    }
+
+    pub fn contains(&self, v: i64) -> bool {
+        self.search(v).is_ok()
+    }
+
+    /// Inserts `v`, widening every member first if it does not fit the current width
+    pub fn insert(&mut self, v: i64) -> bool {
+        let width = width_of(v);
+        if width > self.width {
+            let mut data = Vec::with_capacity((self.len() + 1) * width);
+            for i in 0..self.len() {
+                data.extend(Self::encode(self.get(i), width));
+            }
+            self.data = data;
+            self.width = width;
+        }
+        match self.search(v) {
+            Ok(_) => false,
+            Err(index) => {
+                let at = index * self.width;
+                self.data.splice(at..at, Self::encode(v, self.width));
+                true
+            }
+        }
+    }
+
+    pub fn remove(&mut self, v: i64) -> bool {
+        match self.search(v) {
+            Ok(index) => {
+                self.data.drain(index * self.width..(index + 1) * self.width);
+                true
+            }
+            Err(_) => false,
+        }
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = i64> + '_ {
+        (0..self.len()).map(|i| self.get(i))
+    }
+}
+
+fn member_of(v: i64) -> Bytes {
+    Bytes::copy_from_slice(itoa::Buffer::new().format(v).as_bytes())
+}
+
+/// An unordered set of members, kept as an `IntSet` while it only holds a few integers
+#[derive(Debug, Clone, PartialEq)]
+pub enum Set {
+    Ints(IntSet),
+    Members(Dict<Bytes, ()>),
+}
+
+impl Default for Set {
+    fn default() -> Self {
+        Set::Ints(IntSet::new())
+    }
+}
+
+impl Set {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn len(&self) -> usize {
+        match self {
+            Set::Ints(ints) => ints.len(),
+            Set::Members(members) => members.len(),
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    pub fn contains(&self, member: &[u8]) -> bool {
+        match self {
+            Set::Ints(ints) => IntSet::parse(member).is_some_and(|v| ints.contains(v)),
+            Set::Members(members) => members.contains_key(member),
+        }
+    }
+
+    pub fn insert(&mut self, member: Bytes) -> bool {
+        if let Set::Ints(ints) = self {
+            match IntSet::parse(&member) {
+                Some(v) if ints.contains(v) => return false,
+                Some(v) if ints.len() < MAX_INTSET_ENTRIES => return ints.insert(v),
+                _ => self.convert(),
+            }
+        }
+        match self {
+            Set::Members(members) => members.insert(member, ()).is_none(),
+            Set::Ints(_) => unreachable!("converted above"),
+        }
+    }
+
+    pub fn remove(&mut self, member: &[u8]) -> bool {
+        match self {
+            Set::Ints(ints) => IntSet::parse(member).is_some_and(|v| ints.remove(v)),
+            Set::Members(members) => members.remove(member).is_some(),
+        }
+    }
+
+    fn convert(&mut self) {
+        if let Set::Ints(ints) = self {
+            *self = Set::Members(ints.iter().map(|v| (member_of(v), ())).collect());
+        }
+    }
+
+    pub fn iter(&self) -> Box<dyn Iterator<Item = Bytes> + '_> {
+        match self {
+            Set::Ints(ints) => Box::new(ints.iter().map(member_of)),
+            Set::Members(members) => Box::new(members.keys().cloned()),
+        }
+    }
+
+    /// A random member, without going through the others
+    pub fn random(&self, rng: &mut impl Rng) -> Option<Bytes> {
+        match self {
+            Set::Ints(ints) if ints.is_empty() => None,
+            Set::Ints(ints) => Some(member_of(ints.get(rng.gen_range(0..ints.len())))),
+            Set::Members(members) => members.random(rng).map(|(member, _)| member.clone()),
+        }
+    }
+
+    /// Visits the members of one bucket, see `Dict::scan` for the cursor.
+    /// An intset has no buckets, all its members are visited at once like redis does.
+    pub fn scan(&self, cursor: u64, mut visit: impl FnMut(Bytes)) -> u64 {
+        match self {
+            Set::Ints(ints) => {
+                ints.iter().map(member_of).for_each(visit);
+                0
+            }
+            Set::Members(members) => members.scan(cursor, |member, _| visit(member.clone())),
+        }
+    }
+}
+
+impl FromIterator<Bytes> for Set {
+    fn from_iter<I: IntoIterator<Item = Bytes>>(iter: I) -> Self {
+        let mut set = Set::new();
+        for member in iter {
+            set.insert(member);
+        }
+        set
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn intset_widens_and_stays_sorted() {
+        let mut ints = IntSet::new();
+        for v in [5, -3, 300, 0] {
+            assert!(ints.insert(v));
+        }
+        assert!(!ints.insert(5));
+        assert_eq!(ints.width(), 2);
+        assert!(ints.insert(70_000));
+        assert_eq!(ints.width(), 4);
+        assert!(ints.insert(i64::MIN));
+        assert_eq!(ints.width(), 8);
+        assert_eq!(ints.iter().collect::<Vec<_>>(), vec![i64::MIN, -3, 0, 5, 300, 70_000]);
+
+        assert!(ints.remove(0));
+        assert!(!ints.remove(1));
+        assert!(!ints.contains(0));
+        assert!(ints.contains(70_000));
+        assert_eq!(ints.len(), 5);
+    }
+
+    #[test]
+    fn set_converts_when_needed() {
+        let mut set: Set = ["1", "2", "3"].into_iter().map(Bytes::from).collect();
+        assert!(matches!(set, Set::Ints(_)));
+        // not canonical, so it cannot be stored as an integer
+        assert!(set.insert("01".into()));
+        assert!(matches!(set, Set::Members(_)));
+        assert!(set.contains(b"1") && set.contains(b"01"));
+        assert_eq!(set.len(), 4);
+
+        let mut set: Set = (0..MAX_INTSET_ENTRIES).map(|i| Bytes::from(i.to_string())).collect();
+        assert!(matches!(set, Set::Ints(_)));
+        set.insert("100000".into());
+        assert!(matches!(set, Set::Members(_)));
+        assert_eq!(set.len(), MAX_INTSET_ENTRIES + 1);
+    }
+}
@@ -0,0 +1,443 @@
+// Skiplist ordered by (score, member), a port of the zskiplist of redis.
+// Every link knows how many nodes it skips, which gives the rank of a node in O(log N).
+// Nodes live in an arena and link to each other by index.
+use bytes::Bytes;
+
+const MAX_LEVEL: usize = 32;
+// probability for a node to get one more level
+const P: f64 = 0.25;
+const NIL: usize = usize::MAX;
+const HEAD: usize = 0;
+
+#[derive(Debug, Clone, Copy)]
+struct Level {
+    forward: usize,
+    // number of nodes between this node and `forward`, `forward` included
+    span: usize,
+}
+
+#[derive(Debug, Clone)]
+struct Node {
+    member: Bytes,
+    score: f64,
+    backward: usize,
+    levels: Vec<Level>,
+}
+
+/// Bound of a score range, exclusive for "(1.5"
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct ScoreBound {
+    pub value: f64,
+    pub exclusive: bool,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct ScoreRange {
+    pub min: ScoreBound,
+    pub max: ScoreBound,
+}
+
+impl ScoreRange {
+    fn above_min(&self, score: f64) -> bool {
+        if self.min.exclusive { score > self.min.value } else { score >= self.min.value }
+    }
+
+    fn below_max(&self, score: f64) -> bool {
+        if self.max.exclusive { score < self.max.value } else { score <= self.max.value }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.min.value > self.max.value || (self.min.value == self.max.value && (self.min.exclusive || self.max.exclusive))
+    }
+}
+
+/// Bound of a lexicographical range: "[a", "(a", "-" or "+"
+#[derive(Debug, Clone, PartialEq)]
+pub enum LexBound {
+    NegInf,
+    PosInf,
+    Inclusive(Bytes),
+    Exclusive(Bytes),
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct LexRange {
+    pub min: LexBound,
+    pub max: LexBound,
+}
+
+impl LexRange {
+    fn above_min(&self, member: &[u8]) -> bool {
+        match &self.min {
+            LexBound::NegInf => true,
+            LexBound::PosInf => false,
+            LexBound::Inclusive(min) => member >= min.as_ref(),
+            LexBound::Exclusive(min) => member > min.as_ref(),
+        }
+    }
+
+    fn below_max(&self, member: &[u8]) -> bool {
+        match &self.max {
+            LexBound::NegInf => false,
+            LexBound::PosInf => true,
+            LexBound::Inclusive(max) => member <= max.as_ref(),
+            LexBound::Exclusive(max) => member < max.as_ref(),
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct SkipList {
+    // nodes[HEAD] is the header, it holds no member
+    nodes: Vec<Node>,
+    free: Vec<usize>,
+    len: usize,
+    level: usize,
+}
+
+impl Default for SkipList {
+    fn default() -> Self {
+        let head = Node {
+            member: Bytes::new(),
+            score: 0.0,
+            backward: NIL,
+            levels: vec![Level { forward: NIL, span: 0 }; MAX_LEVEL],
+        };
+        SkipList { nodes: vec![head], free: Vec::new(), len: 0, level: 1 }
+    }
+}
+
+fn random_level() -> usize {
+    let mut level = 1;
+    while level < MAX_LEVEL && rand::random::<f64>() < P {
+        level += 1;
+    }
+    level
+}
+
+impl SkipList {
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    fn forward(&self, node: usize, level: usize) -> usize {
+        self.nodes[node].levels[level].forward
+    }
+
+    fn is_before(&self, node: usize, score: f64, member: &[u8]) -> bool {
+        let node = &self.nodes[node];
+        node.score < score || (node.score == score && node.member.as_ref() < member)
+    }
+
+    fn alloc(&mut self, node: Node) -> usize {
+        match self.free.pop() {
+            Some(index) => {
+                self.nodes[index] = node;
+                index
+            }
+            None => {
+                self.nodes.push(node);
+                self.nodes.len() - 1
+            }
+        }
+    }
+
+    /// Inserts a member that is not in the list yet
+    pub fn insert(&mut self, score: f64, member: Bytes) {
+        let mut update = [HEAD; MAX_LEVEL];
+        let mut rank = [0usize; MAX_LEVEL];
+        let mut x = HEAD;
+        for i in (0..self.level).rev() {
+            rank[i] = if i == self.level - 1 { 0 } else { rank[i + 1] };
+            loop {
+                let next = self.forward(x, i);
+                if next == NIL || !self.is_before(next, score, &member) {
+                    break;
+                }
+                rank[i] += self.nodes[x].levels[i].span;
+                x = next;
+            }
+            update[i] = x;
+        }
+
+        let level = random_level();
+        if level > self.level {
+            for i in self.level..level {
+                rank[i] = 0;
+                update[i] = HEAD;
+                self.nodes[HEAD].levels[i].span = self.len;
+            }
+            self.level = level;
+        }
+
+        let node = self.alloc(Node { member, score, backward: NIL, levels: vec![Level { forward: NIL, span: 0 }; level] });
+        for i in 0..level {
+            let prev = update[i];
+            let prev_level = self.nodes[prev].levels[i];
+            self.nodes[node].levels[i] = Level { forward: prev_level.forward, span: prev_level.span - (rank[0] - rank[i]) };
+            self.nodes[prev].levels[i] = Level { forward: node, span: rank[0] - rank[i] + 1 };
+        }
+        for (i, prev) in update.iter().enumerate().take(self.level).skip(level) {
+            self.nodes[*prev].levels[i].span += 1;
+        }
+
+        self.nodes[node].backward = if update[0] == HEAD { NIL } else { update[0] };
+        let next = self.forward(node, 0);
+        if next != NIL {
+            self.nodes[next].backward = node;
+        }
+        self.len += 1;
+    }
+
+    /// Removes the member with the given score, returns whether it was there
+    pub fn delete(&mut self, score: f64, member: &[u8]) -> bool {
+        let mut update = [HEAD; MAX_LEVEL];
+        let mut x = HEAD;
+        for i in (0..self.level).rev() {
+            loop {
+                let next = self.forward(x, i);
+                if next == NIL || !self.is_before(next, score, member) {
+                    break;
+                }
+                x = next;
+            }
+            update[i] = x;
+        }
+
+        let x = self.forward(x, 0);
+        if x == NIL || self.nodes[x].score != score || self.nodes[x].member != member {
+            return false;
+        }
+
+        for (i, prev) in update.iter().enumerate().take(self.level) {
+            let prev = *prev;
+            if self.forward(prev, i) == x {
+                let removed = self.nodes[x].levels[i];
+                self.nodes[prev].levels[i].span += removed.span;
+                self.nodes[prev].levels[i].span -= 1;
+                self.nodes[prev].levels[i].forward = removed.forward;
+            } else {
+                self.nodes[prev].levels[i].span -= 1;
+            }
+        }
+        let next = self.forward(x, 0);
+        if next != NIL {
+            self.nodes[next].backward = self.nodes[x].backward;
+        }
+        while self.level > 1 && self.forward(HEAD, self.level - 1) == NIL {
+            self.level -= 1;
+        }
+        self.len -= 1;
+
+        self.nodes[x].member = Bytes::new();
+        self.nodes[x].levels = Vec::new();
+        self.free.push(x);
+        true
+    }
+
+    /// 0-based rank of a member with the given score
+    pub fn rank(&self, score: f64, member: &[u8]) -> Option<usize> {
+        let mut rank = 0;
+        let mut x = HEAD;
+        for i in (0..self.level).rev() {
+            loop {
+                let next = self.forward(x, i);
+                if next == NIL {
+                    break;
+                }
+                let node = &self.nodes[next];
+                if node.score < score || (node.score == score && node.member.as_ref() <= member) {
+                    rank += self.nodes[x].levels[i].span;
+                    x = next;
+                } else {
+                    break;
+                }
+            }
+            if x != HEAD && self.nodes[x].score == score && self.nodes[x].member == member {
+                return Some(rank - 1);
+            }
+        }
+        None
+    }
+
+    /// Node at the 0-based `rank`
+    fn node_at(&self, rank: usize) -> Option<usize> {
+        let target = rank + 1;
+        let mut traversed = 0;
+        let mut x = HEAD;
+        for i in (0..self.level).rev() {
+            loop {
+                let next = self.forward(x, i);
+                if next == NIL || traversed + self.nodes[x].levels[i].span > target {
+                    break;
+                }
+                traversed += self.nodes[x].levels[i].span;
+                x = next;
+            }
+            if traversed == target {
+                return Some(x);
+            }
+        }
+        None
+    }
+
+    pub fn get(&self, rank: usize) -> Option<(&Bytes, f64)> {
+        self.node_at(rank).map(|x| (&self.nodes[x].member, self.nodes[x].score))
+    }
+
+    /// Members from rank `start` to rank `end`, both included, from `end` down to `start` if `reverse`
+    pub fn range(&self, start: usize, end: usize, reverse: bool) -> impl Iterator<Item = (&Bytes, f64)> {
+        let (from, count) = if start > end || start >= self.len {
+            (None, 0)
+        } else {
+            let end = end.min(self.len - 1);
+            (self.node_at(if reverse { end } else { start }), end - start + 1)
+        };
+        let mut x = from.unwrap_or(NIL);
+        (0..count).map_while(move |_| {
+            if x == NIL {
+                return None;
+            }
+            let node = &self.nodes[x];
+            x = if reverse { node.backward } else { node.levels[0].forward };
+            Some((&node.member, node.score))
+        })
+    }
+
+    /// Ranks of the first and the last members within `range`
+    pub fn score_range(&self, range: &ScoreRange) -> Option<(usize, usize)> {
+        if range.is_empty() || self.len == 0 {
+            return None;
+        }
+        let mut x = HEAD;
+        for i in (0..self.level).rev() {
+            loop {
+                let next = self.forward(x, i);
+                if next == NIL || range.above_min(self.nodes[next].score) {
+                    break;
+                }
+                x = next;
+            }
+        }
+        let first = self.forward(x, 0);
+        if first == NIL || !range.below_max(self.nodes[first].score) {
+            return None;
+        }
+
+        let mut x = HEAD;
+        for i in (0..self.level).rev() {
+            loop {
+                let next = self.forward(x, i);
+                if next == NIL || !range.below_max(self.nodes[next].score) {
+                    break;
+                }
+                x = next;
+            }
+        }
+        Some((self.rank_of(first), self.rank_of(x)))
+    }
+
+    /// Ranks of the first and the last members within `range`, meant for members sharing the same score
+    pub fn lex_range(&self, range: &LexRange) -> Option<(usize, usize)> {
+        if self.len == 0 {
+            return None;
+        }
+        let mut x = HEAD;
+        for i in (0..self.level).rev() {
+            loop {
+                let next = self.forward(x, i);
+                if next == NIL || range.above_min(&self.nodes[next].member) {
+                    break;
+                }
+                x = next;
+            }
+        }
+        let first = self.forward(x, 0);
+        if first == NIL || !range.below_max(&self.nodes[first].member) {
+            return None;
+        }
+
+        let mut x = HEAD;
+        for i in (0..self.level).rev() {
+            loop {
+                let next = self.forward(x, i);
+                if next == NIL || !range.below_max(&self.nodes[next].member) {
+                    break;
+                }
+                x = next;
+            }
+        }
+        let (first, last) = (self.rank_of(first), self.rank_of(x));
+        (first <= last).then_some((first, last))
+    }
+
+    fn rank_of(&self, node: usize) -> usize {
+        let node = &self.nodes[node];
+        self.rank(node.score, &node.member).expect("node is in the list")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn members(list: &SkipList) -> Vec<(String, f64)> {
+        list.range(0, usize::MAX - 1, false).map(|(m, s)| (String::from_utf8(m.to_vec()).unwrap(), s)).collect()
+    }
+
+    #[test]
+    fn ranks_follow_the_order() {
+        let mut list = SkipList::default();
+        for i in (0..200).rev() {
+            list.insert((i / 2) as f64, format!("m{:03}", i).into());
+        }
+        assert_eq!(list.len(), 200);
+        for i in 0..200 {
+            assert_eq!(list.rank((i / 2) as f64, format!("m{:03}", i).as_bytes()), Some(i));
+            assert_eq!(list.get(i).map(|(m, _)| m.clone()), Some(Bytes::from(format!("m{:03}", i))));
+        }
+        assert_eq!(list.rank(1.0, b"m000"), None);
+
+        for i in (0..200).step_by(2) {
+            assert!(list.delete((i / 2) as f64, format!("m{:03}", i).as_bytes()));
+        }
+        assert!(!list.delete(0.0, b"m000"));
+        assert_eq!(list.len(), 100);
+        for i in 0..100 {
+            assert_eq!(list.rank(i as f64, format!("m{:03}", i * 2 + 1).as_bytes()), Some(i));
+        }
+        let reversed: Vec<f64> = list.range(97, 99, true).map(|(_, s)| s).collect();
+        assert_eq!(reversed, vec![99.0, 98.0, 97.0]);
+    }
+
+    #[test]
+    fn score_and_lex_ranges() {
+        let mut list = SkipList::default();
+        for (score, member) in [(1.0, "a"), (2.0, "b"), (2.0, "c"), (3.0, "d")] {
+            list.insert(score, member.into());
+        }
+        let range = |min: f64, min_ex: bool, max: f64, max_ex: bool| ScoreRange {
+            min: ScoreBound { value: min, exclusive: min_ex },
+            max: ScoreBound { value: max, exclusive: max_ex },
+        };
+        assert_eq!(list.score_range(&range(2.0, false, 2.0, false)), Some((1, 2)));
+        assert_eq!(list.score_range(&range(1.0, true, 3.0, true)), Some((1, 2)));
+        assert_eq!(list.score_range(&range(f64::NEG_INFINITY, false, f64::INFINITY, false)), Some((0, 3)));
+        assert_eq!(list.score_range(&range(3.5, false, 9.0, false)), None);
+        assert_eq!(list.score_range(&range(2.0, true, 2.0, false)), None);
+
+        let mut list = SkipList::default();
+        for member in ["a", "b", "c", "d", "e"] {
+            list.insert(0.0, member.into());
+        }
+        let lex = |min: LexBound, max: LexBound| LexRange { min, max };
+        assert_eq!(list.lex_range(&lex(LexBound::NegInf, LexBound::Inclusive("c".into()))), Some((0, 2)));
+        assert_eq!(list.lex_range(&lex(LexBound::Exclusive("a".into()), LexBound::Exclusive("e".into()))), Some((1, 3)));
+        assert_eq!(list.lex_range(&lex(LexBound::Inclusive("x".into()), LexBound::PosInf)), None);
+        assert_eq!(members(&list).len(), 5);
+    }
+}
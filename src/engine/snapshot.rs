@@ -0,0 +1,540 @@
+// Point in time snapshot of the whole keyspace in a compact binary file
+//
+// layout: MAGIC | version u32 | build info | created at i64 | entry count u64 | entries... | crc64 u64
+// every integer is little endian, every byte string is prefixed by its u64 length
+use std::{path::{Path, PathBuf}, sync::{atomic::{AtomicU64, Ordering}, LazyLock, Mutex}, time::Duration};
+use anyhow::{anyhow, bail, Result};
+use bytes::Bytes;
+use crate::utils::{crc64, get_built_info};
+use super::{Consumer, ConsumerGroup, Entry, PendingEntry, Stream, StreamId, Timestamp, Value, DB};
+
+const MAGIC: &[u8] = b"KVSNAP";
+pub const FORMAT_VERSION: u32 = 1;
+
+const TYPE_STRING: u8 = 0;
+const TYPE_LIST: u8 = 1;
+const TYPE_HASH: u8 = 2;
+const TYPE_SET: u8 = 3;
+const TYPE_ZSET: u8 = 4;
+const TYPE_STREAM: u8 = 5;
+
+pub(crate) struct Encoder {
+    buf: Vec<u8>,
+}
+
+impl Encoder {
+    pub(crate) fn new() -> Self {
+        Encoder { buf: Vec::new() }
+    }
+
+    pub(crate) fn u8(&mut self, v: u8) {
+        self.buf.push(v);
+    }
+
+    pub(crate) fn u32(&mut self, v: u32) {
+        self.buf.extend_from_slice(&v.to_le_bytes());
+    }
+
+    pub(crate) fn u64(&mut self, v: u64) {
+        self.buf.extend_from_slice(&v.to_le_bytes());
+    }
+
+    /// An optional deadline, -1 standing for none
+    pub(crate) fn deadline(&mut self, deadline: Option<Timestamp>) {
+        self.i64(deadline.map(|t| t.timestamp_millis()).unwrap_or(-1));
+    }
+
+    pub(crate) fn i64(&mut self, v: i64) {
+        self.buf.extend_from_slice(&v.to_le_bytes());
+    }
+
+    pub(crate) fn bytes(&mut self, v: &[u8]) {
+        self.u64(v.len() as u64);
+        self.buf.extend_from_slice(v);
+    }
+
+    fn finish(mut self) -> Vec<u8> {
+        let checksum = crc64(0, &self.buf);
+        self.u64(checksum);
+        self.buf
+    }
+}
+
+pub(crate) struct Decoder<'a> {
+    data: &'a [u8],
+}
+
+impl<'a> Decoder<'a> {
+    fn take(&mut self, n: usize) -> Result<&'a [u8]> {
+        if self.data.len() < n {
+            bail!("snapshot is truncated");
+        }
+        let (head, tail) = self.data.split_at(n);
+        self.data = tail;
+        Ok(head)
+    }
+
+    pub(crate) fn u8(&mut self) -> Result<u8> {
+        Ok(self.take(1)?[0])
+    }
+
+    pub(crate) fn u32(&mut self) -> Result<u32> {
+        Ok(u32::from_le_bytes(self.take(4)?.try_into()?))
+    }
+
+    pub(crate) fn u64(&mut self) -> Result<u64> {
+        Ok(u64::from_le_bytes(self.take(8)?.try_into()?))
+    }
+
+    pub(crate) fn i64(&mut self) -> Result<i64> {
+        Ok(i64::from_le_bytes(self.take(8)?.try_into()?))
+    }
+
+    pub(crate) fn deadline(&mut self) -> Result<Option<Timestamp>> {
+        match self.i64()? {
+            -1 => Ok(None),
+            ms => Ok(Some(chrono::DateTime::from_timestamp_millis(ms).ok_or(anyhow!("invalid expire time in snapshot"))?)),
+        }
+    }
+
+    /// Reads a length, refusing lengths that cannot fit in the remaining data
+    pub(crate) fn len(&mut self) -> Result<usize> {
+        let len = self.u64()?;
+        if len > self.data.len() as u64 {
+            bail!("snapshot is truncated");
+        }
+        Ok(len as usize)
+    }
+
+    pub(crate) fn bytes(&mut self) -> Result<Bytes> {
+        let len = self.len()?;
+        Ok(Bytes::copy_from_slice(self.take(len)?))
+    }
+}
+
+fn encode_value(encoder: &mut Encoder, value: &Value) {
+    match value {
+        Value::String(s) => {
+            encoder.u8(TYPE_STRING);
+            encoder.bytes(s);
+        }
+        Value::List(list) => {
+            encoder.u8(TYPE_LIST);
+            encoder.u64(list.len() as u64);
+            for item in list {
+                encoder.bytes(item);
+            }
+        }
+        Value::Hash(hash) => {
+            encoder.u8(TYPE_HASH);
+            encoder.u64(hash.entries().count() as u64);
+            for (field, value, deadline) in hash.entries() {
+                encoder.bytes(field);
+                encoder.bytes(value);
+                encoder.deadline(deadline);
+            }
+        }
+        Value::Set(set) => {
+            encoder.u8(TYPE_SET);
+            encoder.u64(set.len() as u64);
+            for member in set.iter() {
+                encoder.bytes(&member);
+            }
+        }
+        Value::ZSet(zset) => {
+            encoder.u8(TYPE_ZSET);
+            encoder.u64(zset.len() as u64);
+            for (member, score) in zset.iter() {
+                encoder.bytes(member);
+                encoder.u64(score.to_bits());
+            }
+        }
+        Value::Stream(stream) => {
+            encoder.u8(TYPE_STREAM);
+            encode_stream(encoder, stream);
+        }
+    }
+}
+
+fn encode_id(encoder: &mut Encoder, id: StreamId) {
+    encoder.u64(id.ms);
+    encoder.u64(id.seq);
+}
+
+fn decode_id(decoder: &mut Decoder) -> Result<StreamId> {
+    Ok(StreamId::new(decoder.u64()?, decoder.u64()?))
+}
+
+fn encode_stream(encoder: &mut Encoder, stream: &Stream) {
+    encode_id(encoder, stream.last_id);
+    encoder.u64(stream.entries_added);
+    encode_id(encoder, stream.max_deleted_id);
+    encoder.u64(stream.len() as u64);
+    for (id, fields) in &stream.entries {
+        encode_id(encoder, *id);
+        encoder.u64(fields.len() as u64);
+        for (field, value) in fields {
+            encoder.bytes(field);
+            encoder.bytes(value);
+        }
+    }
+
+    encoder.u64(stream.groups.len() as u64);
+    for (name, group) in &stream.groups {
+        encoder.bytes(name);
+        encode_id(encoder, group.last_id);
+        encoder.i64(group.entries_read.map(|read| read as i64).unwrap_or(-1));
+        encoder.u64(group.consumers.len() as u64);
+        for (name, consumer) in &group.consumers {
+            encoder.bytes(name);
+            encoder.i64(consumer.seen_at);
+            encoder.i64(consumer.active_at);
+        }
+        encoder.u64(group.pending.len() as u64);
+        for (id, pending) in &group.pending {
+            encode_id(encoder, *id);
+            encoder.bytes(&pending.consumer);
+            encoder.i64(pending.delivered_at);
+            encoder.u64(pending.deliveries);
+        }
+    }
+}
+
+fn decode_stream(decoder: &mut Decoder) -> Result<Stream> {
+    let mut stream = Stream::new();
+    stream.last_id = decode_id(decoder)?;
+    stream.entries_added = decoder.u64()?;
+    stream.max_deleted_id = decode_id(decoder)?;
+    for _ in 0..decoder.len()? {
+        let id = decode_id(decoder)?;
+        let len = decoder.len()?;
+        let fields = (0..len).map(|_| Ok((decoder.bytes()?, decoder.bytes()?))).collect::<Result<_>>()?;
+        stream.entries.insert(id, fields);
+    }
+
+    for _ in 0..decoder.len()? {
+        let name = decoder.bytes()?;
+        let last_id = decode_id(decoder)?;
+        let entries_read = u64::try_from(decoder.i64()?).ok();
+        let mut group = ConsumerGroup::new(last_id, entries_read);
+        for _ in 0..decoder.len()? {
+            let name = decoder.bytes()?;
+            group.consumers.insert(name, Consumer { seen_at: decoder.i64()?, active_at: decoder.i64()? });
+        }
+        for _ in 0..decoder.len()? {
+            let id = decode_id(decoder)?;
+            let pending = PendingEntry { consumer: decoder.bytes()?, delivered_at: decoder.i64()?, deliveries: decoder.u64()? };
+            group.pending.insert(id, pending);
+        }
+        stream.groups.insert(name, group);
+    }
+    Ok(stream)
+}
+
+fn decode_value(decoder: &mut Decoder) -> Result<Value> {
+    match decoder.u8()? {
+        TYPE_STRING => Ok(Value::String(decoder.bytes()?)),
+        TYPE_LIST => {
+            let len = decoder.len()?;
+            let list = (0..len).map(|_| decoder.bytes()).collect::<Result<_>>()?;
+            Ok(Value::List(list))
+        }
+        TYPE_HASH => {
+            let len = decoder.len()?;
+            let hash = (0..len)
+                .map(|_| Ok((decoder.bytes()?, decoder.bytes()?, decoder.deadline()?)))
+                .collect::<Result<_>>()?;
+            Ok(Value::Hash(hash))
+        }
+        TYPE_SET => {
+            let len = decoder.len()?;
+            let set = (0..len).map(|_| decoder.bytes()).collect::<Result<_>>()?;
+            Ok(Value::Set(set))
+        }
+        TYPE_ZSET => {
+            let len = decoder.len()?;
+            let zset = (0..len)
+                .map(|_| Ok((decoder.bytes()?, f64::from_bits(decoder.u64()?))))
+                .collect::<Result<_>>()?;
+            Ok(Value::ZSet(zset))
+        }
+        TYPE_STREAM => Ok(Value::Stream(decode_stream(decoder)?)),
+        t => Err(anyhow!("unknown value type {} in snapshot", t)),
+    }
+}
+
+pub fn encode(entries: &[(Bytes, Entry)]) -> Vec<u8> {
+    let mut encoder = Encoder::new();
+    encoder.buf.extend_from_slice(MAGIC);
+    encoder.u32(FORMAT_VERSION);
+    encoder.bytes(get_built_info().as_bytes());
+    encoder.i64(chrono::Utc::now().timestamp_millis());
+    encoder.u64(entries.len() as u64);
+
+    for (key, entry) in entries {
+        encoder.bytes(key);
+        encoder.deadline(entry.expire_at);
+        encode_value(&mut encoder, &entry.value);
+    }
+    encoder.finish()
+}
+
+pub fn decode(data: &[u8]) -> Result<Vec<(Bytes, Entry)>> {
+    if data.len() < MAGIC.len() + 8 || &data[..MAGIC.len()] != MAGIC {
+        bail!("not a snapshot file");
+    }
+    let (body, checksum) = data.split_at(data.len() - 8);
+    if crc64(0, body) != u64::from_le_bytes(checksum.try_into()?) {
+        bail!("snapshot checksum mismatch");
+    }
+
+    let mut decoder = Decoder { data: &body[MAGIC.len()..] };
+    let version = decoder.u32()?;
+    if version > FORMAT_VERSION {
+        bail!("unsupported snapshot format version {}", version);
+    }
+    let _built_info = decoder.bytes()?;
+    let _created_at = decoder.i64()?;
+
+    let count = decoder.u64()?;
+    let mut entries = Vec::new();
+    for _ in 0..count {
+        let key = decoder.bytes()?;
+        let expire_at = decoder.deadline()?;
+        let value = decode_value(&mut decoder)?;
+        entries.push((key, Entry { value, expire_at }));
+    }
+    Ok(entries)
+}
+
+// tells apart the temporary files of writers that overlap, such as a cancelled SAVE and the next BGSAVE
+static NEXT_TMP_ID: AtomicU64 = AtomicU64::new(0);
+
+/// Writes the snapshot next to `path` and atomically renames it into place
+pub fn write_file(path: &Path, entries: &[(Bytes, Entry)]) -> Result<()> {
+    let data = encode(entries);
+    let id = NEXT_TMP_ID.fetch_add(1, Ordering::Relaxed);
+    let tmp = path.with_extension(format!("tmp-{}-{}", std::process::id(), id));
+    {
+        use std::io::Write;
+        let mut file = std::fs::File::create(&tmp)?;
+        file.write_all(&data)?;
+        file.sync_all()?;
+    }
+    std::fs::rename(&tmp, path)?;
+    Ok(())
+}
+
+/// Loads the snapshot at `path` into the database, returns the number of keys loaded
+pub async fn load(path: impl AsRef<Path>) -> Result<usize> {
+    let data = std::fs::read(path)?;
+    let entries = decode(&data)?;
+
+    let now = chrono::Utc::now();
+    let mut db = DB.write().await;
+    let mut count = 0;
+    for (key, entry) in entries {
+        if !entry.is_expired(now) {
+            db.put(key, entry);
+            count += 1;
+        }
+    }
+    Ok(count)
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct SaveRule {
+    pub seconds: u64,
+    pub changes: u64,
+}
+
+impl SaveRule {
+    /// Parses rules written as "<seconds> <changes>" pairs, e.g. "3600 1 300 100"
+    pub fn parse_rules(s: &str) -> Result<Vec<SaveRule>> {
+        let numbers = s.split_whitespace().map(|n| n.parse::<u64>()).collect::<Result<Vec<_>, _>>()?;
+        if numbers.len() % 2 != 0 {
+            bail!("save rules must be pairs of <seconds> <changes>");
+        }
+        Ok(numbers.chunks(2).map(|pair| SaveRule { seconds: pair[0], changes: pair[1] }).collect())
+    }
+}
+
+struct SaveState {
+    path: PathBuf,
+    last_save: Timestamp,
+    in_progress: bool,
+}
+
+static STATE: LazyLock<Mutex<SaveState>> = LazyLock::new(|| {
+    Mutex::new(SaveState {
+        path: PathBuf::from("dump.kvdb"),
+        last_save: chrono::Utc::now(),
+        in_progress: false,
+    })
+});
+
+pub fn set_path(path: impl AsRef<Path>) {
+    STATE.lock().unwrap().path = path.as_ref().to_path_buf();
+}
+
+pub fn last_save() -> Timestamp {
+    STATE.lock().unwrap().last_save
+}
+
+pub fn in_progress() -> bool {
+    STATE.lock().unwrap().in_progress
+}
+
+/// A save in progress, which ends when this is dropped: it goes along with the writer, so that the save
+/// ends when the file is written even if the command that started it was cancelled
+struct SaveInProgress {
+    ok: bool,
+}
+
+impl SaveInProgress {
+    fn finish(mut self, ok: bool) {
+        self.ok = ok;
+    }
+}
+
+impl Drop for SaveInProgress {
+    fn drop(&mut self) {
+        let mut state = STATE.lock().unwrap();
+        state.in_progress = false;
+        if self.ok {
+            state.last_save = chrono::Utc::now();
+        }
+    }
+}
+
+/// Marks a save as started, returns the target path
+fn begin() -> Result<(PathBuf, SaveInProgress)> {
+    let mut state = STATE.lock().unwrap();
+    if state.in_progress {
+        bail!("Background save already in progress");
+    }
+    state.in_progress = true;
+    Ok((state.path.clone(), SaveInProgress { ok: false }))
+}
+
+/// Runs `write_file` on a blocking thread, which ends `save` once done
+async fn write_file_blocking(path: PathBuf, entries: Vec<(Bytes, Entry)>, save: SaveInProgress) -> Result<()> {
+    tokio::task::spawn_blocking(move || {
+        let result = write_file(&path, &entries);
+        save.finish(result.is_ok());
+        result
+    })
+    .await
+    .map_err(anyhow::Error::from)
+    .and_then(|result| result)
+}
+
+/// Saves in the foreground, writers wait until the file is written
+pub async fn save() -> Result<()> {
+    let (path, save) = begin()?;
+    let mut db = DB.write().await;
+    let entries = db.engine().snapshot();
+    let result = write_file_blocking(path, entries, save).await;
+    if result.is_ok() {
+        let dirty = db.dirty();
+        db.mark_saved(dirty);
+    }
+    result
+}
+
+/// Copies the keyspace and writes it from a blocking task while clients keep being served
+pub async fn bgsave() -> Result<()> {
+    let (path, save) = begin()?;
+    let (entries, dirty) = {
+        let db = DB.read().await;
+        (db.engine().snapshot(), db.dirty())
+    };
+
+    tokio::spawn(async move {
+        match write_file_blocking(path, entries, save).await {
+            Ok(()) => DB.write().await.mark_saved(dirty),
+            Err(e) => eprintln!("Background saving failed: {}", e),
+        }
+    });
+    Ok(())
+}
+
+/// Starts a background save whenever one of the rules is satisfied
+pub fn start_auto_save(rules: Vec<SaveRule>) {
+    if rules.is_empty() {
+        return;
+    }
+    tokio::spawn(async move {
+        let mut interval = tokio::time::interval(Duration::from_secs(1));
+        loop {
+            interval.tick().await;
+            let dirty = DB.read().await.dirty();
+            let elapsed = (chrono::Utc::now() - last_save()).num_seconds().max(0) as u64;
+            let triggered = rules.iter().any(|rule| dirty >= rule.changes && elapsed >= rule.seconds);
+            if dirty > 0 && triggered && !in_progress() {
+                if let Err(e) = bgsave().await {
+                    eprintln!("Error starting background save: {}", e);
+                }
+            }
+        }
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn encode_and_decode() -> Result<()> {
+        let deadline = chrono::DateTime::from_timestamp_millis(4_102_444_800_000);
+        let mut stream = Stream::new();
+        stream.add(StreamId::new(1, 0), vec![("f".into(), "v".into())]);
+        stream.add(StreamId::new(1, 1), vec![]);
+        stream.remove(StreamId::new(1, 0));
+        let mut group = ConsumerGroup::new(StreamId::new(1, 1), None);
+        group.consumers.insert("alice".into(), Consumer { seen_at: 7, active_at: -1 });
+        group.pending.insert(StreamId::new(1, 1), PendingEntry { consumer: "alice".into(), delivered_at: 5, deliveries: 2 });
+        stream.groups.insert("g".into(), group);
+        let entries = vec![
+            (Bytes::from("k1"), Entry::new(Value::String("v1".into()))),
+            (Bytes::from("k2"), Entry { value: Value::String(Bytes::from(vec![0u8, 255, 13, 10])), expire_at: deadline }),
+            (Bytes::from("k3"), Entry::new(Value::List(["a".into(), Bytes::new(), "c".into()].into()))),
+            (Bytes::from("k4"), Entry::new(Value::Hash([("f".into(), "v".into(), None), ("g".into(), "w".into(), deadline)].into_iter().collect()))),
+            (Bytes::from("k5"), Entry::new(Value::Set(["1", "-7"].into_iter().map(Bytes::from).collect()))),
+            (Bytes::from("k6"), Entry::new(Value::Set(["a", "7"].into_iter().map(Bytes::from).collect()))),
+            (Bytes::from("k7"), Entry::new(Value::ZSet([("a".into(), 1.5), ("b".into(), f64::NEG_INFINITY)].into_iter().collect()))),
+            (Bytes::from("k8"), Entry::new(Value::Stream(stream))),
+        ];
+        let data = encode(&entries);
+        assert_eq!(decode(&data)?, entries);
+
+        let mut corrupted = data.clone();
+        corrupted[20] ^= 1;
+        assert!(decode(&corrupted).is_err());
+        assert!(decode(&data[..data.len() - 1]).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn cancelled_save_ends() -> Result<()> {
+        let (_, save) = begin()?;
+        assert!(in_progress() && begin().is_err());
+        // dropped without finishing, like a save whose writer panicked
+        drop(save);
+        assert!(!in_progress());
+        let (_, save) = begin()?;
+        save.finish(false);
+        assert!(!in_progress());
+        Ok(())
+    }
+
+    #[test]
+    fn parse_save_rules() -> Result<()> {
+        assert_eq!(SaveRule::parse_rules("900 1 60 100")?, vec![
+            SaveRule { seconds: 900, changes: 1 },
+            SaveRule { seconds: 60, changes: 100 },
+        ]);
+        assert!(SaveRule::parse_rules("900").is_err());
+        Ok(())
+    }
+}
@@ -0,0 +1,237 @@
+use std::collections::BTreeMap;
+use std::fmt;
+use bytes::Bytes;
+
+/// ID of a stream entry: milliseconds then a sequence number among the entries of that millisecond
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash, Default)]
+pub struct StreamId {
+    pub ms: u64,
+    pub seq: u64,
+}
+
+impl StreamId {
+    pub const MIN: StreamId = StreamId { ms: 0, seq: 0 };
+    pub const MAX: StreamId = StreamId { ms: u64::MAX, seq: u64::MAX };
+
+    pub fn new(ms: u64, seq: u64) -> Self {
+        StreamId { ms, seq }
+    }
+
+    pub fn next(self) -> Option<StreamId> {
+        match self.seq.checked_add(1) {
+            Some(seq) => Some(StreamId::new(self.ms, seq)),
+            None => Some(StreamId::new(self.ms.checked_add(1)?, 0)),
+        }
+    }
+
+    pub fn prev(self) -> Option<StreamId> {
+        match self.seq.checked_sub(1) {
+            Some(seq) => Some(StreamId::new(self.ms, seq)),
+            None => Some(StreamId::new(self.ms.checked_sub(1)?, u64::MAX)),
+        }
+    }
+
+    pub fn to_bytes(self) -> Bytes {
+        Bytes::from(self.to_string())
+    }
+}
+
+impl fmt::Display for StreamId {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}-{}", self.ms, self.seq)
+    }
+}
+
+pub type Fields = Vec<(Bytes, Bytes)>;
+
+/// An entry delivered to a consumer and not acknowledged yet
+#[derive(Debug, Clone, PartialEq)]
+pub struct PendingEntry {
+    pub consumer: Bytes,
+    // unix time in milliseconds
+    pub delivered_at: i64,
+    pub deliveries: u64,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Consumer {
+    // last time it tried to read or claim, in milliseconds
+    pub seen_at: i64,
+    // last time it actually got entries, -1 if never
+    pub active_at: i64,
+}
+
+impl Consumer {
+    pub fn new(now: i64) -> Self {
+        Consumer { seen_at: now, active_at: -1 }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Default)]
+pub struct ConsumerGroup {
+    pub last_id: StreamId,
+    // number of entries delivered to the group, None once it cannot be known
+    pub entries_read: Option<u64>,
+    pub pending: BTreeMap<StreamId, PendingEntry>,
+    pub consumers: BTreeMap<Bytes, Consumer>,
+}
+
+impl ConsumerGroup {
+    pub fn new(last_id: StreamId, entries_read: Option<u64>) -> Self {
+        ConsumerGroup { last_id, entries_read, ..Default::default() }
+    }
+
+    /// Pending entries of `consumer`
+    pub fn pending_of<'a>(&'a self, consumer: &'a [u8]) -> impl Iterator<Item = (&'a StreamId, &'a PendingEntry)> + 'a {
+        self.pending.iter().filter(move |(_, entry)| entry.consumer == consumer)
+    }
+
+    /// The consumer named `name`, created if needed. The bool tells whether it was.
+    pub fn consumer(&mut self, name: &Bytes, now: i64) -> (&mut Consumer, bool) {
+        let created = !self.consumers.contains_key(name);
+        (self.consumers.entry(name.clone()).or_insert_with(|| Consumer::new(now)), created)
+    }
+}
+
+/// An append only log of entries, each one a list of field/value pairs, like t_stream.c of redis
+#[derive(Debug, Clone, PartialEq, Default)]
+pub struct Stream {
+    pub entries: BTreeMap<StreamId, Fields>,
+    pub last_id: StreamId,
+    // number of entries ever added
+    pub entries_added: u64,
+    pub max_deleted_id: StreamId,
+    pub groups: BTreeMap<Bytes, ConsumerGroup>,
+}
+
+impl Stream {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+
+    pub fn first_id(&self) -> Option<StreamId> {
+        self.entries.keys().next().copied()
+    }
+
+    /// The ID that an entry added at `now_ms` would get
+    pub fn next_id(&self, now_ms: u64) -> Option<StreamId> {
+        if now_ms > self.last_id.ms {
+            Some(StreamId::new(now_ms, 0))
+        } else {
+            self.last_id.next()
+        }
+    }
+
+    /// Appends an entry, `id` must be greater than the last one
+    pub fn add(&mut self, id: StreamId, fields: Fields) {
+        self.entries.insert(id, fields);
+        self.last_id = id;
+        self.entries_added += 1;
+    }
+
+    /// Deletes an entry, leaving a tombstone behind unlike trimming
+    pub fn remove(&mut self, id: StreamId) -> bool {
+        if self.entries.remove(&id).is_none() {
+            return false;
+        }
+        self.max_deleted_id = self.max_deleted_id.max(id);
+        true
+    }
+
+    /// Entries from `start` to `end`, both included
+    pub fn range(&self, start: StreamId, end: StreamId) -> impl DoubleEndedIterator<Item = (&StreamId, &Fields)> {
+        // BTreeMap::range panics on a reversed range
+        self.entries.range(start..=end.max(start)).filter(move |_| start <= end)
+    }
+
+    /// Removes the oldest entries until at most `max_len` are left, at most `limit` of them
+    pub fn trim_len(&mut self, max_len: usize, limit: usize) -> usize {
+        let count = self.len().saturating_sub(max_len).min(limit);
+        for _ in 0..count {
+            self.entries.pop_first();
+        }
+        count
+    }
+
+    /// Removes the entries older than `min_id`, at most `limit` of them
+    pub fn trim_min_id(&mut self, min_id: StreamId, limit: usize) -> usize {
+        let count = self.entries.range(..min_id).take(limit).count();
+        for _ in 0..count {
+            self.entries.pop_first();
+        }
+        count
+    }
+
+    /// Whether entries after `id` were deleted, which makes counting entries from positions impossible
+    pub fn has_tombstone_after(&self, id: StreamId) -> bool {
+        self.max_deleted_id > id && self.first_id().is_some_and(|first| self.max_deleted_id >= first)
+    }
+
+    /// Number of entries added up to `id` included, None when deletions make it unknown
+    pub fn entries_read_at(&self, id: StreamId) -> Option<u64> {
+        if self.entries_added == 0 {
+            return Some(0);
+        }
+        if id >= self.last_id {
+            return Some(self.entries_added);
+        }
+        if self.has_tombstone_after(id) {
+            return None;
+        }
+        let unread = self.entries.range(id.next()?..).count() as u64;
+        Some(self.entries_added.saturating_sub(unread))
+    }
+
+    /// Number of entries the group has yet to read, None when it cannot be known
+    pub fn lag(&self, group: &ConsumerGroup) -> Option<u64> {
+        if self.entries_added == 0 || group.last_id >= self.last_id {
+            return Some(0);
+        }
+        if self.has_tombstone_after(group.last_id) {
+            return None;
+        }
+        let read = group.entries_read.or_else(|| self.entries_read_at(group.last_id))?;
+        Some(self.entries_added.saturating_sub(read))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn ids_and_trimming() {
+        assert_eq!(StreamId::new(1, u64::MAX).next(), Some(StreamId::new(2, 0)));
+        assert_eq!(StreamId::new(2, 0).prev(), Some(StreamId::new(1, u64::MAX)));
+        assert_eq!(StreamId::MAX.next(), None);
+        assert_eq!(StreamId::new(5, 3).to_string(), "5-3");
+
+        let mut stream = Stream::new();
+        for ms in 1..=5 {
+            let id = stream.next_id(ms).unwrap();
+            stream.add(id, vec![("f".into(), "v".into())]);
+        }
+        assert_eq!(stream.next_id(3), Some(StreamId::new(5, 1)));
+        assert_eq!(stream.range(StreamId::new(2, 0), StreamId::new(4, 0)).count(), 3);
+        assert_eq!(stream.range(StreamId::new(4, 0), StreamId::new(2, 0)).count(), 0);
+
+        assert_eq!(stream.trim_len(3, usize::MAX), 2);
+        assert_eq!(stream.first_id(), Some(StreamId::new(3, 0)));
+        assert_eq!(stream.trim_min_id(StreamId::new(5, 0), 1), 1);
+        assert_eq!(stream.len(), 2);
+        assert_eq!(stream.max_deleted_id, StreamId::MIN);
+        assert_eq!(stream.entries_read_at(StreamId::new(4, 0)), Some(4));
+        assert!(stream.remove(StreamId::new(5, 0)));
+        assert_eq!(stream.max_deleted_id, StreamId::new(5, 0));
+        assert_eq!(stream.entries_read_at(StreamId::new(4, 0)), None);
+        assert_eq!(stream.entries_added, 5);
+    }
+}
@@ -0,0 +1,127 @@
+use bytes::Bytes;
+use super::dict::Dict;
+use super::skiplist::{LexRange, ScoreRange, SkipList};
+
+/// Members ordered by score: a map for the score of a member and a skiplist for ranks and ranges
+#[derive(Debug, Clone, Default)]
+pub struct ZSet {
+    scores: Dict<Bytes, f64>,
+    list: SkipList,
+}
+
+impl PartialEq for ZSet {
+    fn eq(&self, other: &Self) -> bool {
+        self.scores == other.scores
+    }
+}
+
+impl ZSet {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn len(&self) -> usize {
+        self.list.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.list.is_empty()
+    }
+
+    pub fn score(&self, member: &[u8]) -> Option<f64> {
+        self.scores.get(member).copied()
+    }
+
+    /// Sets the score of `member`, returns its previous score
+    pub fn insert(&mut self, member: Bytes, score: f64) -> Option<f64> {
+        match self.scores.get_mut(&member) {
+            Some(old) => {
+                let previous = *old;
+                if previous != score {
+                    *old = score;
+                    self.list.delete(previous, &member);
+                    self.list.insert(score, member);
+                }
+                Some(previous)
+            }
+            None => {
+                self.scores.insert(member.clone(), score);
+                self.list.insert(score, member);
+                None
+            }
+        }
+    }
+
+    pub fn remove(&mut self, member: &[u8]) -> Option<f64> {
+        let score = self.scores.remove(member)?;
+        self.list.delete(score, member);
+        Some(score)
+    }
+
+    /// 0-based rank of `member`, counted from the highest score if `reverse`
+    pub fn rank(&self, member: &[u8], reverse: bool) -> Option<usize> {
+        let rank = self.list.rank(self.score(member)?, member)?;
+        Some(if reverse { self.len() - 1 - rank } else { rank })
+    }
+
+    pub fn get(&self, rank: usize) -> Option<(&Bytes, f64)> {
+        self.list.get(rank)
+    }
+
+    /// Members from rank `start` to rank `end`, both included and counted from the highest score if `reverse`
+    pub fn range(&self, start: usize, end: usize, reverse: bool) -> impl Iterator<Item = (&Bytes, f64)> {
+        let (start, end) = match reverse {
+            false => (start, end),
+            true if start >= self.len() => (1, 0),
+            true => (self.len() - 1 - end.min(self.len() - 1), self.len() - 1 - start),
+        };
+        self.list.range(start, end, reverse)
+    }
+
+    pub fn score_range(&self, range: &ScoreRange) -> Option<(usize, usize)> {
+        self.list.score_range(range)
+    }
+
+    pub fn lex_range(&self, range: &LexRange) -> Option<(usize, usize)> {
+        self.list.lex_range(range)
+    }
+
+    /// Visits the members of one bucket of the map of scores, see `Dict::scan` for the cursor
+    pub fn scan(&self, cursor: u64, mut visit: impl FnMut(&Bytes, f64)) -> u64 {
+        self.scores.scan(cursor, |member, score| visit(member, *score))
+    }
+
+    /// Every member from the lowest score up
+    pub fn iter(&self) -> impl Iterator<Item = (&Bytes, f64)> {
+        self.list.range(0, self.len().saturating_sub(1), false)
+    }
+}
+
+impl FromIterator<(Bytes, f64)> for ZSet {
+    fn from_iter<I: IntoIterator<Item = (Bytes, f64)>>(iter: I) -> Self {
+        let mut zset = ZSet::new();
+        for (member, score) in iter {
+            zset.insert(member, score);
+        }
+        zset
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn scores_and_ranks_stay_in_sync() {
+        let mut zset: ZSet = [("a", 1.0), ("b", 2.0), ("c", 3.0)].into_iter().map(|(m, s)| (Bytes::from(m), s)).collect();
+        assert_eq!(zset.insert("a".into(), 5.0), Some(1.0));
+        assert_eq!(zset.rank(b"a", false), Some(2));
+        assert_eq!(zset.rank(b"a", true), Some(0));
+        assert_eq!(zset.remove(b"b"), Some(2.0));
+        assert_eq!(zset.rank(b"b", false), None);
+        let members: Vec<_> = zset.range(0, 5, true).map(|(m, s)| (m.clone(), s)).collect();
+        assert_eq!(members, vec![(Bytes::from("a"), 5.0), (Bytes::from("c"), 3.0)]);
+        assert_eq!(zset.range(1, 1, true).next().map(|(m, _)| m.clone()), Some(Bytes::from("c")));
+        assert_eq!(zset.range(2, 3, true).count(), 0);
+    }
+}
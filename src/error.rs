@@ -1,12 +1,10 @@
-use thiserror;
-
 #[allow(dead_code)]
 #[derive(thiserror::Error, Debug)]
 pub enum Error {
     #[error("syntax error")]
     Syntax,
 
-    #[error("wrong number of argumentes of '{0}' command")]
+    #[error("wrong number of arguments for '{0}' command")]
     WrongArgNumber(String),
 
     #[error("WRONGTYPE Operation against a key holding the wrong kind of value")]
@@ -15,8 +13,15 @@ pub enum Error {
     #[error("{0}")]
     Other(String),
 
+    // the connection is closed after replying with it
+    #[error("Protocol error: {0}")]
+    Protocol(String),
+
     #[error("value is not an integer or out of range")]
     InvalidInteger(#[from] std::num::ParseIntError),
+
+    #[error("value is not a valid float")]
+    InvalidFloat,
 }
 
 impl From<anyhow::Error> for Error {
@@ -6,4 +6,4 @@ pub mod context;
 pub mod command_table;
 pub mod redis_types;
 pub(crate) mod error;
-pub(crate) mod engine;
\ No newline at end of file
+pub mod engine;
\ No newline at end of file
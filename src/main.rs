@@ -1,21 +1,85 @@
 use std::sync::LazyLock;
 use anyhow::Result;
 use clap::Parser;
-use kv::{connection::Connection, utils};
+use kv::{connection::Connection, parser::ParserLimits, engine::{aof::{self, AutoRewrite, FsyncPolicy}, expire, snapshot::{self, SaveRule}}, utils};
 
 #[derive(Debug, Parser)]
 struct Args {
     #[clap(short, long, default_value = "9090")]
     port: u16,
+
+    /// Record every write command in an append only file and replay it on startup
+    #[clap(long, default_value_t = false)]
+    appendonly: bool,
+
+    #[clap(long, default_value = "appendonly.aof")]
+    appendfilename: String,
+
+    #[clap(long, value_enum, default_value = "everysec")]
+    appendfsync: FsyncPolicy,
+
+    /// Rewrite the append only file once it grows by this percentage over its last rewritten size, 0 disables it
+    #[clap(long, default_value = "100")]
+    auto_aof_rewrite_percentage: u64,
+
+    /// Never rewrite the append only file automatically below this size
+    #[clap(long, default_value = "64mb", value_parser = utils::parse_size)]
+    auto_aof_rewrite_min_size: u64,
+
+    /// Snapshot file written by SAVE and BGSAVE and loaded on startup
+    #[clap(long, default_value = "dump.kvdb")]
+    dbfilename: String,
+
+    /// Automatic snapshot rules as "<seconds> <changes>" pairs, e.g. "3600 1 300 100"
+    #[clap(long, default_value = "")]
+    save: String,
+
+    /// Largest bulk string a client may send
+    #[clap(long, default_value = "512mb", value_parser = utils::parse_size)]
+    proto_max_bulk_len: u64,
+
+    /// Largest number of elements in a request array
+    #[clap(long, default_value = "1048576")]
+    max_multibulk_len: usize,
+
+    /// Longest inline command or protocol line
+    #[clap(long, default_value = "64kb", value_parser = utils::parse_size)]
+    max_inline_len: u64,
+
+    /// Deepest nesting of aggregates in a request
+    #[clap(long, default_value = "32")]
+    max_nesting: usize,
 }
 
-static ARG: LazyLock<Args> = LazyLock::new(|| Args::parse());
+static ARG: LazyLock<Args> = LazyLock::new(Args::parse);
 
 #[tokio::main]
 async fn main() -> Result<()> {
     let port = ARG.port;
     println!("Port: {}", port);
 
+    // the append only file is more complete than the snapshot, so it wins when enabled
+    snapshot::set_path(&ARG.dbfilename);
+    if ARG.appendonly {
+        aof::set_auto_rewrite(AutoRewrite {
+            percentage: ARG.auto_aof_rewrite_percentage,
+            min_size: ARG.auto_aof_rewrite_min_size,
+        });
+        aof::open(&ARG.appendfilename, ARG.appendfsync).await?;
+    } else if std::path::Path::new(&ARG.dbfilename).exists() {
+        let count = snapshot::load(&ARG.dbfilename).await?;
+        println!("Loaded {} keys from snapshot", count);
+    }
+    snapshot::start_auto_save(SaveRule::parse_rules(&ARG.save)?);
+    expire::start_active_expire();
+
+    let limits = ParserLimits {
+        max_bulk_len: ARG.proto_max_bulk_len as usize,
+        max_multibulk_len: ARG.max_multibulk_len,
+        max_inline_len: ARG.max_inline_len as usize,
+        max_depth: ARG.max_nesting,
+    };
+
     let listener = utils::bind_port(port).await?;
     println!("Listening on: {}", listener.local_addr()?);
 
@@ -24,7 +88,7 @@ async fn main() -> Result<()> {
             Ok((socket, addr)) = listener.accept() => {
                 println!("Accepted connection from: {}", addr);
                 tokio::spawn(async move {
-                    let mut conn = Connection::new(socket);
+                    let mut conn = Connection::with_limits(socket, limits);
                     conn.serve_loop().await;
                 });
             }
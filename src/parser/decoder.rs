@@ -0,0 +1,381 @@
+// Sans-IO RESP decoder: works on any byte buffer and never touches a socket.
+//
+// Decoding a frame takes two passes, `check` validates the buffered bytes and finds where the frame ends
+// without allocating, then `parse` builds the values from the frozen frame, every string being a slice of it.
+// Requests are checked incrementally instead, resuming from the last argument found when more data comes in.
+use anyhow::Result;
+use std::ops::Range;
+use bytes::{Buf, Bytes, BytesMut};
+use super::{inline, protocol_error, ParserLimits, RespRequest, RespValue};
+
+#[derive(Debug, Clone, Default)]
+pub struct RespDecoder {
+    limits: ParserLimits,
+    // the request being received, kept across calls until it is complete
+    multibulk: Option<Multibulk>,
+}
+
+/// How far an incomplete multibulk request was found to go, like multibulklen and bulklen of redis, so that
+/// the arguments already received are not searched again every time more data comes in. Positions are offsets
+/// in the buffer, which only grows until the request is complete.
+#[derive(Debug, Clone)]
+struct Multibulk {
+    count: usize,
+    // the arguments found so far
+    ranges: Vec<Range<usize>>,
+    // where the next argument starts
+    next: usize,
+    // start and length of the payload of an argument whose header was read already
+    bulk: Option<(usize, usize)>,
+}
+
+/// A line without its CRLF: `start..end`, followed by the next frame at `next`
+struct Line {
+    start: usize,
+    end: usize,
+    next: usize,
+}
+
+impl RespDecoder {
+    pub fn new(limits: ParserLimits) -> Self {
+        RespDecoder { limits, multibulk: None }
+    }
+
+    pub fn limits(&self) -> &ParserLimits {
+        &self.limits
+    }
+
+    /// Decodes one value, returns `Ok(None)` when the buffer does not hold a complete frame yet
+    pub fn decode(&self, buf: &mut BytesMut) -> Result<Option<RespValue>> {
+        let end = match self.check(buf, 0, 0)? {
+            Some(end) => end,
+            None => return Ok(None),
+        };
+        let frame = buf.split_to(end).freeze();
+        Ok(Some(Self::parse(&frame, &mut 0)))
+    }
+
+    /// Decodes one request, either a RESP array of bulk strings or an inline command.
+    /// Empty requests are consumed and skipped, like redis-server does.
+    pub fn decode_request(&mut self, buf: &mut BytesMut) -> Result<Option<RespRequest>> {
+        loop {
+            let request = match buf.first() {
+                None => return Ok(None),
+                Some(b'*') => self.decode_multibulk_request(buf)?,
+                Some(_) => self.decode_inline_request(buf)?,
+            };
+            match request {
+                None => return Ok(None),
+                Some(Some(request)) => return Ok(Some(request)),
+                Some(None) => continue,
+            }
+        }
+    }
+
+    fn find_line(&self, data: &[u8], start: usize, too_long: &str) -> Result<Option<Line>> {
+        let newline = match data[start..].iter().position(|&c| c == b'\n') {
+            Some(pos) => start + pos,
+            None if data.len() - start > self.limits.max_inline_len => return Err(protocol_error(too_long)),
+            None => return Ok(None),
+        };
+        if newline + 1 - start > self.limits.max_inline_len {
+            return Err(protocol_error(too_long));
+        }
+        if newline == start || data[newline - 1] != b'\r' {
+            return Err(protocol_error("expected CRLF at the end of line"));
+        }
+        Ok(Some(Line { start, end: newline - 1, next: newline + 1 }))
+    }
+
+    fn parse_integer(payload: &[u8], invalid: &str) -> Result<i64> {
+        // i64::from_str accepts a leading '+', RESP does not
+        if payload.first() == Some(&b'+') {
+            return Err(protocol_error(invalid));
+        }
+        std::str::from_utf8(payload)
+            .ok()
+            .and_then(|s| s.parse::<i64>().ok())
+            .ok_or_else(|| protocol_error(invalid))
+    }
+
+    fn parse_len(payload: &[u8], max: usize, invalid: &str) -> Result<Option<usize>> {
+        match Self::parse_integer(payload, invalid)? {
+            -1 => Ok(None),
+            cnt if cnt < 0 || cnt as u64 > max as u64 => Err(protocol_error(invalid)),
+            cnt => Ok(Some(cnt as usize)),
+        }
+    }
+
+    fn parse_double(payload: &[u8]) -> Option<f64> {
+        match std::str::from_utf8(payload) {
+            Ok("inf") => Some(f64::INFINITY),
+            Ok("-inf") => Some(f64::NEG_INFINITY),
+            Ok("nan") => Some(f64::NAN),
+            Ok(s) if s.bytes().all(|c| c.is_ascii_digit() || b"+-.eE".contains(&c)) => s.parse::<f64>().ok(),
+            _ => None,
+        }
+    }
+
+    /// Checks that a bulk payload of `len` bytes starting at `start` is complete, returns the next position
+    fn check_bulk(data: &[u8], start: usize, len: usize) -> Result<Option<usize>> {
+        if data.len() < start + len + 2 {
+            return Ok(None);
+        }
+        if &data[start + len..start + len + 2] != b"\r\n" {
+            return Err(protocol_error("expected CRLF after bulk string"));
+        }
+        Ok(Some(start + len + 2))
+    }
+
+    /// Validates the frame starting at `pos`, returns where it ends or `None` if it is incomplete
+    fn check(&self, data: &[u8], pos: usize, depth: usize) -> Result<Option<usize>> {
+        if depth > self.limits.max_depth {
+            return Err(protocol_error("nesting too deep"));
+        }
+        let line = match self.find_line(data, pos, "too big line")? {
+            Some(line) => line,
+            None => return Ok(None),
+        };
+        let payload = &data[line.start + 1..line.end];
+        let limits = &self.limits;
+
+        match data[line.start] {
+            b'+' | b'-' if payload.contains(&b'\r') => Err(protocol_error("unexpected CR in simple string")),
+            b'+' | b'-' => Ok(Some(line.next)),
+            b':' => Self::parse_integer(payload, "invalid integer").map(|_| Some(line.next)),
+            b'$' => match Self::parse_len(payload, limits.max_bulk_len, "invalid bulk length")? {
+                None => Ok(Some(line.next)),
+                Some(len) => Self::check_bulk(data, line.next, len),
+            },
+            b'=' => {
+                let len = Self::parse_len(payload, limits.max_bulk_len, "invalid bulk length")?
+                    .ok_or_else(|| protocol_error("invalid bulk length"))?;
+                let next = Self::check_bulk(data, line.next, len)?;
+                if next.is_some() && (len < 4 || data[line.next + 3] != b':') {
+                    return Err(protocol_error("invalid verbatim string"));
+                }
+                Ok(next)
+            }
+            b'_' if payload.is_empty() => Ok(Some(line.next)),
+            b'_' => Err(protocol_error("invalid null")),
+            b'#' if payload == b"t" || payload == b"f" => Ok(Some(line.next)),
+            b'#' => Err(protocol_error("invalid boolean")),
+            b',' => Self::parse_double(payload)
+                .map(|_| Some(line.next))
+                .ok_or_else(|| protocol_error("invalid double")),
+            b'(' => {
+                let digits = payload.strip_prefix(b"-").unwrap_or(payload);
+                if digits.is_empty() || !digits.iter().all(u8::is_ascii_digit) {
+                    return Err(protocol_error("invalid big number"));
+                }
+                Ok(Some(line.next))
+            }
+            c @ (b'*' | b'~' | b'>' | b'%' | b'|') => {
+                let cnt = Self::parse_len(payload, limits.max_multibulk_len, "invalid multibulk length")?.unwrap_or(0);
+                let elements = if c == b'%' || c == b'|' { cnt * 2 } else { cnt };
+                let mut next = line.next;
+                for _ in 0..elements {
+                    next = match self.check(data, next, depth + 1)? {
+                        Some(next) => next,
+                        None => return Ok(None),
+                    };
+                }
+                Ok(Some(next))
+            }
+            c => Err(protocol_error(format!("invalid type byte '{}'", c.escape_ascii()))),
+        }
+    }
+
+    /// Builds the value of a frame that `check` accepted
+    fn parse(frame: &Bytes, pos: &mut usize) -> RespValue {
+        let start = *pos;
+        let end = start + frame[start..].iter().position(|&c| c == b'\n').unwrap_or(frame.len() - start) - 1;
+        *pos = end + 2;
+        let payload = &frame[start + 1..end];
+        // lengths were validated by check
+        let len = || Self::parse_integer(payload, "").unwrap_or(0);
+
+        match frame[start] {
+            b'+' => RespValue::SimpleString(frame.slice(start + 1..end)),
+            b'-' => RespValue::Error(frame.slice(start + 1..end)),
+            b':' => RespValue::Integer(len()),
+            b'$' if len() < 0 => RespValue::BulkString(None),
+            b'$' => {
+                let data = frame.slice(*pos..*pos + len() as usize);
+                *pos += data.len() + 2;
+                RespValue::BulkString(Some(data))
+            }
+            b'=' => {
+                let data = frame.slice(*pos..*pos + len() as usize);
+                *pos += data.len() + 2;
+                RespValue::Verbatim { format: [data[0], data[1], data[2]], data: data.slice(4..) }
+            }
+            b'*' if len() < 0 => RespValue::NullArray,
+            b'_' => RespValue::Null,
+            b'#' => RespValue::Boolean(payload == b"t"),
+            b',' => RespValue::Double(Self::parse_double(payload).unwrap_or_default()),
+            b'(' => RespValue::BigNumber(frame.slice(start + 1..end)),
+            c @ (b'%' | b'|') => {
+                let pairs = (0..len().max(0))
+                    .map(|_| (Self::parse(frame, pos), Self::parse(frame, pos)))
+                    .collect();
+                if c == b'%' { RespValue::Map(pairs) } else { RespValue::Attribute(pairs) }
+            }
+            c => {
+                let values = (0..len().max(0)).map(|_| Self::parse(frame, pos)).collect();
+                match c {
+                    b'~' => RespValue::Set(values),
+                    b'>' => RespValue::Push(values),
+                    _ => RespValue::Array(values),
+                }
+            }
+        }
+    }
+
+    /// `Some(None)` means an empty request was consumed
+    fn decode_multibulk_request(&mut self, buf: &mut BytesMut) -> Result<Option<Option<RespRequest>>> {
+        // taken out while working on it, an error leaves it dropped
+        let mut request = match self.multibulk.take() {
+            Some(request) => request,
+            None => {
+                let line = match self.find_line(buf, 0, "too big mbulk count string")? {
+                    Some(line) => line,
+                    None => return Ok(None),
+                };
+                match Self::parse_len(&buf[1..line.end], self.limits.max_multibulk_len, "invalid multibulk length")? {
+                    None | Some(0) => {
+                        buf.advance(line.next);
+                        return Ok(Some(None));
+                    }
+                    Some(count) => Multibulk { count, ranges: Vec::with_capacity(count.min(1024)), next: line.next, bulk: None },
+                }
+            }
+        };
+
+        // find every argument before splitting, so that an incomplete request leaves the buffer untouched
+        while request.ranges.len() < request.count {
+            let (start, len) = match request.bulk {
+                Some(bulk) => bulk,
+                None => {
+                    let Some(line) = self.find_line(buf, request.next, "too big bulk count string")? else {
+                        self.multibulk = Some(request);
+                        return Ok(None);
+                    };
+                    match buf[line.start] {
+                        b'$' => {}
+                        c => return Err(protocol_error(format!("expected '$', got '{}'", c.escape_ascii()))),
+                    }
+                    let len = Self::parse_len(&buf[line.start + 1..line.end], self.limits.max_bulk_len, "invalid bulk length")?
+                        .ok_or_else(|| protocol_error("invalid bulk length"))?;
+                    *request.bulk.insert((line.next, len))
+                }
+            };
+            let Some(next) = Self::check_bulk(buf, start, len)? else {
+                self.multibulk = Some(request);
+                return Ok(None);
+            };
+            request.ranges.push(start..start + len);
+            request.next = next;
+            request.bulk = None;
+        }
+
+        let frame = buf.split_to(request.next).freeze();
+        let mut values = request.ranges.into_iter().map(|range| frame.slice(range));
+        Ok(Some(values.next().map(|command| RespRequest {
+            command,
+            args: values.map(|arg| RespValue::BulkString(Some(arg))).collect(),
+        })))
+    }
+
+    fn decode_inline_request(&self, buf: &mut BytesMut) -> Result<Option<Option<RespRequest>>> {
+        let newline = match buf.iter().position(|&c| c == b'\n') {
+            Some(pos) => pos,
+            None if buf.len() > self.limits.max_inline_len => return Err(protocol_error("too big inline request")),
+            None => return Ok(None),
+        };
+        if newline + 1 > self.limits.max_inline_len {
+            return Err(protocol_error("too big inline request"));
+        }
+        let line = buf.split_to(newline + 1);
+        let mut args = inline::split_args(&line)?.into_iter();
+        Ok(Some(args.next().map(|command| RespRequest {
+            command,
+            args: args.map(|arg| RespValue::BulkString(Some(arg))).collect(),
+        })))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn incomplete_frames_need_more_data() -> Result<()> {
+        let decoder = RespDecoder::default();
+        let frame = b"*3\r\n$3\r\nSET\r\n$3\r\nkey\r\n%1\r\n+a\r\n:-1\r\n";
+        let mut buf = BytesMut::new();
+        for (i, &byte) in frame.iter().enumerate() {
+            buf.extend_from_slice(&[byte]);
+            let value = decoder.decode(&mut buf)?;
+            assert_eq!(value.is_some(), i == frame.len() - 1);
+            if let Some(value) = value {
+                assert_eq!(value, RespValue::Array(vec![
+                    RespValue::BulkString(Some("SET".into())),
+                    RespValue::BulkString(Some("key".into())),
+                    RespValue::Map(vec![(RespValue::SimpleString("a".into()), RespValue::Integer(-1))]),
+                ]));
+            }
+        }
+        assert!(buf.is_empty());
+        Ok(())
+    }
+
+    #[test]
+    fn requests_are_sliced_from_the_buffer() -> Result<()> {
+        let mut decoder = RespDecoder::default();
+        let mut buf = BytesMut::from(&b"*0\r\n*2\r\n$3\r\nGET\r\n$3\r\nkey\r\n\r\nPING\r\n*1\r\n$4\r\nPI"[..]);
+        let base = buf.as_ptr() as usize;
+
+        let request = decoder.decode_request(&mut buf)?.unwrap();
+        assert_eq!(request.command, "GET");
+        // zero copy: the command points into the original allocation
+        assert_eq!(request.command.as_ptr() as usize, base + 12);
+
+        assert_eq!(decoder.decode_request(&mut buf)?.unwrap().command, "PING");
+        assert!(decoder.decode_request(&mut buf)?.is_none());
+        buf.extend_from_slice(b"NG\r\n");
+        assert_eq!(decoder.decode_request(&mut buf)?.unwrap().command, "PING");
+        assert!(buf.is_empty());
+        Ok(())
+    }
+
+    #[test]
+    fn requests_resume_where_they_stopped() -> Result<()> {
+        let mut decoder = RespDecoder::default();
+        let frame = b"*3\r\n$3\r\nSET\r\n$3\r\nkey\r\n$5\r\nvalue\r\n*1\r\n$4\r\nPING\r\n";
+        let mut buf = BytesMut::new();
+        let mut requests = vec![];
+        for &byte in frame {
+            buf.extend_from_slice(&[byte]);
+            if buf.len() == 24 {
+                // SET and key were found, the header of the value is still cut
+                assert_eq!(decoder.multibulk.as_ref().map(|request| request.ranges.len()), Some(2));
+            }
+            requests.extend(decoder.decode_request(&mut buf)?);
+        }
+        let commands: Vec<_> = requests.iter().map(|request| (request.command.clone(), request.args.len())).collect();
+        assert_eq!(commands, vec![(Bytes::from("SET"), 2), (Bytes::from("PING"), 0)]);
+        assert_eq!(requests[0].args[1], RespValue::BulkString(Some("value".into())));
+        assert!(buf.is_empty() && decoder.multibulk.is_none());
+        Ok(())
+    }
+
+    #[test]
+    fn invalid_data_fails_before_it_is_complete() {
+        let mut decoder = RespDecoder::new(ParserLimits { max_inline_len: 16, ..Default::default() });
+        assert!(decoder.decode(&mut BytesMut::from(&b"$-2\r\n"[..])).is_err());
+        assert!(decoder.decode(&mut BytesMut::from(&b"$3\r\nabcde"[..])).is_err());
+        assert!(decoder.decode_request(&mut BytesMut::from(&[b'a'; 32][..])).is_err());
+        assert!(decoder.decode_request(&mut BytesMut::from(&b"*1\r\n:1\r\n"[..])).is_err());
+    }
+}
@@ -0,0 +1,197 @@
+// Response encoder: serializes into one reusable buffer per connection.
+// Bulk strings above `ZERO_COPY_THRESHOLD` are not copied, they are queued as their own chunk
+// and sent with the rest of the buffer through a single vectored write.
+use std::{collections::VecDeque, io::IoSlice, sync::LazyLock};
+use bytes::{Buf, BufMut, Bytes, BytesMut};
+use tokio::io::{AsyncWrite, AsyncWriteExt};
+use super::{format_double, RespValue};
+
+const ZERO_COPY_THRESHOLD: usize = 16 * 1024;
+const SHARED_HEADERS: usize = 32;
+const MAX_IO_SLICES: usize = 64;
+
+// "*<n>\r\n" and "$<n>\r\n" for the most common small lengths, like redis shared.mbulkhdr and shared.bulkhdr
+static ARRAY_HEADERS: LazyLock<Vec<Vec<u8>>> = LazyLock::new(|| shared_headers(b'*'));
+static BULK_HEADERS: LazyLock<Vec<Vec<u8>>> = LazyLock::new(|| shared_headers(b'$'));
+
+fn shared_headers(prefix: u8) -> Vec<Vec<u8>> {
+    (0..SHARED_HEADERS).map(|len| format!("{}{}\r\n", prefix as char, len).into_bytes()).collect()
+}
+
+#[derive(Debug, Default)]
+pub struct RespEncoder {
+    buf: BytesMut,
+    // encoded data waiting to be written, in order; `buf` holds what comes after the last chunk
+    chunks: VecDeque<Bytes>,
+}
+
+impl RespEncoder {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Number of encoded bytes not written yet
+    pub fn pending(&self) -> usize {
+        self.chunks.iter().map(Bytes::len).sum::<usize>() + self.buf.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.chunks.is_empty() && self.buf.is_empty()
+    }
+
+    fn header(&mut self, prefix: u8, len: usize) {
+        let shared = match prefix {
+            b'*' => Some(&ARRAY_HEADERS),
+            b'$' => Some(&BULK_HEADERS),
+            _ => None,
+        };
+        match shared {
+            Some(headers) if len < SHARED_HEADERS => self.buf.put_slice(&headers[len]),
+            _ => {
+                self.buf.put_u8(prefix);
+                self.buf.put_slice(itoa::Buffer::new().format(len).as_bytes());
+                self.buf.put_slice(b"\r\n");
+            }
+        }
+    }
+
+    fn line(&mut self, prefix: u8, s: &[u8]) {
+        self.buf.put_u8(prefix);
+        self.buf.put_slice(s);
+        self.buf.put_slice(b"\r\n");
+    }
+
+    fn bulk(&mut self, s: &Bytes) {
+        self.header(b'$', s.len());
+        if s.len() >= ZERO_COPY_THRESHOLD {
+            if !self.buf.is_empty() {
+                self.chunks.push_back(self.buf.split().freeze());
+            }
+            self.chunks.push_back(s.clone());
+        } else {
+            self.buf.put_slice(s);
+        }
+        self.buf.put_slice(b"\r\n");
+    }
+
+    pub fn encode(&mut self, value: &RespValue) {
+        match value {
+            RespValue::SimpleString(s) => self.line(b'+', s),
+            RespValue::Error(s) => self.line(b'-', s),
+            RespValue::Integer(i) => self.line(b':', itoa::Buffer::new().format(*i).as_bytes()),
+            RespValue::BulkString(Some(s)) => self.bulk(s),
+            RespValue::BulkString(None) => self.buf.put_slice(b"$-1\r\n"),
+            RespValue::NullArray => self.buf.put_slice(b"*-1\r\n"),
+            RespValue::Array(values) | RespValue::Set(values) | RespValue::Push(values) => {
+                let prefix = match value {
+                    RespValue::Set(_) => b'~',
+                    RespValue::Push(_) => b'>',
+                    _ => b'*',
+                };
+                self.header(prefix, values.len());
+                for v in values {
+                    self.encode(v);
+                }
+            }
+            RespValue::Map(pairs) | RespValue::Attribute(pairs) => {
+                self.header(if matches!(value, RespValue::Map(_)) { b'%' } else { b'|' }, pairs.len());
+                for (k, v) in pairs {
+                    self.encode(k);
+                    self.encode(v);
+                }
+            }
+            RespValue::Null => self.buf.put_slice(b"_\r\n"),
+            RespValue::Boolean(b) => self.buf.put_slice(if *b { b"#t\r\n" } else { b"#f\r\n" }),
+            RespValue::Double(d) => self.line(b',', format_double(*d).as_bytes()),
+            RespValue::BigNumber(n) => self.line(b'(', n),
+            RespValue::Verbatim { format, data } => {
+                self.header(b'=', data.len() + 4);
+                self.buf.put_slice(format);
+                self.buf.put_u8(b':');
+                self.buf.put_slice(data);
+                self.buf.put_slice(b"\r\n");
+            }
+        }
+    }
+
+    /// Takes everything encoded so far as one contiguous buffer
+    pub fn take(&mut self) -> Bytes {
+        if self.chunks.is_empty() {
+            return self.buf.split().freeze();
+        }
+        let mut out = BytesMut::with_capacity(self.pending());
+        for chunk in self.chunks.drain(..) {
+            out.put_slice(&chunk);
+        }
+        out.put_slice(&self.buf.split());
+        out.freeze()
+    }
+
+    /// Writes everything encoded so far with vectored writes
+    pub async fn write_to<W: AsyncWrite + Unpin>(&mut self, writer: &mut W) -> std::io::Result<()> {
+        if !self.buf.is_empty() {
+            self.chunks.push_back(self.buf.split().freeze());
+        }
+
+        while !self.chunks.is_empty() {
+            let slices: Vec<IoSlice> = self.chunks.iter().take(MAX_IO_SLICES).map(|c| IoSlice::new(c)).collect();
+            let mut written = writer.write_vectored(&slices).await?;
+            if written == 0 {
+                return Err(std::io::ErrorKind::WriteZero.into());
+            }
+
+            while written > 0 {
+                let front = self.chunks.front_mut().expect("written more than queued");
+                if written >= front.len() {
+                    written -= front.len();
+                    self.chunks.pop_front();
+                } else {
+                    front.advance(written);
+                    written = 0;
+                }
+            }
+        }
+        writer.flush().await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn large_bulk_strings_are_not_copied() -> std::io::Result<()> {
+        let large = Bytes::from(vec![b'x'; ZERO_COPY_THRESHOLD * 2]);
+        let value = RespValue::Array(vec![
+            RespValue::Integer(-42),
+            RespValue::BulkString(Some(large.clone())),
+            RespValue::BulkString(Some("small".into())),
+        ]);
+
+        let mut encoder = RespEncoder::new();
+        encoder.encode(&value);
+        assert!(encoder.chunks.iter().any(|chunk| chunk.as_ptr() == large.as_ptr()));
+        assert_eq!(encoder.pending(), value.encoded_len());
+
+        let mut out = Vec::new();
+        encoder.write_to(&mut out).await?;
+        assert!(encoder.is_empty());
+
+        let mut expected = format!("*3\r\n:-42\r\n${}\r\n", large.len()).into_bytes();
+        expected.extend_from_slice(&large);
+        expected.extend_from_slice(b"\r\n$5\r\nsmall\r\n");
+        assert_eq!(out, expected);
+        Ok(())
+    }
+
+    #[test]
+    fn buffer_is_reused() {
+        let mut encoder = RespEncoder::new();
+        encoder.encode(&RespValue::BulkString(Some("value".into())));
+        assert_eq!(encoder.take(), "$5\r\nvalue\r\n");
+        encoder.encode(&RespValue::Array(vec![RespValue::Null; 40]));
+        let encoded = encoder.take();
+        assert!(encoded.starts_with(b"*40\r\n_\r\n"));
+        assert_eq!(encoded.len(), 5 + 40 * 3);
+    }
+}
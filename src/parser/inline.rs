@@ -0,0 +1,116 @@
+// Inline commands, as typed by hand in telnet or netcat: words separated by spaces, terminated by a newline.
+// Quoting follows redis-server: double quotes take escapes such as \n or \x41, single quotes only \'.
+use anyhow::Result;
+use bytes::Bytes;
+use crate::error::Error;
+
+fn hex_digit(c: u8) -> Option<u8> {
+    (c as char).to_digit(16).map(|d| d as u8)
+}
+
+pub fn split_args(line: &[u8]) -> Result<Vec<Bytes>> {
+    let unbalanced = || anyhow::Error::from(Error::Protocol("unbalanced quotes in request".into()));
+    let mut args = Vec::new();
+    let mut i = 0;
+
+    loop {
+        while i < line.len() && line[i].is_ascii_whitespace() {
+            i += 1;
+        }
+        if i >= line.len() {
+            return Ok(args);
+        }
+
+        let mut arg = Vec::new();
+        match line[i] {
+            b'"' => {
+                i += 1;
+                loop {
+                    let c = *line.get(i).ok_or_else(unbalanced)?;
+                    match c {
+                        b'\\' if i + 3 < line.len()
+                            && line[i + 1] == b'x'
+                            && hex_digit(line[i + 2]).is_some()
+                            && hex_digit(line[i + 3]).is_some() =>
+                        {
+                            arg.push(hex_digit(line[i + 2]).unwrap() * 16 + hex_digit(line[i + 3]).unwrap());
+                            i += 4;
+                        }
+                        b'\\' if i + 1 < line.len() => {
+                            arg.push(match line[i + 1] {
+                                b'n' => b'\n',
+                                b'r' => b'\r',
+                                b't' => b'\t',
+                                b'b' => 0x08,
+                                b'a' => 0x07,
+                                other => other,
+                            });
+                            i += 2;
+                        }
+                        b'"' => {
+                            // the closing quote must be followed by a space or the end of the line
+                            if line.get(i + 1).is_some_and(|c| !c.is_ascii_whitespace()) {
+                                return Err(unbalanced());
+                            }
+                            i += 1;
+                            break;
+                        }
+                        c => {
+                            arg.push(c);
+                            i += 1;
+                        }
+                    }
+                }
+            }
+            b'\'' => {
+                i += 1;
+                loop {
+                    let c = *line.get(i).ok_or_else(unbalanced)?;
+                    match c {
+                        b'\\' if line.get(i + 1) == Some(&b'\'') => {
+                            arg.push(b'\'');
+                            i += 2;
+                        }
+                        b'\'' => {
+                            if line.get(i + 1).is_some_and(|c| !c.is_ascii_whitespace()) {
+                                return Err(unbalanced());
+                            }
+                            i += 1;
+                            break;
+                        }
+                        c => {
+                            arg.push(c);
+                            i += 1;
+                        }
+                    }
+                }
+            }
+            _ => {
+                while i < line.len() && !line[i].is_ascii_whitespace() {
+                    arg.push(line[i]);
+                    i += 1;
+                }
+            }
+        }
+        args.push(Bytes::from(arg));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn split_inline_arguments() -> Result<()> {
+        assert_eq!(split_args(b"SET key value\r\n")?, vec!["SET", "key", "value"]);
+        assert_eq!(split_args(b"  PING  ")?, vec!["PING"]);
+        assert!(split_args(b"\r\n")?.is_empty());
+        assert_eq!(split_args(br#"SET "a b" 'c d'"#)?, vec!["SET", "a b", "c d"]);
+        assert_eq!(split_args(br#"SET "\x41\n\"" 'it\'s'"#)?, vec![&b"SET"[..], b"A\n\"", b"it's"]);
+        assert_eq!(split_args(br#"SET "" x"#)?, vec!["SET", "", "x"]);
+        assert!(split_args(br#"SET "abc"#).is_err());
+        assert!(split_args(br#"SET "abc"d"#).is_err());
+        assert!(split_args(b"SET 'abc").is_err());
+        Ok(())
+    }
+}
@@ -1,45 +1,128 @@
-use tokio::io::{AsyncBufReadExt, AsyncReadExt, AsyncWriteExt, BufReader, BufWriter};
-use bytes::Bytes;
+use tokio::io::AsyncReadExt;
+use bytes::{Bytes, BytesMut};
 use std::sync::LazyLock;
-use async_recursion::async_recursion;
 use anyhow::{anyhow, Result};
+use crate::error::Error;
 
-#[derive(Debug, Clone, PartialEq, Eq)]
+mod decoder;
+mod encoder;
+mod inline;
+
+pub use decoder::RespDecoder;
+pub use encoder::RespEncoder;
+
+const READ_BUFFER_SIZE: usize = 16 * 1024;
+
+#[derive(Debug, Clone, PartialEq)]
 pub enum RespValue {
     SimpleString(Bytes),
     Error(Bytes),
     Integer(i64),
     BulkString(Option<Bytes>),
     Array(Vec<RespValue>),
+    // the null array `*-1`, which RESP3 replaces with its null like the null bulk string
+    NullArray,
+
+    // RESP3 only
+    Null,
+    Boolean(bool),
+    Double(f64),
+    BigNumber(Bytes),
+    Map(Vec<(RespValue, RespValue)>),
+    Set(Vec<RespValue>),
+    // format is a three bytes hint such as "txt" or "mkd"
+    Verbatim { format: [u8; 3], data: Bytes },
+    Push(Vec<RespValue>),
+    Attribute(Vec<(RespValue, RespValue)>),
 }
 
+pub const RESP2: u8 = 2;
+pub const RESP3: u8 = 3;
+
 pub static OK_RESP: LazyLock<RespValue> = LazyLock::new(|| RespValue::SimpleString("OK".into()));
 pub static NULL_RESP: LazyLock<RespValue> = LazyLock::new(|| RespValue::BulkString(None));
 
+fn format_double(d: f64) -> String {
+    if d.is_nan() {
+        "nan".into()
+    } else if d.is_infinite() {
+        if d > 0.0 { "inf".into() } else { "-inf".into() }
+    } else {
+        d.to_string()
+    }
+}
+
 pub trait RespWriter: tokio::io::AsyncWrite + Unpin + Send {}
 impl<T> RespWriter for T where T: tokio::io::AsyncWrite + Unpin + Send {}
     
 
 impl RespValue {
-    fn get_expected_len(&self) -> usize {
+    /// Converts the value for a connection speaking `protocol`.
+    /// RESP2 has no counterpart for the RESP3 types, so they are mapped the way redis does.
+    pub fn into_protocol(self, protocol: u8) -> RespValue {
+        if protocol >= RESP3 {
+            return match self {
+                RespValue::BulkString(None) | RespValue::NullArray => RespValue::Null,
+                RespValue::Array(arr) => RespValue::Array(arr.into_iter().map(|v| v.into_protocol(protocol)).collect()),
+                RespValue::Set(arr) => RespValue::Set(arr.into_iter().map(|v| v.into_protocol(protocol)).collect()),
+                RespValue::Push(arr) => RespValue::Push(arr.into_iter().map(|v| v.into_protocol(protocol)).collect()),
+                RespValue::Map(pairs) => RespValue::Map(pairs.into_iter().map(|(k, v)| (k.into_protocol(protocol), v.into_protocol(protocol))).collect()),
+                value => value,
+            };
+        }
+
+        match self {
+            RespValue::Null => RespValue::BulkString(None),
+            RespValue::Boolean(b) => RespValue::Integer(b as i64),
+            RespValue::Double(d) => RespValue::BulkString(Some(format_double(d).into())),
+            RespValue::BigNumber(n) => RespValue::BulkString(Some(n)),
+            RespValue::Verbatim { data, .. } => RespValue::BulkString(Some(data)),
+            RespValue::Array(arr) | RespValue::Set(arr) | RespValue::Push(arr) => {
+                RespValue::Array(arr.into_iter().map(|v| v.into_protocol(protocol)).collect())
+            }
+            RespValue::Map(pairs) | RespValue::Attribute(pairs) => RespValue::Array(
+                pairs
+                    .into_iter()
+                    .flat_map(|(k, v)| [k.into_protocol(protocol), v.into_protocol(protocol)])
+                    .collect(),
+            ),
+            value => value,
+        }
+    }
+
+    pub fn bulk_array(items: impl IntoIterator<Item = Bytes>) -> RespValue {
+        RespValue::Array(items.into_iter().map(|item| RespValue::BulkString(Some(item))).collect())
+    }
+
+    /// Exact number of bytes produced by `write`
+    pub fn encoded_len(&self) -> usize {
+        fn header_len(len: usize) -> usize {
+            itoa::Buffer::new().format(len).len() + 3 // for type byte and \r\n
+        }
         match self {
-            RespValue::SimpleString(s) | RespValue::Error(s) => s.len() + 3, // +3 for +\r\n or -\r\n
-            RespValue::Integer(_) => 32, // 32 is enough for i64
-            RespValue::BulkString(s) => s.as_ref().map(|s| s.len()).unwrap_or(0) + 16, // for length or -1 + $\r\n and etc
-            RespValue::Array(arr) => {
-                let mut len = 3; // for *\r\n
-                for v in arr {
-                    len += v.get_expected_len();
-                }
-                len
+            RespValue::SimpleString(s) | RespValue::Error(s) => s.len() + 3,
+            RespValue::Integer(i) => itoa::Buffer::new().format(*i).len() + 3,
+            RespValue::BulkString(Some(s)) => header_len(s.len()) + s.len() + 2,
+            RespValue::BulkString(None) | RespValue::NullArray => 5,
+            RespValue::Array(arr) | RespValue::Set(arr) | RespValue::Push(arr) => {
+                header_len(arr.len()) + arr.iter().map(|v| v.encoded_len()).sum::<usize>()
             }
+            RespValue::Map(pairs) | RespValue::Attribute(pairs) => {
+                header_len(pairs.len()) + pairs.iter().map(|(k, v)| k.encoded_len() + v.encoded_len()).sum::<usize>()
+            }
+            RespValue::Null => 3,
+            RespValue::Boolean(_) => 4,
+            RespValue::Double(d) => format_double(*d).len() + 3,
+            RespValue::BigNumber(n) => n.len() + 3,
+            RespValue::Verbatim { data, .. } => header_len(data.len() + 4) + data.len() + 6,
         }
     }
 
     pub fn as_str(&self) -> Result<&str> {
         match self {
-            RespValue::SimpleString(s) => Ok(unsafe{ std::str::from_utf8_unchecked(s) }),
-            RespValue::BulkString(Some(s)) => Ok(unsafe{ std::str::from_utf8_unchecked(s) }),
+            RespValue::SimpleString(s) | RespValue::BulkString(Some(s)) => {
+                std::str::from_utf8(s).map_err(|_| anyhow!("Invalid UTF-8 string"))
+            }
             RespValue::BulkString(None) => Err(anyhow!("Null bulk string is invalid")),
             _ => Err(anyhow!("Invalid type to convert to string")),
         }
@@ -60,182 +143,268 @@ impl RespValue {
         }
     }
 
-    async fn write_simple_string(s: &Bytes, writer: &mut impl RespWriter) -> Result<()> {
-        writer.write_all(b"+").await?;
-        writer.write_all(&s).await?;
-        writer.write_all(b"\r\n").await?;
-        Ok(())
-    }
-
-    async fn write_error(s: &Bytes, writer: &mut impl RespWriter) -> Result<()> {
-        writer.write_all(b"-").await?;
-        writer.write_all(&s).await?;
-        writer.write_all(b"\r\n").await?;
+    pub async fn write(&self, writer: &mut impl RespWriter) -> Result<()> {
+        let mut encoder = RespEncoder::new();
+        encoder.encode(self);
+        encoder.write_to(writer).await?;
         Ok(())
     }
+}
 
-    async fn write_integer(i: i64, writer: &mut impl RespWriter) -> Result<()> {
-        writer.write_all(b":").await?;
-        writer.write_all(i.to_string().as_bytes()).await?;
-        writer.write_all(b"\r\n").await?;
-        Ok(())
-    }
 
-    async fn write_bulk_string(s: &Option<Bytes>, writer: &mut impl RespWriter) -> Result<()> {
-        match s {
-            Some(s) => {
-                writer.write_all(b"$").await?;
-                writer.write_all(s.len().to_string().as_bytes()).await?;
-                writer.write_all(b"\r\n").await?;
-                writer.write_all(&s).await?;
-                writer.write_all(b"\r\n").await?;
-            }
-            None => {
-                writer.write_all(b"$-1\r\n").await?;
-            }
-        }
-        Ok(())
-    }
+#[derive(Debug, Clone)]
+pub struct RespRequest {
+    pub command: Bytes,
+    pub args: Vec<RespValue>,
+}
 
-    #[async_recursion]
-    async fn write_array(arr: &[RespValue], writer: &mut impl RespWriter) -> Result<()> {
-        writer.write_all(b"*").await?;
-        writer.write_all(arr.len().to_string().as_bytes()).await?;
-        writer.write_all(b"\r\n").await?;
-        for v in arr {
-            v.write_dispatch(writer).await?;
-        }
-        Ok(())
+impl RespRequest {
+    /// Encodes the request back into the RESP array a client would send
+    pub fn to_resp(&self) -> RespValue {
+        let mut values = Vec::with_capacity(self.args.len() + 1);
+        values.push(RespValue::BulkString(Some(self.command.clone())));
+        values.extend(self.args.iter().cloned());
+        RespValue::Array(values)
     }
+}
 
-    async fn write_dispatch(&self, writer: &mut impl RespWriter) -> Result<()> {
-        match self {
-            RespValue::SimpleString(value) => Self::write_simple_string(value, writer).await?,
-            RespValue::Error(value) => Self::write_error(value, writer).await?,
-            RespValue::Integer(value) => Self::write_integer(*value, writer).await?,
-            RespValue::BulkString(value) => Self::write_bulk_string(value, writer).await?,
-            RespValue::Array(value) => Self::write_array(value, writer).await?,
-        }
-        Ok(())
-    }
 
-    pub async fn write(&self, writer: &mut impl RespWriter) -> Result<()>{
-        let expected_len = self.get_expected_len();
-        let mut writer = BufWriter::with_capacity(expected_len, writer);
-        self.write_dispatch(&mut writer).await?;
+/// Bounds on what a client may declare, so that a single request cannot exhaust the server memory
+#[derive(Debug, Clone, Copy)]
+pub struct ParserLimits {
+    pub max_bulk_len: usize,
+    pub max_multibulk_len: usize,
+    pub max_inline_len: usize,
+    pub max_depth: usize,
+}
 
-        writer.flush().await?;
-        Ok(())
+impl Default for ParserLimits {
+    fn default() -> Self {
+        ParserLimits {
+            max_bulk_len: 512 * 1024 * 1024,
+            max_multibulk_len: 1024 * 1024,
+            max_inline_len: 64 * 1024,
+            max_depth: 32,
+        }
     }
 }
 
-
-#[derive(Debug)]
-pub struct RespRequest {
-    pub command: Bytes,
-    pub args: Vec<RespValue>,
+fn protocol_error(message: impl Into<String>) -> anyhow::Error {
+    Error::Protocol(message.into()).into()
 }
 
-
+/// Async parser reading from a socket, decoding is delegated to `RespDecoder`
 pub struct RespParser<R: AsyncReadExt> {
-    reader: BufReader<R>,
+    reader: R,
+    buffer: BytesMut,
+    decoder: RespDecoder,
 }
 
 impl<R: AsyncReadExt + Unpin + Send> RespParser<R> {
     pub fn new(reader: R) -> Self{
+        Self::with_limits(reader, ParserLimits::default())
+    }
+
+    pub fn with_limits(reader: R, limits: ParserLimits) -> Self {
         RespParser {
-            reader: BufReader::new(reader),
+            reader,
+            buffer: BytesMut::with_capacity(READ_BUFFER_SIZE),
+            decoder: RespDecoder::new(limits),
         }
     }
 
-    pub async fn parse(&mut self) -> Result<RespValue> {
-        let mut line = Vec::new();
-        self.reader.read_until(b'\n', &mut line).await?;
-
-        let length = line.len();
-        if length < 3 {
-            if length == 0 {
-                return Err(anyhow!("EOF"));
-            }
+    /// Whether bytes of further requests are already buffered, e.g. from a pipelined batch
+    pub fn has_buffered(&self) -> bool {
+        !self.buffer.is_empty()
+    }
 
-            return Err(anyhow!("Invalid line {:?}", line));
+    /// Reads what the socket has into the buffer, fails once it is closed
+    pub async fn fill_buffer(&mut self) -> Result<()> {
+        self.buffer.reserve(READ_BUFFER_SIZE);
+        if self.reader.read_buf(&mut self.buffer).await? == 0 {
+            return Err(anyhow!("EOF"));
         }
+        Ok(())
+    }
 
-        let first_char = line[0];
-        
-        match first_char {
-            // simple string
-            b'+' => {
-                let trimed = Bytes::from(line).slice(1..length - 2);
-                Ok(RespValue::SimpleString(trimed))
+    pub async fn parse(&mut self) -> Result<RespValue> {
+        loop {
+            if let Some(value) = self.decoder.decode(&mut self.buffer)? {
+                return Ok(value);
             }
+            self.fill_buffer().await?;
+        }
+    }
 
-            // error
-            b'-' => {
-                let trimed = Bytes::from(line).slice(1..length - 2);
-                Ok(RespValue::Error(trimed))
-            }
+    /// Decodes a request from the bytes already buffered, without reading the socket
+    pub fn try_parse_request(&mut self) -> Result<Option<RespRequest>> {
+        self.decoder.decode_request(&mut self.buffer)
+    }
 
-            // integer
-            b':' => {
-                let value: u64 = std::str::from_utf8(&line[1..length - 2])?.parse()?;
-                Ok(RespValue::Integer(value as i64))
+    /// Reads one request, either a RESP array of bulk strings or an inline command
+    pub async fn parse_request(&mut self) -> Result<RespRequest> {
+        loop {
+            if let Some(request) = self.try_parse_request()? {
+                return Ok(request);
             }
+            self.fill_buffer().await?;
+        }
+    }
+}
 
-            // bulk string
-            b'$' => {
-                let cnt = std::str::from_utf8(&line[1..length - 2])?.parse::<i64>()?;
-                if cnt == -1 {
-                    return Ok(RespValue::BulkString(None));
-                }
-
-                if cnt == 0 {
-                    return Ok(RespValue::BulkString(Some(Bytes::new())));
-                }
-
-                let mut buf = vec![0; cnt as usize + 2]; // +2 for \r\n
-                self.reader.read_exact(&mut buf).await?;
-                let buf = Bytes::from(buf).slice(0..cnt as usize);
-  
-                Ok(RespValue::BulkString(Some(buf)))
-            }
+#[cfg(test)]
+mod tests {
+    use super::*;
 
-            // array
-            b'*' => {
-                let cnt: usize = std::str::from_utf8(&line[1..length - 2])?.parse()?;
-                let mut array = Vec::with_capacity(cnt);
+    async fn round_trip(value: &RespValue) -> Result<RespValue> {
+        let mut buf = Vec::new();
+        value.write(&mut buf).await?;
+        assert_eq!(buf.len(), value.encoded_len());
+        RespParser::new(buf.as_slice()).parse().await
+    }
 
-                for _ in 0..cnt {
-                    let value = Box::pin(self.parse()).await?;
-                    array.push(value);
-                }
-                Ok(RespValue::Array(array))
-            }
-            _ => Err(anyhow!("Invalid character: {}", first_char))
+    #[tokio::test]
+    async fn resp3_round_trip() -> Result<()> {
+        let values = [
+            RespValue::Null,
+            RespValue::Boolean(true),
+            RespValue::Boolean(false),
+            RespValue::Double(1.5),
+            RespValue::Double(f64::NEG_INFINITY),
+            RespValue::BigNumber("-3492890328409238509324850943850943825024385".into()),
+            RespValue::Verbatim { format: *b"txt", data: "Some string".into() },
+            RespValue::Map(vec![(RespValue::SimpleString("first".into()), RespValue::Integer(1))]),
+            RespValue::Set(vec![RespValue::Integer(1), RespValue::BulkString(Some("a".into()))]),
+            RespValue::Push(vec![RespValue::BulkString(Some("message".into()))]),
+            RespValue::Attribute(vec![(RespValue::SimpleString("ttl".into()), RespValue::Integer(3600))]),
+        ];
+        for value in values {
+            assert_eq!(round_trip(&value).await?, value);
         }
+        Ok(())
     }
 
-    pub async fn parse_request(&mut self) -> Result<RespRequest> {
-        match self.parse().await? {
-            RespValue::Array(values) => {
-                let command = values
-                    .get(0)
-                    .and_then(|v| match v {
-                        // resp command must be a bulk string
-                        RespValue::BulkString(Some(cmd)) => Some(cmd.clone()),
-                        _ => None,
-                    })
-                    .ok_or(anyhow!("Invalid command <{:?}>", values))?;
-
-                let args = values.iter().skip(1).cloned().collect();
-                Ok(RespRequest {
-                    command,
-                    args,
-                })
-            }
+    #[tokio::test]
+    async fn limits_are_enforced() {
+        let limits = ParserLimits { max_bulk_len: 8, max_multibulk_len: 4, max_inline_len: 32, max_depth: 2 };
+        let cases: [&[u8]; 5] = [
+            b"*1\r\n$9\r\n123456789\r\n",
+            b"*5\r\n",
+            b"*1\r\n$-1\r\n",
+            b"*1\r\n$-2\r\n",
+            &[b'a'; 64],
+        ];
+        for case in cases {
+            let err = RespParser::with_limits(case, limits).parse_request().await.unwrap_err();
+            assert!(matches!(err.downcast_ref::<Error>(), Some(Error::Protocol(_))), "{:?}: {}", case, err);
+        }
+
+        let ok = RespParser::with_limits(&b"*2\r\n$3\r\nGET\r\n$8\r\n12345678\r\n"[..], limits).parse_request().await;
+        assert!(ok.is_ok());
+    }
+
+    #[tokio::test]
+    async fn nesting_limit() {
+        let limits = ParserLimits { max_depth: 2, ..Default::default() };
+        let nested = b"*1\r\n*1\r\n*1\r\n*1\r\n:1\r\n";
+        let err = RespParser::with_limits(&nested[..], limits).parse().await.unwrap_err();
+        assert_eq!(err.to_string(), "Protocol error: nesting too deep");
+        assert!(RespParser::with_limits(&nested[8..], limits).parse().await.is_ok());
+    }
 
-            content => Err(anyhow!("Invalid request <{:?}>", content).into()),
+    #[tokio::test]
+    async fn conformance_valid_frames() -> Result<()> {
+        let bulk = |s: &'static str| RespValue::BulkString(Some(s.into()));
+        let cases: Vec<(&[u8], RespValue)> = vec![
+            (b"+OK\r\n", RespValue::SimpleString("OK".into())),
+            (b"+\r\n", RespValue::SimpleString("".into())),
+            (b"-ERR unknown\r\n", RespValue::Error("ERR unknown".into())),
+            (b":0\r\n", RespValue::Integer(0)),
+            (b":-5\r\n", RespValue::Integer(-5)),
+            (b":9223372036854775807\r\n", RespValue::Integer(i64::MAX)),
+            (b":-9223372036854775808\r\n", RespValue::Integer(i64::MIN)),
+            (b"$0\r\n\r\n", bulk("")),
+            (b"$-1\r\n", RespValue::BulkString(None)),
+            (b"$4\r\na\r\nb\r\n", bulk("a\r\nb")),
+            (b"*0\r\n", RespValue::Array(vec![])),
+            (b"*-1\r\n", RespValue::NullArray),
+            (b"*2\r\n:1\r\n$1\r\nx\r\n", RespValue::Array(vec![RespValue::Integer(1), bulk("x")])),
+            (b"_\r\n", RespValue::Null),
+            (b"#t\r\n", RespValue::Boolean(true)),
+            (b",1.23\r\n", RespValue::Double(1.23)),
+            (b",-1e10\r\n", RespValue::Double(-1e10)),
+            (b",inf\r\n", RespValue::Double(f64::INFINITY)),
+            (b"(123456789012345678901234567890\r\n", RespValue::BigNumber("123456789012345678901234567890".into())),
+            (b"=7\r\ntxt:abc\r\n", RespValue::Verbatim { format: *b"txt", data: "abc".into() }),
+            (b"%1\r\n+k\r\n:1\r\n", RespValue::Map(vec![(RespValue::SimpleString("k".into()), RespValue::Integer(1))])),
+        ];
+        for (frame, expected) in cases {
+            assert_eq!(RespParser::new(frame).parse().await?, expected, "{}", frame.escape_ascii());
         }
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn conformance_malformed_frames() {
+        let cases: Vec<(&[u8], &str)> = vec![
+            (b":abc\r\n", "Protocol error: invalid integer"),
+            (b":+1\r\n", "Protocol error: invalid integer"),
+            (b":99999999999999999999\r\n", "Protocol error: invalid integer"),
+            (b":1\n", "Protocol error: expected CRLF at the end of line"),
+            (b"+a\rb\r\n", "Protocol error: unexpected CR in simple string"),
+            (b"$3\r\nabcde\r\n", "Protocol error: expected CRLF after bulk string"),
+            (b"$-2\r\n", "Protocol error: invalid bulk length"),
+            (b"$x\r\n", "Protocol error: invalid bulk length"),
+            (b"*-2\r\n", "Protocol error: invalid multibulk length"),
+            (b"#x\r\n", "Protocol error: invalid boolean"),
+            (b",1.2.3\r\n", "Protocol error: invalid double"),
+            (b"(12a\r\n", "Protocol error: invalid big number"),
+            (b"=2\r\nab\r\n", "Protocol error: invalid verbatim string"),
+            (b"_x\r\n", "Protocol error: invalid null"),
+            (b"!3\r\n", "Protocol error: invalid type byte '!'"),
+        ];
+        for (frame, expected) in cases {
+            let err = RespParser::new(frame).parse().await.unwrap_err();
+            assert_eq!(err.to_string(), expected, "{}", frame.escape_ascii());
+        }
+    }
+
+    #[tokio::test]
+    async fn conformance_requests() -> Result<()> {
+        let request = RespParser::new(&b"*2\r\n$4\r\nECHO\r\n$2\r\n\xff\xfe\r\n"[..]).parse_request().await?;
+        assert_eq!(request.command, "ECHO");
+        assert_eq!(request.args, vec![RespValue::BulkString(Some(Bytes::from_static(b"\xff\xfe")))]);
+        assert!(request.args[0].as_str().is_err());
+
+        // empty arrays are skipped
+        let request = RespParser::new(&b"*0\r\n*1\r\n$4\r\nPING\r\n"[..]).parse_request().await?;
+        assert_eq!(request.command, "PING");
+
+        let cases: Vec<(&[u8], &str)> = vec![
+            (b"*1\r\n:1\r\n", "Protocol error: expected '$', got ':'"),
+            (b"*2\r\n$3\r\nGET\r\n+key\r\n", "Protocol error: expected '$', got '+'"),
+            (b"*1\r\n$-1\r\n", "Protocol error: invalid bulk length"),
+            (b"*x\r\n", "Protocol error: invalid multibulk length"),
+            (b"*1\r\n$3\r\nGETX\r\n", "Protocol error: expected CRLF after bulk string"),
+        ];
+        for (frame, expected) in cases {
+            let err = RespParser::new(frame).parse_request().await.unwrap_err();
+            assert_eq!(err.to_string(), expected, "{}", frame.escape_ascii());
+        }
+        Ok(())
     }
 
-}
\ No newline at end of file
+    #[test]
+    fn downgrade_to_resp2() {
+        let map = RespValue::Map(vec![(RespValue::SimpleString("k".into()), RespValue::Null)]);
+        assert_eq!(map.into_protocol(RESP2), RespValue::Array(vec![
+            RespValue::SimpleString("k".into()),
+            RespValue::BulkString(None),
+        ]));
+        assert_eq!(RespValue::Boolean(true).into_protocol(RESP2), RespValue::Integer(1));
+        assert_eq!(RespValue::Double(2.5).into_protocol(RESP2), RespValue::BulkString(Some("2.5".into())));
+        assert_eq!(RespValue::BulkString(None).into_protocol(RESP3), RespValue::Null);
+        assert_eq!(RespValue::NullArray.into_protocol(RESP2), RespValue::NullArray);
+        assert_eq!(RespValue::NullArray.into_protocol(RESP3), RespValue::Null);
+    }
+}
@@ -0,0 +1,202 @@
+// Clients blocked on keys until another command feeds them, like blocking.c of redis.
+// A write that may satisfy waiters `signal`s the key, then the command calls `serve_ready` before it
+// releases the database: waiters of a key are served in the order they blocked, whatever the key they got it from.
+use std::collections::{HashMap, VecDeque};
+use std::sync::{Arc, LazyLock, Mutex};
+use std::time::Duration;
+use bytes::Bytes;
+use tokio::sync::oneshot;
+use crate::engine::{Database, DB};
+use crate::error::*;
+use crate::{context::Context, parser::RespValue};
+
+/// What serving a waiter produced: its reply and the commands to log in place of the blocking one
+pub(crate) struct Served {
+    pub reply: RespValue,
+    pub records: Vec<RespValue>,
+}
+
+impl Served {
+    pub(crate) async fn log(&self, db: &mut Database) -> Result<()> {
+        for record in &self.records {
+            db.append_log(record).await?;
+        }
+        Ok(())
+    }
+}
+
+/// Tries to serve a waiter from a key, None if the key cannot serve it
+pub(crate) type Serve = Box<dyn FnMut(&mut Database, &Bytes) -> Result<Option<Served>> + Send>;
+
+struct Waiter {
+    keys: Vec<Bytes>,
+    // called without the registry locked, since serving may push to other keys
+    serve: Arc<Mutex<Serve>>,
+    reply: oneshot::Sender<Result<RespValue>>,
+}
+
+#[derive(Default)]
+struct Registry {
+    next_id: u64,
+    waiters: HashMap<u64, Waiter>,
+    // waiters of every key, in the order they blocked
+    queues: HashMap<Bytes, VecDeque<u64>>,
+    // signaled keys that still have waiters
+    ready: VecDeque<Bytes>,
+}
+
+impl Registry {
+    fn remove(&mut self, id: u64) -> Option<Waiter> {
+        let waiter = self.waiters.remove(&id)?;
+        for key in &waiter.keys {
+            if let Some(queue) = self.queues.get_mut(key) {
+                queue.retain(|&other| other != id);
+                if queue.is_empty() {
+                    self.queues.remove(key);
+                }
+            }
+        }
+        Some(waiter)
+    }
+}
+
+static REGISTRY: LazyLock<Mutex<Registry>> = LazyLock::new(Default::default);
+
+/// Parses the timeout of a blocking command in seconds, None when it blocks forever
+pub(crate) fn parse_timeout(arg: &RespValue) -> Result<Option<Duration>> {
+    let seconds = std::str::from_utf8(arg.as_bytes()?)
+        .ok()
+        .and_then(|s| s.parse::<f64>().ok())
+        .filter(|seconds| seconds.is_finite())
+        .ok_or_else(|| Error::Other("timeout is not a float or out of range".into()))?;
+    if seconds < 0.0 {
+        return Err(Error::Other("timeout is negative".into()));
+    }
+    if seconds == 0.0 {
+        return Ok(None);
+    }
+    Duration::try_from_secs_f64(seconds)
+        .map(Some)
+        .map_err(|_| Error::Other("timeout is out of range".into()))
+}
+
+/// Tells the waiters of `key`, if any, that it may serve them
+pub(crate) fn signal(key: &Bytes) {
+    let mut registry = REGISTRY.lock().unwrap();
+    if registry.queues.contains_key(key) && !registry.ready.contains(key) {
+        registry.ready.push_back(key.clone());
+    }
+}
+
+/// Serves the waiters of the signaled keys for as long as they can be served
+pub(crate) async fn serve_ready(db: &mut Database) -> Result<()> {
+    loop {
+        let Some(key) = REGISTRY.lock().unwrap().ready.pop_front() else {
+            return Ok(());
+        };
+        loop {
+            let (id, serve) = {
+                let registry = REGISTRY.lock().unwrap();
+                let Some(&id) = registry.queues.get(&key).and_then(VecDeque::front) else {
+                    break;
+                };
+                (id, registry.waiters[&id].serve.clone())
+            };
+
+            let served = (serve.lock().unwrap())(db, &key);
+            let reply = match served {
+                Ok(Some(served)) => {
+                    served.log(db).await?;
+                    Ok(served.reply)
+                }
+                Ok(None) => break,
+                // the client is unblocked with the error, e.g. once its destination holds another type
+                Err(e) => Err(e),
+            };
+            if let Some(waiter) = REGISTRY.lock().unwrap().remove(id) {
+                let _ = waiter.reply.send(reply);
+            }
+        }
+    }
+}
+
+/// A client parked on keys, unregistered when dropped
+pub(crate) struct Blocked {
+    id: u64,
+    reply: oneshot::Receiver<Result<RespValue>>,
+}
+
+/// Parks the client on `keys` until `serve` succeeds for one of them. It takes the database to make sure that
+/// it is locked, so that no write slips in between finding nothing to serve and blocking.
+pub(crate) fn block(_db: &Database, keys: Vec<Bytes>, serve: Serve) -> Blocked {
+    let (sender, receiver) = oneshot::channel();
+    let mut registry = REGISTRY.lock().unwrap();
+    let id = registry.next_id;
+    registry.next_id += 1;
+    for key in &keys {
+        registry.queues.entry(key.clone()).or_default().push_back(id);
+    }
+    registry.waiters.insert(id, Waiter { keys, serve: Arc::new(Mutex::new(serve)), reply: sender });
+    Blocked { id, reply: receiver }
+}
+
+impl Blocked {
+    /// Waits for the reply, None once `timeout` elapsed
+    pub(crate) async fn wait(mut self, timeout: Option<Duration>) -> Result<Option<RespValue>> {
+        let received = match timeout {
+            Some(timeout) => tokio::time::timeout(timeout, &mut self.reply).await.ok(),
+            None => Some((&mut self.reply).await),
+        };
+        if let Some(Ok(reply)) = received {
+            return reply.map(Some);
+        }
+
+        // waiters are only served under the database lock, holding it the reply is either here or never comes
+        let _db = DB.read().await;
+        REGISTRY.lock().unwrap().remove(self.id);
+        match self.reply.try_recv() {
+            Ok(reply) => reply.map(Some),
+            Err(_) => Ok(None),
+        }
+    }
+}
+
+impl Drop for Blocked {
+    fn drop(&mut self) {
+        REGISTRY.lock().unwrap().remove(self.id);
+    }
+}
+
+/// Serves a blocking command, immediately or once it is woken up, both times by the same closure.
+/// `None` if it timed out, each command has its own reply for that.
+pub(crate) async fn serve_or_block(context: Arc<Context>, keys: Vec<Bytes>, timeout: Option<Duration>, mut serve: Serve) -> anyhow::Result<Option<RespValue>> {
+    let mut db = DB.write().await;
+    for key in &keys {
+        if let Some(served) = serve(&mut db, key)? {
+            served.log(&mut db).await?;
+            serve_ready(&mut db).await?;
+            return Ok(Some(served.reply));
+        }
+    }
+    let blocked = block(&db, keys, serve);
+    drop(db);
+
+    context.block_for(timeout);
+    Ok(blocked.wait(timeout).await?)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn timeouts() -> Result<()> {
+        let arg = |s: &str| RespValue::BulkString(Some(Bytes::copy_from_slice(s.as_bytes())));
+        assert_eq!(parse_timeout(&arg("0"))?, None);
+        assert_eq!(parse_timeout(&arg("1.5"))?, Some(Duration::from_millis(1500)));
+        assert!(parse_timeout(&arg("-1")).is_err());
+        assert!(parse_timeout(&arg("abc")).is_err());
+        assert!(parse_timeout(&arg("inf")).is_err());
+        Ok(())
+    }
+}
@@ -0,0 +1,212 @@
+use std::sync::Arc;
+use crate::engine::{Timestamp, DB};
+use super::{arg_i64, check_args};
+use crate::error::*;
+use crate::{context::Context, parser::{RespRequest, RespValue}};
+use crate::command_table::{RouteHandler, ROUTE_MAP};
+
+/// NX, XX, GT and LT of the EXPIRE family
+#[derive(Debug, Default)]
+struct ExpireFlags {
+    // only if the key has no deadline
+    nx: bool,
+    // only if the key has a deadline
+    xx: bool,
+    // only if the new deadline is later, no deadline counts as an infinite one
+    gt: bool,
+    // only if the new deadline is earlier
+    lt: bool,
+}
+
+impl ExpireFlags {
+    fn parse(args: &[RespValue]) -> Result<ExpireFlags> {
+        let mut flags = ExpireFlags::default();
+        for arg in args {
+            match arg.as_bytes()?.to_ascii_uppercase().as_slice() {
+                b"NX" => flags.nx = true,
+                b"XX" => flags.xx = true,
+                b"GT" => flags.gt = true,
+                b"LT" => flags.lt = true,
+                _ => return Err(Error::Other(format!("Unsupported option {}", String::from_utf8_lossy(arg.as_bytes()?)))),
+            }
+        }
+        if flags.nx && (flags.xx || flags.gt || flags.lt) {
+            return Err(Error::Other("NX and XX, GT or LT options at the same time are not compatible".into()));
+        }
+        if flags.gt && flags.lt {
+            return Err(Error::Other("GT and LT options at the same time are not compatible".into()));
+        }
+        Ok(flags)
+    }
+
+    fn allow(&self, current: Option<Timestamp>, deadline: Timestamp) -> bool {
+        match current {
+            None => !self.xx && !self.gt,
+            Some(current) if self.gt => deadline > current,
+            Some(current) if self.lt => deadline < current,
+            Some(_) => !self.nx,
+        }
+    }
+}
+
+/// EXPIRE, PEXPIRE, EXPIREAT and PEXPIREAT: `key time [NX|XX|GT|LT]`, the time in units of `unit_ms`
+/// milliseconds and counted from now if `relative`. Always logged as PEXPIREAT, or as DEL for a deadline
+/// already past, which deletes the key right away.
+async fn expire(request: &RespRequest, name: &str, unit_ms: i64, relative: bool) -> anyhow::Result<RespValue> {
+    check_args(request, name, 2, None)?;
+    let key = request.args[0].as_bytes()?;
+    let time = arg_i64(&request.args[1])?;
+    let flags = ExpireFlags::parse(&request.args[2..])?;
+
+    let base = if relative { chrono::Utc::now().timestamp_millis() } else { 0 };
+    let when = time.checked_mul(unit_ms).and_then(|ms| ms.checked_add(base));
+    let deadline = when
+        .and_then(chrono::DateTime::from_timestamp_millis)
+        .ok_or_else(|| Error::Other(format!("invalid expire time in '{}' command", name)))?;
+
+    let mut db = DB.write().await;
+    let Some(entry) = db.get_mut(key) else {
+        return Ok(RespValue::Integer(0));
+    };
+    if !flags.allow(entry.expire_at, deadline) {
+        return Ok(RespValue::Integer(0));
+    }
+    if deadline <= chrono::Utc::now() {
+        db.delete(key);
+        db.append_log(&RespValue::bulk_array(["DEL".into(), key.clone()])).await?;
+        return Ok(RespValue::Integer(1));
+    }
+    db.set_expire(key, Some(deadline));
+    let record = ["PEXPIREAT".into(), key.clone(), deadline.timestamp_millis().to_string().into()];
+    db.append_log(&RespValue::bulk_array(record)).await?;
+    Ok(RespValue::Integer(1))
+}
+
+/// -2 if the key is missing, -1 if it has no deadline, otherwise what `f` makes of the deadline
+async fn deadline_reply(request: &RespRequest, name: &str, f: impl FnOnce(Timestamp) -> i64) -> anyhow::Result<RespValue> {
+    check_args(request, name, 1, Some(1))?;
+    let db = DB.read().await;
+    let reply = match db.get(request.args[0].as_bytes()?) {
+        None => -2,
+        Some(entry) => entry.expire_at.map_or(-1, f),
+    };
+    Ok(RespValue::Integer(reply))
+}
+
+fn remaining_ms(deadline: Timestamp) -> i64 {
+    (deadline - chrono::Utc::now()).num_milliseconds().max(0)
+}
+
+#[router_macro::route("EXPIRE")]
+async fn expire_command(_context : Arc<Context>, request: RespRequest) -> anyhow::Result<RespValue> {
+    expire(&request, "expire", 1000, true).await
+}
+
+#[router_macro::route("PEXPIRE")]
+async fn pexpire(_context : Arc<Context>, request: RespRequest) -> anyhow::Result<RespValue> {
+    expire(&request, "pexpire", 1, true).await
+}
+
+#[router_macro::route("EXPIREAT")]
+async fn expireat(_context : Arc<Context>, request: RespRequest) -> anyhow::Result<RespValue> {
+    expire(&request, "expireat", 1000, false).await
+}
+
+#[router_macro::route("PEXPIREAT")]
+async fn pexpireat(_context : Arc<Context>, request: RespRequest) -> anyhow::Result<RespValue> {
+    expire(&request, "pexpireat", 1, false).await
+}
+
+/// Seconds left, rounded to the closest one
+#[router_macro::route("TTL")]
+async fn ttl(_context : Arc<Context>, request: RespRequest) -> anyhow::Result<RespValue> {
+    deadline_reply(&request, "ttl", |deadline| (remaining_ms(deadline) + 500) / 1000).await
+}
+
+#[router_macro::route("PTTL")]
+async fn pttl(_context : Arc<Context>, request: RespRequest) -> anyhow::Result<RespValue> {
+    deadline_reply(&request, "pttl", remaining_ms).await
+}
+
+/// The deadline as a unix time in seconds
+#[router_macro::route("EXPIRETIME")]
+async fn expiretime(_context : Arc<Context>, request: RespRequest) -> anyhow::Result<RespValue> {
+    deadline_reply(&request, "expiretime", |deadline| deadline.timestamp()).await
+}
+
+#[router_macro::route("PEXPIRETIME")]
+async fn pexpiretime(_context : Arc<Context>, request: RespRequest) -> anyhow::Result<RespValue> {
+    deadline_reply(&request, "pexpiretime", |deadline| deadline.timestamp_millis()).await
+}
+
+#[router_macro::route("PERSIST")]
+async fn persist(_context : Arc<Context>, request: RespRequest) -> anyhow::Result<RespValue> {
+    check_args(&request, "persist", 1, Some(1))?;
+    let key = request.args[0].as_bytes()?;
+    let mut db = DB.write().await;
+    if db.get_mut(key).and_then(|entry| entry.expire_at).is_none() {
+        return Ok(RespValue::Integer(0));
+    }
+    db.set_expire(key, None);
+    db.append_log(&request.to_resp()).await?;
+    Ok(RespValue::Integer(1))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use bytes::Bytes;
+    use crate::engine::{Entry, Value};
+    use crate::redis_types::test_util::{context, request};
+
+    async fn put(key: &str) {
+        DB.write().await.put(Bytes::copy_from_slice(key.as_bytes()), Entry::new(Value::List(["x".into()].into())));
+    }
+
+    #[tokio::test]
+    async fn deadlines() -> anyhow::Result<()> {
+        put("expire:a").await;
+        assert_eq!(ttl(context(), request("TTL", &["expire:a"])).await?, RespValue::Integer(-1));
+        assert_eq!(ttl(context(), request("TTL", &["expire:none"])).await?, RespValue::Integer(-2));
+        assert_eq!(expire_command(context(), request("EXPIRE", &["expire:none", "10"])).await?, RespValue::Integer(0));
+
+        assert_eq!(expire_command(context(), request("EXPIRE", &["expire:a", "100", "XX"])).await?, RespValue::Integer(0));
+        assert_eq!(expire_command(context(), request("EXPIRE", &["expire:a", "100", "GT"])).await?, RespValue::Integer(0));
+        assert_eq!(expire_command(context(), request("EXPIRE", &["expire:a", "100", "LT"])).await?, RespValue::Integer(1));
+        assert_eq!(ttl(context(), request("TTL", &["expire:a"])).await?, RespValue::Integer(100));
+        assert_eq!(expire_command(context(), request("EXPIRE", &["expire:a", "200", "NX"])).await?, RespValue::Integer(0));
+        assert_eq!(pexpire(context(), request("PEXPIRE", &["expire:a", "50000", "GT"])).await?, RespValue::Integer(0));
+        assert_eq!(pexpire(context(), request("PEXPIRE", &["expire:a", "200000", "GT", "XX"])).await?, RespValue::Integer(1));
+        let RespValue::Integer(ms) = pttl(context(), request("PTTL", &["expire:a"])).await? else { panic!() };
+        assert!(ms > 199_000 && ms <= 200_000);
+
+        assert_eq!(expireat(context(), request("EXPIREAT", &["expire:a", "4102444800"])).await?, RespValue::Integer(1));
+        assert_eq!(expiretime(context(), request("EXPIRETIME", &["expire:a"])).await?, RespValue::Integer(4102444800));
+        assert_eq!(pexpiretime(context(), request("PEXPIRETIME", &["expire:a"])).await?, RespValue::Integer(4102444800000));
+        assert_eq!(persist(context(), request("PERSIST", &["expire:a"])).await?, RespValue::Integer(1));
+        assert_eq!(persist(context(), request("PERSIST", &["expire:a"])).await?, RespValue::Integer(0));
+        assert_eq!(expiretime(context(), request("EXPIRETIME", &["expire:a"])).await?, RespValue::Integer(-1));
+
+        assert!(expire_command(context(), request("EXPIRE", &["expire:a", "10", "NX", "XX"])).await.is_err());
+        assert!(expire_command(context(), request("EXPIRE", &["expire:a", "10", "GT", "LT"])).await.is_err());
+        assert!(expire_command(context(), request("EXPIRE", &["expire:a", "10", "SOON"])).await.is_err());
+        assert!(expire_command(context(), request("EXPIRE", &["expire:a", "9223372036854775807"])).await.is_err());
+
+        // a deadline in the past deletes the key at once
+        assert_eq!(pexpireat(context(), request("PEXPIREAT", &["expire:a", "1"])).await?, RespValue::Integer(1));
+        assert!(DB.read().await.engine().get(b"expire:a").is_none());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn expired_keys_are_gone() -> anyhow::Result<()> {
+        put("expire:b").await;
+        assert_eq!(pexpire(context(), request("PEXPIRE", &["expire:b", "20"])).await?, RespValue::Integer(1));
+        tokio::time::sleep(std::time::Duration::from_millis(30)).await;
+        assert_eq!(ttl(context(), request("TTL", &["expire:b"])).await?, RespValue::Integer(-2));
+        assert_eq!(persist(context(), request("PERSIST", &["expire:b"])).await?, RespValue::Integer(0));
+        // the write access deleted it
+        assert!(DB.read().await.engine().get(b"expire:b").is_none());
+        Ok(())
+    }
+}
@@ -0,0 +1,407 @@
+use std::sync::Arc;
+use bytes::Bytes;
+use crate::engine::{ScoreBound, ScoreRange, ZSet, DB};
+use super::blocking;
+use super::geohash::{self, Shape};
+use super::zset::{add, get_zset, store, AddFlags, Outcome};
+use super::{arg_f64, arg_i64, check_args};
+use crate::error::*;
+use crate::parser::NULL_RESP;
+use crate::{context::Context, parser::{RespRequest, RespValue}};
+use crate::command_table::{RouteHandler, ROUTE_MAP};
+
+/// Meters in one `unit`
+fn parse_unit(arg: &RespValue) -> Result<f64> {
+    match arg.as_bytes()?.to_ascii_lowercase().as_slice() {
+        b"m" => Ok(1.0),
+        b"km" => Ok(1000.0),
+        b"ft" => Ok(0.3048),
+        b"mi" => Ok(1609.34),
+        _ => Err(Error::Other("unsupported unit provided. please use M, KM, FT, MI".into())),
+    }
+}
+
+/// Score of the point at `lon` and `lat`, which must lie in the area geohashes index
+fn parse_point(lon: &RespValue, lat: &RespValue) -> Result<(f64, f64, f64)> {
+    let (longitude, latitude) = (arg_f64(lon)?, arg_f64(lat)?);
+    let score = geohash::score(longitude, latitude)
+        .ok_or_else(|| Error::Other(format!("invalid longitude,latitude pair {:.6},{:.6}", longitude, latitude)))?;
+    Ok((longitude, latitude, score))
+}
+
+/// A distance or a size, in meters once multiplied by its unit
+fn parse_length(arg: &RespValue, name: &str) -> Result<f64> {
+    let length = arg_f64(arg).map_err(|_| Error::Other(format!("need numeric {}", name)))?;
+    if length < 0.0 {
+        return Err(Error::Other(format!("{} cannot be negative", name)));
+    }
+    Ok(length)
+}
+
+/// Distances are replied as strings with 4 decimals whatever the protocol
+fn distance_reply(distance: f64) -> RespValue {
+    RespValue::BulkString(Some(format!("{:.4}", distance).into()))
+}
+
+fn position_reply(score: f64) -> RespValue {
+    let (longitude, latitude) = geohash::decode_score(score);
+    RespValue::Array(vec![RespValue::Double(longitude), RespValue::Double(latitude)])
+}
+
+/// GEOADD key [NX|XX] [CH] longitude latitude member [longitude latitude member ...]
+#[router_macro::route("GEOADD")]
+async fn geoadd(_context : Arc<Context>, request: RespRequest) -> anyhow::Result<RespValue> {
+    check_args(&request, "geoadd", 4, None)?;
+    let key = request.args[0].as_bytes()?;
+
+    let mut flags = AddFlags::default();
+    let mut first_point = 1;
+    for arg in &request.args[1..] {
+        match arg.as_bytes()?.to_ascii_uppercase().as_slice() {
+            b"NX" => flags.nx = true,
+            b"XX" => flags.xx = true,
+            b"CH" => flags.ch = true,
+            _ => break,
+        }
+        first_point += 1;
+    }
+    let points = &request.args[first_point..];
+    if points.is_empty() || !points.len().is_multiple_of(3) {
+        return Err(Error::Syntax.into());
+    }
+    if flags.nx && flags.xx {
+        return Err(Error::Other("XX and NX options at the same time are not compatible".into()).into());
+    }
+    let pairs = points
+        .chunks(3)
+        .map(|point| Ok((parse_point(&point[0], &point[1])?.2, point[2].as_bytes()?.clone())))
+        .collect::<Result<Vec<_>>>()?;
+
+    let mut db = DB.write().await;
+    let outcomes = add(&mut db, &request, key, &pairs, &flags).await?;
+    let added = outcomes.iter().filter(|outcome| matches!(outcome, Outcome::Added(_))).count();
+    let updated = outcomes.iter().filter(|outcome| matches!(outcome, Outcome::Updated(_))).count();
+    Ok(RespValue::Integer((added + if flags.ch { updated } else { 0 }) as i64))
+}
+
+/// GEOPOS key [member ...]
+#[router_macro::route("GEOPOS")]
+async fn geopos(_context : Arc<Context>, request: RespRequest) -> anyhow::Result<RespValue> {
+    check_args(&request, "geopos", 1, None)?;
+    let db = DB.read().await;
+    let zset = get_zset(&db, request.args[0].as_bytes()?)?;
+    let mut positions = Vec::with_capacity(request.args.len() - 1);
+    for member in &request.args[1..] {
+        let score = zset.and_then(|zset| zset.score(member.as_bytes().ok()?));
+        positions.push(score.map(position_reply).unwrap_or_else(|| NULL_RESP.clone()));
+    }
+    Ok(RespValue::Array(positions))
+}
+
+/// GEODIST key member1 member2 [M|KM|FT|MI]
+#[router_macro::route("GEODIST")]
+async fn geodist(_context : Arc<Context>, request: RespRequest) -> anyhow::Result<RespValue> {
+    check_args(&request, "geodist", 3, Some(4))?;
+    let unit = request.args.get(3).map(parse_unit).transpose()?.unwrap_or(1.0);
+    let db = DB.read().await;
+    let Some(zset) = get_zset(&db, request.args[0].as_bytes()?)? else {
+        return Ok(NULL_RESP.clone());
+    };
+    let (Some(first), Some(second)) = (zset.score(request.args[1].as_bytes()?), zset.score(request.args[2].as_bytes()?)) else {
+        return Ok(NULL_RESP.clone());
+    };
+    let ((lon1, lat1), (lon2, lat2)) = (geohash::decode_score(first), geohash::decode_score(second));
+    Ok(distance_reply(geohash::distance(lon1, lat1, lon2, lat2) / unit))
+}
+
+/// GEOHASH key [member ...]
+#[router_macro::route("GEOHASH")]
+async fn geohash_command(_context : Arc<Context>, request: RespRequest) -> anyhow::Result<RespValue> {
+    check_args(&request, "geohash", 1, None)?;
+    let db = DB.read().await;
+    let zset = get_zset(&db, request.args[0].as_bytes()?)?;
+    let mut hashes = Vec::with_capacity(request.args.len() - 1);
+    for member in &request.args[1..] {
+        let score = zset.and_then(|zset| zset.score(member.as_bytes().ok()?));
+        hashes.push(RespValue::BulkString(score.map(|score| geohash::to_string(score).into())));
+    }
+    Ok(RespValue::Array(hashes))
+}
+
+enum Center {
+    Member(Bytes),
+    LonLat(f64, f64),
+}
+
+/// The options GEOSEARCH and GEOSEARCHSTORE share, and those only one of them takes
+struct Search {
+    center: Center,
+    shape: Shape,
+    // meters in the unit of the shape, distances are replied in that unit
+    unit: f64,
+    descending: Option<bool>,
+    count: Option<usize>,
+    any: bool,
+    with_coord: bool,
+    with_dist: bool,
+    with_hash: bool,
+    store_dist: bool,
+}
+
+/// A member found by a search, with its distance to the center in meters and its score
+struct Found {
+    member: Bytes,
+    distance: f64,
+    score: f64,
+}
+
+impl Search {
+    fn parse(args: &[RespValue], name: &str, storing: bool) -> Result<Search> {
+        let mut center = None;
+        let mut shape = None;
+        let mut search = Search {
+            center: Center::LonLat(0.0, 0.0),
+            shape: Shape::Radius(0.0),
+            unit: 1.0,
+            descending: None,
+            count: None,
+            any: false,
+            with_coord: false,
+            with_dist: false,
+            with_hash: false,
+            store_dist: false,
+        };
+        let mut centers = 0;
+        let mut shapes = 0;
+        let mut i = 0;
+        while i < args.len() {
+            let remaining = args.len() - i - 1;
+            match args[i].as_bytes()?.to_ascii_uppercase().as_slice() {
+                b"FROMMEMBER" if remaining >= 1 => {
+                    center = Some(Center::Member(args[i + 1].as_bytes()?.clone()));
+                    centers += 1;
+                    i += 1;
+                }
+                b"FROMLONLAT" if remaining >= 2 => {
+                    let (longitude, latitude, _) = parse_point(&args[i + 1], &args[i + 2])?;
+                    center = Some(Center::LonLat(longitude, latitude));
+                    centers += 1;
+                    i += 2;
+                }
+                b"BYRADIUS" if remaining >= 2 => {
+                    let radius = parse_length(&args[i + 1], "radius")?;
+                    search.unit = parse_unit(&args[i + 2])?;
+                    shape = Some(Shape::Radius(radius * search.unit));
+                    shapes += 1;
+                    i += 2;
+                }
+                b"BYBOX" if remaining >= 3 => {
+                    let width = parse_length(&args[i + 1], "width")?;
+                    let height = parse_length(&args[i + 2], "height")?;
+                    search.unit = parse_unit(&args[i + 3])?;
+                    shape = Some(Shape::Box { width: width * search.unit, height: height * search.unit });
+                    shapes += 1;
+                    i += 3;
+                }
+                b"ASC" => search.descending = Some(false),
+                b"DESC" => search.descending = Some(true),
+                b"COUNT" if remaining >= 1 => {
+                    let count = arg_i64(&args[i + 1])?;
+                    if count <= 0 {
+                        return Err(Error::Other("COUNT must be > 0".into()));
+                    }
+                    search.count = Some(count as usize);
+                    i += 1;
+                    if args.get(i + 1).is_some_and(|arg| arg.as_bytes().is_ok_and(|arg| arg.eq_ignore_ascii_case(b"ANY"))) {
+                        search.any = true;
+                        i += 1;
+                    }
+                }
+                b"ANY" => return Err(Error::Other("the ANY argument requires COUNT argument".into())),
+                b"WITHCOORD" if !storing => search.with_coord = true,
+                b"WITHDIST" if !storing => search.with_dist = true,
+                b"WITHHASH" if !storing => search.with_hash = true,
+                b"STOREDIST" if storing => search.store_dist = true,
+                _ => return Err(Error::Syntax),
+            }
+            i += 1;
+        }
+        match center {
+            Some(center) if centers == 1 => search.center = center,
+            _ => return Err(Error::Other(format!("exactly one of FROMMEMBER or FROMLONLAT can be specified for {}", name))),
+        }
+        match shape {
+            Some(shape) if shapes == 1 => search.shape = shape,
+            _ => return Err(Error::Other(format!("exactly one of BYRADIUS and BYBOX can be specified for {}", name))),
+        }
+        Ok(search)
+    }
+
+    /// Members within the shape, sorted by distance when asked or when only the closest ones are wanted
+    fn run(&self, zset: &ZSet) -> Result<Vec<Found>> {
+        let center = match &self.center {
+            Center::LonLat(longitude, latitude) => (*longitude, *latitude),
+            Center::Member(member) => {
+                let score = zset.score(member).ok_or_else(|| Error::Other("could not decode requested zset member".into()))?;
+                geohash::decode_score(score)
+            }
+        };
+        let limit = self.count.filter(|_| self.any).unwrap_or(usize::MAX);
+        let mut found = vec![];
+        'cells: for (min, max) in geohash::search_ranges(&self.shape, center.0, center.1) {
+            let range = ScoreRange { min: ScoreBound { value: min, exclusive: false }, max: ScoreBound { value: max, exclusive: true } };
+            let Some((start, end)) = zset.score_range(&range) else {
+                continue;
+            };
+            for (member, score) in zset.range(start, end, false) {
+                if found.len() >= limit {
+                    break 'cells;
+                }
+                if let Some(distance) = self.shape.distance(center, geohash::decode_score(score)) {
+                    found.push(Found { member: member.clone(), distance, score });
+                }
+            }
+        }
+
+        // COUNT without ANY wants the closest members
+        let descending = self.descending.or(self.count.filter(|_| !self.any).map(|_| false));
+        if let Some(descending) = descending {
+            found.sort_by(|a, b| a.distance.total_cmp(&b.distance));
+            if descending {
+                found.reverse();
+            }
+        }
+        found.truncate(self.count.unwrap_or(usize::MAX));
+        Ok(found)
+    }
+
+    fn reply(&self, found: Vec<Found>) -> RespValue {
+        if !self.with_coord && !self.with_dist && !self.with_hash {
+            return RespValue::bulk_array(found.into_iter().map(|found| found.member));
+        }
+        let items = found.into_iter().map(|found| {
+            let mut item = vec![RespValue::BulkString(Some(found.member))];
+            if self.with_dist {
+                item.push(distance_reply(found.distance / self.unit));
+            }
+            if self.with_hash {
+                item.push(RespValue::Integer(found.score as i64));
+            }
+            if self.with_coord {
+                item.push(position_reply(found.score));
+            }
+            RespValue::Array(item)
+        });
+        RespValue::Array(items.collect())
+    }
+}
+
+/// GEOSEARCH key FROMMEMBER member|FROMLONLAT longitude latitude BYRADIUS radius unit|BYBOX width height unit
+/// [ASC|DESC] [COUNT count [ANY]] [WITHCOORD] [WITHDIST] [WITHHASH]
+#[router_macro::route("GEOSEARCH")]
+async fn geosearch(_context : Arc<Context>, request: RespRequest) -> anyhow::Result<RespValue> {
+    check_args(&request, "geosearch", 6, None)?;
+    let search = Search::parse(&request.args[1..], "geosearch", false)?;
+    let db = DB.read().await;
+    let found = match get_zset(&db, request.args[0].as_bytes()?)? {
+        Some(zset) => search.run(zset)?,
+        None => vec![],
+    };
+    Ok(search.reply(found))
+}
+
+/// GEOSEARCHSTORE destination source <GEOSEARCH options> [STOREDIST]
+#[router_macro::route("GEOSEARCHSTORE")]
+async fn geosearchstore(_context : Arc<Context>, request: RespRequest) -> anyhow::Result<RespValue> {
+    check_args(&request, "geosearchstore", 7, None)?;
+    let destination = request.args[0].as_bytes()?;
+    let search = Search::parse(&request.args[2..], "geosearchstore", true)?;
+
+    let mut db = DB.write().await;
+    let found = match get_zset(&db, request.args[1].as_bytes()?)? {
+        Some(zset) => search.run(zset)?,
+        None => vec![],
+    };
+    let zset = found
+        .into_iter()
+        .map(|found| (found.member, if search.store_dist { found.distance / search.unit } else { found.score }))
+        .collect();
+    let len = store(&mut db, destination, zset);
+    db.append_log(&request.to_resp()).await?;
+    blocking::serve_ready(&mut db).await?;
+    Ok(RespValue::Integer(len as i64))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::redis_types::test_util::{bulk, bulks, context, request};
+
+    async fn sicily() -> anyhow::Result<()> {
+        let args = ["geo:sicily", "13.361389", "38.115556", "Palermo", "15.087269", "37.502669", "Catania", "12.758489", "38.788135", "edge1", "17.241510", "38.788135", "edge2"];
+        geoadd(context(), request("GEOADD", &args)).await?;
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn positions_and_distances() -> anyhow::Result<()> {
+        sicily().await?;
+        assert_eq!(geoadd(context(), request("GEOADD", &["geo:sicily", "NX", "CH", "13", "38", "Palermo"])).await?, RespValue::Integer(0));
+        assert_eq!(geoadd(context(), request("GEOADD", &["geo:sicily", "XX", "CH", "13", "38", "x", "13.361389", "38.115556", "Palermo"])).await?, RespValue::Integer(0));
+        assert!(geoadd(context(), request("GEOADD", &["geo:sicily", "13", "86", "x"])).await.is_err());
+        assert!(geoadd(context(), request("GEOADD", &["geo:sicily", "13", "38"])).await.is_err());
+
+        assert_eq!(geodist(context(), request("GEODIST", &["geo:sicily", "Palermo", "Catania"])).await?, bulk("166274.1516"));
+        assert_eq!(geodist(context(), request("GEODIST", &["geo:sicily", "Palermo", "Catania", "km"])).await?, bulk("166.2742"));
+        assert_eq!(geodist(context(), request("GEODIST", &["geo:sicily", "Palermo", "x"])).await?, RespValue::BulkString(None));
+        assert!(geodist(context(), request("GEODIST", &["geo:sicily", "Palermo", "Catania", "yd"])).await.is_err());
+
+        assert_eq!(
+            geohash_command(context(), request("GEOHASH", &["geo:sicily", "Palermo", "x", "Catania"])).await?,
+            RespValue::Array(vec![bulk("sqc8b49rny0"), RespValue::BulkString(None), bulk("sqdtr74hyu0")])
+        );
+        let RespValue::Array(positions) = geopos(context(), request("GEOPOS", &["geo:sicily", "Palermo", "x"])).await? else {
+            panic!("GEOPOS replies an array");
+        };
+        let RespValue::Array(palermo) = &positions[0] else {
+            panic!("a position is an array");
+        };
+        assert!(matches!(palermo[0], RespValue::Double(longitude) if (longitude - 13.361389).abs() < 1e-5));
+        assert!(matches!(palermo[1], RespValue::Double(latitude) if (latitude - 38.115556).abs() < 1e-5));
+        assert_eq!(positions[1], RespValue::BulkString(None));
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn searches() -> anyhow::Result<()> {
+        sicily().await?;
+        let search = |args: &[&str]| geosearch(context(), request("GEOSEARCH", &[&["geo:sicily"], args].concat()));
+        assert_eq!(search(&["FROMLONLAT", "15", "37", "BYRADIUS", "200", "km", "ASC"]).await?, bulks(&["Catania", "Palermo"]));
+        assert_eq!(search(&["FROMLONLAT", "15", "37", "BYBOX", "400", "400", "km", "DESC"]).await?, bulks(&["edge1", "edge2", "Palermo", "Catania"]));
+        assert_eq!(search(&["FROMMEMBER", "Palermo", "BYRADIUS", "100", "km", "COUNT", "1"]).await?, bulks(&["Palermo"]));
+        assert_eq!(
+            search(&["FROMLONLAT", "15", "37", "BYRADIUS", "200", "km", "COUNT", "1", "WITHDIST", "WITHHASH"]).await?,
+            RespValue::Array(vec![RespValue::Array(vec![bulk("Catania"), bulk("56.4413"), RespValue::Integer(3479447370796909)])])
+        );
+        let RespValue::Array(any) = search(&["FROMLONLAT", "15", "37", "BYBOX", "400", "400", "km", "COUNT", "2", "ANY"]).await? else {
+            panic!("GEOSEARCH replies an array");
+        };
+        assert_eq!(any.len(), 2);
+
+        assert!(search(&["FROMMEMBER", "x", "BYRADIUS", "1", "km"]).await.is_err());
+        assert!(search(&["FROMLONLAT", "15", "37", "FROMMEMBER", "Palermo", "BYRADIUS", "1", "km"]).await.is_err());
+        assert!(search(&["FROMLONLAT", "15", "37", "BYRADIUS", "1", "km", "ANY"]).await.is_err());
+        assert!(search(&["FROMLONLAT", "15", "37", "BYRADIUS", "1", "km", "COUNT", "0"]).await.is_err());
+        assert!(search(&["FROMLONLAT", "15", "37", "BYRADIUS", "1", "km", "STOREDIST"]).await.is_err());
+
+        let store = |args: &[&str]| geosearchstore(context(), request("GEOSEARCHSTORE", &[&["geo:near", "geo:sicily"], args].concat()));
+        assert_eq!(store(&["FROMLONLAT", "15", "37", "BYRADIUS", "200", "km", "STOREDIST"]).await?, RespValue::Integer(2));
+        let db = DB.read().await;
+        let near = get_zset(&db, b"geo:near")?.unwrap();
+        assert!((near.score(b"Catania").unwrap() - 56.4413).abs() < 1e-3);
+        drop(db);
+        assert_eq!(store(&["FROMLONLAT", "0", "0", "BYRADIUS", "1", "m"]).await?, RespValue::Integer(0));
+        assert!(DB.read().await.get(b"geo:near").is_none());
+        Ok(())
+    }
+}
@@ -0,0 +1,313 @@
+// Geohashes the way geohash.c and geohash_helper.c of redis compute them: 26 steps of longitude and latitude
+// bits interleaved into a 52 bit integer, which is exact as the score of a sorted set member
+use std::f64::consts::PI;
+
+pub(crate) const STEP_MAX: u8 = 26;
+// limits of EPSG:900913 / EPSG:3785 / OSGEO:41001, the projection geohashes index
+pub(crate) const LAT_MIN: f64 = -85.05112878;
+pub(crate) const LAT_MAX: f64 = 85.05112878;
+pub(crate) const LONG_MIN: f64 = -180.0;
+pub(crate) const LONG_MAX: f64 = 180.0;
+const EARTH_RADIUS_IN_METERS: f64 = 6372797.560856;
+const MERCATOR_MAX: f64 = 20037726.37;
+const ALPHABET: &[u8] = b"0123456789bcdefghjkmnpqrstuvwxyz";
+
+fn deg_rad(degrees: f64) -> f64 {
+    degrees * (PI / 180.0)
+}
+
+fn rad_deg(radians: f64) -> f64 {
+    radians / (PI / 180.0)
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub(crate) struct HashBits {
+    pub bits: u64,
+    pub step: u8,
+}
+
+#[derive(Debug, Clone, Copy)]
+struct Range {
+    min: f64,
+    max: f64,
+}
+
+const LONG_RANGE: Range = Range { min: LONG_MIN, max: LONG_MAX };
+const LAT_RANGE: Range = Range { min: LAT_MIN, max: LAT_MAX };
+
+#[derive(Debug, Clone, Copy)]
+struct Area {
+    longitude: Range,
+    latitude: Range,
+}
+
+/// Spreads the 32 bits of `x` over the even bits of the result
+fn spread(x: u32) -> u64 {
+    let mut x = x as u64;
+    x = (x | (x << 16)) & 0x0000_ffff_0000_ffff;
+    x = (x | (x << 8)) & 0x00ff_00ff_00ff_00ff;
+    x = (x | (x << 4)) & 0x0f0f_0f0f_0f0f_0f0f;
+    x = (x | (x << 2)) & 0x3333_3333_3333_3333;
+    (x | (x << 1)) & 0x5555_5555_5555_5555
+}
+
+/// Gathers the even bits of `x`
+fn squash(x: u64) -> u32 {
+    let mut x = x & 0x5555_5555_5555_5555;
+    x = (x | (x >> 1)) & 0x3333_3333_3333_3333;
+    x = (x | (x >> 2)) & 0x0f0f_0f0f_0f0f_0f0f;
+    x = (x | (x >> 4)) & 0x00ff_00ff_00ff_00ff;
+    x = (x | (x >> 8)) & 0x0000_ffff_0000_ffff;
+    ((x | (x >> 16)) & 0x0000_0000_ffff_ffff) as u32
+}
+
+/// Latitude bits go to the even positions, longitude ones to the odd positions
+fn encode_in(long_range: Range, lat_range: Range, longitude: f64, latitude: f64, step: u8) -> Option<HashBits> {
+    if !(LONG_MIN..=LONG_MAX).contains(&longitude) || !(LAT_MIN..=LAT_MAX).contains(&latitude) {
+        return None;
+    }
+    if !(long_range.min..=long_range.max).contains(&longitude) || !(lat_range.min..=lat_range.max).contains(&latitude) {
+        return None;
+    }
+    let cells = (1u64 << step) as f64;
+    let lat_offset = (latitude - lat_range.min) / (lat_range.max - lat_range.min) * cells;
+    let long_offset = (longitude - long_range.min) / (long_range.max - long_range.min) * cells;
+    Some(HashBits { bits: spread(lat_offset as u32) | (spread(long_offset as u32) << 1), step })
+}
+
+/// Geohash of a point at `step` precision, None outside of the indexable area
+pub(crate) fn encode(longitude: f64, latitude: f64, step: u8) -> Option<HashBits> {
+    encode_in(LONG_RANGE, LAT_RANGE, longitude, latitude, step)
+}
+
+fn decode_area(hash: HashBits) -> Area {
+    let cells = (1u64 << hash.step) as f64;
+    let lat_cell = squash(hash.bits) as f64;
+    let long_cell = squash(hash.bits >> 1) as f64;
+    let lat_span = LAT_RANGE.max - LAT_RANGE.min;
+    let long_span = LONG_RANGE.max - LONG_RANGE.min;
+    Area {
+        latitude: Range { min: LAT_RANGE.min + lat_cell / cells * lat_span, max: LAT_RANGE.min + (lat_cell + 1.0) / cells * lat_span },
+        longitude: Range { min: LONG_RANGE.min + long_cell / cells * long_span, max: LONG_RANGE.min + (long_cell + 1.0) / cells * long_span },
+    }
+}
+
+/// The sorted set score of a point, None outside of the indexable area
+pub(crate) fn score(longitude: f64, latitude: f64) -> Option<f64> {
+    encode(longitude, latitude, STEP_MAX).map(|hash| hash.bits as f64)
+}
+
+/// Longitude and latitude of the center of the cell a score stands for
+pub(crate) fn decode_score(score: f64) -> (f64, f64) {
+    let area = decode_area(HashBits { bits: score as u64, step: STEP_MAX });
+    let longitude = ((area.longitude.min + area.longitude.max) / 2.0).clamp(LONG_MIN, LONG_MAX);
+    let latitude = ((area.latitude.min + area.latitude.max) / 2.0).clamp(LAT_MIN, LAT_MAX);
+    (longitude, latitude)
+}
+
+/// The standard 11 characters geohash of a score, which uses latitudes from -90 to 90 unlike scores
+pub(crate) fn to_string(score: f64) -> String {
+    let (longitude, latitude) = decode_score(score);
+    let bits = encode_in(LONG_RANGE, Range { min: -90.0, max: 90.0 }, longitude, latitude, STEP_MAX).map_or(0, |hash| hash.bits);
+    (0..11)
+        .map(|i| {
+            // 52 bits make 10 characters and a bit, the last one is padded with zeros like redis does
+            let index = if i == 10 { 0 } else { (bits >> (52 - (i + 1) * 5)) & 0x1f };
+            ALPHABET[index as usize] as char
+        })
+        .collect()
+}
+
+/// Great circle distance in meters, with the haversine formula
+pub(crate) fn distance(lon1: f64, lat1: f64, lon2: f64, lat2: f64) -> f64 {
+    let (lat1r, lon1r, lat2r, lon2r) = (deg_rad(lat1), deg_rad(lon1), deg_rad(lat2), deg_rad(lon2));
+    let v = ((lon2r - lon1r) / 2.0).sin();
+    // along a meridian
+    if v == 0.0 {
+        return lat_distance(lat1, lat2);
+    }
+    let u = ((lat2r - lat1r) / 2.0).sin();
+    let a = u * u + lat1r.cos() * lat2r.cos() * v * v;
+    2.0 * EARTH_RADIUS_IN_METERS * a.sqrt().asin()
+}
+
+fn lat_distance(lat1: f64, lat2: f64) -> f64 {
+    EARTH_RADIUS_IN_METERS * (deg_rad(lat2) - deg_rad(lat1)).abs()
+}
+
+/// What GEOSEARCH looks into around its center, in meters
+#[derive(Debug, Clone, Copy)]
+pub(crate) enum Shape {
+    Radius(f64),
+    Box { width: f64, height: f64 },
+}
+
+impl Shape {
+    /// Distance from the center to a point within the shape, None if it lies outside
+    pub(crate) fn distance(&self, center: (f64, f64), point: (f64, f64)) -> Option<f64> {
+        match *self {
+            Shape::Radius(radius) => {
+                let distance = distance(center.0, center.1, point.0, point.1);
+                (distance <= radius).then_some(distance)
+            }
+            Shape::Box { width, height } => {
+                // the latitude distance is cheaper so it goes first
+                if lat_distance(point.1, center.1) > height / 2.0 {
+                    return None;
+                }
+                if distance(point.0, point.1, center.0, point.1) > width / 2.0 {
+                    return None;
+                }
+                Some(distance(center.0, center.1, point.0, point.1))
+            }
+        }
+    }
+
+    /// Minimum and maximum longitudes then latitudes of a box around the shape
+    fn bounding_box(&self, longitude: f64, latitude: f64) -> (f64, f64, f64, f64) {
+        let (width, height) = match *self {
+            Shape::Radius(radius) => (radius, radius),
+            Shape::Box { width, height } => (width / 2.0, height / 2.0),
+        };
+        let lat_delta = rad_deg(height / EARTH_RADIUS_IN_METERS);
+        let long_delta_top = rad_deg(width / EARTH_RADIUS_IN_METERS / deg_rad(latitude + lat_delta).cos());
+        let long_delta_bottom = rad_deg(width / EARTH_RADIUS_IN_METERS / deg_rad(latitude - lat_delta).cos());
+        // the widest side is the one closer to the equator
+        let long_delta = if latitude < 0.0 { long_delta_bottom } else { long_delta_top };
+        (longitude - long_delta, longitude + long_delta, latitude - lat_delta, latitude + lat_delta)
+    }
+
+    fn radius(&self) -> f64 {
+        match *self {
+            Shape::Radius(radius) => radius,
+            Shape::Box { width, height } => (width / 2.0).hypot(height / 2.0),
+        }
+    }
+}
+
+/// Coarsest step whose cells are about as large as the radius
+fn estimate_steps(mut radius: f64, latitude: f64) -> u8 {
+    if radius == 0.0 {
+        return STEP_MAX;
+    }
+    let mut step: i32 = 1;
+    while radius < MERCATOR_MAX {
+        radius *= 2.0;
+        step += 1;
+    }
+    // makes sure the range is included in most of the base cases
+    step -= 2;
+    // cells get narrower towards the poles
+    if !(-66.0..=66.0).contains(&latitude) {
+        step -= 1;
+        if !(-80.0..=80.0).contains(&latitude) {
+            step -= 1;
+        }
+    }
+    step.clamp(1, STEP_MAX as i32) as u8
+}
+
+fn move_x(hash: HashBits, direction: i8) -> HashBits {
+    if direction == 0 {
+        return hash;
+    }
+    let x = hash.bits & 0xaaaa_aaaa_aaaa_aaaa;
+    let y = hash.bits & 0x5555_5555_5555_5555;
+    let zz = 0x5555_5555_5555_5555u64 >> (64 - hash.step as u32 * 2);
+    let x = if direction > 0 { x.wrapping_add(zz + 1) } else { (x | zz).wrapping_sub(zz + 1) };
+    HashBits { bits: (x & (0xaaaa_aaaa_aaaa_aaaa >> (64 - hash.step as u32 * 2))) | y, step: hash.step }
+}
+
+fn move_y(hash: HashBits, direction: i8) -> HashBits {
+    if direction == 0 {
+        return hash;
+    }
+    let x = hash.bits & 0xaaaa_aaaa_aaaa_aaaa;
+    let y = hash.bits & 0x5555_5555_5555_5555;
+    let zz = 0xaaaa_aaaa_aaaa_aaaau64 >> (64 - hash.step as u32 * 2);
+    let y = if direction > 0 { y.wrapping_add(zz + 1) } else { (y | zz).wrapping_sub(zz + 1) };
+    HashBits { bits: x | (y & (0x5555_5555_5555_5555 >> (64 - hash.step as u32 * 2))), step: hash.step }
+}
+
+/// Ranges of scores, minimum included and maximum excluded, of the cells that may hold points of `shape`:
+/// the cell of the center and those of its neighbors that the shape reaches into
+pub(crate) fn search_ranges(shape: &Shape, longitude: f64, latitude: f64) -> Vec<(f64, f64)> {
+    let (min_lon, max_lon, min_lat, max_lat) = shape.bounding_box(longitude, latitude);
+    let mut step = estimate_steps(shape.radius(), latitude);
+    let Some(mut hash) = encode(longitude, latitude, step) else {
+        return vec![];
+    };
+    let at = |hash: HashBits, (dx, dy): (i8, i8)| move_y(move_x(hash, dx), dy);
+
+    // the neighbors may not reach the limits of the box at the estimated step
+    let reaches = |hash: HashBits| {
+        decode_area(at(hash, (0, 1))).latitude.max >= max_lat
+            && decode_area(at(hash, (0, -1))).latitude.min <= min_lat
+            && decode_area(at(hash, (1, 0))).longitude.max >= max_lon
+            && decode_area(at(hash, (-1, 0))).longitude.min <= min_lon
+    };
+    if step > 1 && !reaches(hash) {
+        step -= 1;
+        hash = encode(longitude, latitude, step).expect("the center was encoded already");
+    }
+
+    let area = decode_area(hash);
+    let mut cells = vec![hash];
+    for (dx, dy) in [(0, 1), (0, -1), (1, 0), (-1, 0), (1, 1), (-1, 1), (1, -1), (-1, -1)] {
+        // neighbors on the side of the center cell that already covers the box are useless
+        if step >= 2
+            && ((dy < 0 && area.latitude.min < min_lat)
+                || (dy > 0 && area.latitude.max > max_lat)
+                || (dx < 0 && area.longitude.min < min_lon)
+                || (dx > 0 && area.longitude.max > max_lon))
+        {
+            continue;
+        }
+        let cell = at(hash, (dx, dy));
+        // near the poles or with a single step neighbors wrap around onto the same cells
+        if !cells.contains(&cell) {
+            cells.push(cell);
+        }
+    }
+    let shift = (STEP_MAX - step) as u32 * 2;
+    cells.into_iter().map(|cell| ((cell.bits << shift) as f64, ((cell.bits + 1) << shift) as f64)).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn scores_and_geohashes_match_redis() {
+        // GEOADD Sicily 13.361389 38.115556 "Palermo" 15.087269 37.502669 "Catania"
+        let palermo = score(13.361389, 38.115556).unwrap();
+        assert_eq!(palermo, 3479099956230698.0);
+        let (longitude, latitude) = decode_score(palermo);
+        assert!((longitude - 13.361389338970184).abs() < 1e-12 && (latitude - 38.1155563954963).abs() < 1e-12);
+        assert_eq!(to_string(palermo), "sqc8b49rny0");
+        let catania = score(15.087269, 37.502669).unwrap();
+        assert_eq!(to_string(catania), "sqdtr74hyu0");
+
+        let (catania_lon, catania_lat) = decode_score(catania);
+        let meters = distance(longitude, latitude, catania_lon, catania_lat);
+        assert!((meters - 166274.1516).abs() < 1e-3, "{}", meters);
+        assert_eq!(score(0.0, 86.0), None);
+    }
+
+    #[test]
+    fn ranges_cover_the_shape() {
+        let shape = Shape::Radius(200_000.0);
+        let ranges = search_ranges(&shape, 15.0, 37.0);
+        assert!(!ranges.is_empty() && ranges.len() <= 9);
+        for point in [(13.361389, 38.115556), (15.087269, 37.502669), (16.0, 38.2)] {
+            let point_score = score(point.0, point.1).unwrap();
+            let decoded = decode_score(point_score);
+            if shape.distance((15.0, 37.0), decoded).is_some() {
+                assert!(ranges.iter().any(|(min, max)| (*min..*max).contains(&point_score)), "{:?}", point);
+            }
+        }
+        let boxed = Shape::Box { width: 400_000.0, height: 400_000.0 };
+        assert!(boxed.distance((15.0, 37.0), (16.0, 38.2)).is_some());
+        assert!(boxed.distance((15.0, 37.0), (15.0, 39.0)).is_none());
+    }
+}
@@ -0,0 +1,632 @@
+use std::sync::Arc;
+use bytes::Bytes;
+use rand::seq::IteratorRandom;
+use rand::Rng;
+use crate::engine::{Database, Entry, Hash, Timestamp, Value, DB};
+use super::{arg_f64, arg_i64, check_args, format_f64, parse_f64, parse_i64, repeated_picks, ScanArgs};
+use crate::error::*;
+use crate::parser::RESP3;
+use crate::{context::Context, parser::{RespRequest, RespValue}};
+use crate::command_table::{RouteHandler, ROUTE_MAP};
+
+fn get_hash<'a>(db: &'a Database, key: &[u8]) -> Result<Option<&'a Hash>> {
+    db.get(key).map(|entry| entry.as_hash()).transpose()
+}
+
+/// The hash at `key` ready to be written, without its expired fields
+async fn get_hash_mut<'a>(db: &'a mut Database, key: &Bytes) -> Result<Option<&'a mut Hash>> {
+    delete_if_empty(db, key).await?;
+    let mut hash = db.get_mut(key).map(|entry| entry.as_hash_mut()).transpose()?;
+    if let Some(hash) = hash.as_mut() {
+        hash.purge_expired();
+    }
+    Ok(hash)
+}
+
+/// Same as `get_hash_mut`, creating the hash if needed
+async fn get_or_create_hash<'a>(db: &'a mut Database, key: &Bytes) -> Result<&'a mut Hash> {
+    if get_hash_mut(db, key).await?.is_none() {
+        db.put(key.clone(), Entry::new(Value::Hash(Hash::new())));
+    }
+    // not through `get_mut`, which takes a hash without fields for one whose fields all expired
+    let entry = db.engine_mut().get_mut(key).expect("hash was just created");
+    entry.as_hash_mut()
+}
+
+/// Deletes the key once its last field is gone, removed or expired, and logs the deletion
+async fn delete_if_empty(db: &mut Database, key: &Bytes) -> Result<()> {
+    let empty = db.engine().get(key).is_some_and(|entry| entry.as_hash().is_ok_and(Hash::is_empty));
+    if empty {
+        db.delete(key);
+        db.append_log(&RespValue::bulk_array(["DEL".into(), key.clone()])).await?;
+    }
+    Ok(())
+}
+
+#[router_macro::route("HSET")]
+async fn hset(_context : Arc<Context>, request: RespRequest) -> anyhow::Result<RespValue> {
+    if request.args.len() < 3 || request.args.len().is_multiple_of(2) {
+        return Err(Error::WrongArgNumber("hset".into()).into());
+    }
+    let key = request.args[0].as_bytes()?;
+
+    let mut db = DB.write().await;
+    let hash = get_or_create_hash(&mut db, key).await?;
+    let mut added = 0;
+    for pair in request.args[1..].chunks(2) {
+        added += hash.insert(pair[0].as_bytes()?.clone(), pair[1].as_bytes()?.clone()) as i64;
+    }
+    db.append_log(&request.to_resp()).await?;
+    Ok(RespValue::Integer(added))
+}
+
+#[router_macro::route("HSETNX")]
+async fn hsetnx(_context : Arc<Context>, request: RespRequest) -> anyhow::Result<RespValue> {
+    check_args(&request, "hsetnx", 3, Some(3))?;
+    let key = request.args[0].as_bytes()?;
+    let field = request.args[1].as_bytes()?;
+
+    let mut db = DB.write().await;
+    let hash = get_or_create_hash(&mut db, key).await?;
+    if hash.contains(field) {
+        return Ok(RespValue::Integer(0));
+    }
+    hash.insert(field.clone(), request.args[2].as_bytes()?.clone());
+    db.append_log(&request.to_resp()).await?;
+    Ok(RespValue::Integer(1))
+}
+
+#[router_macro::route("HGET")]
+async fn hget(_context : Arc<Context>, request: RespRequest) -> anyhow::Result<RespValue> {
+    check_args(&request, "hget", 2, Some(2))?;
+    let field = request.args[1].as_bytes()?;
+    let db = DB.read().await;
+    let value = get_hash(&db, request.args[0].as_bytes()?)?.and_then(|hash| hash.get(field).cloned());
+    Ok(RespValue::BulkString(value))
+}
+
+#[router_macro::route("HMGET")]
+async fn hmget(_context : Arc<Context>, request: RespRequest) -> anyhow::Result<RespValue> {
+    check_args(&request, "hmget", 2, None)?;
+    let db = DB.read().await;
+    let hash = get_hash(&db, request.args[0].as_bytes()?)?;
+    let mut values = Vec::with_capacity(request.args.len() - 1);
+    for field in &request.args[1..] {
+        let field = field.as_bytes()?;
+        values.push(RespValue::BulkString(hash.and_then(|hash| hash.get(field).cloned())));
+    }
+    Ok(RespValue::Array(values))
+}
+
+#[router_macro::route("HDEL")]
+async fn hdel(_context : Arc<Context>, request: RespRequest) -> anyhow::Result<RespValue> {
+    check_args(&request, "hdel", 2, None)?;
+    let key = request.args[0].as_bytes()?;
+
+    let mut db = DB.write().await;
+    let Some(hash) = get_hash_mut(&mut db, key).await? else {
+        return Ok(RespValue::Integer(0));
+    };
+    let mut deleted = 0;
+    for field in &request.args[1..] {
+        deleted += hash.remove(field.as_bytes()?) as i64;
+    }
+    if deleted > 0 {
+        db.append_log(&request.to_resp()).await?;
+    }
+    delete_if_empty(&mut db, key).await?;
+    Ok(RespValue::Integer(deleted))
+}
+
+#[router_macro::route("HEXISTS")]
+async fn hexists(_context : Arc<Context>, request: RespRequest) -> anyhow::Result<RespValue> {
+    check_args(&request, "hexists", 2, Some(2))?;
+    let db = DB.read().await;
+    let field = request.args[1].as_bytes()?;
+    let exists = get_hash(&db, request.args[0].as_bytes()?)?.is_some_and(|hash| hash.contains(field));
+    Ok(RespValue::Integer(exists as i64))
+}
+
+#[router_macro::route("HLEN")]
+async fn hlen(_context : Arc<Context>, request: RespRequest) -> anyhow::Result<RespValue> {
+    check_args(&request, "hlen", 1, Some(1))?;
+    let db = DB.read().await;
+    let len = get_hash(&db, request.args[0].as_bytes()?)?.map(Hash::len).unwrap_or(0);
+    Ok(RespValue::Integer(len as i64))
+}
+
+#[router_macro::route("HSTRLEN")]
+async fn hstrlen(_context : Arc<Context>, request: RespRequest) -> anyhow::Result<RespValue> {
+    check_args(&request, "hstrlen", 2, Some(2))?;
+    let db = DB.read().await;
+    let field = request.args[1].as_bytes()?;
+    let len = get_hash(&db, request.args[0].as_bytes()?)?.and_then(|hash| hash.get(field)).map(Bytes::len).unwrap_or(0);
+    Ok(RespValue::Integer(len as i64))
+}
+
+async fn fields_of(request: &RespRequest, name: &str, keys: bool, values: bool) -> anyhow::Result<Vec<Bytes>> {
+    check_args(request, name, 1, Some(1))?;
+    let db = DB.read().await;
+    let Some(hash) = get_hash(&db, request.args[0].as_bytes()?)? else {
+        return Ok(vec![]);
+    };
+    let mut items = Vec::with_capacity(hash.len() * (keys as usize + values as usize));
+    for (field, value) in hash.iter() {
+        if keys {
+            items.push(field.clone());
+        }
+        if values {
+            items.push(value.clone());
+        }
+    }
+    Ok(items)
+}
+
+#[router_macro::route("HKEYS")]
+async fn hkeys(_context : Arc<Context>, request: RespRequest) -> anyhow::Result<RespValue> {
+    Ok(RespValue::bulk_array(fields_of(&request, "hkeys", true, false).await?))
+}
+
+#[router_macro::route("HVALS")]
+async fn hvals(_context : Arc<Context>, request: RespRequest) -> anyhow::Result<RespValue> {
+    Ok(RespValue::bulk_array(fields_of(&request, "hvals", false, true).await?))
+}
+
+#[router_macro::route("HGETALL")]
+async fn hgetall(_context : Arc<Context>, request: RespRequest) -> anyhow::Result<RespValue> {
+    let items = fields_of(&request, "hgetall", true, true).await?;
+    let pairs = items
+        .chunks(2)
+        .map(|pair| (RespValue::BulkString(Some(pair[0].clone())), RespValue::BulkString(Some(pair[1].clone()))))
+        .collect();
+    Ok(RespValue::Map(pairs))
+}
+
+#[router_macro::route("HINCRBY")]
+async fn hincrby(_context : Arc<Context>, request: RespRequest) -> anyhow::Result<RespValue> {
+    check_args(&request, "hincrby", 3, Some(3))?;
+    let key = request.args[0].as_bytes()?;
+    let field = request.args[1].as_bytes()?;
+    let delta = arg_i64(&request.args[2])?;
+
+    let mut db = DB.write().await;
+    let hash = get_or_create_hash(&mut db, key).await?;
+    let current = match hash.get(field) {
+        Some(value) => parse_i64(value).map_err(|_| Error::Other("hash value is not an integer".into()))?,
+        None => 0,
+    };
+    let value = current
+        .checked_add(delta)
+        .ok_or_else(|| Error::Other("increment or decrement would overflow".into()))?;
+    hash.update(field.clone(), value.to_string().into());
+    db.append_log(&request.to_resp()).await?;
+    Ok(RespValue::Integer(value))
+}
+
+#[router_macro::route("HINCRBYFLOAT")]
+async fn hincrbyfloat(_context : Arc<Context>, request: RespRequest) -> anyhow::Result<RespValue> {
+    check_args(&request, "hincrbyfloat", 3, Some(3))?;
+    let key = request.args[0].as_bytes()?;
+    let field = request.args[1].as_bytes()?;
+    let delta = arg_f64(&request.args[2])?;
+
+    let mut db = DB.write().await;
+    let hash = get_or_create_hash(&mut db, key).await?;
+    let current = match hash.get(field) {
+        Some(value) => parse_f64(value).map_err(|_| Error::Other("hash value is not a float".into()))?,
+        None => 0.0,
+    };
+    let value = current + delta;
+    if !value.is_finite() {
+        return Err(Error::Other("increment would produce NaN or Infinity".into()).into());
+    }
+    let value = Bytes::from(format_f64(value));
+    hash.update(field.clone(), value.clone());
+    let deadline = hash.expire_at(field);
+
+    // the result is logged instead of the increment, so that a replay cannot drift because of rounding
+    db.append_log(&RespValue::bulk_array(["HSET".into(), key.clone(), field.clone(), value.clone()])).await?;
+    if let Some(deadline) = deadline {
+        db.append_log(&field_expire_record(key, deadline, std::slice::from_ref(field))).await?;
+    }
+    Ok(RespValue::BulkString(Some(value)))
+}
+
+/// HRANDFIELD key [count [WITHVALUES]]
+#[router_macro::route("HRANDFIELD")]
+async fn hrandfield(context : Arc<Context>, request: RespRequest) -> anyhow::Result<RespValue> {
+    check_args(&request, "hrandfield", 1, Some(3))?;
+    let count = request.args.get(1).map(arg_i64).transpose()?;
+    let with_values = match request.args.get(2) {
+        Some(arg) if arg.as_str()?.eq_ignore_ascii_case("WITHVALUES") => true,
+        Some(_) => return Err(Error::Syntax.into()),
+        None => false,
+    };
+
+    let db = DB.read().await;
+    let hash = get_hash(&db, request.args[0].as_bytes()?)?;
+    let mut rng = rand::thread_rng();
+    let Some(count) = count else {
+        let field = hash.and_then(|hash| hash.iter().choose(&mut rng)).map(|(field, _)| field.clone());
+        return Ok(RespValue::BulkString(field));
+    };
+    let Some(hash) = hash else {
+        return Ok(RespValue::Array(vec![]));
+    };
+
+    let picked: Vec<(&Bytes, &Bytes)> = if count >= 0 {
+        let count = (count as usize).min(hash.len());
+        hash.iter().choose_multiple(&mut rng, count)
+    } else {
+        // a negative count may return the same field several times
+        let count = repeated_picks(count)?;
+        let fields: Vec<_> = hash.iter().collect();
+        if fields.is_empty() {
+            vec![]
+        } else {
+            (0..count).map(|_| fields[rng.gen_range(0..fields.len())]).collect()
+        }
+    };
+
+    let bulk = |b: &Bytes| RespValue::BulkString(Some(b.clone()));
+    Ok(RespValue::Array(match (with_values, context.protocol() >= RESP3) {
+        (false, _) => picked.iter().map(|(field, _)| bulk(field)).collect(),
+        (true, false) => picked.iter().flat_map(|(field, value)| [bulk(field), bulk(value)]).collect(),
+        (true, true) => picked.iter().map(|(field, value)| RespValue::Array(vec![bulk(field), bulk(value)])).collect(),
+    }))
+}
+
+/// HSCAN key cursor [MATCH pattern] [COUNT count] [NOVALUES]
+#[router_macro::route("HSCAN")]
+async fn hscan(_context : Arc<Context>, request: RespRequest) -> anyhow::Result<RespValue> {
+    check_args(&request, "hscan", 2, None)?;
+    let scan = ScanArgs::parse(&request.args[1..], true, false)?;
+
+    let db = DB.read().await;
+    let (cursor, fields) = match get_hash(&db, request.args[0].as_bytes()?)? {
+        Some(hash) => scan.walk(|cursor, fields| hash.scan(cursor, |field, value| fields.push((field.clone(), value.clone())))),
+        None => (0, vec![]),
+    };
+    let mut items = vec![];
+    for (field, value) in fields.into_iter().filter(|(field, _)| scan.matches(field)) {
+        items.push(field);
+        if !scan.no_values {
+            items.push(value);
+        }
+    }
+    Ok(ScanArgs::reply(cursor, items))
+}
+
+/// `HPEXPIREAT key deadline FIELDS n field...`, the absolute form every field expiration is logged as
+fn field_expire_record(key: &Bytes, deadline: Timestamp, fields: &[Bytes]) -> RespValue {
+    let head = [
+        "HPEXPIREAT".into(),
+        key.clone(),
+        deadline.timestamp_millis().to_string().into(),
+        "FIELDS".into(),
+        fields.len().to_string().into(),
+    ];
+    RespValue::bulk_array(head.into_iter().chain(fields.iter().cloned()))
+}
+
+/// Parses `FIELDS numfields field...` at the end of the field expiration commands
+fn parse_fields(args: &[RespValue]) -> Result<Vec<Bytes>> {
+    let mut args = args.iter();
+    if !args.next().ok_or(Error::Syntax)?.as_str()?.eq_ignore_ascii_case("FIELDS") {
+        return Err(Error::Other("Mandatory argument FIELDS is missing or not at the right position".into()));
+    }
+    let count = arg_i64(args.next().ok_or(Error::Syntax)?)?;
+    let fields: Vec<Bytes> = args.map(|arg| arg.as_bytes().cloned()).collect::<anyhow::Result<_>>()?;
+    if count <= 0 || count as usize != fields.len() {
+        return Err(Error::Other("The `numfields` parameter must match the number of arguments".into()));
+    }
+    Ok(fields)
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum ExpireCond {
+    Always,
+    NX,
+    XX,
+    GT,
+    LT,
+}
+
+/// HEXPIRE/HPEXPIRE/HEXPIREAT/HPEXPIREAT key time [NX | XX | GT | LT] FIELDS numfields field...
+async fn field_expire(request: &RespRequest, name: &str, unit_ms: bool, absolute: bool) -> anyhow::Result<RespValue> {
+    check_args(request, name, 5, None)?;
+    let key = request.args[0].as_bytes()?;
+    let time = arg_i64(&request.args[1])?;
+    let (cond, fields_at) = match request.args[2].as_str()?.to_ascii_uppercase().as_str() {
+        "NX" => (ExpireCond::NX, 3),
+        "XX" => (ExpireCond::XX, 3),
+        "GT" => (ExpireCond::GT, 3),
+        "LT" => (ExpireCond::LT, 3),
+        _ => (ExpireCond::Always, 2),
+    };
+    let fields = parse_fields(&request.args[fields_at..])?;
+
+    let invalid = || Error::Other(format!("invalid expire time in '{}' command", name));
+    if time < 0 {
+        return Err(invalid().into());
+    }
+    let millis = if unit_ms { Some(time) } else { time.checked_mul(1000) }.ok_or_else(invalid)?;
+    let deadline = if absolute {
+        chrono::DateTime::from_timestamp_millis(millis)
+    } else {
+        chrono::Duration::try_milliseconds(millis).and_then(|d| chrono::Utc::now().checked_add_signed(d))
+    }.ok_or_else(invalid)?;
+
+    let mut db = DB.write().await;
+    let Some(hash) = get_hash_mut(&mut db, key).await? else {
+        return Ok(RespValue::Array(vec![RespValue::Integer(-2); fields.len()]));
+    };
+
+    let now = chrono::Utc::now();
+    let (mut results, mut changed) = (vec![], vec![]);
+    for field in fields {
+        if !hash.contains(&field) {
+            results.push(RespValue::Integer(-2));
+            continue;
+        }
+        let current = hash.expire_at(&field);
+        let allowed = match cond {
+            ExpireCond::Always => true,
+            ExpireCond::NX => current.is_none(),
+            ExpireCond::XX => current.is_some(),
+            // no expiration counts as an infinite one
+            ExpireCond::GT => current.is_some_and(|current| deadline > current),
+            ExpireCond::LT => current.is_none_or(|current| deadline < current),
+        };
+        if !allowed {
+            results.push(RespValue::Integer(0));
+            continue;
+        }
+        if deadline <= now {
+            hash.remove(&field);
+            results.push(RespValue::Integer(2));
+        } else {
+            hash.set_expire(&field, Some(deadline));
+            results.push(RespValue::Integer(1));
+        }
+        changed.push(field);
+    }
+
+    if !changed.is_empty() {
+        db.append_log(&field_expire_record(key, deadline, &changed)).await?;
+    }
+    delete_if_empty(&mut db, key).await?;
+    Ok(RespValue::Array(results))
+}
+
+#[router_macro::route("HEXPIRE")]
+async fn hexpire(_context : Arc<Context>, request: RespRequest) -> anyhow::Result<RespValue> {
+    field_expire(&request, "hexpire", false, false).await
+}
+
+#[router_macro::route("HPEXPIRE")]
+async fn hpexpire(_context : Arc<Context>, request: RespRequest) -> anyhow::Result<RespValue> {
+    field_expire(&request, "hpexpire", true, false).await
+}
+
+#[router_macro::route("HEXPIREAT")]
+async fn hexpireat(_context : Arc<Context>, request: RespRequest) -> anyhow::Result<RespValue> {
+    field_expire(&request, "hexpireat", false, true).await
+}
+
+#[router_macro::route("HPEXPIREAT")]
+async fn hpexpireat(_context : Arc<Context>, request: RespRequest) -> anyhow::Result<RespValue> {
+    field_expire(&request, "hpexpireat", true, true).await
+}
+
+/// HTTL/HPTTL key FIELDS numfields field...: -2 for a missing field, -1 for a field without expiration
+async fn field_ttl(request: &RespRequest, name: &str, unit_ms: bool) -> anyhow::Result<RespValue> {
+    check_args(request, name, 4, None)?;
+    let fields = parse_fields(&request.args[1..])?;
+
+    let db = DB.read().await;
+    let hash = get_hash(&db, request.args[0].as_bytes()?)?;
+    let now = chrono::Utc::now();
+    let ttls = fields
+        .iter()
+        .map(|field| {
+            let ttl = match hash.filter(|hash| hash.contains(field)) {
+                None => -2,
+                Some(hash) => match hash.expire_at(field) {
+                    None => -1,
+                    Some(deadline) if unit_ms => (deadline - now).num_milliseconds().max(0),
+                    // rounded to the nearest second like redis
+                    Some(deadline) => ((deadline - now).num_milliseconds().max(0) + 500) / 1000,
+                },
+            };
+            RespValue::Integer(ttl)
+        })
+        .collect();
+    Ok(RespValue::Array(ttls))
+}
+
+#[router_macro::route("HTTL")]
+async fn httl(_context : Arc<Context>, request: RespRequest) -> anyhow::Result<RespValue> {
+    field_ttl(&request, "httl", false).await
+}
+
+#[router_macro::route("HPTTL")]
+async fn hpttl(_context : Arc<Context>, request: RespRequest) -> anyhow::Result<RespValue> {
+    field_ttl(&request, "hpttl", true).await
+}
+
+/// HPERSIST key FIELDS numfields field...
+#[router_macro::route("HPERSIST")]
+async fn hpersist(_context : Arc<Context>, request: RespRequest) -> anyhow::Result<RespValue> {
+    check_args(&request, "hpersist", 4, None)?;
+    let key = request.args[0].as_bytes()?;
+    let fields = parse_fields(&request.args[1..])?;
+
+    let mut db = DB.write().await;
+    let Some(hash) = get_hash_mut(&mut db, key).await? else {
+        return Ok(RespValue::Array(vec![RespValue::Integer(-2); fields.len()]));
+    };
+    let mut persisted = false;
+    let results = fields
+        .iter()
+        .map(|field| {
+            let result = match (hash.contains(field), hash.expire_at(field)) {
+                (false, _) => -2,
+                (true, None) => -1,
+                (true, Some(_)) => {
+                    hash.set_expire(field, None);
+                    persisted = true;
+                    1
+                }
+            };
+            RespValue::Integer(result)
+        })
+        .collect();
+    if persisted {
+        db.append_log(&request.to_resp()).await?;
+    }
+    delete_if_empty(&mut db, key).await?;
+    Ok(RespValue::Array(results))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::parser::NULL_RESP;
+    use crate::redis_types::test_util::{bulk, bulks, context, request};
+
+    fn integers(values: &[i64]) -> RespValue {
+        RespValue::Array(values.iter().map(|v| RespValue::Integer(*v)).collect())
+    }
+
+    #[tokio::test]
+    async fn set_get_and_delete() -> anyhow::Result<()> {
+        assert_eq!(hset(context(), request("HSET", &["hash:h1", "a", "1", "b", "2"])).await?, RespValue::Integer(2));
+        assert_eq!(hset(context(), request("HSET", &["hash:h1", "a", "3", "c", "4"])).await?, RespValue::Integer(1));
+        assert!(hset(context(), request("HSET", &["hash:h1", "a"])).await.is_err());
+        assert_eq!(hget(context(), request("HGET", &["hash:h1", "a"])).await?, bulk("3"));
+        assert_eq!(hget(context(), request("HGET", &["hash:h1", "x"])).await?, *NULL_RESP);
+        assert_eq!(hmget(context(), request("HMGET", &["hash:h1", "b", "x"])).await?, RespValue::Array(vec![bulk("2"), NULL_RESP.clone()]));
+        assert_eq!(hlen(context(), request("HLEN", &["hash:h1"])).await?, RespValue::Integer(3));
+        assert_eq!(hstrlen(context(), request("HSTRLEN", &["hash:h1", "c"])).await?, RespValue::Integer(1));
+        assert_eq!(hsetnx(context(), request("HSETNX", &["hash:h1", "c", "5"])).await?, RespValue::Integer(0));
+        assert_eq!(hexists(context(), request("HEXISTS", &["hash:h1", "c"])).await?, RespValue::Integer(1));
+
+        let RespValue::Array(mut keys) = hkeys(context(), request("HKEYS", &["hash:h1"])).await? else { panic!() };
+        keys.sort_by_key(|key| key.as_bytes().unwrap().clone());
+        assert_eq!(RespValue::Array(keys), bulks(&["a", "b", "c"]));
+        let RespValue::Map(pairs) = hgetall(context(), request("HGETALL", &["hash:h1"])).await? else { panic!() };
+        assert_eq!(pairs.len(), 3);
+
+        assert_eq!(hdel(context(), request("HDEL", &["hash:h1", "a", "b", "x"])).await?, RespValue::Integer(2));
+        assert_eq!(hdel(context(), request("HDEL", &["hash:h1", "c"])).await?, RespValue::Integer(1));
+        assert!(DB.read().await.get(b"hash:h1").is_none());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn increments() -> anyhow::Result<()> {
+        assert_eq!(hincrby(context(), request("HINCRBY", &["hash:h2", "n", "5"])).await?, RespValue::Integer(5));
+        assert_eq!(hincrby(context(), request("HINCRBY", &["hash:h2", "n", "-7"])).await?, RespValue::Integer(-2));
+        hset(context(), request("HSET", &["hash:h2", "s", "abc", "max", "9223372036854775807"])).await?;
+        let err = hincrby(context(), request("HINCRBY", &["hash:h2", "s", "1"])).await.unwrap_err();
+        assert_eq!(err.to_string(), "hash value is not an integer");
+        let err = hincrby(context(), request("HINCRBY", &["hash:h2", "max", "1"])).await.unwrap_err();
+        assert_eq!(err.to_string(), "increment or decrement would overflow");
+        assert!(hincrby(context(), request("HINCRBY", &["hash:h2", "n", "x"])).await.is_err());
+
+        assert_eq!(hincrbyfloat(context(), request("HINCRBYFLOAT", &["hash:h2", "f", "10.5"])).await?, bulk("10.5"));
+        assert_eq!(hincrbyfloat(context(), request("HINCRBYFLOAT", &["hash:h2", "f", "0.1"])).await?, bulk("10.6"));
+        let err = hincrbyfloat(context(), request("HINCRBYFLOAT", &["hash:h2", "s", "1"])).await.unwrap_err();
+        assert_eq!(err.to_string(), "hash value is not a float");
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn random_fields_and_scan() -> anyhow::Result<()> {
+        hset(context(), request("HSET", &["hash:h3", "a", "1", "b", "2", "ab", "3"])).await?;
+        let RespValue::Array(fields) = hrandfield(context(), request("HRANDFIELD", &["hash:h3", "10"])).await? else { panic!() };
+        assert_eq!(fields.len(), 3);
+        let RespValue::Array(fields) = hrandfield(context(), request("HRANDFIELD", &["hash:h3", "-5", "WITHVALUES"])).await? else { panic!() };
+        assert_eq!(fields.len(), 10);
+        let RespValue::Array(fields) = hrandfield(context(), request("HRANDFIELD", &["hash:h3", "9223372036854775807"])).await? else { panic!() };
+        assert_eq!(fields.len(), 3);
+        assert!(hrandfield(context(), request("HRANDFIELD", &["hash:h3", "-9223372036854775807"])).await.is_err());
+        assert_eq!(hrandfield(context(), request("HRANDFIELD", &["hash:missing"])).await?, *NULL_RESP);
+
+        let reply = hscan(context(), request("HSCAN", &["hash:h3", "0", "MATCH", "a*", "NOVALUES"])).await?;
+        let RespValue::Array(reply) = reply else { panic!() };
+        assert_eq!(reply[0], bulk("0"));
+        let RespValue::Array(mut fields) = reply[1].clone() else { panic!() };
+        fields.sort_by_key(|field| field.as_bytes().unwrap().clone());
+        assert_eq!(fields, vec![bulk("a"), bulk("ab")]);
+        assert!(hscan(context(), request("HSCAN", &["hash:h3", "x"])).await.is_err());
+
+        // a large hash comes back page by page while it keeps growing
+        let args: Vec<String> = (0..300).flat_map(|i| [format!("f{}", i), i.to_string()]).collect();
+        let args: Vec<&str> = ["hash:h5"].into_iter().chain(args.iter().map(String::as_str)).collect();
+        hset(context(), request("HSET", &args)).await?;
+        let mut seen = std::collections::HashSet::new();
+        let mut cursor = "0".to_string();
+        let mut pages = 0;
+        loop {
+            let RespValue::Array(reply) = hscan(context(), request("HSCAN", &["hash:h5", &cursor, "COUNT", "20"])).await? else { panic!() };
+            let RespValue::Array(items) = &reply[1] else { panic!() };
+            seen.extend(items.chunks(2).map(|pair| pair[0].as_bytes().unwrap().clone()));
+            cursor = reply[0].as_str()?.to_string();
+            pages += 1;
+            if cursor == "0" {
+                break;
+            }
+            let field = format!("new{}", pages);
+            hset(context(), request("HSET", &["hash:h5", &field, "x"])).await?;
+        }
+        assert!(pages > 5);
+        assert!((0..300).all(|i| seen.contains(format!("f{}", i).as_bytes())));
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn field_expiration() -> anyhow::Result<()> {
+        hset(context(), request("HSET", &["hash:h4", "a", "1", "b", "2", "c", "3"])).await?;
+        let reply = hexpire(context(), request("HEXPIRE", &["hash:h4", "100", "FIELDS", "2", "a", "x"])).await?;
+        assert_eq!(reply, integers(&[1, -2]));
+        let reply = hexpire(context(), request("HEXPIRE", &["hash:h4", "50", "NX", "FIELDS", "2", "a", "b"])).await?;
+        assert_eq!(reply, integers(&[0, 1]));
+        let reply = hexpire(context(), request("HEXPIRE", &["hash:h4", "80", "GT", "FIELDS", "3", "a", "b", "c"])).await?;
+        assert_eq!(reply, integers(&[0, 1, 0]));
+        assert_eq!(httl(context(), request("HTTL", &["hash:h4", "FIELDS", "3", "a", "c", "x"])).await?, integers(&[100, -1, -2]));
+        assert!(hexpire(context(), request("HEXPIRE", &["hash:h4", "10", "FIELDS", "2", "a"])).await.is_err());
+
+        assert_eq!(hpersist(context(), request("HPERSIST", &["hash:h4", "FIELDS", "2", "a", "c"])).await?, integers(&[1, -1]));
+        // HSET drops the expiration of the field it overwrites
+        hset(context(), request("HSET", &["hash:h4", "b", "new"])).await?;
+        assert_eq!(httl(context(), request("HTTL", &["hash:h4", "FIELDS", "1", "b"])).await?, integers(&[-1]));
+
+        let reply = hpexpire(context(), request("HPEXPIRE", &["hash:h4", "20", "FIELDS", "1", "c"])).await?;
+        assert_eq!(reply, integers(&[1]));
+        tokio::time::sleep(std::time::Duration::from_millis(40)).await;
+        assert_eq!(hget(context(), request("HGET", &["hash:h4", "c"])).await?, *NULL_RESP);
+        assert_eq!(hlen(context(), request("HLEN", &["hash:h4"])).await?, RespValue::Integer(2));
+
+        // a deadline in the past deletes the field right away
+        let reply = hexpireat(context(), request("HEXPIREAT", &["hash:h4", "1", "FIELDS", "2", "a", "b"])).await?;
+        assert_eq!(reply, integers(&[2, 2]));
+        assert!(DB.read().await.get(b"hash:h4").is_none());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn hash_of_expired_fields_is_gone() -> anyhow::Result<()> {
+        hset(context(), request("HSET", &["hash:h5", "a", "1", "b", "2"])).await?;
+        hpexpire(context(), request("HPEXPIRE", &["hash:h5", "20", "FIELDS", "2", "a", "b"])).await?;
+        tokio::time::sleep(std::time::Duration::from_millis(40)).await;
+        // reads take the key for a missing one before any write purges it
+        assert!(DB.read().await.get(b"hash:h5").is_none());
+        assert!(DB.read().await.engine().get(b"hash:h5").is_some());
+
+        assert_eq!(hpersist(context(), request("HPERSIST", &["hash:h5", "FIELDS", "1", "a"])).await?, integers(&[-2]));
+        assert!(DB.read().await.engine().get(b"hash:h5").is_none());
+        Ok(())
+    }
+}
@@ -0,0 +1,168 @@
+use std::sync::Arc;
+use bytes::Bytes;
+use crate::engine::{Database, HyperLogLog, DB};
+use super::check_args;
+use super::string::overwrite;
+use crate::error::*;
+use crate::parser::OK_RESP;
+use crate::{context::Context, parser::{RespRequest, RespValue}};
+use crate::command_table::{RouteHandler, ROUTE_MAP};
+
+/// The HyperLogLog at `key`, an error if it holds anything else than one
+fn get_hll(db: &Database, key: &[u8]) -> Result<Option<HyperLogLog>> {
+    let value = db.get(key).map(|entry| entry.as_string()).transpose()?;
+    value.map(|value| HyperLogLog::from_bytes(value)).transpose()
+}
+
+fn store(db: &mut Database, key: &Bytes, hll: HyperLogLog) {
+    overwrite(db, key, hll.into_bytes().into());
+}
+
+/// PFADD key [element ...]
+#[router_macro::route("PFADD")]
+async fn pfadd(_context : Arc<Context>, request: RespRequest) -> anyhow::Result<RespValue> {
+    check_args(&request, "pfadd", 1, None)?;
+    let key = request.args[0].as_bytes()?;
+    let elements = request.args[1..].iter().map(|arg| arg.as_bytes().map(Bytes::as_ref)).collect::<anyhow::Result<Vec<_>>>()?;
+
+    let mut db = DB.write().await;
+    let (mut hll, created) = match get_hll(&db, key)? {
+        Some(hll) => (hll, false),
+        None => (HyperLogLog::new(), true),
+    };
+    let changed = hll.add(elements) || created;
+    if changed {
+        store(&mut db, key, hll);
+        db.append_log(&request.to_resp()).await?;
+    }
+    Ok(RespValue::Integer(changed as i64))
+}
+
+/// PFCOUNT key [key ...], the count of the union of several keys
+#[router_macro::route("PFCOUNT")]
+async fn pfcount(_context : Arc<Context>, request: RespRequest) -> anyhow::Result<RespValue> {
+    check_args(&request, "pfcount", 1, None)?;
+    if request.args.len() > 1 {
+        let db = DB.read().await;
+        let mut registers = vec![0; HyperLogLog::REGISTERS];
+        for key in &request.args {
+            if let Some(hll) = get_hll(&db, key.as_bytes()?)? {
+                hll.merge_into(&mut registers);
+            }
+        }
+        return Ok(RespValue::Integer(HyperLogLog::estimate(&registers) as i64));
+    }
+
+    let key = request.args[0].as_bytes()?;
+    let mut db = DB.write().await;
+    let Some(mut hll) = get_hll(&db, key)? else {
+        return Ok(RespValue::Integer(0));
+    };
+    if let Some(count) = hll.cached_count() {
+        return Ok(RespValue::Integer(count as i64));
+    }
+    // the count is cached in the value, which is then written like redis does
+    let count = hll.count();
+    store(&mut db, key, hll);
+    db.append_log(&request.to_resp()).await?;
+    Ok(RespValue::Integer(count as i64))
+}
+
+/// PFMERGE destkey [sourcekey ...], the destination being part of the union
+#[router_macro::route("PFMERGE")]
+async fn pfmerge(_context : Arc<Context>, request: RespRequest) -> anyhow::Result<RespValue> {
+    check_args(&request, "pfmerge", 1, None)?;
+    let dest = request.args[0].as_bytes()?;
+
+    let mut db = DB.write().await;
+    let mut registers = vec![0; HyperLogLog::REGISTERS];
+    // the result stays sparse unless an input is dense
+    let mut sparse = true;
+    for key in &request.args {
+        if let Some(hll) = get_hll(&db, key.as_bytes()?)? {
+            sparse &= hll.is_sparse();
+            hll.merge_into(&mut registers);
+        }
+    }
+    store(&mut db, dest, HyperLogLog::from_registers(&registers, sparse));
+    db.append_log(&request.to_resp()).await?;
+    Ok(OK_RESP.clone())
+}
+
+/// PFDEBUG GETREG|DECODE|ENCODING|TODENSE key
+#[router_macro::route("PFDEBUG")]
+async fn pfdebug(_context : Arc<Context>, request: RespRequest) -> anyhow::Result<RespValue> {
+    check_args(&request, "pfdebug", 2, Some(2))?;
+    let subcommand = request.args[0].as_str()?.to_ascii_uppercase();
+    let key = request.args[1].as_bytes()?;
+
+    let mut db = DB.write().await;
+    let mut hll = get_hll(&db, key)?.ok_or_else(|| Error::Other("The specified key does not exist".into()))?;
+    match subcommand.as_str() {
+        "GETREG" => Ok(RespValue::Array(hll.registers().into_iter().map(|value| RespValue::Integer(value as i64)).collect())),
+        "DECODE" => {
+            let decoded = hll.decode().ok_or_else(|| Error::Other("HLL encoding is not sparse".into()))?;
+            Ok(RespValue::SimpleString(decoded.into()))
+        }
+        "ENCODING" => Ok(RespValue::SimpleString(if hll.is_sparse() { "sparse" } else { "dense" }.into())),
+        "TODENSE" => {
+            let converted = hll.to_dense();
+            if converted {
+                store(&mut db, key, hll);
+                db.append_log(&request.to_resp()).await?;
+            }
+            Ok(RespValue::Integer(converted as i64))
+        }
+        _ => Err(Error::Other(format!("Unknown PFDEBUG subcommand '{}'", request.args[0].as_str()?)).into()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::engine::{Entry, Value};
+    use crate::redis_types::test_util::{context, request};
+
+    #[tokio::test]
+    async fn add_count_and_merge() -> anyhow::Result<()> {
+        assert_eq!(pfadd(context(), request("PFADD", &["hll:a", "a", "b", "c", "d", "e", "f", "g"])).await?, RespValue::Integer(1));
+        assert_eq!(pfadd(context(), request("PFADD", &["hll:a", "a", "b"])).await?, RespValue::Integer(0));
+        assert_eq!(pfcount(context(), request("PFCOUNT", &["hll:a"])).await?, RespValue::Integer(7));
+        assert!(get_hll(&*DB.read().await, b"hll:a")?.unwrap().cached_count().is_some());
+
+        assert_eq!(pfadd(context(), request("PFADD", &["hll:empty"])).await?, RespValue::Integer(1));
+        assert_eq!(pfadd(context(), request("PFADD", &["hll:empty"])).await?, RespValue::Integer(0));
+        assert_eq!(pfcount(context(), request("PFCOUNT", &["hll:empty", "hll:missing"])).await?, RespValue::Integer(0));
+
+        pfadd(context(), request("PFADD", &["hll:b", "e", "f", "g", "h", "i"])).await?;
+        assert_eq!(pfcount(context(), request("PFCOUNT", &["hll:a", "hll:b"])).await?, RespValue::Integer(9));
+        pfmerge(context(), request("PFMERGE", &["hll:merged", "hll:a", "hll:b", "hll:missing"])).await?;
+        assert_eq!(pfcount(context(), request("PFCOUNT", &["hll:merged"])).await?, RespValue::Integer(9));
+        let encoding = pfdebug(context(), request("PFDEBUG", &["ENCODING", "hll:merged"])).await?;
+        assert_eq!(encoding, RespValue::SimpleString("sparse".into()));
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn debug_and_wrong_values() -> anyhow::Result<()> {
+        pfadd(context(), request("PFADD", &["hll:d"])).await?;
+        assert_eq!(pfdebug(context(), request("PFDEBUG", &["DECODE", "hll:d"])).await?, RespValue::SimpleString("Z:16384".into()));
+        assert_eq!(pfdebug(context(), request("PFDEBUG", &["TODENSE", "hll:d"])).await?, RespValue::Integer(1));
+        assert_eq!(pfdebug(context(), request("PFDEBUG", &["TODENSE", "hll:d"])).await?, RespValue::Integer(0));
+        assert!(pfdebug(context(), request("PFDEBUG", &["DECODE", "hll:d"])).await.is_err());
+        let RespValue::Array(registers) = pfdebug(context(), request("PFDEBUG", &["GETREG", "hll:d"])).await? else { panic!() };
+        assert_eq!(registers.len(), HyperLogLog::REGISTERS);
+        assert_eq!(DB.read().await.get(b"hll:d").map(|entry| entry.as_string().map(Bytes::len)).transpose()?, Some(HyperLogLog::DENSE_SIZE));
+
+        // merging a dense one makes the result dense
+        pfmerge(context(), request("PFMERGE", &["hll:d2", "hll:d"])).await?;
+        assert_eq!(pfdebug(context(), request("PFDEBUG", &["ENCODING", "hll:d2"])).await?, RespValue::SimpleString("dense".into()));
+
+        DB.write().await.put("hll:string".into(), Entry::new(Value::String("hello".into())));
+        let err = pfadd(context(), request("PFADD", &["hll:string", "a"])).await.unwrap_err();
+        assert_eq!(err.to_string(), "WRONGTYPE Key is not a valid HyperLogLog string value.");
+        assert!(pfcount(context(), request("PFCOUNT", &["hll:d", "hll:string"])).await.is_err());
+        assert!(pfdebug(context(), request("PFDEBUG", &["ENCODING", "hll:missing"])).await.is_err());
+        Ok(())
+    }
+}
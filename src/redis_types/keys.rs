@@ -0,0 +1,342 @@
+use std::sync::Arc;
+use bytes::Bytes;
+use crate::engine::{Database, Entry, DB};
+use super::blocking;
+use super::{arg_i64, check_args, ScanArgs};
+use crate::error::*;
+use crate::parser::{NULL_RESP, OK_RESP};
+use crate::utils::glob_match;
+use crate::{context::Context, parser::{RespRequest, RespValue}};
+use crate::command_table::{RouteHandler, ROUTE_MAP};
+
+/// Drops removed values on a blocking thread, so that freeing large ones does not hold up the server
+fn free_later<T: Send + 'static>(values: T) {
+    tokio::task::spawn_blocking(move || drop(values));
+}
+
+/// Removes the keys that exist, an expired one is dropped too but not counted
+fn remove(db: &mut Database, keys: &[RespValue]) -> Result<Vec<Entry>> {
+    let mut removed = vec![];
+    for key in keys {
+        let key = key.as_bytes()?;
+        let live = db.get(key).is_some();
+        if let Some(entry) = db.delete(key).filter(|_| live) {
+            removed.push(entry);
+        }
+    }
+    Ok(removed)
+}
+
+/// Moves `key` to `new_key`, overwriting it unless `nx`. Errors if `key` is missing.
+async fn rename(request: &RespRequest, nx: bool) -> anyhow::Result<bool> {
+    let key = request.args[0].as_bytes()?;
+    let new_key = request.args[1].as_bytes()?;
+    let mut db = DB.write().await;
+    if db.get(key).is_none() {
+        return Err(Error::Other("no such key".into()).into());
+    }
+    if nx && db.get(new_key).is_some() {
+        return Ok(false);
+    }
+    if key == new_key {
+        return Ok(!nx);
+    }
+    let entry = db.delete(key).expect("key was just checked");
+    db.put(new_key.clone(), entry);
+    db.append_log(&request.to_resp()).await?;
+    blocking::signal(new_key);
+    blocking::serve_ready(&mut db).await?;
+    Ok(true)
+}
+
+/// ASYNC or SYNC, the only arguments FLUSHDB and FLUSHALL take
+fn parse_flush_mode(request: &RespRequest) -> Result<bool> {
+    match request.args.first() {
+        None => Ok(false),
+        Some(arg) => match arg.as_bytes()?.to_ascii_uppercase().as_slice() {
+            b"ASYNC" => Ok(true),
+            b"SYNC" => Ok(false),
+            _ => Err(Error::Syntax),
+        },
+    }
+}
+
+/// There is a single database, so FLUSHDB and FLUSHALL are the same
+async fn flush(request: &RespRequest, name: &str) -> anyhow::Result<RespValue> {
+    check_args(request, name, 0, Some(1))?;
+    let lazy = parse_flush_mode(request)?;
+    let mut db = DB.write().await;
+    let flushed = db.flush();
+    if lazy {
+        free_later(flushed);
+    }
+    db.append_log(&request.to_resp()).await?;
+    Ok(OK_RESP.clone())
+}
+
+/// DEL key [key ...]
+#[router_macro::route("DEL")]
+async fn del(_context : Arc<Context>, request: RespRequest) -> anyhow::Result<RespValue> {
+    check_args(&request, "del", 1, None)?;
+    let mut db = DB.write().await;
+    let removed = remove(&mut db, &request.args)?;
+    if !removed.is_empty() {
+        db.append_log(&request.to_resp()).await?;
+    }
+    Ok(RespValue::Integer(removed.len() as i64))
+}
+
+/// UNLINK key [key ...], which frees the values in the background
+#[router_macro::route("UNLINK")]
+async fn unlink(_context : Arc<Context>, request: RespRequest) -> anyhow::Result<RespValue> {
+    check_args(&request, "unlink", 1, None)?;
+    let mut db = DB.write().await;
+    let removed = remove(&mut db, &request.args)?;
+    let count = removed.len();
+    if count > 0 {
+        db.append_log(&request.to_resp()).await?;
+        free_later(removed);
+    }
+    Ok(RespValue::Integer(count as i64))
+}
+
+/// How many of `keys` exist, a key given twice is counted twice
+fn count_live(db: &Database, keys: &[RespValue]) -> Result<i64> {
+    let mut count = 0;
+    for key in keys {
+        count += db.get(key.as_bytes()?).is_some() as i64;
+    }
+    Ok(count)
+}
+
+/// EXISTS key [key ...]
+#[router_macro::route("EXISTS")]
+async fn exists(_context : Arc<Context>, request: RespRequest) -> anyhow::Result<RespValue> {
+    check_args(&request, "exists", 1, None)?;
+    let db = DB.read().await;
+    Ok(RespValue::Integer(count_live(&db, &request.args)?))
+}
+
+/// TOUCH key [key ...]
+#[router_macro::route("TOUCH")]
+async fn touch(_context : Arc<Context>, request: RespRequest) -> anyhow::Result<RespValue> {
+    check_args(&request, "touch", 1, None)?;
+    let db = DB.read().await;
+    Ok(RespValue::Integer(count_live(&db, &request.args)?))
+}
+
+#[router_macro::route("TYPE")]
+async fn type_command(_context : Arc<Context>, request: RespRequest) -> anyhow::Result<RespValue> {
+    check_args(&request, "type", 1, Some(1))?;
+    let db = DB.read().await;
+    let name = db.get(request.args[0].as_bytes()?).map_or("none", |entry| entry.value.type_name());
+    Ok(RespValue::SimpleString(name.into()))
+}
+
+/// RENAME key newkey
+#[router_macro::route("RENAME")]
+async fn rename_command(_context : Arc<Context>, request: RespRequest) -> anyhow::Result<RespValue> {
+    check_args(&request, "rename", 2, Some(2))?;
+    rename(&request, false).await?;
+    Ok(OK_RESP.clone())
+}
+
+/// RENAMENX key newkey
+#[router_macro::route("RENAMENX")]
+async fn renamenx(_context : Arc<Context>, request: RespRequest) -> anyhow::Result<RespValue> {
+    check_args(&request, "renamenx", 2, Some(2))?;
+    let renamed = rename(&request, true).await?;
+    Ok(RespValue::Integer(renamed as i64))
+}
+
+/// COPY source destination [DB destination-db] [REPLACE]
+#[router_macro::route("COPY")]
+async fn copy(_context : Arc<Context>, request: RespRequest) -> anyhow::Result<RespValue> {
+    check_args(&request, "copy", 2, None)?;
+    let source = request.args[0].as_bytes()?;
+    let destination = request.args[1].as_bytes()?;
+    let mut replace = false;
+    let mut args = request.args[2..].iter();
+    while let Some(arg) = args.next() {
+        match arg.as_bytes()?.to_ascii_uppercase().as_slice() {
+            b"REPLACE" => replace = true,
+            // there is only the database 0
+            b"DB" => {
+                if arg_i64(args.next().ok_or(Error::Syntax)?)? != 0 {
+                    return Err(Error::Other("DB index is out of range".into()).into());
+                }
+            }
+            _ => return Err(Error::Syntax.into()),
+        }
+    }
+    if source == destination {
+        return Err(Error::Other("source and destination objects are the same".into()).into());
+    }
+
+    let mut db = DB.write().await;
+    let Some(entry) = db.get(source).cloned() else {
+        return Ok(RespValue::Integer(0));
+    };
+    if !replace && db.get(destination).is_some() {
+        return Ok(RespValue::Integer(0));
+    }
+    db.put(destination.clone(), entry);
+    db.append_log(&request.to_resp()).await?;
+    blocking::signal(destination);
+    blocking::serve_ready(&mut db).await?;
+    Ok(RespValue::Integer(1))
+}
+
+/// KEYS pattern
+#[router_macro::route("KEYS")]
+async fn keys(_context : Arc<Context>, request: RespRequest) -> anyhow::Result<RespValue> {
+    check_args(&request, "keys", 1, Some(1))?;
+    let pattern = request.args[0].as_bytes()?;
+    let now = chrono::Utc::now();
+    let db = DB.read().await;
+    let keys: Vec<Bytes> = db
+        .engine()
+        .scan()
+        .filter(|(key, entry)| !entry.is_expired(now) && glob_match(pattern, key))
+        .map(|(key, _)| key.clone())
+        .collect();
+    Ok(RespValue::bulk_array(keys))
+}
+
+/// SCAN cursor [MATCH pattern] [COUNT count] [TYPE type]
+#[router_macro::route("SCAN")]
+async fn scan(_context : Arc<Context>, request: RespRequest) -> anyhow::Result<RespValue> {
+    check_args(&request, "scan", 1, None)?;
+    let scan = ScanArgs::parse(&request.args, false, true)?;
+    let now = chrono::Utc::now();
+    let db = DB.read().await;
+    let (cursor, keys) = scan.walk(|cursor, keys| {
+        db.engine().scan_from(cursor, &mut |key, entry| keys.push((key.clone(), entry.value.type_name(), entry.is_expired(now))))
+    });
+    let keys = keys
+        .into_iter()
+        .filter(|(key, type_name, expired)| !expired && scan.matches(key) && scan.matches_type(type_name))
+        .map(|(key, _, _)| key)
+        .collect();
+    Ok(ScanArgs::reply(cursor, keys))
+}
+
+// random picks RANDOMKEY makes before giving up on a keyspace of mostly expired keys, like dbRandomKey
+const RANDOM_KEY_TRIES: usize = 100;
+
+#[router_macro::route("RANDOMKEY")]
+async fn randomkey(_context : Arc<Context>, request: RespRequest) -> anyhow::Result<RespValue> {
+    check_args(&request, "randomkey", 0, Some(0))?;
+    let now = chrono::Utc::now();
+    let db = DB.read().await;
+    let key = (0..RANDOM_KEY_TRIES)
+        .map_while(|_| db.engine().random())
+        .find(|(_, entry)| !entry.is_expired(now));
+    Ok(key.map_or_else(|| NULL_RESP.clone(), |(key, _)| RespValue::BulkString(Some(key.clone()))))
+}
+
+/// Counts the expired keys that were not removed yet, like redis does
+#[router_macro::route("DBSIZE")]
+async fn dbsize(_context : Arc<Context>, request: RespRequest) -> anyhow::Result<RespValue> {
+    check_args(&request, "dbsize", 0, Some(0))?;
+    let db = DB.read().await;
+    Ok(RespValue::Integer(db.engine().len() as i64))
+}
+
+/// FLUSHDB [ASYNC|SYNC]
+#[router_macro::route("FLUSHDB")]
+async fn flushdb(_context : Arc<Context>, request: RespRequest) -> anyhow::Result<RespValue> {
+    flush(&request, "flushdb").await
+}
+
+/// FLUSHALL [ASYNC|SYNC]
+#[router_macro::route("FLUSHALL")]
+async fn flushall(_context : Arc<Context>, request: RespRequest) -> anyhow::Result<RespValue> {
+    flush(&request, "flushall").await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::engine::Value;
+    use crate::redis_types::test_util::{bulks, context, request};
+
+    async fn put(key: &str, value: Value) {
+        DB.write().await.put(Bytes::copy_from_slice(key.as_bytes()), Entry::new(value));
+    }
+
+    #[tokio::test]
+    async fn delete_and_inspect() -> anyhow::Result<()> {
+        put("keys:a", Value::String("1".into())).await;
+        put("keys:b", Value::List(["x".into()].into())).await;
+        let mut expired = Entry::new(Value::String("old".into()));
+        expired.expire_at = Some(chrono::Utc::now() - chrono::Duration::seconds(1));
+        DB.write().await.put("keys:expired".into(), expired);
+
+        assert_eq!(exists(context(), request("EXISTS", &["keys:a", "keys:a", "keys:expired", "keys:none"])).await?, RespValue::Integer(2));
+        assert_eq!(touch(context(), request("TOUCH", &["keys:a", "keys:b"])).await?, RespValue::Integer(2));
+        assert_eq!(type_command(context(), request("TYPE", &["keys:b"])).await?, RespValue::SimpleString("list".into()));
+        assert_eq!(type_command(context(), request("TYPE", &["keys:expired"])).await?, RespValue::SimpleString("none".into()));
+        let RespValue::Array(mut found) = keys(context(), request("KEYS", &["keys:[a-z]"])).await? else {
+            panic!("KEYS replies an array");
+        };
+        found.sort_by_key(|key| key.as_bytes().unwrap().clone());
+        assert_eq!(RespValue::Array(found), bulks(&["keys:a", "keys:b"]));
+        assert_ne!(randomkey(context(), request("RANDOMKEY", &[])).await?, RespValue::BulkString(None));
+
+        assert_eq!(del(context(), request("DEL", &["keys:a", "keys:expired", "keys:none"])).await?, RespValue::Integer(1));
+        assert!(DB.read().await.engine().get(b"keys:expired").is_none());
+        assert_eq!(unlink(context(), request("UNLINK", &["keys:b", "keys:b"])).await?, RespValue::Integer(1));
+        assert_eq!(exists(context(), request("EXISTS", &["keys:a", "keys:b"])).await?, RespValue::Integer(0));
+        assert!(flushall(context(), request("FLUSHALL", &["LAZY"])).await.is_err());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn rename_and_copy() -> anyhow::Result<()> {
+        put("keys:r1", Value::String("1".into())).await;
+        put("keys:r2", Value::String("2".into())).await;
+        assert!(rename_command(context(), request("RENAME", &["keys:none", "keys:r3"])).await.is_err());
+        assert_eq!(renamenx(context(), request("RENAMENX", &["keys:r1", "keys:r2"])).await?, RespValue::Integer(0));
+        assert_eq!(renamenx(context(), request("RENAMENX", &["keys:r1", "keys:r1"])).await?, RespValue::Integer(0));
+        assert_eq!(rename_command(context(), request("RENAME", &["keys:r1", "keys:r1"])).await?, OK_RESP.clone());
+        assert_eq!(rename_command(context(), request("RENAME", &["keys:r1", "keys:r2"])).await?, OK_RESP.clone());
+        assert!(DB.read().await.get(b"keys:r1").is_none());
+        assert_eq!(DB.read().await.get(b"keys:r2").unwrap().value, Value::String("1".into()));
+
+        assert_eq!(copy(context(), request("COPY", &["keys:r2", "keys:c"])).await?, RespValue::Integer(1));
+        put("keys:r2", Value::String("3".into())).await;
+        assert_eq!(copy(context(), request("COPY", &["keys:r2", "keys:c"])).await?, RespValue::Integer(0));
+        assert_eq!(copy(context(), request("COPY", &["keys:r2", "keys:c", "DB", "0", "REPLACE"])).await?, RespValue::Integer(1));
+        assert_eq!(DB.read().await.get(b"keys:c").unwrap().value, Value::String("3".into()));
+        assert!(copy(context(), request("COPY", &["keys:r2", "keys:c", "DB", "1"])).await.is_err());
+        assert!(copy(context(), request("COPY", &["keys:c", "keys:c"])).await.is_err());
+        assert_eq!(copy(context(), request("COPY", &["keys:none", "keys:d"])).await?, RespValue::Integer(0));
+        assert_eq!(type_command(context(), request("TYPE", &["keys:c"])).await?, RespValue::SimpleString("string".into()));
+        Ok(())
+    }
+    #[tokio::test]
+    async fn scan_pages() -> anyhow::Result<()> {
+        for i in 0..200 {
+            let value = if i % 2 == 0 { Value::String("v".into()) } else { Value::List(["x".into()].into()) };
+            put(&format!("keys:scan:{}", i), value).await;
+        }
+        let mut strings = std::collections::HashSet::new();
+        let mut cursor = "0".to_string();
+        loop {
+            let reply = scan(context(), request("SCAN", &[&cursor, "MATCH", "keys:scan:*", "COUNT", "50", "TYPE", "STRING"])).await?;
+            let RespValue::Array(reply) = reply else { panic!() };
+            let RespValue::Array(keys) = &reply[1] else { panic!() };
+            strings.extend(keys.iter().map(|key| key.as_bytes().unwrap().clone()));
+            cursor = reply[0].as_str()?.to_string();
+            if cursor == "0" {
+                break;
+            }
+        }
+        assert_eq!(strings.len(), 100);
+        assert!(strings.iter().all(|key| key.ends_with(b"0") || key.ends_with(b"2") || key.ends_with(b"4") || key.ends_with(b"6") || key.ends_with(b"8")));
+        assert!(scan(context(), request("SCAN", &["-1"])).await.is_err());
+        assert!(scan(context(), request("SCAN", &["0", "COUNT", "0"])).await.is_err());
+        Ok(())
+    }
+}
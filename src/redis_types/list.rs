@@ -0,0 +1,604 @@
+use std::collections::VecDeque;
+use std::sync::Arc;
+use bytes::Bytes;
+use crate::engine::{Database, Entry, Value, DB};
+use super::blocking::{self, Served};
+use super::{arg_i64, check_args};
+use super::string::clamp_range;
+use crate::error::*;
+use crate::parser::{NULL_RESP, OK_RESP};
+use crate::{context::Context, parser::{RespRequest, RespValue}};
+use crate::command_table::{RouteHandler, ROUTE_MAP};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum End {
+    Left,
+    Right,
+}
+
+impl End {
+    pub(crate) fn parse(arg: &RespValue) -> Result<End> {
+        match arg.as_str()?.to_ascii_uppercase().as_str() {
+            "LEFT" => Ok(End::Left),
+            "RIGHT" => Ok(End::Right),
+            _ => Err(Error::Syntax),
+        }
+    }
+}
+
+pub(crate) fn get_list<'a>(db: &'a Database, key: &[u8]) -> Result<Option<&'a VecDeque<Bytes>>> {
+    db.get(key).map(|entry| entry.as_list()).transpose()
+}
+
+pub(crate) fn get_list_mut<'a>(db: &'a mut Database, key: &[u8]) -> Result<Option<&'a mut VecDeque<Bytes>>> {
+    db.get_mut(key).map(|entry| entry.as_list_mut()).transpose()
+}
+
+/// Pushes `items` one after the other at `end` of the list, creating it if needed; returns the new length
+pub(crate) fn push(db: &mut Database, key: &Bytes, items: impl IntoIterator<Item = Bytes>, end: End) -> Result<usize> {
+    if db.get(key).is_none() {
+        db.put(key.clone(), Entry::new(Value::List(VecDeque::new())));
+    }
+    let list = get_list_mut(db, key)?.expect("list was just created");
+    for item in items {
+        match end {
+            End::Left => list.push_front(item),
+            End::Right => list.push_back(item),
+        }
+    }
+    let len = list.len();
+    blocking::signal(key);
+    Ok(len)
+}
+
+/// Pops up to `count` items from `end` of the list, deleting the key once the list is empty
+pub(crate) fn pop(db: &mut Database, key: &[u8], count: usize, end: End) -> Result<Option<Vec<Bytes>>> {
+    let Some(list) = get_list_mut(db, key)? else {
+        return Ok(None);
+    };
+    let count = count.min(list.len());
+    let items = match end {
+        End::Left => list.drain(..count).collect(),
+        End::Right => list.drain(list.len() - count..).rev().collect(),
+    };
+    if list.is_empty() {
+        db.delete(key);
+    }
+    Ok(Some(items))
+}
+
+async fn push_command(request: &RespRequest, name: &str, end: End, only_existing: bool) -> anyhow::Result<RespValue> {
+    check_args(request, name, 2, None)?;
+    let key = request.args[0].as_bytes()?;
+    let items = request.args[1..].iter().map(|arg| arg.as_bytes().cloned()).collect::<anyhow::Result<Vec<_>>>()?;
+
+    let mut db = DB.write().await;
+    if get_list(&db, key)?.is_none() && only_existing {
+        return Ok(RespValue::Integer(0));
+    }
+    let len = push(&mut db, key, items, end)?;
+    db.append_log(&request.to_resp()).await?;
+    blocking::serve_ready(&mut db).await?;
+    Ok(RespValue::Integer(len as i64))
+}
+
+#[router_macro::route("LPUSH")]
+async fn lpush(_context : Arc<Context>, request: RespRequest) -> anyhow::Result<RespValue> {
+    push_command(&request, "lpush", End::Left, false).await
+}
+
+#[router_macro::route("RPUSH")]
+async fn rpush(_context : Arc<Context>, request: RespRequest) -> anyhow::Result<RespValue> {
+    push_command(&request, "rpush", End::Right, false).await
+}
+
+#[router_macro::route("LPUSHX")]
+async fn lpushx(_context : Arc<Context>, request: RespRequest) -> anyhow::Result<RespValue> {
+    push_command(&request, "lpushx", End::Left, true).await
+}
+
+#[router_macro::route("RPUSHX")]
+async fn rpushx(_context : Arc<Context>, request: RespRequest) -> anyhow::Result<RespValue> {
+    push_command(&request, "rpushx", End::Right, true).await
+}
+
+/// LPOP/RPOP key [count]
+async fn pop_command(request: &RespRequest, name: &str, end: End) -> anyhow::Result<RespValue> {
+    check_args(request, name, 1, Some(2))?;
+    let key = request.args[0].as_bytes()?;
+    let count = match request.args.get(1) {
+        Some(arg) => {
+            let count = arg_i64(arg)?;
+            if count < 0 {
+                return Err(Error::Other("value is out of range, must be positive".into()).into());
+            }
+            Some(count as usize)
+        }
+        None => None,
+    };
+
+    let mut db = DB.write().await;
+    let Some(items) = pop(&mut db, key, count.unwrap_or(1), end)? else {
+        return Ok(if count.is_some() { RespValue::NullArray } else { NULL_RESP.clone() });
+    };
+    if !items.is_empty() {
+        db.append_log(&request.to_resp()).await?;
+    }
+    Ok(match count {
+        Some(_) => RespValue::bulk_array(items),
+        None => RespValue::BulkString(items.into_iter().next()),
+    })
+}
+
+#[router_macro::route("LPOP")]
+async fn lpop(_context : Arc<Context>, request: RespRequest) -> anyhow::Result<RespValue> {
+    pop_command(&request, "lpop", End::Left).await
+}
+
+#[router_macro::route("RPOP")]
+async fn rpop(_context : Arc<Context>, request: RespRequest) -> anyhow::Result<RespValue> {
+    pop_command(&request, "rpop", End::Right).await
+}
+
+#[router_macro::route("LLEN")]
+async fn llen(_context : Arc<Context>, request: RespRequest) -> anyhow::Result<RespValue> {
+    check_args(&request, "llen", 1, Some(1))?;
+    let db = DB.read().await;
+    let len = get_list(&db, request.args[0].as_bytes()?)?.map(VecDeque::len).unwrap_or(0);
+    Ok(RespValue::Integer(len as i64))
+}
+
+#[router_macro::route("LRANGE")]
+async fn lrange(_context : Arc<Context>, request: RespRequest) -> anyhow::Result<RespValue> {
+    check_args(&request, "lrange", 3, Some(3))?;
+    let start = arg_i64(&request.args[1])?;
+    let stop = arg_i64(&request.args[2])?;
+
+    let db = DB.read().await;
+    let Some(list) = get_list(&db, request.args[0].as_bytes()?)? else {
+        return Ok(RespValue::Array(vec![]));
+    };
+    let range = clamp_range(start, stop, list.len()).unwrap_or(0..0);
+    Ok(RespValue::bulk_array(list.range(range).cloned()))
+}
+
+/// Resolves a possibly negative index into the position of an existing element
+fn resolve_index(index: i64, len: usize) -> Option<usize> {
+    let index = if index < 0 { len as i64 + index } else { index };
+    (0..len as i64).contains(&index).then_some(index as usize)
+}
+
+#[router_macro::route("LINDEX")]
+async fn lindex(_context : Arc<Context>, request: RespRequest) -> anyhow::Result<RespValue> {
+    check_args(&request, "lindex", 2, Some(2))?;
+    let index = arg_i64(&request.args[1])?;
+
+    let db = DB.read().await;
+    let item = get_list(&db, request.args[0].as_bytes()?)?
+        .and_then(|list| resolve_index(index, list.len()).map(|i| list[i].clone()));
+    Ok(RespValue::BulkString(item))
+}
+
+#[router_macro::route("LSET")]
+async fn lset(_context : Arc<Context>, request: RespRequest) -> anyhow::Result<RespValue> {
+    check_args(&request, "lset", 3, Some(3))?;
+    let key = request.args[0].as_bytes()?;
+    let index = arg_i64(&request.args[1])?;
+    let item = request.args[2].as_bytes()?;
+
+    let mut db = DB.write().await;
+    let list = get_list_mut(&mut db, key)?.ok_or_else(|| Error::Other("no such key".into()))?;
+    let index = resolve_index(index, list.len()).ok_or_else(|| Error::Other("index out of range".into()))?;
+    list[index] = item.clone();
+    db.append_log(&request.to_resp()).await?;
+    Ok(OK_RESP.clone())
+}
+
+/// LINSERT key BEFORE|AFTER pivot element
+#[router_macro::route("LINSERT")]
+async fn linsert(_context : Arc<Context>, request: RespRequest) -> anyhow::Result<RespValue> {
+    check_args(&request, "linsert", 4, Some(4))?;
+    let key = request.args[0].as_bytes()?;
+    let after = match request.args[1].as_str()?.to_ascii_uppercase().as_str() {
+        "BEFORE" => false,
+        "AFTER" => true,
+        _ => return Err(Error::Syntax.into()),
+    };
+    let pivot = request.args[2].as_bytes()?;
+    let item = request.args[3].as_bytes()?;
+
+    let mut db = DB.write().await;
+    let Some(list) = get_list_mut(&mut db, key)? else {
+        return Ok(RespValue::Integer(0));
+    };
+    let Some(position) = list.iter().position(|x| x == pivot) else {
+        return Ok(RespValue::Integer(-1));
+    };
+    list.insert(position + after as usize, item.clone());
+    let len = list.len();
+    db.append_log(&request.to_resp()).await?;
+    Ok(RespValue::Integer(len as i64))
+}
+
+/// LREM key count element: removes the first `count` occurrences, the last ones if negative, all of them if 0
+#[router_macro::route("LREM")]
+async fn lrem(_context : Arc<Context>, request: RespRequest) -> anyhow::Result<RespValue> {
+    check_args(&request, "lrem", 3, Some(3))?;
+    let key = request.args[0].as_bytes()?;
+    let count = arg_i64(&request.args[1])?;
+    let item = request.args[2].as_bytes()?;
+
+    let mut db = DB.write().await;
+    let Some(list) = get_list_mut(&mut db, key)? else {
+        return Ok(RespValue::Integer(0));
+    };
+    let limit = if count == 0 { usize::MAX } else { count.unsigned_abs() as usize };
+    let mut positions: Vec<usize> = if count < 0 {
+        list.iter().enumerate().rev().filter(|(_, x)| *x == item).map(|(i, _)| i).take(limit).collect()
+    } else {
+        list.iter().enumerate().filter(|(_, x)| *x == item).map(|(i, _)| i).take(limit).collect()
+    };
+    positions.sort_unstable();
+    let mut removed = positions.iter().peekable();
+    let mut index = 0;
+    list.retain(|_| {
+        let keep = removed.next_if_eq(&&index).is_none();
+        index += 1;
+        keep
+    });
+
+    if list.is_empty() {
+        db.delete(key);
+    }
+    if !positions.is_empty() {
+        db.append_log(&request.to_resp()).await?;
+    }
+    Ok(RespValue::Integer(positions.len() as i64))
+}
+
+#[router_macro::route("LTRIM")]
+async fn ltrim(_context : Arc<Context>, request: RespRequest) -> anyhow::Result<RespValue> {
+    check_args(&request, "ltrim", 3, Some(3))?;
+    let key = request.args[0].as_bytes()?;
+    let start = arg_i64(&request.args[1])?;
+    let stop = arg_i64(&request.args[2])?;
+
+    let mut db = DB.write().await;
+    let Some(list) = get_list_mut(&mut db, key)? else {
+        return Ok(OK_RESP.clone());
+    };
+    match clamp_range(start, stop, list.len()) {
+        Some(range) => {
+            list.truncate(range.end);
+            list.drain(..range.start);
+        }
+        None => list.clear(),
+    }
+    if list.is_empty() {
+        db.delete(key);
+    }
+    db.append_log(&request.to_resp()).await?;
+    Ok(OK_RESP.clone())
+}
+
+/// LPOS key element [RANK rank] [COUNT num-matches] [MAXLEN len]
+#[router_macro::route("LPOS")]
+async fn lpos(_context : Arc<Context>, request: RespRequest) -> anyhow::Result<RespValue> {
+    check_args(&request, "lpos", 2, None)?;
+    let key = request.args[0].as_bytes()?;
+    let item = request.args[1].as_bytes()?;
+
+    let (mut rank, mut count, mut max_len) = (1i64, None, 0usize);
+    let mut args = request.args[2..].iter();
+    while let Some(arg) = args.next() {
+        let option = arg.as_str()?.to_ascii_uppercase();
+        let value = arg_i64(args.next().ok_or(Error::Syntax)?)?;
+        match option.as_str() {
+            "RANK" => {
+                if value == 0 || value == i64::MIN {
+                    return Err(Error::Other("RANK can't be zero: use 1 to start from the first match, 2 from the second ... or use negative to start from the last match".into()).into());
+                }
+                rank = value;
+            }
+            "COUNT" => {
+                if value < 0 {
+                    return Err(Error::Other("COUNT can't be negative".into()).into());
+                }
+                count = Some(value as usize);
+            }
+            "MAXLEN" => {
+                if value < 0 {
+                    return Err(Error::Other("MAXLEN can't be negative".into()).into());
+                }
+                max_len = value as usize;
+            }
+            _ => return Err(Error::Syntax.into()),
+        }
+    }
+
+    let db = DB.read().await;
+    let list = get_list(&db, key)?;
+    let list = list.map(|list| list.iter().enumerate()).into_iter().flatten();
+    let scanned: Box<dyn Iterator<Item = (usize, &Bytes)>> = if rank < 0 { Box::new(list.rev()) } else { Box::new(list) };
+    let limit = if max_len == 0 { usize::MAX } else { max_len };
+    let mut matches = scanned
+        .take(limit)
+        .filter(|(_, x)| *x == item)
+        .skip(rank.unsigned_abs() as usize - 1)
+        .map(|(i, _)| RespValue::Integer(i as i64));
+
+    Ok(match count {
+        None => matches.next().unwrap_or(RespValue::BulkString(None)),
+        Some(0) => RespValue::Array(matches.collect()),
+        Some(count) => RespValue::Array(matches.take(count).collect()),
+    })
+}
+
+/// Pops from `end_from` of `source` and pushes to `end_to` of `destination`, both type checked before anything moves
+pub(crate) fn move_item(db: &mut Database, source: &Bytes, destination: &Bytes, end_from: End, end_to: End) -> Result<Option<Bytes>> {
+    if get_list(db, source)?.is_none_or(VecDeque::is_empty) {
+        return Ok(None);
+    }
+    get_list(db, destination)?;
+
+    let item = pop(db, source, 1, end_from)?.and_then(|items| items.into_iter().next());
+    if let Some(item) = &item {
+        push(db, destination, [item.clone()], end_to)?;
+    }
+    Ok(item)
+}
+
+/// LMOVE source destination LEFT|RIGHT LEFT|RIGHT
+#[router_macro::route("LMOVE")]
+async fn lmove(_context : Arc<Context>, request: RespRequest) -> anyhow::Result<RespValue> {
+    check_args(&request, "lmove", 4, Some(4))?;
+    let source = request.args[0].as_bytes()?;
+    let destination = request.args[1].as_bytes()?;
+    let end_from = End::parse(&request.args[2])?;
+    let end_to = End::parse(&request.args[3])?;
+
+    let mut db = DB.write().await;
+    let item = move_item(&mut db, source, destination, end_from, end_to)?;
+    if item.is_some() {
+        db.append_log(&request.to_resp()).await?;
+        blocking::serve_ready(&mut db).await?;
+    }
+    Ok(RespValue::BulkString(item))
+}
+
+fn end_name(end: End) -> Bytes {
+    match end {
+        End::Left => "LEFT".into(),
+        End::Right => "RIGHT".into(),
+    }
+}
+
+/// BLPOP/BRPOP key [key ...] timeout, logged as the LPOP/RPOP that served it
+async fn blocking_pop(context: Arc<Context>, request: &RespRequest, name: &str, end: End) -> anyhow::Result<RespValue> {
+    check_args(request, name, 2, None)?;
+    let (timeout, keys) = request.args.split_last().expect("checked above");
+    let keys = keys.iter().map(|key| key.as_bytes().cloned()).collect::<anyhow::Result<Vec<_>>>()?;
+
+    let command = match end {
+        End::Left => Bytes::from("LPOP"),
+        End::Right => Bytes::from("RPOP"),
+    };
+    let serve = move |db: &mut Database, key: &Bytes| -> Result<Option<Served>> {
+        let item = pop(db, key, 1, end)?.and_then(|items| items.into_iter().next());
+        Ok(item.map(|item| Served {
+            reply: RespValue::bulk_array([key.clone(), item]),
+            records: vec![RespValue::bulk_array([command.clone(), key.clone()])],
+        }))
+    };
+    let reply = blocking::serve_or_block(context, keys, blocking::parse_timeout(timeout)?, Box::new(serve)).await?;
+    Ok(reply.unwrap_or(RespValue::NullArray))
+}
+
+#[router_macro::route("BLPOP")]
+async fn blpop(context : Arc<Context>, request: RespRequest) -> anyhow::Result<RespValue> {
+    blocking_pop(context, &request, "blpop", End::Left).await
+}
+
+#[router_macro::route("BRPOP")]
+async fn brpop(context : Arc<Context>, request: RespRequest) -> anyhow::Result<RespValue> {
+    blocking_pop(context, &request, "brpop", End::Right).await
+}
+
+/// BLMOVE source destination LEFT|RIGHT LEFT|RIGHT timeout, logged as the LMOVE that served it
+#[router_macro::route("BLMOVE")]
+async fn blmove(context : Arc<Context>, request: RespRequest) -> anyhow::Result<RespValue> {
+    check_args(&request, "blmove", 5, Some(5))?;
+    let source = request.args[0].as_bytes()?.clone();
+    let destination = request.args[1].as_bytes()?.clone();
+    let end_from = End::parse(&request.args[2])?;
+    let end_to = End::parse(&request.args[3])?;
+
+    let serve = move |db: &mut Database, key: &Bytes| -> Result<Option<Served>> {
+        let item = move_item(db, key, &destination, end_from, end_to)?;
+        Ok(item.map(|item| Served {
+            reply: RespValue::BulkString(Some(item)),
+            records: vec![RespValue::bulk_array(["LMOVE".into(), key.clone(), destination.clone(), end_name(end_from), end_name(end_to)])],
+        }))
+    };
+    let reply = blocking::serve_or_block(context, vec![source], blocking::parse_timeout(&request.args[4])?, Box::new(serve)).await?;
+    // a timed out BLMOVE replies with a nil bulk string, unlike BLPOP
+    Ok(reply.unwrap_or_else(|| NULL_RESP.clone()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::redis_types::test_util::{bulk, bulks, context, request};
+
+    async fn range(key: &str) -> anyhow::Result<RespValue> {
+        lrange(context(), request("LRANGE", &[key, "0", "-1"])).await
+    }
+
+    #[tokio::test]
+    async fn push_pop_and_range() -> anyhow::Result<()> {
+        assert_eq!(rpush(context(), request("RPUSH", &["list:l1", "a", "b", "c"])).await?, RespValue::Integer(3));
+        assert_eq!(lpush(context(), request("LPUSH", &["list:l1", "y", "z"])).await?, RespValue::Integer(5));
+        assert_eq!(range("list:l1").await?, bulks(&["z", "y", "a", "b", "c"]));
+        assert_eq!(lrange(context(), request("LRANGE", &["list:l1", "-2", "100"])).await?, bulks(&["b", "c"]));
+        assert_eq!(lrange(context(), request("LRANGE", &["list:l1", "3", "1"])).await?, bulks(&[]));
+
+        assert_eq!(lpop(context(), request("LPOP", &["list:l1"])).await?, bulk("z"));
+        assert_eq!(rpop(context(), request("RPOP", &["list:l1", "2"])).await?, bulks(&["c", "b"]));
+        assert_eq!(lpop(context(), request("LPOP", &["list:l1", "10"])).await?, bulks(&["y", "a"]));
+        assert_eq!(llen(context(), request("LLEN", &["list:l1"])).await?, RespValue::Integer(0));
+        assert!(DB.read().await.get(b"list:l1").is_none());
+        assert_eq!(lpop(context(), request("LPOP", &["list:l1"])).await?, *NULL_RESP);
+        assert_eq!(lpop(context(), request("LPOP", &["list:l1", "2"])).await?, RespValue::NullArray);
+
+        assert_eq!(lpushx(context(), request("LPUSHX", &["list:l1", "a"])).await?, RespValue::Integer(0));
+        assert!(DB.read().await.get(b"list:l1").is_none());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn wrong_type() -> anyhow::Result<()> {
+        DB.write().await.put("list:string".into(), Entry::new(Value::String("v".into())));
+        for (command, args) in [("LPUSH", vec!["list:string", "a"]), ("LRANGE", vec!["list:string", "0", "-1"])] {
+            let err = match command {
+                "LPUSH" => lpush(context(), request(command, &args)).await,
+                _ => lrange(context(), request(command, &args)).await,
+            }.unwrap_err();
+            assert!(matches!(err.downcast_ref::<Error>(), Some(Error::WrongType)));
+        }
+        rpush(context(), request("RPUSH", &["list:src", "a"])).await?;
+        assert!(lmove(context(), request("LMOVE", &["list:src", "list:string", "LEFT", "LEFT"])).await.is_err());
+        assert_eq!(range("list:src").await?, bulks(&["a"]));
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn index_set_and_insert() -> anyhow::Result<()> {
+        rpush(context(), request("RPUSH", &["list:l2", "a", "b", "c"])).await?;
+        assert_eq!(lindex(context(), request("LINDEX", &["list:l2", "-1"])).await?, bulk("c"));
+        assert_eq!(lindex(context(), request("LINDEX", &["list:l2", "3"])).await?, *NULL_RESP);
+        assert_eq!(lset(context(), request("LSET", &["list:l2", "1", "B"])).await?, *OK_RESP);
+        assert!(lset(context(), request("LSET", &["list:l2", "5", "x"])).await.is_err());
+        assert!(lset(context(), request("LSET", &["list:missing", "0", "x"])).await.is_err());
+
+        assert_eq!(linsert(context(), request("LINSERT", &["list:l2", "BEFORE", "B", "x"])).await?, RespValue::Integer(4));
+        assert_eq!(linsert(context(), request("LINSERT", &["list:l2", "after", "c", "y"])).await?, RespValue::Integer(5));
+        assert_eq!(linsert(context(), request("LINSERT", &["list:l2", "AFTER", "nope", "y"])).await?, RespValue::Integer(-1));
+        assert_eq!(linsert(context(), request("LINSERT", &["list:missing", "AFTER", "a", "y"])).await?, RespValue::Integer(0));
+        assert_eq!(range("list:l2").await?, bulks(&["a", "x", "B", "c", "y"]));
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn remove_and_trim() -> anyhow::Result<()> {
+        rpush(context(), request("RPUSH", &["list:l3", "a", "b", "a", "c", "a", "b"])).await?;
+        assert_eq!(lrem(context(), request("LREM", &["list:l3", "-2", "a"])).await?, RespValue::Integer(2));
+        assert_eq!(range("list:l3").await?, bulks(&["a", "b", "c", "b"]));
+        assert_eq!(lrem(context(), request("LREM", &["list:l3", "1", "b"])).await?, RespValue::Integer(1));
+        assert_eq!(lrem(context(), request("LREM", &["list:l3", "0", "x"])).await?, RespValue::Integer(0));
+        assert_eq!(range("list:l3").await?, bulks(&["a", "c", "b"]));
+
+        assert_eq!(ltrim(context(), request("LTRIM", &["list:l3", "1", "-1"])).await?, *OK_RESP);
+        assert_eq!(range("list:l3").await?, bulks(&["c", "b"]));
+        ltrim(context(), request("LTRIM", &["list:l3", "5", "10"])).await?;
+        assert!(DB.read().await.get(b"list:l3").is_none());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn positions() -> anyhow::Result<()> {
+        rpush(context(), request("RPUSH", &["list:l4", "a", "b", "c", "1", "2", "3", "c", "c"])).await?;
+        let lpos_of = |args: &[&str]| {
+            let mut full = vec!["list:l4"];
+            full.extend_from_slice(args);
+            lpos(context(), request("LPOS", &full))
+        };
+        assert_eq!(lpos_of(&["c"]).await?, RespValue::Integer(2));
+        assert_eq!(lpos_of(&["c", "RANK", "2"]).await?, RespValue::Integer(6));
+        assert_eq!(lpos_of(&["c", "RANK", "-1"]).await?, RespValue::Integer(7));
+        assert_eq!(lpos_of(&["c", "COUNT", "2"]).await?, RespValue::Array(vec![RespValue::Integer(2), RespValue::Integer(6)]));
+        assert_eq!(lpos_of(&["c", "COUNT", "0", "RANK", "-2"]).await?, RespValue::Array(vec![RespValue::Integer(6), RespValue::Integer(2)]));
+        assert_eq!(lpos_of(&["c", "COUNT", "0", "MAXLEN", "3"]).await?, RespValue::Array(vec![RespValue::Integer(2)]));
+        assert_eq!(lpos_of(&["x"]).await?, *NULL_RESP);
+        assert!(lpos_of(&["c", "RANK", "0"]).await.is_err());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn move_between_lists() -> anyhow::Result<()> {
+        rpush(context(), request("RPUSH", &["list:from", "a", "b", "c"])).await?;
+        assert_eq!(lmove(context(), request("LMOVE", &["list:from", "list:to", "RIGHT", "LEFT"])).await?, bulk("c"));
+        assert_eq!(lmove(context(), request("LMOVE", &["list:from", "list:to", "LEFT", "RIGHT"])).await?, bulk("a"));
+        assert_eq!(range("list:to").await?, bulks(&["c", "a"]));
+        // rotating a list onto itself
+        assert_eq!(lmove(context(), request("LMOVE", &["list:to", "list:to", "LEFT", "RIGHT"])).await?, bulk("c"));
+        assert_eq!(range("list:to").await?, bulks(&["a", "c"]));
+        assert_eq!(lmove(context(), request("LMOVE", &["list:none", "list:to", "LEFT", "RIGHT"])).await?, *NULL_RESP);
+        assert!(lmove(context(), request("LMOVE", &["list:from", "list:to", "UP", "RIGHT"])).await.is_err());
+        Ok(())
+    }
+
+    fn spawn_blocking(command: &'static str, args: &'static [&'static str]) -> tokio::task::JoinHandle<anyhow::Result<RespValue>> {
+        tokio::spawn(async move {
+            let handler = crate::command_table::get_handler(command)?;
+            handler(context(), request(command, args)).await
+        })
+    }
+
+    async fn settle() {
+        tokio::time::sleep(std::time::Duration::from_millis(20)).await;
+    }
+
+    #[tokio::test]
+    async fn blocking_pop_is_woken_by_push() -> anyhow::Result<()> {
+        rpush(context(), request("RPUSH", &["list:b1", "ready"])).await?;
+        assert_eq!(blpop(context(), request("BLPOP", &["list:b0", "list:b1", "0"])).await?, bulks(&["list:b1", "ready"]));
+
+        let waiter = spawn_blocking("BRPOP", &["list:b0", "list:b1", "0"]);
+        settle().await;
+        assert!(!waiter.is_finished());
+        rpush(context(), request("RPUSH", &["list:b1", "a", "b"])).await?;
+        assert_eq!(waiter.await??, bulks(&["list:b1", "b"]));
+        assert_eq!(range("list:b1").await?, bulks(&["a"]));
+
+        let reply = blpop(context(), request("BLPOP", &["list:b0", "0.05"])).await?;
+        assert_eq!(reply, RespValue::NullArray);
+        assert!(blpop(context(), request("BLPOP", &["list:b0", "-1"])).await.is_err());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn waiters_are_served_in_order() -> anyhow::Result<()> {
+        let first = spawn_blocking("BLPOP", &["list:f1", "list:f2", "0"]);
+        settle().await;
+        let second = spawn_blocking("BLPOP", &["list:f2", "0"]);
+        settle().await;
+        let third = spawn_blocking("BLPOP", &["list:f2", "list:f1", "0"]);
+        settle().await;
+
+        rpush(context(), request("RPUSH", &["list:f2", "a", "b"])).await?;
+        assert_eq!(first.await??, bulks(&["list:f2", "a"]));
+        assert_eq!(second.await??, bulks(&["list:f2", "b"]));
+        assert!(!third.is_finished());
+        lpush(context(), request("LPUSH", &["list:f1", "c"])).await?;
+        assert_eq!(third.await??, bulks(&["list:f1", "c"]));
+        assert!(DB.read().await.get(b"list:f1").is_none());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn blocking_move_feeds_other_waiters() -> anyhow::Result<()> {
+        let mover = spawn_blocking("BLMOVE", &["list:m1", "list:m2", "LEFT", "RIGHT", "0"]);
+        settle().await;
+        let popper = spawn_blocking("BRPOP", &["list:m2", "0"]);
+        settle().await;
+
+        rpush(context(), request("RPUSH", &["list:m1", "x"])).await?;
+        assert_eq!(mover.await??, bulk("x"));
+        assert_eq!(popper.await??, bulks(&["list:m2", "x"]));
+        assert!(DB.read().await.get(b"list:m2").is_none());
+
+        let reply = blmove(context(), request("BLMOVE", &["list:m1", "list:m2", "LEFT", "RIGHT", "0.01"])).await?;
+        assert_eq!(reply, *NULL_RESP);
+        Ok(())
+    }
+}
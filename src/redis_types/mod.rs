@@ -1,9 +1,160 @@
 use std::sync::Arc;
 use anyhow::Result;
-use crate::{context::Context, parser::{RespRequest, RespValue}, utils::get_built_info};
+use bytes::Bytes;
+use crate::{context::Context, error::Error, parser::{RespRequest, RespValue}, utils::{get_built_info, glob_match}};
+mod blocking;
+mod expire;
+mod geo;
+mod geohash;
+mod hash;
+mod hyperloglog;
+mod keys;
+mod list;
+mod set;
+mod stream;
 mod string;
+mod zset;
 use crate::command_table::{RouteHandler, ROUTE_MAP};
 
+/// Fails with the redis arity error unless the command got between `min` and `max` arguments
+pub(crate) fn check_args(request: &RespRequest, name: &str, min: usize, max: Option<usize>) -> crate::error::Result<()> {
+    let count = request.args.len();
+    if count < min || max.is_some_and(|max| count > max) {
+        return Err(Error::WrongArgNumber(name.into()));
+    }
+    Ok(())
+}
+
+/// Parses an integer as strictly as redis does: no '+', no spaces and no leading zeros
+pub(crate) fn parse_i64(bytes: &[u8]) -> crate::error::Result<i64> {
+    let digits = bytes.strip_prefix(b"-").unwrap_or(bytes);
+    let strict = !digits.is_empty() && (digits == b"0" || digits[0] != b'0') && digits.iter().all(u8::is_ascii_digit);
+    // an invalid input still goes through str::parse, so that the error carries a ParseIntError
+    let s = std::str::from_utf8(bytes).ok().filter(|_| strict).unwrap_or("-");
+    Ok(s.parse::<i64>()?)
+}
+
+pub(crate) fn parse_f64(bytes: &[u8]) -> crate::error::Result<f64> {
+    let s = std::str::from_utf8(bytes).map_err(|_| Error::InvalidFloat)?;
+    if s.is_empty() || s.trim() != s {
+        return Err(Error::InvalidFloat);
+    }
+    match s.parse::<f64>() {
+        Ok(f) if !f.is_nan() => Ok(f),
+        _ => Err(Error::InvalidFloat),
+    }
+}
+
+pub(crate) fn arg_i64(arg: &RespValue) -> crate::error::Result<i64> {
+    parse_i64(arg.as_bytes()?)
+}
+
+pub(crate) fn arg_f64(arg: &RespValue) -> crate::error::Result<f64> {
+    parse_f64(arg.as_bytes()?)
+}
+
+/// Formats a float the way redis replies with it, like `%.17g`: 17 significant digits without the trailing
+/// zeros, in exponent form below 1e-4 and from 1e17 on, e.g. "3" rather than "3.0" and "1e+21"
+pub(crate) fn format_f64(f: f64) -> String {
+    if f.is_infinite() {
+        return if f > 0.0 { "inf".into() } else { "-inf".into() };
+    }
+    // rounded to 17 digits first, since the rounding may carry into the exponent that picks the form
+    let scientific = format!("{:.16e}", f);
+    let (mantissa, exponent) = scientific.split_once('e').expect("exponent form");
+    let exponent: i32 = exponent.parse().expect("integer exponent");
+    if !(-4..17).contains(&exponent) {
+        let sign = if exponent < 0 { '-' } else { '+' };
+        return format!("{}e{}{:02}", trim_fraction(mantissa), sign, exponent.abs());
+    }
+    trim_fraction(&format!("{:.*}", (16 - exponent) as usize, f)).into()
+}
+
+fn trim_fraction(digits: &str) -> &str {
+    match digits.contains('.') {
+        true => digits.trim_end_matches('0').trim_end_matches('.'),
+        false => digits,
+    }
+}
+
+// replies are built whole before they are sent, which bounds the elements a negative count may repeat
+const MAX_REPEATED_PICKS: i64 = 16 * 1024 * 1024;
+
+/// How many elements the negative `count` of HRANDFIELD or SRANDMEMBER picks, the same one possibly several times
+pub(crate) fn repeated_picks(count: i64) -> crate::error::Result<usize> {
+    count
+        .checked_neg()
+        .filter(|count| *count <= MAX_REPEATED_PICKS)
+        .map(|count| count as usize)
+        .ok_or_else(|| Error::Other("value is out of range".into()))
+}
+
+/// Arguments of the SCAN family: `cursor [MATCH pattern] [COUNT count]`, NOVALUES for HSCAN and TYPE for SCAN
+pub(crate) struct ScanArgs {
+    pub cursor: u64,
+    pub pattern: Option<Bytes>,
+    pub count: usize,
+    pub no_values: bool,
+    pub type_name: Option<Bytes>,
+}
+
+impl ScanArgs {
+    pub(crate) fn parse(args: &[RespValue], allow_no_values: bool, allow_type: bool) -> crate::error::Result<ScanArgs> {
+        let cursor = args
+            .first()
+            .and_then(|arg| arg.as_str().ok()?.parse::<u64>().ok())
+            .ok_or_else(|| Error::Other("invalid cursor".into()))?;
+        let mut scan = ScanArgs { cursor, pattern: None, count: 10, no_values: false, type_name: None };
+
+        let mut args = args[1..].iter();
+        while let Some(arg) = args.next() {
+            match arg.as_str()?.to_ascii_uppercase().as_str() {
+                "MATCH" => scan.pattern = Some(args.next().ok_or(Error::Syntax)?.as_bytes()?.clone()),
+                "COUNT" => {
+                    let count = arg_i64(args.next().ok_or(Error::Syntax)?)?;
+                    if count < 1 {
+                        return Err(Error::Syntax);
+                    }
+                    scan.count = count as usize;
+                }
+                "NOVALUES" if allow_no_values => scan.no_values = true,
+                "TYPE" if allow_type => scan.type_name = Some(args.next().ok_or(Error::Syntax)?.as_bytes()?.clone()),
+                _ => return Err(Error::Syntax),
+            }
+        }
+        Ok(scan)
+    }
+
+    pub(crate) fn matches(&self, item: &[u8]) -> bool {
+        self.pattern.as_ref().is_none_or(|pattern| glob_match(pattern, item))
+    }
+
+    pub(crate) fn matches_type(&self, type_name: &str) -> bool {
+        self.type_name.as_ref().is_none_or(|wanted| wanted.eq_ignore_ascii_case(type_name.as_bytes()))
+    }
+
+    /// Calls `step` from the cursor on, each call visiting one bucket and returning the next cursor, until COUNT
+    /// items came back, ten times COUNT buckets were visited or the iteration is over. Like redis, the caller
+    /// filters the items afterwards, so a page may come back empty even though the cursor is not 0 yet.
+    pub(crate) fn walk<T>(&self, mut step: impl FnMut(u64, &mut Vec<T>) -> u64) -> (u64, Vec<T>) {
+        let mut items = vec![];
+        let mut cursor = self.cursor;
+        let mut steps = self.count.saturating_mul(10);
+        loop {
+            cursor = step(cursor, &mut items);
+            steps -= 1;
+            if cursor == 0 || steps == 0 || items.len() >= self.count {
+                return (cursor, items);
+            }
+        }
+    }
+
+    /// The cursor to continue from, 0 once done, and the page of items
+    pub(crate) fn reply(cursor: u64, items: Vec<Bytes>) -> RespValue {
+        RespValue::Array(vec![RespValue::BulkString(Some(cursor.to_string().into())), RespValue::bulk_array(items)])
+    }
+}
+
 #[router_macro::route("PING")]
 async fn ping(_context : Arc<Context>, _request: RespRequest) -> Result<RespValue> {
     Ok(RespValue::SimpleString("PONG".into()))
@@ -13,4 +164,57 @@ async fn ping(_context : Arc<Context>, _request: RespRequest) -> Result<RespValu
 async fn version(_context : Arc<Context>, _request: RespRequest) -> Result<RespValue> {
     let version_info = get_built_info();
     Ok(RespValue::BulkString(Some(version_info.into())))
-}
\ No newline at end of file
+}
+#[cfg(test)]
+pub(crate) mod test_util {
+    use std::sync::Arc;
+    use bytes::Bytes;
+    use crate::{context::Context, parser::{RespRequest, RespValue}};
+
+    pub(crate) fn request(command: &str, args: &[&str]) -> RespRequest {
+        RespRequest {
+            command: Bytes::copy_from_slice(command.as_bytes()),
+            args: args.iter().map(|arg| bulk(arg)).collect(),
+        }
+    }
+
+    pub(crate) fn context() -> Arc<Context> {
+        Arc::new(Context::new(None, 3))
+    }
+
+    pub(crate) fn bulk(s: &str) -> RespValue {
+        RespValue::BulkString(Some(Bytes::copy_from_slice(s.as_bytes())))
+    }
+
+    pub(crate) fn bulks(items: &[&str]) -> RespValue {
+        RespValue::Array(items.iter().map(|item| bulk(item)).collect())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn floats_have_17_significant_digits() {
+        for (f, expected) in [
+            (3.0, "3"),
+            (-0.0, "-0"),
+            (10.6, "10.6"),
+            (0.1, "0.10000000000000001"),
+            (0.1 + 0.2, "0.30000000000000004"),
+            (1e16, "10000000000000000"),
+            (1e17, "1e+17"),
+            (1e21, "1e+21"),
+            (-1.5e300, "-1.5000000000000001e+300"),
+            (1e-4, "0.0001"),
+            (1.25e-5, "1.2500000000000001e-05"),
+            (5e-324, "4.9406564584124654e-324"),
+            (f64::MAX, "1.7976931348623157e+308"),
+            (f64::NEG_INFINITY, "-inf"),
+        ] {
+            assert_eq!(format_f64(f), expected);
+            assert_eq!(parse_f64(expected.as_bytes()).unwrap().to_bits(), f.to_bits());
+        }
+    }
+}
@@ -0,0 +1,444 @@
+use std::collections::HashSet;
+use std::sync::Arc;
+use bytes::Bytes;
+use rand::seq::IteratorRandom;
+use crate::engine::{Database, Entry, Set, Value, DB};
+use super::{arg_i64, check_args, repeated_picks, ScanArgs};
+use crate::error::*;
+use crate::{context::Context, parser::{RespRequest, RespValue}};
+use crate::command_table::{RouteHandler, ROUTE_MAP};
+
+fn get_set<'a>(db: &'a Database, key: &[u8]) -> Result<Option<&'a Set>> {
+    db.get(key).map(|entry| entry.as_set()).transpose()
+}
+
+fn get_set_mut<'a>(db: &'a mut Database, key: &[u8]) -> Result<Option<&'a mut Set>> {
+    db.get_mut(key).map(|entry| entry.as_set_mut()).transpose()
+}
+
+/// Deletes the key once its last member is gone
+fn delete_if_empty(db: &mut Database, key: &[u8]) {
+    if get_set(db, key).ok().flatten().is_some_and(Set::is_empty) {
+        db.delete(key);
+    }
+}
+
+fn members_reply(members: impl IntoIterator<Item = Bytes>) -> RespValue {
+    RespValue::Set(members.into_iter().map(|member| RespValue::BulkString(Some(member))).collect())
+}
+
+#[router_macro::route("SADD")]
+async fn sadd(_context : Arc<Context>, request: RespRequest) -> anyhow::Result<RespValue> {
+    check_args(&request, "sadd", 2, None)?;
+    let key = request.args[0].as_bytes()?;
+
+    let mut db = DB.write().await;
+    if get_set(&db, key)?.is_none() {
+        db.put(key.clone(), Entry::new(Value::Set(Set::new())));
+    }
+    let set = get_set_mut(&mut db, key)?.expect("set was just created");
+    let mut added = 0;
+    for member in &request.args[1..] {
+        added += set.insert(member.as_bytes()?.clone()) as i64;
+    }
+    if added > 0 {
+        db.append_log(&request.to_resp()).await?;
+    }
+    Ok(RespValue::Integer(added))
+}
+
+#[router_macro::route("SREM")]
+async fn srem(_context : Arc<Context>, request: RespRequest) -> anyhow::Result<RespValue> {
+    check_args(&request, "srem", 2, None)?;
+    let key = request.args[0].as_bytes()?;
+
+    let mut db = DB.write().await;
+    let Some(set) = get_set_mut(&mut db, key)? else {
+        return Ok(RespValue::Integer(0));
+    };
+    let mut removed = 0;
+    for member in &request.args[1..] {
+        removed += set.remove(member.as_bytes()?) as i64;
+    }
+    delete_if_empty(&mut db, key);
+    if removed > 0 {
+        db.append_log(&request.to_resp()).await?;
+    }
+    Ok(RespValue::Integer(removed))
+}
+
+#[router_macro::route("SISMEMBER")]
+async fn sismember(_context : Arc<Context>, request: RespRequest) -> anyhow::Result<RespValue> {
+    check_args(&request, "sismember", 2, Some(2))?;
+    let member = request.args[1].as_bytes()?;
+    let db = DB.read().await;
+    let found = get_set(&db, request.args[0].as_bytes()?)?.is_some_and(|set| set.contains(member));
+    Ok(RespValue::Integer(found as i64))
+}
+
+#[router_macro::route("SMISMEMBER")]
+async fn smismember(_context : Arc<Context>, request: RespRequest) -> anyhow::Result<RespValue> {
+    check_args(&request, "smismember", 2, None)?;
+    let db = DB.read().await;
+    let set = get_set(&db, request.args[0].as_bytes()?)?;
+    let mut found = Vec::with_capacity(request.args.len() - 1);
+    for member in &request.args[1..] {
+        let member = member.as_bytes()?;
+        found.push(RespValue::Integer(set.is_some_and(|set| set.contains(member)) as i64));
+    }
+    Ok(RespValue::Array(found))
+}
+
+#[router_macro::route("SMEMBERS")]
+async fn smembers(_context : Arc<Context>, request: RespRequest) -> anyhow::Result<RespValue> {
+    check_args(&request, "smembers", 1, Some(1))?;
+    let db = DB.read().await;
+    let members: Vec<Bytes> = get_set(&db, request.args[0].as_bytes()?)?.map(|set| set.iter().collect()).unwrap_or_default();
+    Ok(members_reply(members))
+}
+
+#[router_macro::route("SCARD")]
+async fn scard(_context : Arc<Context>, request: RespRequest) -> anyhow::Result<RespValue> {
+    check_args(&request, "scard", 1, Some(1))?;
+    let db = DB.read().await;
+    let len = get_set(&db, request.args[0].as_bytes()?)?.map(Set::len).unwrap_or(0);
+    Ok(RespValue::Integer(len as i64))
+}
+
+fn parse_count(arg: Option<&RespValue>) -> Result<Option<i64>> {
+    arg.map(arg_i64).transpose()
+}
+
+/// SPOP key [count], logged as the SREM of the members it picked
+#[router_macro::route("SPOP")]
+async fn spop(_context : Arc<Context>, request: RespRequest) -> anyhow::Result<RespValue> {
+    check_args(&request, "spop", 1, Some(2))?;
+    let key = request.args[0].as_bytes()?;
+    let count = parse_count(request.args.get(1))?;
+    if count.is_some_and(|count| count < 0) {
+        return Err(Error::Other("value is out of range, must be positive".into()).into());
+    }
+
+    let mut db = DB.write().await;
+    let Some(set) = get_set_mut(&mut db, key)? else {
+        return Ok(match count {
+            Some(_) => RespValue::Array(vec![]),
+            None => RespValue::BulkString(None),
+        });
+    };
+    // one random bucket at a time, rather than going through the whole set
+    let picks = count.map_or(1, |count| (count as usize).min(set.len()));
+    let popped: Vec<Bytes> = (0..picks)
+        .map(|_| {
+            let member = set.random(&mut rand::thread_rng()).expect("no more picks than members");
+            set.remove(&member);
+            member
+        })
+        .collect();
+    delete_if_empty(&mut db, key);
+
+    if !popped.is_empty() {
+        let record = [Bytes::from("SREM"), key.clone()].into_iter().chain(popped.iter().cloned());
+        db.append_log(&RespValue::bulk_array(record)).await?;
+    }
+    Ok(match count {
+        Some(_) => RespValue::bulk_array(popped),
+        None => RespValue::BulkString(popped.into_iter().next()),
+    })
+}
+
+/// SRANDMEMBER key [count], a negative count may return the same member several times
+#[router_macro::route("SRANDMEMBER")]
+async fn srandmember(_context : Arc<Context>, request: RespRequest) -> anyhow::Result<RespValue> {
+    check_args(&request, "srandmember", 1, Some(2))?;
+    let count = parse_count(request.args.get(1))?;
+
+    let db = DB.read().await;
+    let set = get_set(&db, request.args[0].as_bytes()?)?;
+    let mut rng = rand::thread_rng();
+    let Some(count) = count else {
+        return Ok(RespValue::BulkString(set.and_then(|set| set.iter().choose(&mut rng))));
+    };
+    let Some(set) = set else {
+        return Ok(RespValue::Array(vec![]));
+    };
+
+    if count >= 0 {
+        let count = (count as usize).min(set.len());
+        return Ok(RespValue::bulk_array(set.iter().choose_multiple(&mut rng, count)));
+    }
+    let count = repeated_picks(count)?;
+    Ok(RespValue::bulk_array((0..count).map_while(|_| set.random(&mut rng))))
+}
+
+#[router_macro::route("SMOVE")]
+async fn smove(_context : Arc<Context>, request: RespRequest) -> anyhow::Result<RespValue> {
+    check_args(&request, "smove", 3, Some(3))?;
+    let source = request.args[0].as_bytes()?;
+    let destination = request.args[1].as_bytes()?;
+    let member = request.args[2].as_bytes()?;
+
+    let mut db = DB.write().await;
+    // both types are checked before anything moves
+    let Some(set) = get_set(&db, source)? else {
+        return Ok(RespValue::Integer(0));
+    };
+    let found = set.contains(member);
+    get_set(&db, destination)?;
+    if !found {
+        return Ok(RespValue::Integer(0));
+    }
+    if source == destination {
+        return Ok(RespValue::Integer(1));
+    }
+
+    get_set_mut(&mut db, source)?.expect("checked above").remove(member);
+    delete_if_empty(&mut db, source);
+    if get_set(&db, destination)?.is_none() {
+        db.put(destination.clone(), Entry::new(Value::Set(Set::new())));
+    }
+    get_set_mut(&mut db, destination)?.expect("set was just created").insert(member.clone());
+    db.append_log(&request.to_resp()).await?;
+    Ok(RespValue::Integer(1))
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum SetOp {
+    Inter,
+    Union,
+    Diff,
+}
+
+/// Applies `op` to the sets at `keys`, missing keys standing for empty sets
+fn combine(db: &Database, keys: &[RespValue], op: SetOp, limit: usize) -> Result<Vec<Bytes>> {
+    let mut sets = Vec::with_capacity(keys.len());
+    for key in keys {
+        sets.push(get_set(db, key.as_bytes()?)?);
+    }
+
+    Ok(match op {
+        SetOp::Inter => {
+            let Some(mut sets) = sets.into_iter().collect::<Option<Vec<&Set>>>() else {
+                return Ok(vec![]);
+            };
+            // the smallest set drives the lookups in the others
+            sets.sort_by_key(|set| set.len());
+            let (first, rest) = sets.split_first().expect("at least one key");
+            first.iter().filter(|member| rest.iter().all(|set| set.contains(member))).take(limit).collect()
+        }
+        SetOp::Union => {
+            let mut members = HashSet::new();
+            for set in sets.into_iter().flatten() {
+                members.extend(set.iter());
+            }
+            members.into_iter().collect()
+        }
+        SetOp::Diff => {
+            let (first, rest) = sets.split_first().expect("at least one key");
+            let Some(first) = first else {
+                return Ok(vec![]);
+            };
+            first.iter().filter(|member| rest.iter().flatten().all(|set| !set.contains(member))).collect()
+        }
+    })
+}
+
+async fn combine_command(request: &RespRequest, name: &str, op: SetOp) -> anyhow::Result<RespValue> {
+    check_args(request, name, 1, None)?;
+    let db = DB.read().await;
+    Ok(members_reply(combine(&db, &request.args, op, usize::MAX)?))
+}
+
+/// SINTERSTORE/SUNIONSTORE/SDIFFSTORE destination key [key ...], whatever the destination held before
+async fn store_command(request: &RespRequest, name: &str, op: SetOp) -> anyhow::Result<RespValue> {
+    check_args(request, name, 2, None)?;
+    let destination = request.args[0].as_bytes()?;
+
+    let mut db = DB.write().await;
+    let members = combine(&db, &request.args[1..], op, usize::MAX)?;
+    let len = members.len();
+    if members.is_empty() {
+        db.delete(destination);
+    } else {
+        db.put(destination.clone(), Entry::new(Value::Set(members.into_iter().collect())));
+    }
+    db.append_log(&request.to_resp()).await?;
+    Ok(RespValue::Integer(len as i64))
+}
+
+#[router_macro::route("SINTER")]
+async fn sinter(_context : Arc<Context>, request: RespRequest) -> anyhow::Result<RespValue> {
+    combine_command(&request, "sinter", SetOp::Inter).await
+}
+
+#[router_macro::route("SUNION")]
+async fn sunion(_context : Arc<Context>, request: RespRequest) -> anyhow::Result<RespValue> {
+    combine_command(&request, "sunion", SetOp::Union).await
+}
+
+#[router_macro::route("SDIFF")]
+async fn sdiff(_context : Arc<Context>, request: RespRequest) -> anyhow::Result<RespValue> {
+    combine_command(&request, "sdiff", SetOp::Diff).await
+}
+
+#[router_macro::route("SINTERSTORE")]
+async fn sinterstore(_context : Arc<Context>, request: RespRequest) -> anyhow::Result<RespValue> {
+    store_command(&request, "sinterstore", SetOp::Inter).await
+}
+
+#[router_macro::route("SUNIONSTORE")]
+async fn sunionstore(_context : Arc<Context>, request: RespRequest) -> anyhow::Result<RespValue> {
+    store_command(&request, "sunionstore", SetOp::Union).await
+}
+
+#[router_macro::route("SDIFFSTORE")]
+async fn sdiffstore(_context : Arc<Context>, request: RespRequest) -> anyhow::Result<RespValue> {
+    store_command(&request, "sdiffstore", SetOp::Diff).await
+}
+
+/// SINTERCARD numkeys key [key ...] [LIMIT limit]
+#[router_macro::route("SINTERCARD")]
+async fn sintercard(_context : Arc<Context>, request: RespRequest) -> anyhow::Result<RespValue> {
+    check_args(&request, "sintercard", 2, None)?;
+    let numkeys = arg_i64(&request.args[0])?;
+    if numkeys <= 0 {
+        return Err(Error::Other("numkeys should be greater than 0".into()).into());
+    }
+    let numkeys = numkeys as usize;
+    if numkeys > request.args.len() - 1 {
+        return Err(Error::Other("Number of keys can't be greater than number of args".into()).into());
+    }
+    let keys = &request.args[1..=numkeys];
+
+    let mut limit = usize::MAX;
+    let mut args = request.args[numkeys + 1..].iter();
+    while let Some(arg) = args.next() {
+        if !arg.as_str()?.eq_ignore_ascii_case("LIMIT") {
+            return Err(Error::Syntax.into());
+        }
+        let value = arg_i64(args.next().ok_or(Error::Syntax)?)?;
+        if value < 0 {
+            return Err(Error::Other("LIMIT can't be negative".into()).into());
+        }
+        // 0 stands for no limit
+        limit = if value == 0 { usize::MAX } else { value as usize };
+    }
+
+    let db = DB.read().await;
+    Ok(RespValue::Integer(combine(&db, keys, SetOp::Inter, limit)?.len() as i64))
+}
+
+/// SSCAN key cursor [MATCH pattern] [COUNT count]
+#[router_macro::route("SSCAN")]
+async fn sscan(_context : Arc<Context>, request: RespRequest) -> anyhow::Result<RespValue> {
+    check_args(&request, "sscan", 2, None)?;
+    let scan = ScanArgs::parse(&request.args[1..], false, false)?;
+
+    let db = DB.read().await;
+    let (cursor, members) = match get_set(&db, request.args[0].as_bytes()?)? {
+        Some(set) => scan.walk(|cursor, members| set.scan(cursor, |member| members.push(member))),
+        None => (0, vec![]),
+    };
+    Ok(ScanArgs::reply(cursor, members.into_iter().filter(|member| scan.matches(member)).collect()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::redis_types::test_util::{bulk, context, request};
+
+    /// The members of a reply, sorted so that they can be compared
+    fn sorted(reply: RespValue) -> Vec<String> {
+        let (RespValue::Set(items) | RespValue::Array(items)) = reply else { panic!("not a collection: {:?}", reply) };
+        let mut members: Vec<String> = items.iter().map(|item| item.as_str().unwrap().to_string()).collect();
+        members.sort();
+        members
+    }
+
+    fn is_intset(db: &Database, key: &[u8]) -> bool {
+        matches!(get_set(db, key), Ok(Some(Set::Ints(_))))
+    }
+
+    #[tokio::test]
+    async fn add_remove_and_members() -> anyhow::Result<()> {
+        assert_eq!(sadd(context(), request("SADD", &["set:s1", "3", "1", "2", "1"])).await?, RespValue::Integer(3));
+        assert!(is_intset(&*DB.read().await, b"set:s1"));
+        assert_eq!(sadd(context(), request("SADD", &["set:s1", "a"])).await?, RespValue::Integer(1));
+        assert!(!is_intset(&*DB.read().await, b"set:s1"));
+
+        assert_eq!(sorted(smembers(context(), request("SMEMBERS", &["set:s1"])).await?), ["1", "2", "3", "a"]);
+        assert_eq!(scard(context(), request("SCARD", &["set:s1"])).await?, RespValue::Integer(4));
+        assert_eq!(sismember(context(), request("SISMEMBER", &["set:s1", "a"])).await?, RespValue::Integer(1));
+        let found = smismember(context(), request("SMISMEMBER", &["set:s1", "2", "x"])).await?;
+        assert_eq!(found, RespValue::Array(vec![RespValue::Integer(1), RespValue::Integer(0)]));
+
+        assert_eq!(srem(context(), request("SREM", &["set:s1", "1", "2", "3", "x"])).await?, RespValue::Integer(3));
+        assert_eq!(srem(context(), request("SREM", &["set:s1", "a"])).await?, RespValue::Integer(1));
+        assert!(DB.read().await.get(b"set:s1").is_none());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn random_members() -> anyhow::Result<()> {
+        sadd(context(), request("SADD", &["set:s2", "a", "b", "c", "d"])).await?;
+        assert_eq!(sorted(srandmember(context(), request("SRANDMEMBER", &["set:s2", "10"])).await?).len(), 4);
+        assert_eq!(sorted(srandmember(context(), request("SRANDMEMBER", &["set:s2", "-10"])).await?).len(), 10);
+        assert_eq!(sorted(srandmember(context(), request("SRANDMEMBER", &["set:s2", "9223372036854775807"])).await?).len(), 4);
+        assert!(srandmember(context(), request("SRANDMEMBER", &["set:s2", "-9223372036854775807"])).await.is_err());
+
+        let popped = sorted(spop(context(), request("SPOP", &["set:s2", "3"])).await?);
+        assert_eq!(popped.len(), 3);
+        let last = spop(context(), request("SPOP", &["set:s2"])).await?;
+        assert!(!popped.contains(&last.as_str()?.to_string()));
+        assert!(DB.read().await.get(b"set:s2").is_none());
+        assert_eq!(spop(context(), request("SPOP", &["set:s2"])).await?, RespValue::BulkString(None));
+
+        let members: Vec<String> = (0..1000).map(|i| format!("m{}", i)).collect();
+        let args: Vec<&str> = ["set:s3"].into_iter().chain(members.iter().map(String::as_str)).collect();
+        sadd(context(), request("SADD", &args)).await?;
+        let popped = sorted(spop(context(), request("SPOP", &["set:s3", "990"])).await?);
+        assert_eq!(popped.iter().collect::<HashSet<_>>().len(), 990);
+        assert_eq!(scard(context(), request("SCARD", &["set:s3"])).await?, RespValue::Integer(10));
+        assert_eq!(sorted(spop(context(), request("SPOP", &["set:s3", "9223372036854775807"])).await?).len(), 10);
+        assert!(DB.read().await.get(b"set:s3").is_none());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn algebra() -> anyhow::Result<()> {
+        sadd(context(), request("SADD", &["set:a", "1", "2", "3", "x"])).await?;
+        sadd(context(), request("SADD", &["set:b", "2", "3", "4"])).await?;
+        assert_eq!(sorted(sinter(context(), request("SINTER", &["set:a", "set:b"])).await?), ["2", "3"]);
+        assert_eq!(sorted(sinter(context(), request("SINTER", &["set:a", "set:none"])).await?), Vec::<String>::new());
+        assert_eq!(sorted(sunion(context(), request("SUNION", &["set:a", "set:b", "set:none"])).await?), ["1", "2", "3", "4", "x"]);
+        assert_eq!(sorted(sdiff(context(), request("SDIFF", &["set:a", "set:b"])).await?), ["1", "x"]);
+
+        let card = sintercard(context(), request("SINTERCARD", &["2", "set:a", "set:b", "LIMIT", "1"])).await?;
+        assert_eq!(card, RespValue::Integer(1));
+        assert!(sintercard(context(), request("SINTERCARD", &["3", "set:a", "set:b"])).await.is_err());
+
+        assert_eq!(sinterstore(context(), request("SINTERSTORE", &["set:dst", "set:a", "set:b"])).await?, RespValue::Integer(2));
+        assert!(is_intset(&*DB.read().await, b"set:dst"));
+        assert_eq!(sdiffstore(context(), request("SDIFFSTORE", &["set:dst", "set:a", "set:a"])).await?, RespValue::Integer(0));
+        assert!(DB.read().await.get(b"set:dst").is_none());
+
+        assert_eq!(smove(context(), request("SMOVE", &["set:a", "set:b", "x"])).await?, RespValue::Integer(1));
+        assert_eq!(smove(context(), request("SMOVE", &["set:a", "set:b", "x"])).await?, RespValue::Integer(0));
+        assert_eq!(sismember(context(), request("SISMEMBER", &["set:b", "x"])).await?, RespValue::Integer(1));
+
+        DB.write().await.put("set:string".into(), Entry::new(Value::String("v".into())));
+        assert!(sunion(context(), request("SUNION", &["set:a", "set:string"])).await.is_err());
+        assert!(smove(context(), request("SMOVE", &["set:a", "set:string", "1"])).await.is_err());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn scan_matches() -> anyhow::Result<()> {
+        sadd(context(), request("SADD", &["set:s3", "apple", "avocado", "banana"])).await?;
+        let RespValue::Array(reply) = sscan(context(), request("SSCAN", &["set:s3", "0", "MATCH", "a*"])).await? else { panic!() };
+        assert_eq!(reply[0], bulk("0"));
+        assert_eq!(sorted(reply[1].clone()), ["apple", "avocado"]);
+        assert!(sscan(context(), request("SSCAN", &["set:s3", "0", "NOVALUES"])).await.is_err());
+        Ok(())
+    }
+}
@@ -0,0 +1,1359 @@
+use std::collections::{BTreeMap, HashMap};
+use std::ops::Bound;
+use std::sync::Arc;
+use std::time::Duration;
+use bytes::Bytes;
+use crate::engine::{ConsumerGroup, Database, Entry, Fields, PendingEntry, Stream, StreamId, Value, DB};
+use super::blocking::{self, Served};
+use super::{arg_i64, check_args};
+use crate::error::*;
+use crate::parser::{NULL_RESP, OK_RESP, RESP3};
+use crate::{context::Context, parser::{RespRequest, RespValue}};
+use crate::command_table::{RouteHandler, ROUTE_MAP};
+
+// stream-node-max-entries of redis times 100, how many entries approximate trimming removes at most by default
+const APPROX_TRIM_LIMIT: usize = 100 * 100;
+
+fn get_stream<'a>(db: &'a Database, key: &[u8]) -> Result<Option<&'a Stream>> {
+    db.get(key).map(|entry| entry.as_stream()).transpose()
+}
+
+fn get_stream_mut<'a>(db: &'a mut Database, key: &[u8]) -> Result<Option<&'a mut Stream>> {
+    db.get_mut(key).map(|entry| entry.as_stream_mut()).transpose()
+}
+
+fn now_ms() -> i64 {
+    chrono::Utc::now().timestamp_millis()
+}
+
+fn no_group(key: &[u8], group: &[u8]) -> Error {
+    Error::Other(format!(
+        "NOGROUP No such key '{}' or consumer group '{}'",
+        String::from_utf8_lossy(key),
+        String::from_utf8_lossy(group)
+    ))
+}
+
+/// Runs `f` on the stream at `key` and its group `name`, both of which must exist.
+/// The group is taken out of the stream meanwhile so that both can be used at once.
+fn with_group<T>(db: &mut Database, key: &[u8], name: &Bytes, f: impl FnOnce(&Stream, &mut ConsumerGroup) -> Result<T>) -> Result<T> {
+    let stream = get_stream_mut(db, key)?.ok_or_else(|| no_group(key, name))?;
+    let mut group = stream.groups.remove(name).ok_or_else(|| no_group(key, name))?;
+    let result = f(stream, &mut group);
+    stream.groups.insert(name.clone(), group);
+    result
+}
+
+fn invalid_id() -> Error {
+    Error::Other("Invalid stream ID specified as stream command argument".into())
+}
+
+fn parse_u64(bytes: &[u8]) -> Result<u64> {
+    std::str::from_utf8(bytes).ok().and_then(|s| s.parse::<u64>().ok()).ok_or_else(invalid_id)
+}
+
+/// Parses "ms-seq", or "ms" alone which gets `seq` as its sequence number
+fn parse_id(bytes: &[u8], seq: u64) -> Result<StreamId> {
+    match bytes.iter().position(|&b| b == b'-') {
+        Some(dash) => Ok(StreamId::new(parse_u64(&bytes[..dash])?, parse_u64(&bytes[dash + 1..])?)),
+        None => Ok(StreamId::new(parse_u64(bytes)?, seq)),
+    }
+}
+
+fn arg_id(arg: &RespValue, seq: u64) -> Result<StreamId> {
+    parse_id(arg.as_bytes()?, seq)
+}
+
+/// A bound of XRANGE and alike: "-", "+", an ID, or "(" followed by an ID to exclude it
+fn parse_bound(arg: &RespValue, start: bool) -> Result<StreamId> {
+    let bytes = arg.as_bytes()?;
+    match bytes.as_ref() {
+        b"-" => return Ok(StreamId::MIN),
+        b"+" => return Ok(StreamId::MAX),
+        _ => {}
+    }
+    // a bare "ms" covers the whole millisecond
+    let seq = if start { 0 } else { u64::MAX };
+    let Some(id) = bytes.strip_prefix(b"(") else {
+        return parse_id(bytes, seq);
+    };
+    let id = parse_id(id, seq)?;
+    let id = if start { id.next() } else { id.prev() };
+    id.ok_or_else(|| Error::Other(format!("invalid {} ID for the interval", if start { "start" } else { "end" })))
+}
+
+fn id_reply(id: StreamId) -> RespValue {
+    RespValue::BulkString(Some(id.to_bytes()))
+}
+
+fn entry_reply(id: &StreamId, fields: &Fields) -> RespValue {
+    let fields = fields.iter().flat_map(|(field, value)| [field.clone(), value.clone()]);
+    RespValue::Array(vec![id_reply(*id), RespValue::bulk_array(fields)])
+}
+
+/// Entries per stream, as XREAD and XREADGROUP reply: a map in RESP3, pairs in RESP2
+fn streams_reply(protocol: u8, streams: Vec<(Bytes, Vec<RespValue>)>) -> RespValue {
+    let streams = streams.into_iter().map(|(key, entries)| (RespValue::BulkString(Some(key)), RespValue::Array(entries)));
+    if protocol >= RESP3 {
+        RespValue::Map(streams.collect())
+    } else {
+        RespValue::Array(streams.map(|(key, entries)| RespValue::Array(vec![key, entries])).collect())
+    }
+}
+
+/// A map whose keys are names, as XINFO replies
+fn info_reply(pairs: Vec<(&str, RespValue)>) -> RespValue {
+    RespValue::Map(pairs.into_iter().map(|(name, value)| (RespValue::BulkString(Some(Bytes::copy_from_slice(name.as_bytes()))), value)).collect())
+}
+
+#[derive(Debug, Clone, Copy)]
+enum TrimBy {
+    MaxLen(usize),
+    MinId(StreamId),
+}
+
+#[derive(Debug, Clone, Copy)]
+struct Trim {
+    by: TrimBy,
+    limit: usize,
+}
+
+impl Trim {
+    /// Parses `MAXLEN|MINID [=|~] threshold [LIMIT count]` if it starts at `args[*i]`, moving `i` past it.
+    /// Approximate trimming removes exactly the same entries as `=` would, but no more than LIMIT of them.
+    fn parse(args: &[RespValue], i: &mut usize) -> Result<Option<Trim>> {
+        let Some(arg) = args.get(*i) else {
+            return Ok(None);
+        };
+        let strategy = arg.as_bytes()?.to_ascii_uppercase();
+        if strategy != b"MAXLEN" && strategy != b"MINID" {
+            return Ok(None);
+        }
+        *i += 1;
+
+        let mut approx = false;
+        match args.get(*i).map(RespValue::as_bytes).transpose()?.map(Bytes::as_ref) {
+            Some(b"~") => {
+                approx = true;
+                *i += 1;
+            }
+            Some(b"=") => *i += 1,
+            _ => {}
+        }
+        let threshold = args.get(*i).ok_or(Error::Syntax)?;
+        *i += 1;
+        let by = if strategy == b"MAXLEN" {
+            let max_len = arg_i64(threshold)?;
+            if max_len < 0 {
+                return Err(Error::Other("The MAXLEN argument must be >= 0.".into()));
+            }
+            TrimBy::MaxLen(max_len as usize)
+        } else {
+            TrimBy::MinId(arg_id(threshold, 0)?)
+        };
+
+        let mut limit = if approx { APPROX_TRIM_LIMIT } else { usize::MAX };
+        if args.get(*i).map(RespValue::as_bytes).transpose()?.is_some_and(|arg| arg.eq_ignore_ascii_case(b"LIMIT")) {
+            let count = arg_i64(args.get(*i + 1).ok_or(Error::Syntax)?)?;
+            if count < 0 {
+                return Err(Error::Other("The LIMIT argument must be >= 0.".into()));
+            }
+            if !approx {
+                return Err(Error::Other("syntax error, LIMIT cannot be used without the special ~ option".into()));
+            }
+            // 0 stands for no limit
+            limit = if count == 0 { usize::MAX } else { count as usize };
+            *i += 2;
+        }
+        Ok(Some(Trim { by, limit }))
+    }
+
+    fn apply(&self, stream: &mut Stream) -> usize {
+        match self.by {
+            TrimBy::MaxLen(max_len) => stream.trim_len(max_len, self.limit),
+            TrimBy::MinId(min_id) => stream.trim_min_id(min_id, self.limit),
+        }
+    }
+}
+
+/// The ID of a new entry: "*" for an automatic one, "ms-*" for an automatic sequence number, or an explicit ID
+fn new_id(stream: &Stream, arg: &[u8]) -> Result<StreamId> {
+    let too_small = || Error::Other("The ID specified in XADD is equal or smaller than the target stream top item".into());
+    if arg == b"*" {
+        return stream
+            .next_id(now_ms() as u64)
+            .ok_or_else(|| Error::Other("The stream has exhausted the last possible ID, unable to add more items".into()));
+    }
+    let id = match arg.strip_suffix(b"-*") {
+        Some(ms) => {
+            let ms = parse_u64(ms)?;
+            match ms.cmp(&stream.last_id.ms) {
+                std::cmp::Ordering::Less => return Err(too_small()),
+                std::cmp::Ordering::Equal => StreamId::new(ms, stream.last_id.seq.checked_add(1).ok_or_else(too_small)?),
+                std::cmp::Ordering::Greater => StreamId::new(ms, 0),
+            }
+        }
+        None => parse_id(arg, 0)?,
+    };
+    if id == StreamId::MIN {
+        return Err(Error::Other("The ID specified in XADD must be greater than 0-0".into()));
+    }
+    if id <= stream.last_id {
+        return Err(too_small());
+    }
+    Ok(id)
+}
+
+/// XADD key [NOMKSTREAM] [MAXLEN|MINID [=|~] threshold [LIMIT count]] *|id field value [field value ...],
+/// logged with the ID the entry got
+#[router_macro::route("XADD")]
+async fn xadd(_context : Arc<Context>, request: RespRequest) -> anyhow::Result<RespValue> {
+    check_args(&request, "xadd", 4, None)?;
+    let key = request.args[0].as_bytes()?;
+
+    let mut i = 1;
+    let mut no_mkstream = false;
+    let mut trim = None;
+    loop {
+        if request.args.get(i).ok_or(Error::Syntax)?.as_bytes()?.eq_ignore_ascii_case(b"NOMKSTREAM") {
+            no_mkstream = true;
+            i += 1;
+        } else if let Some(parsed) = Trim::parse(&request.args, &mut i)? {
+            trim = Some(parsed);
+        } else {
+            break;
+        }
+    }
+    let id_index = i;
+    let fields = &request.args[id_index + 1..];
+    if fields.is_empty() || !fields.len().is_multiple_of(2) {
+        return Err(Error::WrongArgNumber("xadd".into()).into());
+    }
+    let fields = fields
+        .chunks(2)
+        .map(|pair| Ok((pair[0].as_bytes()?.clone(), pair[1].as_bytes()?.clone())))
+        .collect::<Result<Fields>>()?;
+
+    let mut db = DB.write().await;
+    let id = match get_stream(&db, key)? {
+        Some(stream) => new_id(stream, request.args[id_index].as_bytes()?)?,
+        None if no_mkstream => return Ok(NULL_RESP.clone()),
+        None => new_id(&Stream::new(), request.args[id_index].as_bytes()?)?,
+    };
+    if get_stream(&db, key)?.is_none() {
+        db.put(key.clone(), Entry::new(Value::Stream(Stream::new())));
+    }
+    let stream = get_stream_mut(&mut db, key)?.expect("stream was just created");
+    stream.add(id, fields);
+    if let Some(trim) = trim {
+        trim.apply(stream);
+    }
+
+    let mut record = vec![Bytes::from("XADD")];
+    for arg in &request.args {
+        record.push(arg.as_bytes()?.clone());
+    }
+    record[id_index + 1] = id.to_bytes();
+    db.append_log(&RespValue::bulk_array(record)).await?;
+    blocking::signal(key);
+    blocking::serve_ready(&mut db).await?;
+    Ok(id_reply(id))
+}
+
+/// XTRIM key MAXLEN|MINID [=|~] threshold [LIMIT count]
+#[router_macro::route("XTRIM")]
+async fn xtrim(_context : Arc<Context>, request: RespRequest) -> anyhow::Result<RespValue> {
+    check_args(&request, "xtrim", 3, None)?;
+    let key = request.args[0].as_bytes()?;
+    let mut i = 1;
+    let trim = Trim::parse(&request.args, &mut i)?.ok_or(Error::Syntax)?;
+    if i != request.args.len() {
+        return Err(Error::Syntax.into());
+    }
+
+    let mut db = DB.write().await;
+    let Some(stream) = get_stream_mut(&mut db, key)? else {
+        return Ok(RespValue::Integer(0));
+    };
+    let removed = trim.apply(stream);
+    if removed > 0 {
+        db.append_log(&request.to_resp()).await?;
+    }
+    Ok(RespValue::Integer(removed as i64))
+}
+
+#[router_macro::route("XLEN")]
+async fn xlen(_context : Arc<Context>, request: RespRequest) -> anyhow::Result<RespValue> {
+    check_args(&request, "xlen", 1, Some(1))?;
+    let db = DB.read().await;
+    let len = get_stream(&db, request.args[0].as_bytes()?)?.map(Stream::len).unwrap_or(0);
+    Ok(RespValue::Integer(len as i64))
+}
+
+#[router_macro::route("XDEL")]
+async fn xdel(_context : Arc<Context>, request: RespRequest) -> anyhow::Result<RespValue> {
+    check_args(&request, "xdel", 2, None)?;
+    let key = request.args[0].as_bytes()?;
+    let ids = request.args[1..].iter().map(|arg| arg_id(arg, 0)).collect::<Result<Vec<_>>>()?;
+
+    let mut db = DB.write().await;
+    let Some(stream) = get_stream_mut(&mut db, key)? else {
+        return Ok(RespValue::Integer(0));
+    };
+    let removed = ids.into_iter().filter(|id| stream.remove(*id)).count();
+    if removed > 0 {
+        db.append_log(&request.to_resp()).await?;
+    }
+    Ok(RespValue::Integer(removed as i64))
+}
+
+/// XRANGE key start end [COUNT count], XREVRANGE key end start [COUNT count]
+async fn range_command(request: &RespRequest, name: &str, rev: bool) -> anyhow::Result<RespValue> {
+    check_args(request, name, 3, Some(5))?;
+    let (start, end) = if rev { (&request.args[2], &request.args[1]) } else { (&request.args[1], &request.args[2]) };
+    let (start, end) = (parse_bound(start, true)?, parse_bound(end, false)?);
+    let count = match &request.args[3..] {
+        [] => usize::MAX,
+        [option, count] if option.as_bytes()?.eq_ignore_ascii_case(b"COUNT") => arg_i64(count)?.max(0) as usize,
+        _ => return Err(Error::Syntax.into()),
+    };
+
+    let db = DB.read().await;
+    let Some(stream) = get_stream(&db, request.args[0].as_bytes()?)? else {
+        return Ok(RespValue::Array(vec![]));
+    };
+    let entries = stream.range(start, end);
+    let entries: Vec<RespValue> = if rev {
+        entries.rev().take(count).map(|(id, fields)| entry_reply(id, fields)).collect()
+    } else {
+        entries.take(count).map(|(id, fields)| entry_reply(id, fields)).collect()
+    };
+    Ok(RespValue::Array(entries))
+}
+
+#[router_macro::route("XRANGE")]
+async fn xrange(_context : Arc<Context>, request: RespRequest) -> anyhow::Result<RespValue> {
+    range_command(&request, "xrange", false).await
+}
+
+#[router_macro::route("XREVRANGE")]
+async fn xrevrange(_context : Arc<Context>, request: RespRequest) -> anyhow::Result<RespValue> {
+    range_command(&request, "xrevrange", true).await
+}
+
+/// Options of XREAD and XREADGROUP: `[COUNT count] [BLOCK milliseconds] [NOACK] STREAMS key [key ...] id [id ...]`
+struct ReadArgs {
+    count: usize,
+    // Some(None) blocks forever
+    block: Option<Option<Duration>>,
+    no_ack: bool,
+    keys: Vec<Bytes>,
+    ids: Vec<Bytes>,
+}
+
+impl ReadArgs {
+    fn parse(args: &[RespValue], name: &str, group: bool) -> Result<ReadArgs> {
+        let mut read = ReadArgs { count: usize::MAX, block: None, no_ack: false, keys: vec![], ids: vec![] };
+        let mut i = 0;
+        loop {
+            let option = args.get(i).ok_or(Error::Syntax)?.as_bytes()?.to_ascii_uppercase();
+            match option.as_slice() {
+                b"COUNT" => {
+                    let count = arg_i64(args.get(i + 1).ok_or(Error::Syntax)?)?;
+                    // 0 stands for no limit
+                    read.count = if count <= 0 { usize::MAX } else { count as usize };
+                    i += 2;
+                }
+                b"BLOCK" => {
+                    let timeout = arg_i64(args.get(i + 1).ok_or(Error::Syntax)?)?;
+                    if timeout < 0 {
+                        return Err(Error::Other("timeout is negative".into()));
+                    }
+                    read.block = Some((timeout > 0).then(|| Duration::from_millis(timeout as u64)));
+                    i += 2;
+                }
+                b"NOACK" if group => {
+                    read.no_ack = true;
+                    i += 1;
+                }
+                b"STREAMS" => break,
+                _ => return Err(Error::Syntax),
+            }
+        }
+
+        let streams = &args[i + 1..];
+        if streams.is_empty() || !streams.len().is_multiple_of(2) {
+            let symbol = if group { '>' } else { '$' };
+            return Err(Error::Other(format!(
+                "Unbalanced '{}' list of streams: for each stream key an ID or '{}' must be specified.", name, symbol
+            )));
+        }
+        let (keys, ids) = streams.split_at(streams.len() / 2);
+        read.keys = keys.iter().map(|key| Ok(key.as_bytes()?.clone())).collect::<Result<_>>()?;
+        read.ids = ids.iter().map(|id| Ok(id.as_bytes()?.clone())).collect::<Result<_>>()?;
+        Ok(read)
+    }
+}
+
+/// Up to `count` entries after `after`
+fn read_after(stream: &Stream, after: StreamId, count: usize) -> Vec<RespValue> {
+    match after.next() {
+        Some(start) => stream.range(start, StreamId::MAX).take(count).map(|(id, fields)| entry_reply(id, fields)).collect(),
+        None => vec![],
+    }
+}
+
+/// Blocks on `keys` until `serve` succeeds for one of them, replies with a null once `timeout` elapsed
+async fn block(context: Arc<Context>, db: tokio::sync::RwLockWriteGuard<'_, Database>, keys: Vec<Bytes>, timeout: Option<Duration>, serve: blocking::Serve) -> anyhow::Result<RespValue> {
+    let blocked = blocking::block(&db, keys, serve);
+    drop(db);
+    context.block_for(timeout);
+    Ok(blocked.wait(timeout).await?.unwrap_or(RespValue::NullArray))
+}
+
+/// XREAD [COUNT count] [BLOCK milliseconds] STREAMS key [key ...] id|$ [id|$ ...]
+#[router_macro::route("XREAD")]
+async fn xread(context : Arc<Context>, request: RespRequest) -> anyhow::Result<RespValue> {
+    check_args(&request, "xread", 3, None)?;
+    let read = ReadArgs::parse(&request.args, "xread", false)?;
+    let protocol = context.protocol();
+
+    let db = DB.write().await;
+    let mut after = HashMap::new();
+    let mut streams = vec![];
+    for (key, id) in read.keys.iter().zip(&read.ids) {
+        let stream = get_stream(&db, key)?;
+        let id = match id.as_ref() {
+            // only entries added from now on
+            b"$" => stream.map_or(StreamId::MIN, |stream| stream.last_id),
+            id => parse_id(id, 0)?,
+        };
+        after.insert(key.clone(), id);
+        let entries = stream.map(|stream| read_after(stream, id, read.count)).unwrap_or_default();
+        if !entries.is_empty() {
+            streams.push((key.clone(), entries));
+        }
+    }
+    if !streams.is_empty() {
+        return Ok(streams_reply(protocol, streams));
+    }
+    let Some(timeout) = read.block else {
+        return Ok(RespValue::NullArray);
+    };
+
+    let count = read.count;
+    let serve = move |db: &mut Database, key: &Bytes| -> Result<Option<Served>> {
+        let entries = get_stream(db, key)?.map(|stream| read_after(stream, after[key], count)).unwrap_or_default();
+        Ok((!entries.is_empty()).then(|| Served { reply: streams_reply(protocol, vec![(key.clone(), entries)]), records: vec![] }))
+    };
+    block(context, db, read.keys, timeout, Box::new(serve)).await
+}
+
+/// The command restoring the delivery of `id` to `consumer` when the log is replayed
+fn claim_record(key: &Bytes, group: &Bytes, consumer: &Bytes, id: StreamId, pending: &PendingEntry) -> RespValue {
+    RespValue::bulk_array([
+        "XCLAIM".into(), key.clone(), group.clone(), consumer.clone(), "0".into(), id.to_bytes(),
+        "TIME".into(), pending.delivered_at.to_string().into(),
+        "RETRYCOUNT".into(), pending.deliveries.to_string().into(),
+        "FORCE".into(), "JUSTID".into(),
+    ])
+}
+
+/// The command restoring the last delivered ID of a group
+fn set_id_record(key: &Bytes, name: &Bytes, group: &ConsumerGroup) -> RespValue {
+    let read = group.entries_read.map_or("-1".into(), |read| read.to_string());
+    RespValue::bulk_array(["XGROUP".into(), "SETID".into(), key.clone(), name.clone(), group.last_id.to_bytes(), "ENTRIESREAD".into(), read.into()])
+}
+
+fn create_consumer_record(key: &Bytes, group: &Bytes, consumer: &Bytes) -> RespValue {
+    RespValue::bulk_array(["XGROUP".into(), "CREATECONSUMER".into(), key.clone(), group.clone(), consumer.clone()])
+}
+
+/// Marks `consumer` as seen, creating it if needed, along with the command creating it again
+fn touch_consumer(group: &mut ConsumerGroup, key: &Bytes, name: &Bytes, consumer: &Bytes, now: i64, active: bool, records: &mut Vec<RespValue>) {
+    let (state, created) = group.consumer(consumer, now);
+    state.seen_at = now;
+    if active {
+        state.active_at = now;
+    }
+    if created {
+        records.push(create_consumer_record(key, name, consumer));
+    }
+}
+
+/// Delivers up to `count` entries the group has not seen yet to `consumer`.
+/// Returns the entries along with the commands that replay the delivery.
+fn deliver_new(db: &mut Database, key: &Bytes, name: &Bytes, consumer: &Bytes, count: usize, no_ack: bool) -> Result<(Vec<RespValue>, Vec<RespValue>)> {
+    let now = now_ms();
+    with_group(db, key, name, |stream, group| {
+        let delivered: Vec<(StreamId, RespValue)> = match group.last_id.next() {
+            Some(start) => stream.range(start, StreamId::MAX).take(count).map(|(id, fields)| (*id, entry_reply(id, fields))).collect(),
+            None => vec![],
+        };
+
+        let mut records = vec![];
+        touch_consumer(group, key, name, consumer, now, !delivered.is_empty(), &mut records);
+        for (id, _) in &delivered {
+            group.entries_read = match group.entries_read {
+                Some(read) if !stream.has_tombstone_after(group.last_id) => Some(read + 1),
+                _ => stream.entries_read_at(*id),
+            };
+            group.last_id = *id;
+            if !no_ack {
+                let pending = PendingEntry { consumer: consumer.clone(), delivered_at: now, deliveries: 1 };
+                records.push(claim_record(key, name, consumer, *id, &pending));
+                group.pending.insert(*id, pending);
+            }
+        }
+        if !delivered.is_empty() {
+            records.push(set_id_record(key, name, group));
+        }
+        Ok((delivered.into_iter().map(|(_, entry)| entry).collect(), records))
+    })
+}
+
+/// Entries after `after` already delivered to `consumer`, a deleted one standing as its ID and a nil
+fn read_history(db: &mut Database, key: &Bytes, name: &Bytes, consumer: &Bytes, after: StreamId, count: usize) -> Result<(Vec<RespValue>, Vec<RespValue>)> {
+    with_group(db, key, name, |stream, group| {
+        let entries = group
+            .pending
+            .range((Bound::Excluded(after), Bound::Unbounded))
+            .filter(|(_, pending)| pending.consumer == consumer)
+            .take(count)
+            .map(|(id, _)| match stream.entries.get(id) {
+                Some(fields) => entry_reply(id, fields),
+                None => RespValue::Array(vec![id_reply(*id), NULL_RESP.clone()]),
+            })
+            .collect();
+        let mut records = vec![];
+        touch_consumer(group, key, name, consumer, now_ms(), false, &mut records);
+        Ok((entries, records))
+    })
+}
+
+/// XREADGROUP GROUP group consumer [COUNT count] [BLOCK milliseconds] [NOACK] STREAMS key [key ...] id|> [id|> ...],
+/// logged as the XCLAIM and XGROUP SETID that replay the deliveries
+#[router_macro::route("XREADGROUP")]
+async fn xreadgroup(context : Arc<Context>, request: RespRequest) -> anyhow::Result<RespValue> {
+    check_args(&request, "xreadgroup", 6, None)?;
+    if !request.args[0].as_bytes()?.eq_ignore_ascii_case(b"GROUP") {
+        return Err(Error::Syntax.into());
+    }
+    let name = request.args[1].as_bytes()?.clone();
+    let consumer = request.args[2].as_bytes()?.clone();
+    let read = ReadArgs::parse(&request.args[3..], "xreadgroup", true)?;
+    let protocol = context.protocol();
+    // None reads new entries, an ID reads the history of the consumer
+    let ids = read
+        .ids
+        .iter()
+        .map(|id| if id.as_ref() == b">" { Ok(None) } else { parse_id(id, 0).map(Some) })
+        .collect::<Result<Vec<_>>>()?;
+
+    let mut db = DB.write().await;
+    // every group must exist before anything is delivered
+    for key in &read.keys {
+        with_group(&mut db, key, &name, |_, _| Ok(()))?;
+    }
+    let mut streams = vec![];
+    for (key, id) in read.keys.iter().zip(&ids) {
+        let (entries, records) = match id {
+            None => deliver_new(&mut db, key, &name, &consumer, read.count, read.no_ack)?,
+            Some(after) => read_history(&mut db, key, &name, &consumer, *after, read.count)?,
+        };
+        for record in &records {
+            db.append_log(record).await?;
+        }
+        if id.is_some() || !entries.is_empty() {
+            streams.push((key.clone(), entries));
+        }
+    }
+    if !streams.is_empty() {
+        return Ok(streams_reply(protocol, streams));
+    }
+    let Some(timeout) = read.block else {
+        return Ok(RespValue::NullArray);
+    };
+
+    let (count, no_ack) = (read.count, read.no_ack);
+    let serve = move |db: &mut Database, key: &Bytes| -> Result<Option<Served>> {
+        let (entries, records) = deliver_new(db, key, &name, &consumer, count, no_ack)?;
+        Ok((!entries.is_empty()).then(|| Served { reply: streams_reply(protocol, vec![(key.clone(), entries)]), records }))
+    };
+    block(context, db, read.keys, timeout, Box::new(serve)).await
+}
+
+/// XACK key group id [id ...]
+#[router_macro::route("XACK")]
+async fn xack(_context : Arc<Context>, request: RespRequest) -> anyhow::Result<RespValue> {
+    check_args(&request, "xack", 3, None)?;
+    let key = request.args[0].as_bytes()?;
+    let ids = request.args[2..].iter().map(|arg| arg_id(arg, 0)).collect::<Result<Vec<_>>>()?;
+
+    let mut db = DB.write().await;
+    let group = get_stream_mut(&mut db, key)?.and_then(|stream| stream.groups.get_mut(request.args[1].as_bytes().ok()?));
+    let Some(group) = group else {
+        return Ok(RespValue::Integer(0));
+    };
+    let acked = ids.iter().filter(|id| group.pending.remove(id).is_some()).count();
+    if acked > 0 {
+        db.append_log(&request.to_resp()).await?;
+    }
+    Ok(RespValue::Integer(acked as i64))
+}
+
+/// XPENDING key group [[IDLE min-idle-time] start end count [consumer]]
+#[router_macro::route("XPENDING")]
+async fn xpending(_context : Arc<Context>, request: RespRequest) -> anyhow::Result<RespValue> {
+    check_args(&request, "xpending", 2, Some(8))?;
+    let key = request.args[0].as_bytes()?;
+    let name = request.args[1].as_bytes()?;
+
+    let db = DB.read().await;
+    let group = get_stream(&db, key)?.and_then(|stream| stream.groups.get(name)).ok_or_else(|| no_group(key, name))?;
+    let now = now_ms();
+
+    if request.args.len() == 2 {
+        let (Some((first, _)), Some((last, _))) = (group.pending.first_key_value(), group.pending.last_key_value()) else {
+            return Ok(RespValue::Array(vec![RespValue::Integer(0), NULL_RESP.clone(), NULL_RESP.clone(), RespValue::NullArray]));
+        };
+        let mut consumers: BTreeMap<&Bytes, u64> = BTreeMap::new();
+        for pending in group.pending.values() {
+            *consumers.entry(&pending.consumer).or_default() += 1;
+        }
+        let consumers = consumers
+            .into_iter()
+            .map(|(consumer, count)| RespValue::bulk_array([consumer.clone(), count.to_string().into()]))
+            .collect();
+        return Ok(RespValue::Array(vec![
+            RespValue::Integer(group.pending.len() as i64),
+            id_reply(*first),
+            id_reply(*last),
+            RespValue::Array(consumers),
+        ]));
+    }
+
+    let mut args = &request.args[2..];
+    let mut min_idle = 0;
+    if args[0].as_bytes()?.eq_ignore_ascii_case(b"IDLE") {
+        min_idle = arg_i64(args.get(1).ok_or(Error::Syntax)?)?;
+        args = &args[2..];
+    }
+    if args.len() != 3 && args.len() != 4 {
+        return Err(Error::Syntax.into());
+    }
+    let (start, end) = (parse_bound(&args[0], true)?, parse_bound(&args[1], false)?);
+    let count = arg_i64(&args[2])?.max(0) as usize;
+    let consumer = args.get(3).map(RespValue::as_bytes).transpose()?;
+    if start > end {
+        return Ok(RespValue::Array(vec![]));
+    }
+
+    let entries = group
+        .pending
+        .range(start..=end)
+        .filter(|(_, pending)| consumer.is_none_or(|consumer| pending.consumer == consumer))
+        .filter(|(_, pending)| now - pending.delivered_at >= min_idle)
+        .take(count)
+        .map(|(id, pending)| {
+            RespValue::Array(vec![
+                id_reply(*id),
+                RespValue::BulkString(Some(pending.consumer.clone())),
+                RespValue::Integer((now - pending.delivered_at).max(0)),
+                RespValue::Integer(pending.deliveries as i64),
+            ])
+        })
+        .collect();
+    Ok(RespValue::Array(entries))
+}
+
+/// Options of XCLAIM after its IDs
+#[derive(Debug, Default)]
+struct ClaimOptions {
+    delivered_at: Option<i64>,
+    retry_count: Option<u64>,
+    force: bool,
+    just_id: bool,
+    last_id: Option<StreamId>,
+}
+
+/// Gives the pending entry `id` to `consumer`, unless it was deleted from the stream in the meantime
+fn claim(stream: &Stream, group: &mut ConsumerGroup, id: StreamId, consumer: &Bytes, delivered_at: i64, count: bool) -> Option<PendingEntry> {
+    if !stream.entries.contains_key(&id) {
+        group.pending.remove(&id);
+        return None;
+    }
+    let pending = group.pending.get_mut(&id)?;
+    pending.consumer = consumer.clone();
+    pending.delivered_at = delivered_at;
+    if count {
+        pending.deliveries += 1;
+    }
+    Some(pending.clone())
+}
+
+/// XCLAIM key group consumer min-idle-time id [id ...] [IDLE ms] [TIME unix-time-ms] [RETRYCOUNT count] [FORCE] [JUSTID] [LASTID id],
+/// logged as one XCLAIM per entry with its exact delivery time and count
+#[router_macro::route("XCLAIM")]
+async fn xclaim(_context : Arc<Context>, request: RespRequest) -> anyhow::Result<RespValue> {
+    check_args(&request, "xclaim", 5, None)?;
+    let key = request.args[0].as_bytes()?;
+    let name = request.args[1].as_bytes()?;
+    let consumer = request.args[2].as_bytes()?;
+    let min_idle = arg_i64(&request.args[3])
+        .map_err(|_| Error::Other("Invalid min-idle-time argument for XCLAIM".into()))?
+        .max(0);
+
+    let mut i = 4;
+    let mut ids = vec![];
+    while let Some(Ok(id)) = request.args.get(i).map(|arg| arg_id(arg, 0)) {
+        ids.push(id);
+        i += 1;
+    }
+    let now = now_ms();
+    let mut options = ClaimOptions::default();
+    let mut args = request.args[i..].iter();
+    while let Some(arg) = args.next() {
+        let mut value = || args.next().ok_or(Error::Syntax);
+        match arg.as_bytes()?.to_ascii_uppercase().as_slice() {
+            b"IDLE" => options.delivered_at = Some(now - arg_i64(value()?)?.max(0)),
+            b"TIME" => options.delivered_at = Some(arg_i64(value()?)?),
+            b"RETRYCOUNT" => options.retry_count = Some(arg_i64(value()?)?.max(0) as u64),
+            b"LASTID" => options.last_id = Some(arg_id(value()?, 0)?),
+            b"FORCE" => options.force = true,
+            b"JUSTID" => options.just_id = true,
+            _ => return Err(Error::Other(format!("Unrecognized XCLAIM option '{}'", String::from_utf8_lossy(arg.as_bytes()?))).into()),
+        }
+    }
+    let delivered_at = options.delivered_at.unwrap_or(now).min(now);
+
+    let mut db = DB.write().await;
+    let (reply, records) = with_group(&mut db, key, name, |stream, group| {
+        let mut records = vec![];
+        if let Some(last_id) = options.last_id.filter(|last_id| *last_id > group.last_id) {
+            group.last_id = last_id;
+            records.push(set_id_record(key, name, group));
+        }
+
+        let mut claimed = vec![];
+        for id in ids {
+            match group.pending.get(&id) {
+                None if options.force && stream.entries.contains_key(&id) => {
+                    group.pending.insert(id, PendingEntry { consumer: consumer.clone(), delivered_at, deliveries: 1 });
+                }
+                // an entry deleted since it was added is forced into the list as is, which is how a rewritten
+                // log restores the pending entries of deleted messages, it is not part of the reply
+                None if options.force && id <= stream.last_id => {
+                    let pending = PendingEntry { consumer: consumer.clone(), delivered_at, deliveries: options.retry_count.unwrap_or(1) };
+                    records.push(claim_record(key, name, consumer, id, &pending));
+                    group.pending.insert(id, pending);
+                    continue;
+                }
+                None => continue,
+                Some(pending) if min_idle > 0 && now - pending.delivered_at < min_idle => continue,
+                Some(_) => {}
+            }
+            let was_pending = group.pending.contains_key(&id);
+            match claim(stream, group, id, consumer, delivered_at, !options.just_id) {
+                Some(mut pending) => {
+                    if let Some(retry_count) = options.retry_count {
+                        pending.deliveries = retry_count;
+                        group.pending.insert(id, pending.clone());
+                    }
+                    records.push(claim_record(key, name, consumer, id, &pending));
+                    claimed.push(id);
+                }
+                None if was_pending => records.push(RespValue::bulk_array(["XACK".into(), key.clone(), name.clone(), id.to_bytes()])),
+                None => {}
+            }
+        }
+        touch_consumer(group, key, name, consumer, now, !claimed.is_empty(), &mut records);
+
+        let reply: Vec<RespValue> = claimed
+            .iter()
+            .map(|id| match options.just_id {
+                true => id_reply(*id),
+                false => entry_reply(id, &stream.entries[id]),
+            })
+            .collect();
+        Ok((reply, records))
+    })?;
+    for record in &records {
+        db.append_log(record).await?;
+    }
+    Ok(RespValue::Array(reply))
+}
+
+/// XAUTOCLAIM key group consumer min-idle-time start [COUNT count] [JUSTID]
+#[router_macro::route("XAUTOCLAIM")]
+async fn xautoclaim(_context : Arc<Context>, request: RespRequest) -> anyhow::Result<RespValue> {
+    check_args(&request, "xautoclaim", 5, Some(8))?;
+    let key = request.args[0].as_bytes()?;
+    let name = request.args[1].as_bytes()?;
+    let consumer = request.args[2].as_bytes()?;
+    let min_idle = arg_i64(&request.args[3])
+        .map_err(|_| Error::Other("Invalid min-idle-time argument for XAUTOCLAIM".into()))?
+        .max(0);
+    let start = parse_bound(&request.args[4], true)?;
+
+    let mut count = 100;
+    let mut just_id = false;
+    let mut args = request.args[5..].iter();
+    while let Some(arg) = args.next() {
+        match arg.as_bytes()?.to_ascii_uppercase().as_slice() {
+            b"COUNT" => {
+                let value = arg_i64(args.next().ok_or(Error::Syntax)?)?;
+                // bounded like redis, which scans up to ten times more entries than it claims
+                if !(1..=i64::MAX / 10).contains(&value) {
+                    return Err(Error::Other("COUNT must be > 0".into()).into());
+                }
+                count = value as usize;
+            }
+            b"JUSTID" => just_id = true,
+            _ => return Err(Error::Syntax.into()),
+        }
+    }
+
+    let now = now_ms();
+    let mut db = DB.write().await;
+    let (reply, records) = with_group(&mut db, key, name, |stream, group| {
+        let mut attempts = count * 10;
+        let mut candidates = vec![];
+        let mut next = StreamId::MIN;
+        for (id, pending) in group.pending.range(start..) {
+            if attempts == 0 || candidates.len() == count {
+                next = *id;
+                break;
+            }
+            attempts -= 1;
+            if !stream.entries.contains_key(id) || now - pending.delivered_at >= min_idle {
+                candidates.push(*id);
+            }
+        }
+
+        let mut records = vec![];
+        let mut claimed = vec![];
+        let mut deleted = vec![];
+        for id in candidates {
+            match claim(stream, group, id, consumer, now, !just_id) {
+                Some(pending) => {
+                    records.push(claim_record(key, name, consumer, id, &pending));
+                    claimed.push(id);
+                }
+                None => {
+                    records.push(RespValue::bulk_array(["XACK".into(), key.clone(), name.clone(), id.to_bytes()]));
+                    deleted.push(id);
+                }
+            }
+        }
+        touch_consumer(group, key, name, consumer, now, !claimed.is_empty(), &mut records);
+
+        let entries = claimed
+            .iter()
+            .map(|id| match just_id {
+                true => id_reply(*id),
+                false => entry_reply(id, &stream.entries[id]),
+            })
+            .collect();
+        let reply = RespValue::Array(vec![
+            id_reply(next),
+            RespValue::Array(entries),
+            RespValue::bulk_array(deleted.into_iter().map(StreamId::to_bytes)),
+        ]);
+        Ok((reply, records))
+    })?;
+    for record in &records {
+        db.append_log(record).await?;
+    }
+    Ok(reply)
+}
+
+/// ENTRIESREAD n, -1 standing for an unknown count
+fn parse_entries_read(arg: Option<&RespValue>) -> Result<Option<u64>> {
+    let read = arg_i64(arg.ok_or(Error::Syntax)?)?;
+    if read < -1 {
+        return Err(Error::Other("value for ENTRIESREAD must be positive or -1".into()));
+    }
+    Ok(u64::try_from(read).ok())
+}
+
+/// The ID of XGROUP CREATE and SETID, None standing for "$", the last ID of the stream
+fn group_id(arg: &RespValue) -> Result<Option<StreamId>> {
+    match arg.as_bytes()?.as_ref() {
+        b"$" => Ok(None),
+        _ => arg_id(arg, 0).map(Some),
+    }
+}
+
+fn no_such_group(key: &[u8], group: &[u8]) -> Error {
+    Error::Other(format!(
+        "NOGROUP No such consumer group '{}' for key name '{}'",
+        String::from_utf8_lossy(group),
+        String::from_utf8_lossy(key)
+    ))
+}
+
+/// XGROUP CREATE|SETID|DESTROY|CREATECONSUMER|DELCONSUMER key group ...
+#[router_macro::route("XGROUP")]
+async fn xgroup(_context : Arc<Context>, request: RespRequest) -> anyhow::Result<RespValue> {
+    check_args(&request, "xgroup", 3, None)?;
+    let subcommand = request.args[0].as_bytes()?.to_ascii_uppercase();
+    let (min, max) = match subcommand.as_slice() {
+        b"CREATE" => (4, 7),
+        b"SETID" => (4, 6),
+        b"DESTROY" => (3, 3),
+        b"CREATECONSUMER" | b"DELCONSUMER" => (4, 4),
+        _ => {
+            let name = String::from_utf8_lossy(request.args[0].as_bytes()?).into_owned();
+            return Err(Error::Other(format!("unknown subcommand '{}'. Try XGROUP HELP.", name)).into());
+        }
+    };
+    let full_name = format!("xgroup|{}", String::from_utf8_lossy(&subcommand).to_lowercase());
+    check_args(&request, &full_name, min, Some(max))?;
+    let key = request.args[1].as_bytes()?;
+    let name = request.args[2].as_bytes()?;
+
+    let mut mkstream = false;
+    let mut entries_read = None;
+    if matches!(subcommand.as_slice(), b"CREATE" | b"SETID") {
+        let mut options = request.args[4..].iter();
+        while let Some(option) = options.next() {
+            match option.as_bytes()?.to_ascii_uppercase().as_slice() {
+                b"MKSTREAM" if subcommand == b"CREATE" => mkstream = true,
+                b"ENTRIESREAD" => entries_read = Some(parse_entries_read(options.next())?),
+                _ => return Err(Error::Syntax.into()),
+            }
+        }
+    }
+    // checked before MKSTREAM creates anything
+    let id = match subcommand.as_slice() {
+        b"CREATE" | b"SETID" => group_id(&request.args[3])?,
+        _ => None,
+    };
+
+    let mut db = DB.write().await;
+    if get_stream(&db, key)?.is_none() {
+        if !mkstream {
+            return Err(Error::Other(
+                "The XGROUP subcommand requires the key to exist. Note that for CREATE you may want to use the MKSTREAM option to create an empty stream automatically.".into(),
+            ).into());
+        }
+        db.put(key.clone(), Entry::new(Value::Stream(Stream::new())));
+    }
+    let stream = get_stream_mut(&mut db, key)?.expect("stream was just created");
+
+    let (reply, record) = match subcommand.as_slice() {
+        b"CREATE" | b"SETID" => {
+            let id = id.unwrap_or(stream.last_id);
+            let entries_read = entries_read.unwrap_or_else(|| stream.entries_read_at(id));
+            if subcommand == b"CREATE" {
+                if stream.groups.contains_key(name) {
+                    return Err(Error::Other("BUSYGROUP Consumer Group name already exists".into()).into());
+                }
+                stream.groups.insert(name.clone(), ConsumerGroup::new(id, entries_read));
+            } else {
+                let group = stream.groups.get_mut(name).ok_or_else(|| no_such_group(key, name))?;
+                group.last_id = id;
+                group.entries_read = entries_read;
+            }
+            // logged with the ID "$" stood for
+            let mut record = vec!["XGROUP".into(), subcommand.clone().into(), key.clone(), name.clone(), id.to_bytes()];
+            if mkstream {
+                record.push("MKSTREAM".into());
+            }
+            record.extend(["ENTRIESREAD".into(), entries_read.map_or("-1".into(), |read| read.to_string()).into()]);
+            (OK_RESP.clone(), Some(RespValue::bulk_array(record)))
+        }
+        b"DESTROY" => {
+            let destroyed = stream.groups.remove(name).is_some();
+            // consumers blocked on the group get an error
+            blocking::signal(key);
+            (RespValue::Integer(destroyed as i64), destroyed.then(|| request.to_resp()))
+        }
+        b"CREATECONSUMER" => {
+            let group = stream.groups.get_mut(name).ok_or_else(|| no_such_group(key, name))?;
+            let (_, created) = group.consumer(request.args[3].as_bytes()?, now_ms());
+            (RespValue::Integer(created as i64), created.then(|| request.to_resp()))
+        }
+        _ => {
+            let group = stream.groups.get_mut(name).ok_or_else(|| no_such_group(key, name))?;
+            let consumer = request.args[3].as_bytes()?;
+            let existed = group.consumers.remove(consumer).is_some();
+            let before = group.pending.len();
+            group.pending.retain(|_, pending| pending.consumer != consumer);
+            (RespValue::Integer((before - group.pending.len()) as i64), existed.then(|| request.to_resp()))
+        }
+    };
+    if let Some(record) = record {
+        db.append_log(&record).await?;
+    }
+    blocking::serve_ready(&mut db).await?;
+    Ok(reply)
+}
+
+/// XSETID key last-id [ENTRIESADDED entries-added] [MAXDELETEDID max-deleted-id]
+#[router_macro::route("XSETID")]
+async fn xsetid(_context : Arc<Context>, request: RespRequest) -> anyhow::Result<RespValue> {
+    check_args(&request, "xsetid", 2, Some(6))?;
+    let key = request.args[0].as_bytes()?;
+    let id = arg_id(&request.args[1], 0)?;
+    let mut entries_added = None;
+    let mut max_deleted_id = None;
+    let mut options = request.args[2..].iter();
+    while let Some(option) = options.next() {
+        let value = options.next().ok_or(Error::Syntax)?;
+        match option.as_bytes()?.to_ascii_uppercase().as_slice() {
+            b"ENTRIESADDED" => {
+                let added = arg_i64(value)?;
+                if added < 0 {
+                    return Err(Error::Other("entries_added must be positive".into()).into());
+                }
+                entries_added = Some(added as u64);
+            }
+            b"MAXDELETEDID" => max_deleted_id = Some(arg_id(value, 0)?),
+            _ => return Err(Error::Syntax.into()),
+        }
+    }
+
+    let mut db = DB.write().await;
+    let stream = get_stream_mut(&mut db, key)?.ok_or_else(|| Error::Other("no such key".into()))?;
+    if max_deleted_id.is_some_and(|max_deleted_id| id < max_deleted_id) {
+        return Err(Error::Other("The ID specified in XSETID is smaller than the provided max_deleted_entry_id".into()).into());
+    }
+    if entries_added.is_some_and(|added| added < stream.len() as u64) {
+        return Err(Error::Other("The entries_added specified in XSETID is smaller than the target stream length".into()).into());
+    }
+    if stream.entries.last_key_value().is_some_and(|(top, _)| id < *top) {
+        return Err(Error::Other("The ID specified in XSETID is smaller than the target stream top item".into()).into());
+    }
+    stream.last_id = id;
+    if let Some(added) = entries_added {
+        stream.entries_added = added;
+    }
+    if let Some(max_deleted_id) = max_deleted_id {
+        stream.max_deleted_id = max_deleted_id;
+    }
+    db.append_log(&request.to_resp()).await?;
+    Ok(OK_RESP.clone())
+}
+
+fn optional_u64(value: Option<u64>) -> RespValue {
+    value.map_or(NULL_RESP.clone(), |value| RespValue::Integer(value as i64))
+}
+
+fn stream_info(stream: &Stream, full: Option<usize>) -> RespValue {
+    let mut info = vec![
+        ("length", RespValue::Integer(stream.len() as i64)),
+        ("last-generated-id", id_reply(stream.last_id)),
+        ("max-deleted-entry-id", id_reply(stream.max_deleted_id)),
+        ("entries-added", RespValue::Integer(stream.entries_added as i64)),
+        ("recorded-first-entry-id", id_reply(stream.first_id().unwrap_or_default())),
+    ];
+    let Some(count) = full else {
+        let edge = |entry: Option<(&StreamId, &Fields)>| entry.map_or(NULL_RESP.clone(), |(id, fields)| entry_reply(id, fields));
+        info.extend([
+            ("groups", RespValue::Integer(stream.groups.len() as i64)),
+            ("first-entry", edge(stream.entries.first_key_value())),
+            ("last-entry", edge(stream.entries.last_key_value())),
+        ]);
+        return info_reply(info);
+    };
+
+    let entries = stream.entries.iter().take(count).map(|(id, fields)| entry_reply(id, fields)).collect();
+    let groups = stream
+        .groups
+        .iter()
+        .map(|(name, group)| {
+            let pending = group.pending.iter().take(count).map(|(id, pending)| {
+                RespValue::Array(vec![
+                    id_reply(*id),
+                    RespValue::BulkString(Some(pending.consumer.clone())),
+                    RespValue::Integer(pending.delivered_at),
+                    RespValue::Integer(pending.deliveries as i64),
+                ])
+            });
+            let consumers = group.consumers.iter().map(|(consumer, state)| {
+                let pending: Vec<_> = group.pending_of(consumer).collect();
+                let listed = pending.iter().take(count).map(|(id, pending)| {
+                    RespValue::Array(vec![id_reply(**id), RespValue::Integer(pending.delivered_at), RespValue::Integer(pending.deliveries as i64)])
+                });
+                info_reply(vec![
+                    ("name", RespValue::BulkString(Some(consumer.clone()))),
+                    ("seen-time", RespValue::Integer(state.seen_at)),
+                    ("active-time", RespValue::Integer(state.active_at)),
+                    ("pel-count", RespValue::Integer(pending.len() as i64)),
+                    ("pending", RespValue::Array(listed.collect())),
+                ])
+            });
+            info_reply(vec![
+                ("name", RespValue::BulkString(Some(name.clone()))),
+                ("last-delivered-id", id_reply(group.last_id)),
+                ("entries-read", optional_u64(group.entries_read)),
+                ("lag", optional_u64(stream.lag(group))),
+                ("pel-count", RespValue::Integer(group.pending.len() as i64)),
+                ("pending", RespValue::Array(pending.collect())),
+                ("consumers", RespValue::Array(consumers.collect())),
+            ])
+        })
+        .collect();
+    info.extend([("entries", RespValue::Array(entries)), ("groups", RespValue::Array(groups))]);
+    info_reply(info)
+}
+
+/// XINFO STREAM key [FULL [COUNT count]], XINFO GROUPS key, XINFO CONSUMERS key group
+#[router_macro::route("XINFO")]
+async fn xinfo(_context : Arc<Context>, request: RespRequest) -> anyhow::Result<RespValue> {
+    check_args(&request, "xinfo", 2, None)?;
+    let subcommand = request.args[0].as_bytes()?.to_ascii_uppercase();
+    let key = request.args[1].as_bytes()?;
+    let db = DB.read().await;
+    let stream = || -> Result<&Stream> { get_stream(&db, key)?.ok_or_else(|| Error::Other("no such key".into())) };
+    let now = now_ms();
+
+    match subcommand.as_slice() {
+        b"STREAM" => {
+            let full = match &request.args[2..] {
+                [] => None,
+                [full] if full.as_bytes()?.eq_ignore_ascii_case(b"FULL") => Some(10),
+                [full, option, count] if full.as_bytes()?.eq_ignore_ascii_case(b"FULL") && option.as_bytes()?.eq_ignore_ascii_case(b"COUNT") => {
+                    // 0 stands for everything
+                    let count = arg_i64(count)?;
+                    Some(if count <= 0 { usize::MAX } else { count as usize })
+                }
+                _ => return Err(Error::Syntax.into()),
+            };
+            Ok(stream_info(stream()?, full))
+        }
+        b"GROUPS" => {
+            check_args(&request, "xinfo|groups", 2, Some(2))?;
+            let stream = stream()?;
+            let groups = stream.groups.iter().map(|(name, group)| {
+                info_reply(vec![
+                    ("name", RespValue::BulkString(Some(name.clone()))),
+                    ("consumers", RespValue::Integer(group.consumers.len() as i64)),
+                    ("pending", RespValue::Integer(group.pending.len() as i64)),
+                    ("last-delivered-id", id_reply(group.last_id)),
+                    ("entries-read", optional_u64(group.entries_read)),
+                    ("lag", optional_u64(stream.lag(group))),
+                ])
+            });
+            Ok(RespValue::Array(groups.collect()))
+        }
+        b"CONSUMERS" => {
+            check_args(&request, "xinfo|consumers", 3, Some(3))?;
+            let name = request.args[2].as_bytes()?;
+            let group = stream()?.groups.get(name).ok_or_else(|| no_such_group(key, name))?;
+            let consumers = group.consumers.iter().map(|(consumer, state)| {
+                info_reply(vec![
+                    ("name", RespValue::BulkString(Some(consumer.clone()))),
+                    ("pending", RespValue::Integer(group.pending_of(consumer).count() as i64)),
+                    ("idle", RespValue::Integer((now - state.seen_at).max(0))),
+                    ("inactive", RespValue::Integer(if state.active_at < 0 { -1 } else { (now - state.active_at).max(0) })),
+                ])
+            });
+            Ok(RespValue::Array(consumers.collect()))
+        }
+        _ => {
+            let name = String::from_utf8_lossy(request.args[0].as_bytes()?).into_owned();
+            Err(Error::Other(format!("unknown subcommand '{}'. Try XINFO HELP.", name)).into())
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::redis_types::test_util::{bulk, bulks, context, request};
+
+    fn entry(id: &str, fields: &[&str]) -> RespValue {
+        RespValue::Array(vec![bulk(id), bulks(fields)])
+    }
+
+    fn integer(reply: &RespValue) -> i64 {
+        match reply {
+            RespValue::Integer(n) => *n,
+            other => panic!("not an integer: {:?}", other),
+        }
+    }
+
+    #[tokio::test]
+    async fn add_range_and_trim() -> anyhow::Result<()> {
+        for id in ["1-1", "1-2", "2-0", "3-5"] {
+            xadd(context(), request("XADD", &["stream:s1", id, "f", id])).await?;
+        }
+        assert_eq!(xadd(context(), request("XADD", &["stream:s1", "3-*", "f", "x"])).await?, bulk("3-6"));
+        assert!(xadd(context(), request("XADD", &["stream:s1", "3-6", "f", "x"])).await.is_err());
+        assert!(xadd(context(), request("XADD", &["stream:s1", "2-*", "f", "x"])).await.is_err());
+        assert!(xadd(context(), request("XADD", &["stream:s2", "0-0", "f", "x"])).await.is_err());
+        assert!(xadd(context(), request("XADD", &["stream:s1", "*", "f"])).await.is_err());
+        assert_eq!(xadd(context(), request("XADD", &["stream:s2", "NOMKSTREAM", "*", "f", "x"])).await?, RespValue::BulkString(None));
+        assert_eq!(xlen(context(), request("XLEN", &["stream:s1"])).await?, RespValue::Integer(5));
+
+        let range = xrange(context(), request("XRANGE", &["stream:s1", "(1-1", "2"])).await?;
+        assert_eq!(range, RespValue::Array(vec![entry("1-2", &["f", "1-2"]), entry("2-0", &["f", "2-0"])]));
+        let range = xrevrange(context(), request("XREVRANGE", &["stream:s1", "+", "-", "COUNT", "1"])).await?;
+        assert_eq!(range, RespValue::Array(vec![entry("3-6", &["f", "x"])]));
+        assert_eq!(xrange(context(), request("XRANGE", &["stream:s1", "3", "1"])).await?, RespValue::Array(vec![]));
+
+        assert_eq!(xdel(context(), request("XDEL", &["stream:s1", "2-0", "9-9"])).await?, RespValue::Integer(1));
+        assert_eq!(xtrim(context(), request("XTRIM", &["stream:s1", "MAXLEN", "3"])).await?, RespValue::Integer(1));
+        assert!(xtrim(context(), request("XTRIM", &["stream:s1", "MAXLEN", "1", "LIMIT", "1"])).await.is_err());
+        assert_eq!(xtrim(context(), request("XTRIM", &["stream:s1", "MINID", "~", "4", "LIMIT", "1"])).await?, RespValue::Integer(1));
+        xadd(context(), request("XADD", &["stream:s1", "MAXLEN", "=", "1", "*", "f", "y"])).await?;
+        assert_eq!(xlen(context(), request("XLEN", &["stream:s1"])).await?, RespValue::Integer(1));
+
+        // emptied streams stay around
+        assert_eq!(xtrim(context(), request("XTRIM", &["stream:s1", "MAXLEN", "0"])).await?, RespValue::Integer(1));
+        assert!(DB.read().await.get(b"stream:s1").is_some());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn groups_deliver_at_least_once() -> anyhow::Result<()> {
+        for id in ["1-0", "2-0", "3-0"] {
+            xadd(context(), request("XADD", &["stream:g", id, "n", id])).await?;
+        }
+        xgroup(context(), request("XGROUP", &["CREATE", "stream:g", "workers", "0"])).await?;
+        assert!(xgroup(context(), request("XGROUP", &["CREATE", "stream:g", "workers", "$"])).await.is_err());
+        assert!(xgroup(context(), request("XGROUP", &["CREATE", "stream:none", "workers", "$"])).await.is_err());
+        assert!(xgroup(context(), request("XGROUP", &["CREATE", "stream:none", "workers", "bad", "MKSTREAM"])).await.is_err());
+        assert!(DB.read().await.get(b"stream:none").is_none());
+
+        let read = |consumer: &'static str, id: &'static str| {
+            xreadgroup(context(), request("XREADGROUP", &["GROUP", "workers", consumer, "COUNT", "2", "STREAMS", "stream:g", id]))
+        };
+        let reply = read("alice", ">").await?;
+        let entries = RespValue::Array(vec![entry("1-0", &["n", "1-0"]), entry("2-0", &["n", "2-0"])]);
+        assert_eq!(reply, RespValue::Array(vec![RespValue::Array(vec![bulk("stream:g"), entries])]));
+        read("bob", ">").await?;
+        assert_eq!(read("bob", ">").await?, RespValue::NullArray);
+
+        let summary = xpending(context(), request("XPENDING", &["stream:g", "workers"])).await?;
+        let consumers = RespValue::Array(vec![bulks(&["alice", "2"]), bulks(&["bob", "1"])]);
+        assert_eq!(summary, RespValue::Array(vec![RespValue::Integer(3), bulk("1-0"), bulk("3-0"), consumers]));
+
+        assert_eq!(xack(context(), request("XACK", &["stream:g", "workers", "1-0", "1-0"])).await?, RespValue::Integer(1));
+        let history = read("alice", "0").await?;
+        let entries = RespValue::Array(vec![entry("2-0", &["n", "2-0"])]);
+        assert_eq!(history, RespValue::Array(vec![RespValue::Array(vec![bulk("stream:g"), entries])]));
+
+        // bob crashed, alice takes his entry over
+        let claimed = xclaim(context(), request("XCLAIM", &["stream:g", "workers", "alice", "0", "3-0", "JUSTID"])).await?;
+        assert_eq!(claimed, bulks(&["3-0"]));
+        let pending = xpending(context(), request("XPENDING", &["stream:g", "workers", "-", "+", "10", "alice"])).await?;
+        let RespValue::Array(pending) = pending else { panic!() };
+        assert_eq!(pending.len(), 2);
+
+        xdel(context(), request("XDEL", &["stream:g", "2-0"])).await?;
+        let reply = xautoclaim(context(), request("XAUTOCLAIM", &["stream:g", "workers", "bob", "0", "-", "COUNT", "1"])).await?;
+        assert_eq!(reply, RespValue::Array(vec![bulk("3-0"), RespValue::Array(vec![]), bulks(&["2-0"])]));
+        let reply = xautoclaim(context(), request("XAUTOCLAIM", &["stream:g", "workers", "bob", "0", "3-0"])).await?;
+        assert_eq!(reply, RespValue::Array(vec![bulk("0-0"), RespValue::Array(vec![entry("3-0", &["n", "3-0"])]), bulks(&[])]));
+        let pending = xpending(context(), request("XPENDING", &["stream:g", "workers", "-", "+", "10"])).await?;
+        let RespValue::Array(pending) = pending else { panic!() };
+        let RespValue::Array(pending) = &pending[0] else { panic!() };
+        // JUSTID claims do not count as deliveries
+        assert_eq!((&pending[0], &pending[1], &pending[3]), (&bulk("3-0"), &bulk("bob"), &RespValue::Integer(2)));
+
+        assert_eq!(xgroup(context(), request("XGROUP", &["DELCONSUMER", "stream:g", "workers", "bob"])).await?, RespValue::Integer(1));
+        assert_eq!(xgroup(context(), request("XGROUP", &["DESTROY", "stream:g", "workers"])).await?, RespValue::Integer(1));
+        assert!(read("alice", ">").await.is_err());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn blocking_reads() -> anyhow::Result<()> {
+        xadd(context(), request("XADD", &["stream:b", "1-0", "f", "old"])).await?;
+        let waiter = tokio::spawn(xread(context(), request("XREAD", &["BLOCK", "0", "STREAMS", "stream:b", "$"])));
+        tokio::time::sleep(Duration::from_millis(20)).await;
+        assert!(!waiter.is_finished());
+        xadd(context(), request("XADD", &["stream:b", "2-0", "f", "new"])).await?;
+        let entries = RespValue::Array(vec![entry("2-0", &["f", "new"])]);
+        assert_eq!(waiter.await??, RespValue::Array(vec![RespValue::Array(vec![bulk("stream:b"), entries])]));
+
+        xgroup(context(), request("XGROUP", &["CREATE", "stream:b", "g", "$"])).await?;
+        let args = &["GROUP", "g", "c", "BLOCK", "0", "STREAMS", "stream:b", ">"];
+        let waiter = tokio::spawn(xreadgroup(context(), request("XREADGROUP", args)));
+        tokio::time::sleep(Duration::from_millis(20)).await;
+        xadd(context(), request("XADD", &["stream:b", "3-0", "f", "last"])).await?;
+        let entries = RespValue::Array(vec![entry("3-0", &["f", "last"])]);
+        assert_eq!(waiter.await??, RespValue::Array(vec![RespValue::Array(vec![bulk("stream:b"), entries])]));
+        let pending = xpending(context(), request("XPENDING", &["stream:b", "g"])).await?;
+        let RespValue::Array(pending) = pending else { panic!() };
+        assert_eq!(pending[0], RespValue::Integer(1));
+
+        let reply = xread(context(), request("XREAD", &["BLOCK", "50", "STREAMS", "stream:b", "$"])).await?;
+        assert_eq!(reply, RespValue::NullArray);
+        assert!(xread(context(), request("XREAD", &["STREAMS", "stream:b", "stream:c", "$"])).await.is_err());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn info_and_set_id() -> anyhow::Result<()> {
+        for id in ["1-0", "2-0", "3-0"] {
+            xadd(context(), request("XADD", &["stream:i", id, "f", "v"])).await?;
+        }
+        xgroup(context(), request("XGROUP", &["CREATE", "stream:i", "g", "0"])).await?;
+        xreadgroup(context(), request("XREADGROUP", &["GROUP", "g", "c", "COUNT", "1", "STREAMS", "stream:i", ">"])).await?;
+
+        let RespValue::Array(groups) = xinfo(context(), request("XINFO", &["GROUPS", "stream:i"])).await? else { panic!() };
+        let RespValue::Map(group) = &groups[0] else { panic!() };
+        let field = |name: &str| group.iter().find(|(key, _)| *key == bulk(name)).map(|(_, value)| value.clone()).unwrap();
+        assert_eq!(field("entries-read"), RespValue::Integer(1));
+        assert_eq!(field("lag"), RespValue::Integer(2));
+        assert_eq!(field("pending"), RespValue::Integer(1));
+
+        let RespValue::Map(info) = xinfo(context(), request("XINFO", &["STREAM", "stream:i"])).await? else { panic!() };
+        assert!(info.contains(&(bulk("last-entry"), entry("3-0", &["f", "v"]))));
+        assert!(info.contains(&(bulk("entries-added"), RespValue::Integer(3))));
+
+        // a deletion in the middle makes the lag unknown
+        xdel(context(), request("XDEL", &["stream:i", "2-0"])).await?;
+        let RespValue::Array(groups) = xinfo(context(), request("XINFO", &["GROUPS", "stream:i"])).await? else { panic!() };
+        let RespValue::Map(group) = &groups[0] else { panic!() };
+        assert!(group.contains(&(bulk("lag"), RespValue::BulkString(None))));
+
+        let RespValue::Array(consumers) = xinfo(context(), request("XINFO", &["CONSUMERS", "stream:i", "g"])).await? else { panic!() };
+        let RespValue::Map(consumer) = &consumers[0] else { panic!() };
+        assert!(consumer.contains(&(bulk("pending"), RespValue::Integer(1))));
+        assert!(integer(&consumer[2].1) >= 0);
+
+        assert!(xsetid(context(), request("XSETID", &["stream:i", "2-0"])).await.is_err());
+        xsetid(context(), request("XSETID", &["stream:i", "9-0", "ENTRIESADDED", "10", "MAXDELETEDID", "5-0"])).await?;
+        assert!(xadd(context(), request("XADD", &["stream:i", "8-0", "f", "v"])).await.is_err());
+        let RespValue::Map(info) = xinfo(context(), request("XINFO", &["STREAM", "stream:i"])).await? else { panic!() };
+        assert!(info.contains(&(bulk("max-deleted-entry-id"), bulk("5-0"))));
+        assert!(xsetid(context(), request("XSETID", &["stream:none", "1-0"])).await.is_err());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn rewritten_log_keeps_pending_entries() -> anyhow::Result<()> {
+        for id in ["1-0", "2-0", "3-0"] {
+            xadd(context(), request("XADD", &["stream:r", id, "f", "v"])).await?;
+        }
+        xgroup(context(), request("XGROUP", &["CREATE", "stream:r", "g", "0"])).await?;
+        xreadgroup(context(), request("XREADGROUP", &["GROUP", "g", "c", "STREAMS", "stream:r", ">"])).await?;
+        // the message goes but its pending entry stays until it is acknowledged or claimed
+        xdel(context(), request("XDEL", &["stream:r", "2-0"])).await?;
+
+        let entry = DB.read().await.get(b"stream:r").unwrap().clone();
+        let mut log = Vec::new();
+        for command in crate::engine::aof::rewrite_commands(&Bytes::from("stream:r2"), &entry) {
+            command.write(&mut log).await?;
+        }
+        crate::engine::aof::replay(&log).await?;
+
+        let db = DB.read().await;
+        let (original, rebuilt) = (get_stream(&db, b"stream:r")?.unwrap(), get_stream(&db, b"stream:r2")?.unwrap());
+        assert_eq!(rebuilt.groups[&Bytes::from("g")].pending.len(), 3);
+        assert_eq!(rebuilt.groups[&Bytes::from("g")].pending, original.groups[&Bytes::from("g")].pending);
+        assert_eq!((rebuilt.last_id, rebuilt.max_deleted_id), (original.last_id, original.max_deleted_id));
+        drop(db);
+
+        // a claim drops it like it does on the original stream
+        let reply = xautoclaim(context(), request("XAUTOCLAIM", &["stream:r2", "g", "d", "0", "-", "JUSTID"])).await?;
+        assert_eq!(reply, RespValue::Array(vec![bulk("0-0"), bulks(&["1-0", "3-0"]), bulks(&["2-0"])]));
+        Ok(())
+    }
+}
@@ -1,20 +1,14 @@
-use bytes::Bytes;
-use itertools::Itertools;
-use itertools::PeekingNext;
-use tokio::sync::RwLock;
+use bytes::{Bytes, BytesMut};
+use crate::engine::{Database, Entry, Timestamp, Value, DB};
+use super::{arg_f64, arg_i64, check_args, format_f64, parse_f64, parse_i64};
 use crate::error::*;
-use core::time;
-use std::sync::LazyLock;
+use std::ops::Range;
 use std::sync::Arc;
-use crate::parser::OK_RESP;
+use crate::parser::{NULL_RESP, OK_RESP};
 use crate::{context::Context, parser::{RespRequest, RespValue}};
 use crate::command_table::{RouteHandler, ROUTE_MAP};
 
-static STRING_MAP: LazyLock<RwLock<std::collections::HashMap<String, String>>> = LazyLock::new(|| {
-    RwLock::new(std::collections::HashMap::new())
-});
-
-#[derive(Debug)]
+#[derive(Debug, PartialEq, Eq)]
 enum ExistCond {
     None,
     // if key not exists
@@ -27,12 +21,12 @@ enum ExistCond {
 enum Expiration {
     None,
     KeepTTL,
-    Deadline(chrono::DateTime<chrono::Utc>),
+    Deadline(Timestamp),
 }
 
 #[derive(Debug)]
 struct SetOption {
-    exist_cond: Option<ExistCond>,
+    exist_cond: ExistCond,
     // return old value if key exists
     get: bool,
     expire: Expiration,
@@ -44,6 +38,38 @@ struct SetCommand {
     option: SetOption,
 }
 
+// largest string value, the default proto-max-bulk-len of redis
+const MAX_STRING_LEN: usize = 512 * 1024 * 1024;
+
+/// Turns the argument of EX, PX, EXAT or PXAT into an absolute deadline
+fn parse_deadline(unit: &str, arg: &RespValue, command: &str) -> Result<Timestamp> {
+    let invalid = || Error::Other(format!("invalid expire time in '{}' command", command));
+    let expire = arg_i64(arg)?;
+    if expire <= 0 {
+        return Err(invalid());
+    }
+    match unit {
+        "EX" => chrono::Duration::try_seconds(expire).and_then(|d| chrono::Utc::now().checked_add_signed(d)),
+        "PX" => chrono::Duration::try_milliseconds(expire).and_then(|d| chrono::Utc::now().checked_add_signed(d)),
+        "EXAT" => chrono::DateTime::from_timestamp(expire, 0),
+        _ => chrono::DateTime::from_timestamp_millis(expire),
+    }.ok_or_else(invalid)
+}
+
+/// `SET key value [PXAT deadline]`, the record logged for writes whose relative expiry must not be replayed
+fn set_record(key: &Bytes, value: &Bytes, expire_at: Option<Timestamp>) -> RespValue {
+    let mut record = vec!["SET".into(), key.clone(), value.clone()];
+    if let Some(deadline) = expire_at {
+        record.push("PXAT".into());
+        record.push(deadline.timestamp_millis().to_string().into());
+    }
+    RespValue::bulk_array(record)
+}
+
+fn get_string<'a>(db: &'a Database, key: &[u8]) -> Result<Option<&'a Bytes>> {
+    db.get(key).map(|entry| entry.as_string()).transpose()
+}
+
 fn prase_set_command(request: RespRequest) -> Result<SetCommand> {
     let mut arg_iter = request.args.into_iter();
     let wrong_arg_number = || Error::WrongArgNumber("set".into());
@@ -52,31 +78,28 @@ fn prase_set_command(request: RespRequest) -> Result<SetCommand> {
     let value = arg_iter.next().ok_or_else(wrong_arg_number)?.as_bytes()?.clone();
 
     let mut option = SetOption {
-        exist_cond: None,
+        exist_cond: ExistCond::None,
         get: false,
         expire: Expiration::None,
     };
 
     let mut iter = arg_iter.peekable();
     while let Some(arg) = iter.next() {
-        match arg.as_str()? {
-            "EX" => {
-                let expire_sec = iter.next().ok_or(Error::Syntax)?.as_i64()?;
-                let deadline = chrono::Utc::now() + chrono::Duration::seconds(expire_sec);
+        match arg.as_str()?.to_ascii_uppercase().as_str() {
+            unit @ ("EX" | "PX" | "EXAT" | "PXAT") => {
+                if !matches!(option.expire, Expiration::None) {
+                    return Err(Error::Syntax);
+                }
+                let deadline = parse_deadline(unit, &iter.next().ok_or(Error::Syntax)?, "set")?;
                 option.expire = Expiration::Deadline(deadline);
             }
-            "PX" => {
-                let expire_ms = iter.next().ok_or(Error::Syntax)?.as_i64()?;
-                let deadline = chrono::Utc::now() + chrono::Duration::milliseconds(expire_ms);
-                option.expire = Expiration::Deadline(deadline);
+            "NX" if option.exist_cond != ExistCond::XX => {
+                option.exist_cond = ExistCond::NX;
             }
-            "NX" => {
-                option.exist_cond = Some(ExistCond::NX);
+            "XX" if option.exist_cond != ExistCond::NX => {
+                option.exist_cond = ExistCond::XX;
             }
-            "XX" => {
-                option.exist_cond = Some(ExistCond::XX);
-            }
-            "KEEPTTL" => {
+            "KEEPTTL" if matches!(option.expire, Expiration::None | Expiration::KeepTTL) => {
                 option.expire = Expiration::KeepTTL;
             }
             "GET" => {
@@ -93,10 +116,1147 @@ fn prase_set_command(request: RespRequest) -> Result<SetCommand> {
     })
 }
 
-async fn set(_context : Arc<Context>, request: RespRequest) -> Result<RespValue> {
-    todo!("set")
+#[router_macro::route("SET")]
+async fn set(_context : Arc<Context>, request: RespRequest) -> anyhow::Result<RespValue> {
+    let SetCommand { key, value, option } = prase_set_command(request)?;
+
+    let mut db = DB.write().await;
+    let old = db.get(&key).cloned();
+    let old_value = match (&old, option.get) {
+        (Some(old), true) => Some(old.as_string()?.clone()),
+        _ => None,
+    };
+
+    let should_set = match option.exist_cond {
+        ExistCond::None => true,
+        ExistCond::NX => old.is_none(),
+        ExistCond::XX => old.is_some(),
+    };
+
+    if should_set {
+        let expire_at = match option.expire {
+            Expiration::None => None,
+            Expiration::KeepTTL => old.as_ref().and_then(|old| old.expire_at),
+            Expiration::Deadline(deadline) => Some(deadline),
+        };
+
+        // relative deadlines are logged as absolute ones so that a replay keeps the original expiry
+        let record = set_record(&key, &value, expire_at);
+        db.put(key, Entry { value: Value::String(value), expire_at });
+        db.append_log(&record).await?;
+    }
+
+    let response = match (option.get, should_set) {
+        (true, _) => RespValue::BulkString(old_value),
+        (false, true) => OK_RESP.clone(),
+        (false, false) => NULL_RESP.clone(),
+    };
+    Ok(response)
+}
+
+#[router_macro::route("GET")]
+async fn get(_context : Arc<Context>, request: RespRequest) -> anyhow::Result<RespValue> {
+    check_args(&request, "get", 1, Some(1))?;
+    let key = request.args[0].as_bytes()?;
+
+    let db = DB.read().await;
+    Ok(RespValue::BulkString(get_string(&db, key)?.cloned()))
+}
+
+#[router_macro::route("MGET")]
+async fn mget(_context : Arc<Context>, request: RespRequest) -> anyhow::Result<RespValue> {
+    check_args(&request, "mget", 1, None)?;
+
+    let db = DB.read().await;
+    let mut values = Vec::with_capacity(request.args.len());
+    for key in &request.args {
+        // keys holding another type read as nil instead of failing the whole command
+        let value = db.get(key.as_bytes()?).and_then(|entry| entry.as_string().ok()).cloned();
+        values.push(RespValue::BulkString(value));
+    }
+    Ok(RespValue::Array(values))
+}
+
+fn parse_pairs(request: &RespRequest, name: &str) -> Result<Vec<(Bytes, Bytes)>> {
+    if request.args.is_empty() || !request.args.len().is_multiple_of(2) {
+        return Err(Error::WrongArgNumber(name.into()));
+    }
+    request.args
+        .chunks(2)
+        .map(|pair| Ok((pair[0].as_bytes()?.clone(), pair[1].as_bytes()?.clone())))
+        .collect()
+}
+
+#[router_macro::route("MSET")]
+async fn mset(_context : Arc<Context>, request: RespRequest) -> anyhow::Result<RespValue> {
+    let pairs = parse_pairs(&request, "mset")?;
+
+    let mut db = DB.write().await;
+    for (key, value) in pairs {
+        db.put(key, Entry::new(Value::String(value)));
+    }
+    db.append_log(&request.to_resp()).await?;
+    Ok(OK_RESP.clone())
+}
+
+#[router_macro::route("MSETNX")]
+async fn msetnx(_context : Arc<Context>, request: RespRequest) -> anyhow::Result<RespValue> {
+    let pairs = parse_pairs(&request, "msetnx")?;
+
+    let mut db = DB.write().await;
+    if pairs.iter().any(|(key, _)| db.get(key).is_some()) {
+        return Ok(RespValue::Integer(0));
+    }
+    for (key, value) in pairs {
+        db.put(key, Entry::new(Value::String(value)));
+    }
+    db.append_log(&request.to_resp()).await?;
+    Ok(RespValue::Integer(1))
+}
+
+/// Replaces the string value of `key`, keeping its time to live
+pub(crate) fn overwrite(db: &mut Database, key: &Bytes, value: Bytes) {
+    match db.get_mut(key) {
+        Some(entry) => entry.value = Value::String(value),
+        None => {
+            db.put(key.clone(), Entry::new(Value::String(value)));
+        }
+    }
+}
+
+/// Runs `f` on the string value of `key` in place, an empty string being created if the key is missing.
+/// The buffer is only copied when something else still holds it, such as a reply being sent.
+fn modify_string<T>(db: &mut Database, key: &Bytes, f: impl FnOnce(&mut BytesMut) -> T) -> Result<T> {
+    if get_string(db, key)?.is_none() {
+        db.put(key.clone(), Entry::new(Value::String(Bytes::new())));
+    }
+    let value = db.get_mut(key).expect("string was just created").as_string_mut()?;
+    let mut buf = std::mem::take(value).try_into_mut().unwrap_or_else(|shared| BytesMut::from(shared.as_ref()));
+    let result = f(&mut buf);
+    *value = buf.freeze();
+    Ok(result)
+}
+
+async fn incr_by(request: &RespRequest, delta: i64) -> anyhow::Result<RespValue> {
+    let key = request.args[0].as_bytes()?;
+
+    let mut db = DB.write().await;
+    let current = match get_string(&db, key)? {
+        Some(value) => parse_i64(value)?,
+        None => 0,
+    };
+    let value = current
+        .checked_add(delta)
+        .ok_or_else(|| Error::Other("increment or decrement would overflow".into()))?;
+
+    overwrite(&mut db, key, value.to_string().into());
+    db.append_log(&request.to_resp()).await?;
+    Ok(RespValue::Integer(value))
+}
+
+#[router_macro::route("INCR")]
+async fn incr(_context : Arc<Context>, request: RespRequest) -> anyhow::Result<RespValue> {
+    check_args(&request, "incr", 1, Some(1))?;
+    incr_by(&request, 1).await
+}
+
+#[router_macro::route("DECR")]
+async fn decr(_context : Arc<Context>, request: RespRequest) -> anyhow::Result<RespValue> {
+    check_args(&request, "decr", 1, Some(1))?;
+    incr_by(&request, -1).await
+}
+
+#[router_macro::route("INCRBY")]
+async fn incrby(_context : Arc<Context>, request: RespRequest) -> anyhow::Result<RespValue> {
+    check_args(&request, "incrby", 2, Some(2))?;
+    let delta = arg_i64(&request.args[1])?;
+    incr_by(&request, delta).await
+}
+
+#[router_macro::route("DECRBY")]
+async fn decrby(_context : Arc<Context>, request: RespRequest) -> anyhow::Result<RespValue> {
+    check_args(&request, "decrby", 2, Some(2))?;
+    let delta = arg_i64(&request.args[1])?
+        .checked_neg()
+        .ok_or_else(|| Error::Other("decrement would overflow".into()))?;
+    incr_by(&request, delta).await
+}
+
+#[router_macro::route("INCRBYFLOAT")]
+async fn incrbyfloat(_context : Arc<Context>, request: RespRequest) -> anyhow::Result<RespValue> {
+    check_args(&request, "incrbyfloat", 2, Some(2))?;
+    let key = request.args[0].as_bytes()?;
+    let delta = arg_f64(&request.args[1])?;
+
+    let mut db = DB.write().await;
+    let current = match get_string(&db, key)? {
+        Some(value) => parse_f64(value)?,
+        None => 0.0,
+    };
+    let value = current + delta;
+    if !value.is_finite() {
+        return Err(Error::Other("increment would produce NaN or Infinity".into()).into());
+    }
+
+    // the result is logged instead of the increment, so that a replay cannot drift because of rounding
+    let value = Bytes::from(format_f64(value));
+    overwrite(&mut db, key, value.clone());
+    let expire_at = db.get(key).and_then(|entry| entry.expire_at);
+    db.append_log(&set_record(key, &value, expire_at)).await?;
+    Ok(RespValue::BulkString(Some(value)))
+}
+
+#[router_macro::route("APPEND")]
+async fn append(_context : Arc<Context>, request: RespRequest) -> anyhow::Result<RespValue> {
+    check_args(&request, "append", 2, Some(2))?;
+    let key = request.args[0].as_bytes()?;
+    let suffix = request.args[1].as_bytes()?;
+
+    let mut db = DB.write().await;
+    let current = get_string(&db, key)?.map_or(0, |v| v.len());
+    if current + suffix.len() > MAX_STRING_LEN {
+        return Err(Error::Other("string exceeds maximum allowed size (proto-max-bulk-len)".into()).into());
+    }
+    let len = modify_string(&mut db, key, |value| {
+        value.extend_from_slice(suffix);
+        value.len()
+    })?;
+    db.append_log(&request.to_resp()).await?;
+    Ok(RespValue::Integer(len as i64))
+}
+
+#[router_macro::route("STRLEN")]
+async fn strlen(_context : Arc<Context>, request: RespRequest) -> anyhow::Result<RespValue> {
+    check_args(&request, "strlen", 1, Some(1))?;
+    let db = DB.read().await;
+    let len = get_string(&db, request.args[0].as_bytes()?)?.map(|v| v.len()).unwrap_or(0);
+    Ok(RespValue::Integer(len as i64))
+}
+
+/// Clamps redis style inclusive indexes, negative ones counting from the end, to a range of `0..len`
+pub(crate) fn clamp_range(start: i64, end: i64, len: usize) -> Option<std::ops::Range<usize>> {
+    let len = len as i64;
+    let start = if start < 0 { (len + start).max(0) } else { start };
+    let end = if end < 0 { len + end } else { end.min(len - 1) };
+    if start > end || start >= len {
+        return None;
+    }
+    Some(start as usize..end as usize + 1)
+}
+
+#[router_macro::route("GETRANGE")]
+async fn getrange(_context : Arc<Context>, request: RespRequest) -> anyhow::Result<RespValue> {
+    check_args(&request, "getrange", 3, Some(3))?;
+    let start = arg_i64(&request.args[1])?;
+    let end = arg_i64(&request.args[2])?;
+
+    let db = DB.read().await;
+    let value = get_string(&db, request.args[0].as_bytes()?)?.cloned().unwrap_or_default();
+    let range = clamp_range(start, end, value.len()).unwrap_or(0..0);
+    Ok(RespValue::BulkString(Some(value.slice(range))))
+}
+
+#[router_macro::route("SETRANGE")]
+async fn setrange(_context : Arc<Context>, request: RespRequest) -> anyhow::Result<RespValue> {
+    check_args(&request, "setrange", 3, Some(3))?;
+    let key = request.args[0].as_bytes()?;
+    let offset = arg_i64(&request.args[1])?;
+    let patch = request.args[2].as_bytes()?;
+    if offset < 0 {
+        return Err(Error::Other("offset is out of range".into()).into());
+    }
+    let offset = offset as usize;
+
+    let mut db = DB.write().await;
+    let current = get_string(&db, key)?;
+    if patch.is_empty() {
+        return Ok(RespValue::Integer(current.map(|v| v.len()).unwrap_or(0) as i64));
+    }
+    if offset + patch.len() > MAX_STRING_LEN {
+        return Err(Error::Other("string exceeds maximum allowed size (proto-max-bulk-len)".into()).into());
+    }
+
+    let len = modify_string(&mut db, key, |value| {
+        if value.len() < offset + patch.len() {
+            value.resize(offset + patch.len(), 0);
+        }
+        value[offset..offset + patch.len()].copy_from_slice(patch);
+        value.len()
+    })?;
+    db.append_log(&request.to_resp()).await?;
+    Ok(RespValue::Integer(len as i64))
+}
+
+#[router_macro::route("GETDEL")]
+async fn getdel(_context : Arc<Context>, request: RespRequest) -> anyhow::Result<RespValue> {
+    check_args(&request, "getdel", 1, Some(1))?;
+    let key = request.args[0].as_bytes()?;
+
+    let mut db = DB.write().await;
+    let value = get_string(&db, key)?.cloned();
+    if value.is_some() {
+        db.delete(key);
+        db.append_log(&request.to_resp()).await?;
+    }
+    Ok(RespValue::BulkString(value))
+}
+
+/// GETEX key [EX seconds | PX milliseconds | EXAT unix-time-seconds | PXAT unix-time-milliseconds | PERSIST]
+#[router_macro::route("GETEX")]
+async fn getex(_context : Arc<Context>, request: RespRequest) -> anyhow::Result<RespValue> {
+    check_args(&request, "getex", 1, None)?;
+    let key = request.args[0].as_bytes()?;
+
+    let mut expire = Expiration::KeepTTL;
+    let mut args = request.args[1..].iter();
+    while let Some(arg) = args.next() {
+        if !matches!(expire, Expiration::KeepTTL) {
+            return Err(Error::Syntax.into());
+        }
+        match arg.as_str()?.to_ascii_uppercase().as_str() {
+            unit @ ("EX" | "PX" | "EXAT" | "PXAT") => {
+                let deadline = parse_deadline(unit, args.next().ok_or(Error::Syntax)?, "getex")?;
+                expire = Expiration::Deadline(deadline);
+            }
+            "PERSIST" => expire = Expiration::None,
+            _ => return Err(Error::Syntax.into()),
+        }
+    }
+
+    let mut db = DB.write().await;
+    let value = match get_string(&db, key)? {
+        Some(value) => value.clone(),
+        None => return Ok(NULL_RESP.clone()),
+    };
+
+    let expire_at = match expire {
+        Expiration::KeepTTL => return Ok(RespValue::BulkString(Some(value))),
+        Expiration::None => None,
+        Expiration::Deadline(deadline) => Some(deadline),
+    };
+    db.set_expire(key, expire_at);
+    db.append_log(&set_record(key, &value, expire_at)).await?;
+    Ok(RespValue::BulkString(Some(value)))
 }
 
-async fn get(_context : Arc<Context>, request: RespRequest) -> Result<RespValue> {
-    todo!("get")
-}
\ No newline at end of file
+#[router_macro::route("SETNX")]
+async fn setnx(_context : Arc<Context>, request: RespRequest) -> anyhow::Result<RespValue> {
+    check_args(&request, "setnx", 2, Some(2))?;
+    let key = request.args[0].as_bytes()?;
+    let value = request.args[1].as_bytes()?;
+
+    let mut db = DB.write().await;
+    if db.get(key).is_some() {
+        return Ok(RespValue::Integer(0));
+    }
+    db.put(key.clone(), Entry::new(Value::String(value.clone())));
+    db.append_log(&request.to_resp()).await?;
+    Ok(RespValue::Integer(1))
+}
+
+async fn set_with_expire(request: &RespRequest, name: &str, unit: &str) -> anyhow::Result<RespValue> {
+    check_args(request, name, 3, Some(3))?;
+    let key = request.args[0].as_bytes()?;
+    let deadline = parse_deadline(unit, &request.args[1], name)?;
+    let value = request.args[2].as_bytes()?;
+
+    let mut db = DB.write().await;
+    db.put(key.clone(), Entry { value: Value::String(value.clone()), expire_at: Some(deadline) });
+    db.append_log(&set_record(key, value, Some(deadline))).await?;
+    Ok(OK_RESP.clone())
+}
+
+#[router_macro::route("SETEX")]
+async fn setex(_context : Arc<Context>, request: RespRequest) -> anyhow::Result<RespValue> {
+    set_with_expire(&request, "setex", "EX").await
+}
+
+#[router_macro::route("PSETEX")]
+async fn psetex(_context : Arc<Context>, request: RespRequest) -> anyhow::Result<RespValue> {
+    set_with_expire(&request, "psetex", "PX").await
+}
+
+// (start in a, end in a, start in b, end in b), all inclusive
+type LcsMatch = (usize, usize, usize, usize);
+
+/// Longest common subsequence of `a` and `b`, with the matching ranges from the last one to the first
+fn lcs(a: &[u8], b: &[u8], min_match_len: usize) -> Result<(Vec<u8>, Vec<LcsMatch>)> {
+    let width = b.len() + 1;
+    let cells = (a.len() + 1)
+        .checked_mul(width)
+        .filter(|&cells| cells * std::mem::size_of::<u32>() <= MAX_STRING_LEN)
+        .ok_or_else(|| Error::Other("Insufficient memory, transient memory for LCS exceeds proto-max-bulk-len".into()))?;
+
+    // table[i][j] is the length of the LCS of a[..i] and b[..j]
+    let mut table = vec![0u32; cells];
+    for i in 1..=a.len() {
+        for j in 1..=b.len() {
+            table[i * width + j] = if a[i - 1] == b[j - 1] {
+                table[(i - 1) * width + j - 1] + 1
+            } else {
+                table[(i - 1) * width + j].max(table[i * width + j - 1])
+            };
+        }
+    }
+
+    let mut result = vec![0u8; table[a.len() * width + b.len()] as usize];
+    let mut matches = Vec::new();
+    let (mut i, mut j, mut idx) = (a.len(), b.len(), result.len());
+    // the range being tracked, walking backwards
+    let mut range: Option<LcsMatch> = None;
+
+    while i > 0 && j > 0 {
+        let mut emit = false;
+        if a[i - 1] == b[j - 1] {
+            result[idx - 1] = a[i - 1];
+            match range.as_mut() {
+                None => range = Some((i - 1, i - 1, j - 1, j - 1)),
+                Some(r) if r.0 == i && r.2 == j => {
+                    r.0 -= 1;
+                    r.2 -= 1;
+                }
+                Some(_) => emit = true,
+            }
+            if range.is_some_and(|r| r.0 == 0 || r.2 == 0) {
+                emit = true;
+            }
+            idx -= 1;
+            i -= 1;
+            j -= 1;
+        } else {
+            if table[(i - 1) * width + j] > table[i * width + j - 1] {
+                i -= 1;
+            } else {
+                j -= 1;
+            }
+            emit = range.is_some();
+        }
+
+        if emit {
+            if let Some(r) = range.take() {
+                if r.1 - r.0 + 1 >= min_match_len {
+                    matches.push(r);
+                }
+            }
+        }
+    }
+    Ok((result, matches))
+}
+
+/// LCS key1 key2 [LEN] [IDX] [MINMATCHLEN min-match-len] [WITHMATCHLEN]
+#[router_macro::route("LCS")]
+async fn lcs_command(_context : Arc<Context>, request: RespRequest) -> anyhow::Result<RespValue> {
+    check_args(&request, "lcs", 2, None)?;
+    let (mut len_only, mut idx, mut with_match_len, mut min_match_len) = (false, false, false, 0);
+    let mut args = request.args[2..].iter();
+    while let Some(arg) = args.next() {
+        match arg.as_str()?.to_ascii_uppercase().as_str() {
+            "LEN" => len_only = true,
+            "IDX" => idx = true,
+            "WITHMATCHLEN" => with_match_len = true,
+            "MINMATCHLEN" => min_match_len = arg_i64(args.next().ok_or(Error::Syntax)?)?.max(0) as usize,
+            _ => return Err(Error::Syntax.into()),
+        }
+    }
+    if len_only && idx {
+        return Err(Error::Other("If you want both the length and indexes, please just use IDX.".into()).into());
+    }
+
+    let (a, b) = {
+        let db = DB.read().await;
+        let a = get_string(&db, request.args[0].as_bytes()?)?.cloned().unwrap_or_default();
+        let b = get_string(&db, request.args[1].as_bytes()?)?.cloned().unwrap_or_default();
+        (a, b)
+    };
+    let (result, matches) = lcs(&a, &b, min_match_len)?;
+
+    if len_only {
+        return Ok(RespValue::Integer(result.len() as i64));
+    }
+    if !idx {
+        return Ok(RespValue::BulkString(Some(result.into())));
+    }
+
+    let range = |start: usize, end: usize| RespValue::Array(vec![RespValue::Integer(start as i64), RespValue::Integer(end as i64)]);
+    let matches = matches
+        .into_iter()
+        .map(|(a_start, a_end, b_start, b_end)| {
+            let mut item = vec![range(a_start, a_end), range(b_start, b_end)];
+            if with_match_len {
+                item.push(RespValue::Integer((a_end - a_start + 1) as i64));
+            }
+            RespValue::Array(item)
+        })
+        .collect();
+    Ok(RespValue::Map(vec![
+        (RespValue::BulkString(Some("matches".into())), RespValue::Array(matches)),
+        (RespValue::BulkString(Some("len".into())), RespValue::Integer(result.len() as i64)),
+    ]))
+}
+
+// largest bit offset, the last bit of the largest string
+const MAX_BIT_OFFSET: u64 = MAX_STRING_LEN as u64 * 8 - 1;
+
+fn invalid_bit_offset() -> Error {
+    Error::Other("bit offset is not an integer or out of range".into())
+}
+
+fn parse_bit_offset(arg: &RespValue) -> Result<u64> {
+    arg_i64(arg)
+        .ok()
+        .and_then(|offset| u64::try_from(offset).ok())
+        .filter(|&offset| offset <= MAX_BIT_OFFSET)
+        .ok_or_else(invalid_bit_offset)
+}
+
+/// Bit `offset` of `value`, bit 0 being the most significant bit of the first byte like redis
+fn get_bit(value: &[u8], offset: u64) -> bool {
+    let byte = (offset / 8) as usize;
+    byte < value.len() && value[byte] & (0x80 >> (offset % 8)) != 0
+}
+
+fn set_bit(value: &mut [u8], offset: u64, bit: bool) {
+    let (byte, mask) = ((offset / 8) as usize, 0x80 >> (offset % 8));
+    if bit {
+        value[byte] |= mask;
+    } else {
+        value[byte] &= !mask;
+    }
+}
+
+/// Runs `f` in place on the string value of `key`, grown to hold at least `bits` bits
+fn modify_bits<T>(db: &mut Database, key: &Bytes, bits: u64, f: impl FnOnce(&mut [u8]) -> T) -> Result<T> {
+    modify_string(db, key, |value| {
+        let len = bits.div_ceil(8) as usize;
+        if value.len() < len {
+            value.resize(len, 0);
+        }
+        f(value)
+    })
+}
+
+#[router_macro::route("SETBIT")]
+async fn setbit(_context : Arc<Context>, request: RespRequest) -> anyhow::Result<RespValue> {
+    check_args(&request, "setbit", 3, Some(3))?;
+    let key = request.args[0].as_bytes()?;
+    let offset = parse_bit_offset(&request.args[1])?;
+    let bit = match request.args[2].as_bytes()?.as_ref() {
+        b"0" => false,
+        b"1" => true,
+        _ => return Err(Error::Other("bit is not an integer or out of range".into()).into()),
+    };
+
+    let mut db = DB.write().await;
+    let old = modify_bits(&mut db, key, offset + 1, |value| {
+        let old = get_bit(value, offset);
+        set_bit(value, offset, bit);
+        old
+    })?;
+    db.append_log(&request.to_resp()).await?;
+    Ok(RespValue::Integer(old as i64))
+}
+
+#[router_macro::route("GETBIT")]
+async fn getbit(_context : Arc<Context>, request: RespRequest) -> anyhow::Result<RespValue> {
+    check_args(&request, "getbit", 2, Some(2))?;
+    let offset = parse_bit_offset(&request.args[1])?;
+    let db = DB.read().await;
+    let value = get_string(&db, request.args[0].as_bytes()?)?.map(|v| v.as_ref()).unwrap_or_default();
+    Ok(RespValue::Integer(get_bit(value, offset) as i64))
+}
+
+/// Whether the optional BYTE|BIT argument of BITCOUNT and BITPOS counts in bits
+fn parse_bit_unit(unit: Option<&RespValue>) -> Result<bool> {
+    match unit.map(|unit| unit.as_str()).transpose()?.map(|unit| unit.to_ascii_uppercase()).as_deref() {
+        None | Some("BYTE") => Ok(false),
+        Some("BIT") => Ok(true),
+        _ => Err(Error::Syntax),
+    }
+}
+
+/// The bits of `value` from `start` to `end`, indexes of bytes unless `bits`, as a range of bit offsets
+fn bit_range(value: &[u8], start: i64, end: i64, bits: bool) -> Option<Range<u64>> {
+    if bits {
+        clamp_range(start, end, value.len() * 8).map(|range| range.start as u64..range.end as u64)
+    } else {
+        clamp_range(start, end, value.len()).map(|range| range.start as u64 * 8..range.end as u64 * 8)
+    }
+}
+
+/// Number of bits set in `range`, counting whole bytes at once
+fn count_bits(value: &[u8], range: Range<u64>) -> u64 {
+    let (first, last) = ((range.start / 8) as usize, ((range.end - 1) / 8) as usize);
+    value[first..=last]
+        .iter()
+        .enumerate()
+        .map(|(i, &byte)| {
+            let mut byte = byte;
+            if i == 0 {
+                byte &= 0xff >> (range.start % 8);
+            }
+            if first + i == last {
+                byte &= 0xff << (7 - (range.end - 1) % 8);
+            }
+            byte.count_ones() as u64
+        })
+        .sum()
+}
+
+/// BITCOUNT key [start end [BYTE|BIT]]
+#[router_macro::route("BITCOUNT")]
+async fn bitcount(_context : Arc<Context>, request: RespRequest) -> anyhow::Result<RespValue> {
+    check_args(&request, "bitcount", 1, Some(4))?;
+    let db = DB.read().await;
+    let value = get_string(&db, request.args[0].as_bytes()?)?.map(|v| v.as_ref()).unwrap_or_default();
+    let range = match &request.args[1..] {
+        [] => Some(0..value.len() as u64 * 8),
+        [start, end, unit @ ..] => bit_range(value, arg_i64(start)?, arg_i64(end)?, parse_bit_unit(unit.first())?),
+        _ => return Err(Error::Syntax.into()),
+    };
+    let count = range.filter(|range| !range.is_empty()).map_or(0, |range| count_bits(value, range));
+    Ok(RespValue::Integer(count as i64))
+}
+
+/// BITPOS key bit [start [end [BYTE|BIT]]]
+#[router_macro::route("BITPOS")]
+async fn bitpos(_context : Arc<Context>, request: RespRequest) -> anyhow::Result<RespValue> {
+    check_args(&request, "bitpos", 2, Some(5))?;
+    let bit = match request.args[1].as_bytes()?.as_ref() {
+        b"0" => false,
+        b"1" => true,
+        _ => return Err(Error::Other("The bit argument must be 1 or 0.".into()).into()),
+    };
+
+    let db = DB.read().await;
+    let Some(value) = get_string(&db, request.args[0].as_bytes()?)? else {
+        // a missing key is an empty string of clear bits
+        return Ok(RespValue::Integer(if bit { -1 } else { 0 }));
+    };
+    let end_given = request.args.len() > 3;
+    let range = match &request.args[2..] {
+        [] => Some(0..value.len() as u64 * 8),
+        [start] => bit_range(value, arg_i64(start)?, -1, false),
+        [start, end, unit @ ..] => bit_range(value, arg_i64(start)?, arg_i64(end)?, parse_bit_unit(unit.first())?),
+    };
+    let Some(range) = range.filter(|range| !range.is_empty()) else {
+        return Ok(RespValue::Integer(-1));
+    };
+
+    // whole bytes without the bit are skipped at once
+    let skip = if bit { 0x00 } else { 0xff };
+    let mut offset = range.start;
+    while offset < range.end {
+        if offset % 8 == 0 && offset + 8 <= range.end && value[(offset / 8) as usize] == skip {
+            offset += 8;
+            continue;
+        }
+        if get_bit(value, offset) == bit {
+            return Ok(RespValue::Integer(offset as i64));
+        }
+        offset += 1;
+    }
+    // looking for a clear bit without an end, the string counts as padded with zeros
+    let found = if !bit && !end_given { range.end as i64 } else { -1 };
+    Ok(RespValue::Integer(found))
+}
+
+/// BITOP AND|OR|XOR|NOT destkey key [key ...]
+#[router_macro::route("BITOP")]
+async fn bitop(_context : Arc<Context>, request: RespRequest) -> anyhow::Result<RespValue> {
+    check_args(&request, "bitop", 3, None)?;
+    let op = request.args[0].as_str()?.to_ascii_uppercase();
+    let dest = request.args[1].as_bytes()?;
+    let sources = &request.args[2..];
+    match op.as_str() {
+        "AND" | "OR" | "XOR" => {}
+        "NOT" if sources.len() == 1 => {}
+        "NOT" => return Err(Error::Other("BITOP NOT must be called with a single source key.".into()).into()),
+        _ => return Err(Error::Syntax.into()),
+    }
+
+    let mut db = DB.write().await;
+    let mut values = Vec::with_capacity(sources.len());
+    for source in sources {
+        values.push(get_string(&db, source.as_bytes()?)?.cloned().unwrap_or_default());
+    }
+    // shorter strings count as padded with zeros
+    let len = values.iter().map(Bytes::len).max().unwrap_or(0);
+    let byte = |value: &Bytes, i: usize| value.get(i).copied().unwrap_or(0);
+    let result: Vec<u8> = (0..len)
+        .map(|i| {
+            let mut bytes = values.iter().map(|value| byte(value, i));
+            let first = bytes.next().unwrap_or(0);
+            match op.as_str() {
+                "AND" => bytes.fold(first, |acc, b| acc & b),
+                "OR" => bytes.fold(first, |acc, b| acc | b),
+                "XOR" => bytes.fold(first, |acc, b| acc ^ b),
+                _ => !first,
+            }
+        })
+        .collect();
+
+    if result.is_empty() {
+        if db.delete(dest).is_some() {
+            db.append_log(&request.to_resp()).await?;
+        }
+    } else {
+        db.put(dest.clone(), Entry::new(Value::String(result.into())));
+        db.append_log(&request.to_resp()).await?;
+    }
+    Ok(RespValue::Integer(len as i64))
+}
+
+#[derive(Debug, Clone, Copy)]
+enum Overflow {
+    Wrap,
+    Sat,
+    Fail,
+}
+
+#[derive(Debug, Clone, Copy)]
+struct FieldType {
+    signed: bool,
+    bits: u32,
+}
+
+impl FieldType {
+    fn parse(arg: &RespValue) -> Result<FieldType> {
+        let invalid = || Error::Other("Invalid bitfield type. Use something like i16 u8. Note that u64 is not supported but i64 is.".into());
+        let arg = arg.as_str()?;
+        let signed = match arg.as_bytes().first() {
+            Some(b'i' | b'I') => true,
+            Some(b'u' | b'U') => false,
+            _ => return Err(invalid()),
+        };
+        let bits = arg[1..].parse::<u32>().map_err(|_| invalid())?;
+        let max = if signed { 64 } else { 63 };
+        if bits == 0 || bits > max {
+            return Err(invalid());
+        }
+        Ok(FieldType { signed, bits })
+    }
+
+    /// Bit offset of a field, "#n" standing for the n-th field of this type
+    fn offset(&self, arg: &RespValue) -> Result<u64> {
+        let arg = arg.as_bytes()?;
+        let (multiply, digits) = match arg.strip_prefix(b"#") {
+            Some(digits) => (true, digits),
+            None => (false, arg.as_ref()),
+        };
+        let offset = std::str::from_utf8(digits)
+            .ok()
+            .and_then(|digits| digits.parse::<u64>().ok())
+            .and_then(|offset| if multiply { offset.checked_mul(self.bits as u64) } else { Some(offset) })
+            .ok_or_else(invalid_bit_offset)?;
+        offset.checked_add(self.bits as u64 - 1).filter(|end| *end <= MAX_BIT_OFFSET).ok_or_else(invalid_bit_offset)?;
+        Ok(offset)
+    }
+
+    fn min(&self) -> i128 {
+        if self.signed { -(1i128 << (self.bits - 1)) } else { 0 }
+    }
+
+    fn max(&self) -> i128 {
+        if self.signed { (1i128 << (self.bits - 1)) - 1 } else { (1i128 << self.bits) - 1 }
+    }
+
+    /// The field at `offset` of `value`, bits past its end reading as zeros
+    fn get(&self, value: &[u8], offset: u64) -> i64 {
+        let mut field = 0u64;
+        for i in 0..self.bits as u64 {
+            field = (field << 1) | get_bit(value, offset + i) as u64;
+        }
+        if self.signed && self.bits < 64 && field >> (self.bits - 1) == 1 {
+            // sign extension
+            field |= u64::MAX << self.bits;
+        }
+        field as i64
+    }
+
+    fn set(&self, value: &mut [u8], offset: u64, field: i64) {
+        for i in 0..self.bits as u64 {
+            set_bit(value, offset + i, (field as u64 >> (self.bits as u64 - 1 - i)) & 1 == 1);
+        }
+    }
+
+    /// Fits `value` into the type according to `overflow`, None if it fails
+    fn fit(&self, value: i128, overflow: Overflow) -> Option<i64> {
+        if (self.min()..=self.max()).contains(&value) {
+            return Some(value as i64);
+        }
+        match overflow {
+            Overflow::Fail => None,
+            Overflow::Sat if value > self.max() => Some(self.max() as i64),
+            Overflow::Sat => Some(self.min() as i64),
+            Overflow::Wrap => {
+                let wrapped = value.rem_euclid(1i128 << self.bits);
+                let wrapped = if wrapped > self.max() { wrapped - (1i128 << self.bits) } else { wrapped };
+                Some(wrapped as i64)
+            }
+        }
+    }
+}
+
+#[derive(Debug)]
+enum FieldOp {
+    Get,
+    Set(i64),
+    IncrBy(i64),
+}
+
+/// Parses the subcommands of BITFIELD, in order and each one with the overflow mode in effect
+fn parse_bitfield(args: &[RespValue], read_only: bool) -> Result<Vec<(FieldOp, FieldType, u64, Overflow)>> {
+    let mut ops = Vec::new();
+    let mut overflow = Overflow::Wrap;
+    let mut args = args.iter();
+    while let Some(arg) = args.next() {
+        let mut next = || args.next().ok_or(Error::Syntax);
+        let subcommand = arg.as_str()?.to_ascii_uppercase();
+        if subcommand == "OVERFLOW" {
+            overflow = match next()?.as_str()?.to_ascii_uppercase().as_str() {
+                "WRAP" => Overflow::Wrap,
+                "SAT" => Overflow::Sat,
+                "FAIL" => Overflow::Fail,
+                _ => return Err(Error::Other("Invalid OVERFLOW type specified".into())),
+            };
+            continue;
+        }
+        if read_only && subcommand != "GET" {
+            return Err(Error::Other("BITFIELD_RO only supports the GET subcommand".into()));
+        }
+        let field = FieldType::parse(next()?)?;
+        let offset = field.offset(next()?)?;
+        let op = match subcommand.as_str() {
+            "GET" => FieldOp::Get,
+            "SET" => FieldOp::Set(arg_i64(next()?)?),
+            "INCRBY" => FieldOp::IncrBy(arg_i64(next()?)?),
+            _ => return Err(Error::Syntax),
+        };
+        ops.push((op, field, offset, overflow));
+    }
+    Ok(ops)
+}
+
+/// Runs subcommands of BITFIELD that are all GET, without writing anything
+fn get_fields(value: &[u8], ops: &[(FieldOp, FieldType, u64, Overflow)]) -> Vec<RespValue> {
+    ops.iter().map(|(_, field, offset, _)| RespValue::Integer(field.get(value, *offset))).collect()
+}
+
+/// Runs the subcommands of BITFIELD on `value`, nil standing for a write that failed to fit
+fn run_bitfield(value: &mut [u8], ops: &[(FieldOp, FieldType, u64, Overflow)]) -> Vec<RespValue> {
+    ops.iter()
+        .map(|(op, field, offset, overflow)| {
+            let old = field.get(value, *offset);
+            let new = match op {
+                FieldOp::Get => return RespValue::Integer(old),
+                // like redis, an unsigned field takes the two's complement of a negative value
+                FieldOp::Set(new) if !field.signed => field.fit(*new as u64 as i128, *overflow),
+                FieldOp::Set(new) => field.fit(*new as i128, *overflow),
+                FieldOp::IncrBy(increment) => field.fit(old as i128 + *increment as i128, *overflow),
+            };
+            let Some(new) = new else {
+                return NULL_RESP.clone();
+            };
+            field.set(value, *offset, new);
+            match op {
+                FieldOp::Set(_) => RespValue::Integer(old),
+                _ => RespValue::Integer(new),
+            }
+        })
+        .collect()
+}
+
+/// BITFIELD key [GET type offset] [SET type offset value] [INCRBY type offset increment] [OVERFLOW WRAP|SAT|FAIL] ...
+#[router_macro::route("BITFIELD")]
+async fn bitfield(_context : Arc<Context>, request: RespRequest) -> anyhow::Result<RespValue> {
+    check_args(&request, "bitfield", 1, None)?;
+    let key = request.args[0].as_bytes()?;
+    let ops = parse_bitfield(&request.args[1..], false)?;
+
+    // the bits that writes need the string to hold
+    let bits = ops
+        .iter()
+        .filter(|(op, ..)| !matches!(op, FieldOp::Get))
+        .map(|(_, field, offset, _)| offset + field.bits as u64)
+        .max();
+    let Some(bits) = bits else {
+        let db = DB.read().await;
+        let value = get_string(&db, key)?.map(|v| v.as_ref()).unwrap_or_default();
+        return Ok(RespValue::Array(get_fields(value, &ops)));
+    };
+
+    let mut db = DB.write().await;
+    let replies = modify_bits(&mut db, key, bits, |value| run_bitfield(value, &ops))?;
+    db.append_log(&request.to_resp()).await?;
+    Ok(RespValue::Array(replies))
+}
+
+/// BITFIELD_RO key [GET type offset ...]
+#[router_macro::route("BITFIELD_RO")]
+async fn bitfield_ro(_context : Arc<Context>, request: RespRequest) -> anyhow::Result<RespValue> {
+    check_args(&request, "bitfield_ro", 1, None)?;
+    let ops = parse_bitfield(&request.args[1..], true)?;
+    let db = DB.read().await;
+    let value = get_string(&db, request.args[0].as_bytes()?)?.map(|v| v.as_ref()).unwrap_or_default();
+    Ok(RespValue::Array(get_fields(value, &ops)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::redis_types::test_util::{bulk, context, request};
+
+    #[tokio::test]
+    async fn set_and_get() -> anyhow::Result<()> {
+        assert_eq!(set(context(), request("SET", &["string:k1", "v1"])).await?, *OK_RESP);
+        assert_eq!(get(context(), request("GET", &["string:k1"])).await?, bulk("v1"));
+        assert_eq!(get(context(), request("GET", &["string:missing"])).await?, *NULL_RESP);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn set_conditions_and_get_option() -> anyhow::Result<()> {
+        assert_eq!(set(context(), request("SET", &["string:k2", "v1", "XX"])).await?, *NULL_RESP);
+        assert_eq!(set(context(), request("SET", &["string:k2", "v1", "nx"])).await?, *OK_RESP);
+        assert_eq!(set(context(), request("SET", &["string:k2", "v2", "NX", "GET"])).await?, bulk("v1"));
+        assert_eq!(set(context(), request("SET", &["string:k2", "v3", "XX", "GET"])).await?, bulk("v1"));
+        assert_eq!(get(context(), request("GET", &["string:k2"])).await?, bulk("v3"));
+        assert!(set(context(), request("SET", &["string:k2", "v", "NX", "XX"])).await.is_err());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn set_expiration() -> anyhow::Result<()> {
+        set(context(), request("SET", &["string:k3", "v", "PX", "20"])).await?;
+        assert_eq!(get(context(), request("GET", &["string:k3"])).await?, bulk("v"));
+        set(context(), request("SET", &["string:k3", "v2", "KEEPTTL"])).await?;
+        tokio::time::sleep(std::time::Duration::from_millis(40)).await;
+        assert_eq!(get(context(), request("GET", &["string:k3"])).await?, *NULL_RESP);
+
+        assert!(set(context(), request("SET", &["string:k3", "v", "EX", "0"])).await.is_err());
+        assert!(set(context(), request("SET", &["string:k3", "v", "EX", "10", "KEEPTTL"])).await.is_err());
+
+        let deadline = (chrono::Utc::now() + chrono::Duration::seconds(100)).timestamp_millis();
+        set(context(), request("SET", &["string:k3", "v", "PXAT", &deadline.to_string()])).await?;
+        let expire_at = DB.read().await.get(b"string:k3").and_then(|entry| entry.expire_at);
+        assert_eq!(expire_at.map(|t| t.timestamp_millis()), Some(deadline));
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn integer_commands() -> anyhow::Result<()> {
+        assert_eq!(incr(context(), request("INCR", &["string:counter"])).await?, RespValue::Integer(1));
+        assert_eq!(incrby(context(), request("INCRBY", &["string:counter", "41"])).await?, RespValue::Integer(42));
+        assert_eq!(decrby(context(), request("DECRBY", &["string:counter", "50"])).await?, RespValue::Integer(-8));
+        assert_eq!(decr(context(), request("DECR", &["string:counter"])).await?, RespValue::Integer(-9));
+
+        set(context(), request("SET", &["string:counter", "9223372036854775807"])).await?;
+        let err = incr(context(), request("INCR", &["string:counter"])).await.unwrap_err();
+        assert_eq!(err.to_string(), "increment or decrement would overflow");
+
+        for invalid in ["abc", " 1", "+1", "01", ""] {
+            set(context(), request("SET", &["string:counter", invalid])).await?;
+            let err = incr(context(), request("INCR", &["string:counter"])).await.unwrap_err();
+            assert!(matches!(err.downcast_ref::<Error>(), Some(Error::InvalidInteger(_))), "{:?}", invalid);
+        }
+
+        set(context(), request("SET", &["string:float", "10.5"])).await?;
+        assert_eq!(incrbyfloat(context(), request("INCRBYFLOAT", &["string:float", "0.1"])).await?, bulk("10.6"));
+        assert_eq!(incrbyfloat(context(), request("INCRBYFLOAT", &["string:float", "-5.6"])).await?, bulk("5"));
+        assert!(incrbyfloat(context(), request("INCRBYFLOAT", &["string:float", "inf"])).await.is_err());
+        assert_eq!(incrbyfloat(context(), request("INCRBYFLOAT", &["string:big", "1e21"])).await?, bulk("1e+21"));
+        assert_eq!(incrbyfloat(context(), request("INCRBYFLOAT", &["string:small", "0.00001"])).await?, bulk("1.0000000000000001e-05"));
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn incr_keeps_ttl() -> anyhow::Result<()> {
+        set(context(), request("SET", &["string:ttl", "1", "EX", "100"])).await?;
+        incr(context(), request("INCR", &["string:ttl"])).await?;
+        assert!(DB.read().await.get(b"string:ttl").and_then(|entry| entry.expire_at).is_some());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn multi_keys() -> anyhow::Result<()> {
+        mset(context(), request("MSET", &["string:m1", "a", "string:m2", "b"])).await?;
+        let values = mget(context(), request("MGET", &["string:m1", "string:missing", "string:m2"])).await?;
+        assert_eq!(values, RespValue::Array(vec![bulk("a"), NULL_RESP.clone(), bulk("b")]));
+        assert!(mset(context(), request("MSET", &["string:m1", "a", "string:m2"])).await.is_err());
+
+        let result = msetnx(context(), request("MSETNX", &["string:m3", "c", "string:m1", "x"])).await?;
+        assert_eq!(result, RespValue::Integer(0));
+        assert_eq!(get(context(), request("GET", &["string:m3"])).await?, *NULL_RESP);
+        let result = msetnx(context(), request("MSETNX", &["string:m3", "c", "string:m4", "d"])).await?;
+        assert_eq!(result, RespValue::Integer(1));
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn ranges() -> anyhow::Result<()> {
+        assert_eq!(append(context(), request("APPEND", &["string:r", "Hello"])).await?, RespValue::Integer(5));
+        assert_eq!(append(context(), request("APPEND", &["string:r", " World"])).await?, RespValue::Integer(11));
+        assert_eq!(strlen(context(), request("STRLEN", &["string:r"])).await?, RespValue::Integer(11));
+        assert_eq!(getrange(context(), request("GETRANGE", &["string:r", "0", "4"])).await?, bulk("Hello"));
+        assert_eq!(getrange(context(), request("GETRANGE", &["string:r", "-5", "-1"])).await?, bulk("World"));
+        assert_eq!(getrange(context(), request("GETRANGE", &["string:r", "5", "2"])).await?, bulk(""));
+        assert_eq!(getrange(context(), request("GETRANGE", &["string:r", "0", "100"])).await?, bulk("Hello World"));
+
+        assert_eq!(setrange(context(), request("SETRANGE", &["string:r", "6", "Redis"])).await?, RespValue::Integer(11));
+        assert_eq!(get(context(), request("GET", &["string:r"])).await?, bulk("Hello Redis"));
+        // the buffer is written in place rather than copied
+        let buffer = DB.read().await.get(b"string:r").unwrap().as_string()?.as_ptr();
+        setrange(context(), request("SETRANGE", &["string:r", "0", "J"])).await?;
+        assert_eq!(DB.read().await.get(b"string:r").unwrap().as_string()?.as_ptr(), buffer);
+        assert_eq!(get(context(), request("GET", &["string:r"])).await?, bulk("Jello Redis"));
+        assert_eq!(setrange(context(), request("SETRANGE", &["string:pad", "3", "x"])).await?, RespValue::Integer(4));
+        assert_eq!(get(context(), request("GET", &["string:pad"])).await?, bulk("\0\0\0x"));
+        assert_eq!(setrange(context(), request("SETRANGE", &["string:empty", "3", ""])).await?, RespValue::Integer(0));
+        assert_eq!(get(context(), request("GET", &["string:empty"])).await?, *NULL_RESP);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn getdel_getex_and_setex() -> anyhow::Result<()> {
+        set(context(), request("SET", &["string:g", "v"])).await?;
+        assert_eq!(getex(context(), request("GETEX", &["string:g", "EX", "100"])).await?, bulk("v"));
+        assert!(DB.read().await.get(b"string:g").and_then(|entry| entry.expire_at).is_some());
+        assert_eq!(getex(context(), request("GETEX", &["string:g", "PERSIST"])).await?, bulk("v"));
+        assert!(DB.read().await.get(b"string:g").and_then(|entry| entry.expire_at).is_none());
+        assert!(getex(context(), request("GETEX", &["string:g", "EX", "1", "PERSIST"])).await.is_err());
+
+        assert_eq!(getdel(context(), request("GETDEL", &["string:g"])).await?, bulk("v"));
+        assert_eq!(getdel(context(), request("GETDEL", &["string:g"])).await?, *NULL_RESP);
+
+        assert_eq!(setnx(context(), request("SETNX", &["string:g", "a"])).await?, RespValue::Integer(1));
+        assert_eq!(setnx(context(), request("SETNX", &["string:g", "b"])).await?, RespValue::Integer(0));
+        assert_eq!(psetex(context(), request("PSETEX", &["string:g", "20", "c"])).await?, *OK_RESP);
+        assert_eq!(get(context(), request("GET", &["string:g"])).await?, bulk("c"));
+        tokio::time::sleep(std::time::Duration::from_millis(40)).await;
+        assert_eq!(get(context(), request("GET", &["string:g"])).await?, *NULL_RESP);
+        assert!(setex(context(), request("SETEX", &["string:g", "-1", "c"])).await.is_err());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn lcs_matches_redis() -> anyhow::Result<()> {
+        mset(context(), request("MSET", &["string:lcs1", "ohmytext", "string:lcs2", "mynewtext"])).await?;
+        assert_eq!(lcs_command(context(), request("LCS", &["string:lcs1", "string:lcs2"])).await?, bulk("mytext"));
+        let len = lcs_command(context(), request("LCS", &["string:lcs1", "string:lcs2", "LEN"])).await?;
+        assert_eq!(len, RespValue::Integer(6));
+
+        let range = |a: i64, b: i64| RespValue::Array(vec![RespValue::Integer(a), RespValue::Integer(b)]);
+        let idx = lcs_command(context(), request("LCS", &["string:lcs1", "string:lcs2", "IDX", "MINMATCHLEN", "4", "WITHMATCHLEN"])).await?;
+        assert_eq!(idx, RespValue::Map(vec![
+            (bulk("matches"), RespValue::Array(vec![RespValue::Array(vec![range(4, 7), range(5, 8), RespValue::Integer(4)])])),
+            (bulk("len"), RespValue::Integer(6)),
+        ]));
+        let idx = lcs_command(context(), request("LCS", &["string:lcs1", "string:lcs2", "IDX"])).await?;
+        let RespValue::Map(pairs) = idx else { panic!() };
+        assert_eq!(pairs[0].1, RespValue::Array(vec![
+            RespValue::Array(vec![range(4, 7), range(5, 8)]),
+            RespValue::Array(vec![range(2, 3), range(0, 1)]),
+        ]));
+        assert!(lcs_command(context(), request("LCS", &["string:lcs1", "string:lcs2", "LEN", "IDX"])).await.is_err());
+        Ok(())
+    }
+
+    async fn put_raw(key: &'static str, value: &'static [u8]) {
+        DB.write().await.put(Bytes::from(key), Entry::new(Value::String(Bytes::from_static(value))));
+    }
+
+    #[tokio::test]
+    async fn bitmaps() -> anyhow::Result<()> {
+        let field_set = |args: &'static [&'static str]| bitfield(context(), request("BITFIELD", args));
+        assert_eq!(setbit(context(), request("SETBIT", &["string:bits", "7", "1"])).await?, RespValue::Integer(0));
+        assert_eq!(setbit(context(), request("SETBIT", &["string:bits", "7", "0"])).await?, RespValue::Integer(1));
+        setbit(context(), request("SETBIT", &["string:bits", "17", "1"])).await?;
+        assert_eq!(get(context(), request("GET", &["string:bits"])).await?, bulk("\0\0@"));
+        assert_eq!(getbit(context(), request("GETBIT", &["string:bits", "17"])).await?, RespValue::Integer(1));
+        assert_eq!(getbit(context(), request("GETBIT", &["string:bits", "1000"])).await?, RespValue::Integer(0));
+        assert!(setbit(context(), request("SETBIT", &["string:bits", "1", "2"])).await.is_err());
+        assert!(setbit(context(), request("SETBIT", &["string:bits", "4294967296", "1"])).await.is_err());
+
+        // writes go to the buffer in place once nothing else holds it
+        setbit(context(), request("SETBIT", &["string:bits2", "80000", "1"])).await?;
+        let buffer = DB.read().await.get(b"string:bits2").unwrap().as_string()?.as_ptr();
+        setbit(context(), request("SETBIT", &["string:bits2", "3", "1"])).await?;
+        field_set(&["string:bits2", "SET", "u8", "#5", "7"]).await?;
+        let value = DB.read().await.get(b"string:bits2").unwrap().as_string()?.clone();
+        assert_eq!((value.as_ptr(), value.len(), &value[..6]), (buffer, 10001, &b"\x10\0\0\0\0\x07"[..]));
+        // and are copied while it is shared
+        setbit(context(), request("SETBIT", &["string:bits2", "0", "1"])).await?;
+        assert_eq!(value[0], 0x10);
+        assert_eq!(getbit(context(), request("GETBIT", &["string:bits2", "0"])).await?, RespValue::Integer(1));
+
+        set(context(), request("SET", &["string:foobar", "foobar"])).await?;
+        let count = |args: &'static [&'static str]| bitcount(context(), request("BITCOUNT", args));
+        assert_eq!(count(&["string:foobar"]).await?, RespValue::Integer(26));
+        assert_eq!(count(&["string:foobar", "1", "1"]).await?, RespValue::Integer(6));
+        assert_eq!(count(&["string:foobar", "-2", "-1"]).await?, RespValue::Integer(7));
+        assert_eq!(count(&["string:foobar", "5", "30", "BIT"]).await?, RespValue::Integer(17));
+        assert_eq!(count(&["string:missing", "0", "-1"]).await?, RespValue::Integer(0));
+        assert!(count(&["string:foobar", "1"]).await.is_err());
+
+        let pos = |args: &'static [&'static str]| bitpos(context(), request("BITPOS", args));
+        put_raw("string:pos1", b"\xff\xf0\x00").await;
+        assert_eq!(pos(&["string:pos1", "0"]).await?, RespValue::Integer(12));
+        put_raw("string:pos2", b"\x00\xff\xf0").await;
+        assert_eq!(pos(&["string:pos2", "1", "0"]).await?, RespValue::Integer(8));
+        assert_eq!(pos(&["string:pos2", "1", "2"]).await?, RespValue::Integer(16));
+        assert_eq!(pos(&["string:pos2", "1", "7", "15", "BIT"]).await?, RespValue::Integer(8));
+        put_raw("string:pos3", b"\xff\xff").await;
+        assert_eq!(pos(&["string:pos3", "0"]).await?, RespValue::Integer(16));
+        assert_eq!(pos(&["string:pos3", "0", "0", "-1"]).await?, RespValue::Integer(-1));
+        assert_eq!(pos(&["string:missing", "0"]).await?, RespValue::Integer(0));
+        assert_eq!(pos(&["string:missing", "1"]).await?, RespValue::Integer(-1));
+        assert!(pos(&["string:pos3", "2"]).await.is_err());
+
+        set(context(), request("SET", &["string:abcdef", "abcdef"])).await?;
+        let op = bitop(context(), request("BITOP", &["AND", "string:and", "string:foobar", "string:abcdef"])).await?;
+        assert_eq!(op, RespValue::Integer(6));
+        assert_eq!(get(context(), request("GET", &["string:and"])).await?, bulk("`bc`ab"));
+        bitop(context(), request("BITOP", &["XOR", "string:xor", "string:foobar", "string:foobar", "string:missing"])).await?;
+        assert_eq!(get(context(), request("GET", &["string:xor"])).await?, bulk("\0\0\0\0\0\0"));
+        bitop(context(), request("BITOP", &["NOT", "string:not", "string:pos3"])).await?;
+        assert_eq!(get(context(), request("GET", &["string:not"])).await?, bulk("\0\0"));
+        assert!(bitop(context(), request("BITOP", &["NOT", "string:not", "string:pos3", "string:pos1"])).await.is_err());
+        assert_eq!(bitop(context(), request("BITOP", &["OR", "string:not", "string:missing"])).await?, RespValue::Integer(0));
+        assert_eq!(get(context(), request("GET", &["string:not"])).await?, *NULL_RESP);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn bitfields() -> anyhow::Result<()> {
+        let field = |args: &'static [&'static str]| bitfield(context(), request("BITFIELD", args));
+        let integers = |values: &[i64]| RespValue::Array(values.iter().map(|&v| RespValue::Integer(v)).collect());
+        assert_eq!(field(&["string:bf", "INCRBY", "i5", "100", "1", "GET", "u4", "0"]).await?, integers(&[1, 0]));
+
+        let incr = &["string:bf2", "INCRBY", "u2", "100", "1", "OVERFLOW", "SAT", "INCRBY", "u2", "102", "1"];
+        assert_eq!(field(incr).await?, integers(&[1, 1]));
+        assert_eq!(field(incr).await?, integers(&[2, 2]));
+        assert_eq!(field(incr).await?, integers(&[3, 3]));
+        assert_eq!(field(incr).await?, integers(&[0, 3]));
+        let fail = field(&["string:bf2", "OVERFLOW", "FAIL", "INCRBY", "u2", "102", "1"]).await?;
+        assert_eq!(fail, RespValue::Array(vec![NULL_RESP.clone()]));
+
+        assert_eq!(field(&["string:bf3", "SET", "i8", "0", "200", "GET", "i8", "0"]).await?, integers(&[0, -56]));
+        assert_eq!(field(&["string:bf3", "SET", "u8", "#1", "255", "GET", "u8", "8"]).await?, integers(&[0, 255]));
+        assert_eq!(field(&["string:bf3", "OVERFLOW", "SAT", "SET", "u8", "#1", "-1", "INCRBY", "i8", "0", "-100"]).await?, integers(&[255, -128]));
+        assert_eq!(field(&["string:bf3", "SET", "i64", "0", "-1", "INCRBY", "i64", "0", "1"]).await?, integers(&[(0x80ffu64 << 48) as i64, 0]));
+        assert_eq!(field(&["string:bf3", "INCRBY", "i64", "0", "9223372036854775807", "INCRBY", "i64", "0", "1"]).await?, integers(&[i64::MAX, i64::MIN]));
+
+        assert!(field(&["string:bf3", "GET", "u64", "0"]).await.is_err());
+        assert!(field(&["string:bf3", "GET", "i65", "0"]).await.is_err());
+        assert!(field(&["string:bf3", "GET", "i8", "-1"]).await.is_err());
+        assert!(field(&["string:bf3", "SET", "u8", "18446744073709551615", "1"]).await.is_err());
+        assert!(field(&["string:bf3", "SET", "u8", "#2305843009213693951", "1"]).await.is_err());
+        assert!(field(&["string:bf3", "OVERFLOW", "NONE"]).await.is_err());
+        let ro = bitfield_ro(context(), request("BITFIELD_RO", &["string:bf3", "GET", "u8", "#0"])).await?;
+        assert_eq!(ro, integers(&[128]));
+        assert!(bitfield_ro(context(), request("BITFIELD_RO", &["string:bf3", "SET", "u8", "0", "1"])).await.is_err());
+        assert_eq!(field(&["string:bf4", "GET", "u8", "0"]).await?, integers(&[0]));
+        assert_eq!(get(context(), request("GET", &["string:bf4"])).await?, *NULL_RESP);
+        Ok(())
+    }
+}
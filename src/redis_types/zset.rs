@@ -0,0 +1,873 @@
+use std::collections::HashMap;
+use std::sync::Arc;
+use bytes::Bytes;
+use crate::engine::{Database, Entry, LexBound, LexRange, ScoreBound, ScoreRange, Set, Value, ZSet, DB};
+use super::blocking::{self, Served};
+use super::string::clamp_range;
+use super::{arg_f64, arg_i64, check_args, format_f64, ScanArgs};
+use crate::error::*;
+use crate::parser::{NULL_RESP, RESP3};
+use crate::{context::Context, parser::{RespRequest, RespValue}};
+use crate::command_table::{RouteHandler, ROUTE_MAP};
+
+pub(crate) fn get_zset<'a>(db: &'a Database, key: &[u8]) -> Result<Option<&'a ZSet>> {
+    db.get(key).map(|entry| entry.as_zset()).transpose()
+}
+
+fn get_zset_mut<'a>(db: &'a mut Database, key: &[u8]) -> Result<Option<&'a mut ZSet>> {
+    db.get_mut(key).map(|entry| entry.as_zset_mut()).transpose()
+}
+
+/// Deletes the key once its last member is gone
+fn delete_if_empty(db: &mut Database, key: &[u8]) {
+    if get_zset(db, key).ok().flatten().is_some_and(ZSet::is_empty) {
+        db.delete(key);
+    }
+}
+
+/// Replaces whatever `destination` held with `zset`, returns its length
+pub(crate) fn store(db: &mut Database, destination: &Bytes, zset: ZSet) -> usize {
+    let len = zset.len();
+    if zset.is_empty() {
+        db.delete(destination);
+    } else {
+        db.put(destination.clone(), Entry::new(Value::ZSet(zset)));
+        blocking::signal(destination);
+    }
+    len
+}
+
+/// "1.5" or "(1.5" for an exclusive bound, "-inf" and "+inf" included
+fn parse_score_bound(arg: &RespValue) -> Result<ScoreBound> {
+    let bytes = arg.as_bytes()?;
+    let (exclusive, value) = match bytes.strip_prefix(b"(") {
+        Some(value) => (true, value),
+        None => (false, bytes.as_ref()),
+    };
+    let value = std::str::from_utf8(value)
+        .ok()
+        .and_then(|s| s.parse::<f64>().ok())
+        .filter(|value| !value.is_nan())
+        .ok_or_else(|| Error::Other("min or max is not a float".into()))?;
+    Ok(ScoreBound { value, exclusive })
+}
+
+/// "[a" or "(a" for an exclusive bound, "-" and "+" for the ends
+fn parse_lex_bound(arg: &RespValue) -> Result<LexBound> {
+    let bytes = arg.as_bytes()?;
+    match bytes.first() {
+        Some(b'-') if bytes.len() == 1 => Ok(LexBound::NegInf),
+        Some(b'+') if bytes.len() == 1 => Ok(LexBound::PosInf),
+        Some(b'[') => Ok(LexBound::Inclusive(bytes.slice(1..))),
+        Some(b'(') => Ok(LexBound::Exclusive(bytes.slice(1..))),
+        _ => Err(Error::Other("min or max not valid string range item".into())),
+    }
+}
+
+/// Members, followed by their scores if asked: flat in RESP2, one pair per member in RESP3
+fn scored_reply(context: &Context, members: Vec<(Bytes, f64)>, with_scores: bool) -> RespValue {
+    if !with_scores {
+        return RespValue::bulk_array(members.into_iter().map(|(member, _)| member));
+    }
+    let pair = |(member, score): (Bytes, f64)| [RespValue::BulkString(Some(member)), RespValue::Double(score)];
+    if context.protocol() >= RESP3 {
+        RespValue::Array(members.into_iter().map(|member| RespValue::Array(pair(member).into())).collect())
+    } else {
+        RespValue::Array(members.into_iter().flat_map(pair).collect())
+    }
+}
+
+#[derive(Debug, Default)]
+pub(crate) struct AddFlags {
+    pub nx: bool,
+    pub xx: bool,
+    pub gt: bool,
+    pub lt: bool,
+    pub ch: bool,
+    pub incr: bool,
+}
+
+pub(crate) enum Outcome {
+    Added(f64),
+    Updated(f64),
+    Unchanged(f64),
+    // NX, XX, GT or LT left the member alone
+    Skipped,
+}
+
+/// Adds or updates one member the way the ZADD flags ask
+fn add_member(zset: &mut ZSet, member: &Bytes, score: f64, flags: &AddFlags) -> Result<Outcome> {
+    let Some(current) = zset.score(member) else {
+        if flags.xx {
+            return Ok(Outcome::Skipped);
+        }
+        zset.insert(member.clone(), score);
+        return Ok(Outcome::Added(score));
+    };
+    if flags.nx {
+        return Ok(Outcome::Skipped);
+    }
+    let score = if flags.incr { current + score } else { score };
+    if score.is_nan() {
+        return Err(Error::Other("resulting score is not a number (NaN)".into()));
+    }
+    if (flags.gt && score <= current) || (flags.lt && score >= current) {
+        return Ok(Outcome::Skipped);
+    }
+    if score == current {
+        return Ok(Outcome::Unchanged(score));
+    }
+    zset.insert(member.clone(), score);
+    Ok(Outcome::Updated(score))
+}
+
+/// Applies `pairs` of score and member to `key`, creating it only if a member gets added
+pub(crate) async fn add(db: &mut Database, request: &RespRequest, key: &Bytes, pairs: &[(f64, Bytes)], flags: &AddFlags) -> anyhow::Result<Vec<Outcome>> {
+    if get_zset(db, key)?.is_none() {
+        db.put(key.clone(), Entry::new(Value::ZSet(ZSet::new())));
+    }
+    let zset = get_zset_mut(db, key)?.expect("zset was just created");
+    let outcomes: Result<Vec<Outcome>> = pairs.iter().map(|(score, member)| add_member(zset, member, *score, flags)).collect();
+    delete_if_empty(db, key);
+    let outcomes = outcomes?;
+
+    if outcomes.iter().any(|outcome| matches!(outcome, Outcome::Added(_) | Outcome::Updated(_))) {
+        db.append_log(&request.to_resp()).await?;
+    }
+    if outcomes.iter().any(|outcome| matches!(outcome, Outcome::Added(_))) {
+        blocking::signal(key);
+        blocking::serve_ready(db).await?;
+    }
+    Ok(outcomes)
+}
+
+fn incr_reply(outcome: &Outcome) -> RespValue {
+    match outcome {
+        Outcome::Added(score) | Outcome::Updated(score) | Outcome::Unchanged(score) => RespValue::Double(*score),
+        Outcome::Skipped => NULL_RESP.clone(),
+    }
+}
+
+/// ZADD key [NX|XX] [GT|LT] [CH] [INCR] score member [score member ...]
+#[router_macro::route("ZADD")]
+async fn zadd(_context : Arc<Context>, request: RespRequest) -> anyhow::Result<RespValue> {
+    check_args(&request, "zadd", 3, None)?;
+    let key = request.args[0].as_bytes()?;
+
+    let mut flags = AddFlags::default();
+    let mut first_pair = 1;
+    for arg in &request.args[1..] {
+        match arg.as_bytes()?.to_ascii_uppercase().as_slice() {
+            b"NX" => flags.nx = true,
+            b"XX" => flags.xx = true,
+            b"GT" => flags.gt = true,
+            b"LT" => flags.lt = true,
+            b"CH" => flags.ch = true,
+            b"INCR" => flags.incr = true,
+            _ => break,
+        }
+        first_pair += 1;
+    }
+    let pairs = &request.args[first_pair..];
+    if pairs.is_empty() || !pairs.len().is_multiple_of(2) {
+        return Err(Error::Syntax.into());
+    }
+    if flags.nx && flags.xx {
+        return Err(Error::Other("XX and NX options at the same time are not compatible".into()).into());
+    }
+    if (flags.gt && flags.lt) || ((flags.gt || flags.lt) && flags.nx) {
+        return Err(Error::Other("GT, LT, and/or NX options at the same time are not compatible".into()).into());
+    }
+    if flags.incr && pairs.len() > 2 {
+        return Err(Error::Other("INCR option supports a single increment-element pair".into()).into());
+    }
+    let pairs = pairs
+        .chunks(2)
+        .map(|pair| Ok((arg_f64(&pair[0])?, pair[1].as_bytes()?.clone())))
+        .collect::<Result<Vec<_>>>()?;
+
+    let mut db = DB.write().await;
+    let outcomes = add(&mut db, &request, key, &pairs, &flags).await?;
+    if flags.incr {
+        return Ok(incr_reply(&outcomes[0]));
+    }
+    let added = outcomes.iter().filter(|outcome| matches!(outcome, Outcome::Added(_))).count();
+    let updated = outcomes.iter().filter(|outcome| matches!(outcome, Outcome::Updated(_))).count();
+    Ok(RespValue::Integer((added + if flags.ch { updated } else { 0 }) as i64))
+}
+
+/// ZINCRBY key increment member
+#[router_macro::route("ZINCRBY")]
+async fn zincrby(_context : Arc<Context>, request: RespRequest) -> anyhow::Result<RespValue> {
+    check_args(&request, "zincrby", 3, Some(3))?;
+    let key = request.args[0].as_bytes()?;
+    let pairs = [(arg_f64(&request.args[1])?, request.args[2].as_bytes()?.clone())];
+
+    let mut db = DB.write().await;
+    let outcomes = add(&mut db, &request, key, &pairs, &AddFlags { incr: true, ..Default::default() }).await?;
+    Ok(incr_reply(&outcomes[0]))
+}
+
+#[router_macro::route("ZCARD")]
+async fn zcard(_context : Arc<Context>, request: RespRequest) -> anyhow::Result<RespValue> {
+    check_args(&request, "zcard", 1, Some(1))?;
+    let db = DB.read().await;
+    let len = get_zset(&db, request.args[0].as_bytes()?)?.map(ZSet::len).unwrap_or(0);
+    Ok(RespValue::Integer(len as i64))
+}
+
+#[router_macro::route("ZSCORE")]
+async fn zscore(_context : Arc<Context>, request: RespRequest) -> anyhow::Result<RespValue> {
+    check_args(&request, "zscore", 2, Some(2))?;
+    let db = DB.read().await;
+    let score = get_zset(&db, request.args[0].as_bytes()?)?.and_then(|zset| zset.score(request.args[1].as_bytes().ok()?));
+    Ok(score.map(RespValue::Double).unwrap_or_else(|| NULL_RESP.clone()))
+}
+
+#[router_macro::route("ZMSCORE")]
+async fn zmscore(_context : Arc<Context>, request: RespRequest) -> anyhow::Result<RespValue> {
+    check_args(&request, "zmscore", 2, None)?;
+    let db = DB.read().await;
+    let zset = get_zset(&db, request.args[0].as_bytes()?)?;
+    let mut scores = Vec::with_capacity(request.args.len() - 1);
+    for member in &request.args[1..] {
+        let score = zset.and_then(|zset| zset.score(member.as_bytes().ok()?));
+        scores.push(score.map(RespValue::Double).unwrap_or_else(|| NULL_RESP.clone()));
+    }
+    Ok(RespValue::Array(scores))
+}
+
+/// ZRANK/ZREVRANK key member [WITHSCORE]
+async fn rank_command(request: &RespRequest, name: &str, reverse: bool) -> anyhow::Result<RespValue> {
+    check_args(request, name, 2, Some(3))?;
+    let with_score = match request.args.get(2) {
+        None => false,
+        Some(arg) if arg.as_bytes()?.eq_ignore_ascii_case(b"WITHSCORE") => true,
+        Some(_) => return Err(Error::Syntax.into()),
+    };
+    let member = request.args[1].as_bytes()?;
+
+    let db = DB.read().await;
+    let Some(zset) = get_zset(&db, request.args[0].as_bytes()?)? else {
+        return Ok(NULL_RESP.clone());
+    };
+    let (Some(rank), Some(score)) = (zset.rank(member, reverse), zset.score(member)) else {
+        return Ok(NULL_RESP.clone());
+    };
+    Ok(match with_score {
+        true => RespValue::Array(vec![RespValue::Integer(rank as i64), RespValue::Double(score)]),
+        false => RespValue::Integer(rank as i64),
+    })
+}
+
+#[router_macro::route("ZRANK")]
+async fn zrank(_context : Arc<Context>, request: RespRequest) -> anyhow::Result<RespValue> {
+    rank_command(&request, "zrank", false).await
+}
+
+#[router_macro::route("ZREVRANK")]
+async fn zrevrank(_context : Arc<Context>, request: RespRequest) -> anyhow::Result<RespValue> {
+    rank_command(&request, "zrevrank", true).await
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum RangeBy {
+    Rank,
+    Score,
+    Lex,
+}
+
+#[derive(Debug)]
+enum Bounds {
+    Rank(i64, i64),
+    Score(ScoreRange),
+    Lex(LexRange),
+}
+
+/// What the ZRANGE family selects
+#[derive(Debug)]
+struct RangeQuery {
+    bounds: Bounds,
+    rev: bool,
+    limit: Option<(i64, i64)>,
+    with_scores: bool,
+}
+
+impl RangeQuery {
+    /// Parses `start stop [BYSCORE|BYLEX] [REV] [LIMIT offset count] [WITHSCORES]`. The legacy commands
+    /// fix `by` and `rev` themselves and only take LIMIT and WITHSCORES.
+    fn parse(args: &[RespValue], mut by: RangeBy, mut rev: bool, legacy: bool, allow_scores: bool) -> Result<RangeQuery> {
+        let mut limit = None;
+        let mut with_scores = false;
+        let mut options = args[2..].iter();
+        while let Some(arg) = options.next() {
+            match arg.as_bytes()?.to_ascii_uppercase().as_slice() {
+                b"BYSCORE" if !legacy => by = RangeBy::Score,
+                b"BYLEX" if !legacy => by = RangeBy::Lex,
+                b"REV" if !legacy => rev = true,
+                b"WITHSCORES" if allow_scores => with_scores = true,
+                b"LIMIT" => {
+                    let offset = arg_i64(options.next().ok_or(Error::Syntax)?)?;
+                    let count = arg_i64(options.next().ok_or(Error::Syntax)?)?;
+                    limit = Some((offset, count));
+                }
+                _ => return Err(Error::Syntax),
+            }
+        }
+        if limit.is_some() && by == RangeBy::Rank {
+            return Err(Error::Other("syntax error, LIMIT is only supported in combination with either BYSCORE or BYLEX".into()));
+        }
+        if with_scores && by == RangeBy::Lex {
+            return Err(Error::Other("syntax error, WITHSCORES not supported in combination with BYLEX".into()));
+        }
+
+        // reversed score and lex ranges are given from max to min
+        let (min, max) = if rev && by != RangeBy::Rank { (&args[1], &args[0]) } else { (&args[0], &args[1]) };
+        let bounds = match by {
+            RangeBy::Rank => Bounds::Rank(arg_i64(min)?, arg_i64(max)?),
+            RangeBy::Score => Bounds::Score(ScoreRange { min: parse_score_bound(min)?, max: parse_score_bound(max)? }),
+            RangeBy::Lex => Bounds::Lex(LexRange { min: parse_lex_bound(min)?, max: parse_lex_bound(max)? }),
+        };
+        Ok(RangeQuery { bounds, rev, limit, with_scores })
+    }
+
+    /// The selected members in reply order, found by rank so that skipping `offset` costs O(log N)
+    fn run(&self, zset: &ZSet) -> Vec<(Bytes, f64)> {
+        let collect = |start: usize, end: usize| zset.range(start, end, self.rev).map(|(member, score)| (member.clone(), score)).collect();
+        let ranks = match &self.bounds {
+            Bounds::Rank(start, stop) => {
+                return match clamp_range(*start, *stop, zset.len()) {
+                    Some(range) => collect(range.start, range.end - 1),
+                    None => vec![],
+                };
+            }
+            Bounds::Score(range) => zset.score_range(range),
+            Bounds::Lex(range) => zset.lex_range(range),
+        };
+        let Some((low, high)) = ranks else {
+            return vec![];
+        };
+
+        let (offset, count) = self.limit.unwrap_or((0, -1));
+        let available = high - low + 1;
+        if offset < 0 || offset as usize >= available || count == 0 {
+            return vec![];
+        }
+        let offset = offset as usize;
+        let take = if count < 0 { available - offset } else { (count as usize).min(available - offset) };
+        // a reversed range starts from `high`, counted from the end
+        let start = if self.rev { zset.len() - 1 - high + offset } else { low + offset };
+        collect(start, start + take - 1)
+    }
+}
+
+async fn range_command(context: &Context, request: &RespRequest, query: RangeQuery) -> anyhow::Result<RespValue> {
+    let db = DB.read().await;
+    let members = match get_zset(&db, request.args[0].as_bytes()?)? {
+        Some(zset) => query.run(zset),
+        None => vec![],
+    };
+    Ok(scored_reply(context, members, query.with_scores))
+}
+
+/// ZRANGE key start stop [BYSCORE|BYLEX] [REV] [LIMIT offset count] [WITHSCORES]
+#[router_macro::route("ZRANGE")]
+async fn zrange(context : Arc<Context>, request: RespRequest) -> anyhow::Result<RespValue> {
+    check_args(&request, "zrange", 3, None)?;
+    let query = RangeQuery::parse(&request.args[1..], RangeBy::Rank, false, false, true)?;
+    range_command(&context, &request, query).await
+}
+
+#[router_macro::route("ZREVRANGE")]
+async fn zrevrange(context : Arc<Context>, request: RespRequest) -> anyhow::Result<RespValue> {
+    check_args(&request, "zrevrange", 3, Some(4))?;
+    let query = RangeQuery::parse(&request.args[1..], RangeBy::Rank, true, true, true)?;
+    range_command(&context, &request, query).await
+}
+
+#[router_macro::route("ZRANGEBYSCORE")]
+async fn zrangebyscore(context : Arc<Context>, request: RespRequest) -> anyhow::Result<RespValue> {
+    check_args(&request, "zrangebyscore", 3, None)?;
+    let query = RangeQuery::parse(&request.args[1..], RangeBy::Score, false, true, true)?;
+    range_command(&context, &request, query).await
+}
+
+#[router_macro::route("ZREVRANGEBYSCORE")]
+async fn zrevrangebyscore(context : Arc<Context>, request: RespRequest) -> anyhow::Result<RespValue> {
+    check_args(&request, "zrevrangebyscore", 3, None)?;
+    let query = RangeQuery::parse(&request.args[1..], RangeBy::Score, true, true, true)?;
+    range_command(&context, &request, query).await
+}
+
+#[router_macro::route("ZRANGEBYLEX")]
+async fn zrangebylex(context : Arc<Context>, request: RespRequest) -> anyhow::Result<RespValue> {
+    check_args(&request, "zrangebylex", 3, None)?;
+    let query = RangeQuery::parse(&request.args[1..], RangeBy::Lex, false, true, false)?;
+    range_command(&context, &request, query).await
+}
+
+#[router_macro::route("ZREVRANGEBYLEX")]
+async fn zrevrangebylex(context : Arc<Context>, request: RespRequest) -> anyhow::Result<RespValue> {
+    check_args(&request, "zrevrangebylex", 3, None)?;
+    let query = RangeQuery::parse(&request.args[1..], RangeBy::Lex, true, true, false)?;
+    range_command(&context, &request, query).await
+}
+
+/// ZRANGESTORE destination source start stop [BYSCORE|BYLEX] [REV] [LIMIT offset count]
+#[router_macro::route("ZRANGESTORE")]
+async fn zrangestore(_context : Arc<Context>, request: RespRequest) -> anyhow::Result<RespValue> {
+    check_args(&request, "zrangestore", 4, None)?;
+    let destination = request.args[0].as_bytes()?;
+    let query = RangeQuery::parse(&request.args[2..], RangeBy::Rank, false, false, false)?;
+
+    let mut db = DB.write().await;
+    let members = match get_zset(&db, request.args[1].as_bytes()?)? {
+        Some(zset) => query.run(zset),
+        None => vec![],
+    };
+    let len = store(&mut db, destination, members.into_iter().collect());
+    db.append_log(&request.to_resp()).await?;
+    blocking::serve_ready(&mut db).await?;
+    Ok(RespValue::Integer(len as i64))
+}
+
+#[router_macro::route("ZREM")]
+async fn zrem(_context : Arc<Context>, request: RespRequest) -> anyhow::Result<RespValue> {
+    check_args(&request, "zrem", 2, None)?;
+    let key = request.args[0].as_bytes()?;
+
+    let mut db = DB.write().await;
+    let Some(zset) = get_zset_mut(&mut db, key)? else {
+        return Ok(RespValue::Integer(0));
+    };
+    let mut removed = 0;
+    for member in &request.args[1..] {
+        removed += zset.remove(member.as_bytes()?).is_some() as i64;
+    }
+    delete_if_empty(&mut db, key);
+    if removed > 0 {
+        db.append_log(&request.to_resp()).await?;
+    }
+    Ok(RespValue::Integer(removed))
+}
+
+/// ZREMRANGEBYRANK/ZREMRANGEBYSCORE/ZREMRANGEBYLEX key min max
+async fn remove_range(request: &RespRequest, name: &str, by: RangeBy) -> anyhow::Result<RespValue> {
+    check_args(request, name, 3, Some(3))?;
+    let key = request.args[0].as_bytes()?;
+    let query = RangeQuery::parse(&request.args[1..], by, false, true, false)?;
+
+    let mut db = DB.write().await;
+    let Some(zset) = get_zset_mut(&mut db, key)? else {
+        return Ok(RespValue::Integer(0));
+    };
+    let members = query.run(zset);
+    for (member, _) in &members {
+        zset.remove(member);
+    }
+    delete_if_empty(&mut db, key);
+    if !members.is_empty() {
+        db.append_log(&request.to_resp()).await?;
+    }
+    Ok(RespValue::Integer(members.len() as i64))
+}
+
+#[router_macro::route("ZREMRANGEBYRANK")]
+async fn zremrangebyrank(_context : Arc<Context>, request: RespRequest) -> anyhow::Result<RespValue> {
+    remove_range(&request, "zremrangebyrank", RangeBy::Rank).await
+}
+
+#[router_macro::route("ZREMRANGEBYSCORE")]
+async fn zremrangebyscore(_context : Arc<Context>, request: RespRequest) -> anyhow::Result<RespValue> {
+    remove_range(&request, "zremrangebyscore", RangeBy::Score).await
+}
+
+#[router_macro::route("ZREMRANGEBYLEX")]
+async fn zremrangebylex(_context : Arc<Context>, request: RespRequest) -> anyhow::Result<RespValue> {
+    remove_range(&request, "zremrangebylex", RangeBy::Lex).await
+}
+
+/// ZCOUNT key min max, from the ranks of both ends
+#[router_macro::route("ZCOUNT")]
+async fn zcount(_context : Arc<Context>, request: RespRequest) -> anyhow::Result<RespValue> {
+    check_args(&request, "zcount", 3, Some(3))?;
+    let range = ScoreRange { min: parse_score_bound(&request.args[1])?, max: parse_score_bound(&request.args[2])? };
+    let db = DB.read().await;
+    let ranks = get_zset(&db, request.args[0].as_bytes()?)?.and_then(|zset| zset.score_range(&range));
+    Ok(RespValue::Integer(ranks.map_or(0, |(low, high)| high - low + 1) as i64))
+}
+
+/// ZLEXCOUNT key min max, from the ranks of both ends
+#[router_macro::route("ZLEXCOUNT")]
+async fn zlexcount(_context : Arc<Context>, request: RespRequest) -> anyhow::Result<RespValue> {
+    check_args(&request, "zlexcount", 3, Some(3))?;
+    let range = LexRange { min: parse_lex_bound(&request.args[1])?, max: parse_lex_bound(&request.args[2])? };
+    let db = DB.read().await;
+    let ranks = get_zset(&db, request.args[0].as_bytes()?)?.and_then(|zset| zset.lex_range(&range));
+    Ok(RespValue::Integer(ranks.map_or(0, |(low, high)| high - low + 1) as i64))
+}
+
+/// Pops up to `count` members with the lowest scores, or with the highest ones if `max`
+fn pop(db: &mut Database, key: &Bytes, count: usize, max: bool) -> Result<Vec<(Bytes, f64)>> {
+    let Some(zset) = get_zset_mut(db, key)? else {
+        return Ok(vec![]);
+    };
+    if count == 0 {
+        return Ok(vec![]);
+    }
+    let popped: Vec<(Bytes, f64)> = zset.range(0, count - 1, max).map(|(member, score)| (member.clone(), score)).collect();
+    for (member, _) in &popped {
+        zset.remove(member);
+    }
+    delete_if_empty(db, key);
+    Ok(popped)
+}
+
+/// ZPOPMIN/ZPOPMAX key [count]
+async fn pop_command(context: &Context, request: &RespRequest, name: &str, max: bool) -> anyhow::Result<RespValue> {
+    check_args(request, name, 1, Some(2))?;
+    let key = request.args[0].as_bytes()?;
+    let count = request.args.get(1).map(arg_i64).transpose()?;
+    if count.is_some_and(|count| count < 0) {
+        return Err(Error::Other("value is out of range, must be positive".into()).into());
+    }
+
+    let mut db = DB.write().await;
+    let popped = pop(&mut db, key, count.unwrap_or(1) as usize, max)?;
+    if !popped.is_empty() {
+        db.append_log(&request.to_resp()).await?;
+    }
+    Ok(match count {
+        Some(_) => scored_reply(context, popped, true),
+        // a single member is a flat pair whatever the protocol
+        None => RespValue::Array(popped.into_iter().flat_map(|(member, score)| [RespValue::BulkString(Some(member)), RespValue::Double(score)]).collect()),
+    })
+}
+
+#[router_macro::route("ZPOPMIN")]
+async fn zpopmin(context : Arc<Context>, request: RespRequest) -> anyhow::Result<RespValue> {
+    pop_command(&context, &request, "zpopmin", false).await
+}
+
+#[router_macro::route("ZPOPMAX")]
+async fn zpopmax(context : Arc<Context>, request: RespRequest) -> anyhow::Result<RespValue> {
+    pop_command(&context, &request, "zpopmax", true).await
+}
+
+/// BZPOPMIN/BZPOPMAX key [key ...] timeout, logged as the ZPOPMIN/ZPOPMAX that served it
+async fn blocking_pop(context: Arc<Context>, request: &RespRequest, name: &str, max: bool) -> anyhow::Result<RespValue> {
+    check_args(request, name, 2, None)?;
+    let (timeout, keys) = request.args.split_last().expect("checked above");
+    let keys = keys.iter().map(|key| key.as_bytes().cloned()).collect::<anyhow::Result<Vec<_>>>()?;
+
+    let command = if max { Bytes::from("ZPOPMAX") } else { Bytes::from("ZPOPMIN") };
+    let serve = move |db: &mut Database, key: &Bytes| -> Result<Option<Served>> {
+        let popped = pop(db, key, 1, max)?;
+        Ok(popped.into_iter().next().map(|(member, score)| Served {
+            reply: RespValue::Array(vec![RespValue::BulkString(Some(key.clone())), RespValue::BulkString(Some(member)), RespValue::Double(score)]),
+            records: vec![RespValue::bulk_array([command.clone(), key.clone()])],
+        }))
+    };
+    let reply = blocking::serve_or_block(context, keys, blocking::parse_timeout(timeout)?, Box::new(serve)).await?;
+    Ok(reply.unwrap_or(RespValue::NullArray))
+}
+
+#[router_macro::route("BZPOPMIN")]
+async fn bzpopmin(context : Arc<Context>, request: RespRequest) -> anyhow::Result<RespValue> {
+    blocking_pop(context, &request, "bzpopmin", false).await
+}
+
+#[router_macro::route("BZPOPMAX")]
+async fn bzpopmax(context : Arc<Context>, request: RespRequest) -> anyhow::Result<RespValue> {
+    blocking_pop(context, &request, "bzpopmax", true).await
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Aggregate {
+    Sum,
+    Min,
+    Max,
+}
+
+impl Aggregate {
+    fn apply(self, a: f64, b: f64) -> f64 {
+        match self {
+            // inf + -inf
+            Aggregate::Sum => zero_if_nan(a + b),
+            Aggregate::Min => a.min(b),
+            Aggregate::Max => a.max(b),
+        }
+    }
+}
+
+fn zero_if_nan(score: f64) -> f64 {
+    if score.is_nan() { 0.0 } else { score }
+}
+
+/// An input of ZUNIONSTORE/ZINTERSTORE: a sorted set, or a plain set whose members all score 1
+enum Input<'a> {
+    ZSet(&'a ZSet),
+    Set(&'a Set),
+}
+
+impl Input<'_> {
+    fn len(&self) -> usize {
+        match self {
+            Input::ZSet(zset) => zset.len(),
+            Input::Set(set) => set.len(),
+        }
+    }
+
+    fn score(&self, member: &[u8]) -> Option<f64> {
+        match self {
+            Input::ZSet(zset) => zset.score(member),
+            Input::Set(set) => set.contains(member).then_some(1.0),
+        }
+    }
+
+    fn iter(&self) -> Box<dyn Iterator<Item = (Bytes, f64)> + '_> {
+        match self {
+            Input::ZSet(zset) => Box::new(zset.iter().map(|(member, score)| (member.clone(), score))),
+            Input::Set(set) => Box::new(set.iter().map(|member| (member, 1.0))),
+        }
+    }
+}
+
+fn get_input<'a>(db: &'a Database, key: &[u8]) -> Result<Option<Input<'a>>> {
+    match db.get(key).map(|entry| &entry.value) {
+        None => Ok(None),
+        Some(Value::ZSet(zset)) => Ok(Some(Input::ZSet(zset))),
+        Some(Value::Set(set)) => Ok(Some(Input::Set(set))),
+        Some(_) => Err(Error::WrongType),
+    }
+}
+
+/// Union or intersection of the inputs at `keys`, scores multiplied by their weight then aggregated
+fn combine(db: &Database, keys: &[RespValue], weights: &[f64], aggregate: Aggregate, inter: bool) -> Result<ZSet> {
+    let mut inputs = Vec::with_capacity(keys.len());
+    for (key, weight) in keys.iter().zip(weights) {
+        inputs.push(get_input(db, key.as_bytes()?)?.map(|input| (input, *weight)));
+    }
+
+    if inter {
+        let Some(mut inputs) = inputs.into_iter().collect::<Option<Vec<_>>>() else {
+            return Ok(ZSet::new());
+        };
+        // the smallest input drives the lookups in the others
+        inputs.sort_by_key(|(input, _)| input.len());
+        let ((first, weight), rest) = inputs.split_first().expect("at least one key");
+        let mut result = ZSet::new();
+        'members: for (member, score) in first.iter() {
+            let mut total = zero_if_nan(score * weight);
+            for (input, weight) in rest {
+                let Some(score) = input.score(&member) else {
+                    continue 'members;
+                };
+                total = aggregate.apply(total, zero_if_nan(score * weight));
+            }
+            result.insert(member, total);
+        }
+        return Ok(result);
+    }
+
+    let mut scores: HashMap<Bytes, f64> = HashMap::new();
+    for (input, weight) in inputs.iter().flatten() {
+        for (member, score) in input.iter() {
+            let score = zero_if_nan(score * weight);
+            scores.entry(member).and_modify(|total| *total = aggregate.apply(*total, score)).or_insert(score);
+        }
+    }
+    Ok(scores.into_iter().collect())
+}
+
+/// ZUNIONSTORE/ZINTERSTORE destination numkeys key [key ...] [WEIGHTS weight [weight ...]] [AGGREGATE SUM|MIN|MAX]
+async fn store_command(request: &RespRequest, name: &str, inter: bool) -> anyhow::Result<RespValue> {
+    check_args(request, name, 3, None)?;
+    let destination = request.args[0].as_bytes()?;
+    let numkeys = arg_i64(&request.args[1])?;
+    if numkeys < 1 {
+        return Err(Error::Other(format!("at least 1 input key is needed for '{}' command", name)).into());
+    }
+    let numkeys = numkeys as usize;
+    if numkeys > request.args.len() - 2 {
+        return Err(Error::Syntax.into());
+    }
+    let keys = &request.args[2..2 + numkeys];
+
+    let mut weights = vec![1.0; numkeys];
+    let mut aggregate = Aggregate::Sum;
+    let mut options = request.args[2 + numkeys..].iter();
+    while let Some(arg) = options.next() {
+        match arg.as_bytes()?.to_ascii_uppercase().as_slice() {
+            b"WEIGHTS" => {
+                for weight in weights.iter_mut() {
+                    *weight = arg_f64(options.next().ok_or(Error::Syntax)?)
+                        .map_err(|_| Error::Other("weight value is not a float".into()))?;
+                }
+            }
+            b"AGGREGATE" => {
+                aggregate = match options.next().ok_or(Error::Syntax)?.as_bytes()?.to_ascii_uppercase().as_slice() {
+                    b"SUM" => Aggregate::Sum,
+                    b"MIN" => Aggregate::Min,
+                    b"MAX" => Aggregate::Max,
+                    _ => return Err(Error::Syntax.into()),
+                };
+            }
+            _ => return Err(Error::Syntax.into()),
+        }
+    }
+
+    let mut db = DB.write().await;
+    let zset = combine(&db, keys, &weights, aggregate, inter)?;
+    let len = store(&mut db, destination, zset);
+    db.append_log(&request.to_resp()).await?;
+    blocking::serve_ready(&mut db).await?;
+    Ok(RespValue::Integer(len as i64))
+}
+
+#[router_macro::route("ZUNIONSTORE")]
+async fn zunionstore(_context : Arc<Context>, request: RespRequest) -> anyhow::Result<RespValue> {
+    store_command(&request, "zunionstore", false).await
+}
+
+#[router_macro::route("ZINTERSTORE")]
+async fn zinterstore(_context : Arc<Context>, request: RespRequest) -> anyhow::Result<RespValue> {
+    store_command(&request, "zinterstore", true).await
+}
+
+/// ZSCAN key cursor [MATCH pattern] [COUNT count]
+#[router_macro::route("ZSCAN")]
+async fn zscan(_context : Arc<Context>, request: RespRequest) -> anyhow::Result<RespValue> {
+    check_args(&request, "zscan", 2, None)?;
+    let scan = ScanArgs::parse(&request.args[1..], false, false)?;
+
+    let db = DB.read().await;
+    let (cursor, members) = match get_zset(&db, request.args[0].as_bytes()?)? {
+        Some(zset) => scan.walk(|cursor, members| zset.scan(cursor, |member, score| members.push((member.clone(), score)))),
+        None => (0, vec![]),
+    };
+    let mut items = vec![];
+    for (member, score) in members.into_iter().filter(|(member, _)| scan.matches(member)) {
+        items.extend([member, Bytes::from(format_f64(score))]);
+    }
+    Ok(ScanArgs::reply(cursor, items))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::redis_types::test_util::{bulk, bulks, context, request};
+
+    async fn range(key: &str, args: &[&str]) -> anyhow::Result<RespValue> {
+        let args: Vec<&str> = [key].into_iter().chain(args.iter().copied()).collect();
+        zrange(context(), request("ZRANGE", &args)).await
+    }
+
+    #[tokio::test]
+    async fn add_with_flags() -> anyhow::Result<()> {
+        assert_eq!(zadd(context(), request("ZADD", &["zset:z1", "1", "a", "2", "b"])).await?, RespValue::Integer(2));
+        assert_eq!(zadd(context(), request("ZADD", &["zset:z1", "NX", "5", "a", "3", "c"])).await?, RespValue::Integer(1));
+        assert_eq!(zadd(context(), request("ZADD", &["zset:z1", "XX", "CH", "5", "a", "4", "d"])).await?, RespValue::Integer(1));
+        assert_eq!(zadd(context(), request("ZADD", &["zset:z1", "GT", "CH", "1", "a", "6", "b"])).await?, RespValue::Integer(1));
+        assert_eq!(zscore(context(), request("ZSCORE", &["zset:z1", "a"])).await?, RespValue::Double(5.0));
+        assert_eq!(zadd(context(), request("ZADD", &["zset:z1", "INCR", "2.5", "a"])).await?, RespValue::Double(7.5));
+        assert_eq!(zadd(context(), request("ZADD", &["zset:z1", "LT", "INCR", "1", "a"])).await?, RespValue::BulkString(None));
+        assert_eq!(zincrby(context(), request("ZINCRBY", &["zset:z1", "-0.5", "a"])).await?, RespValue::Double(7.0));
+
+        assert!(zadd(context(), request("ZADD", &["zset:z1", "NX", "XX", "1", "a"])).await.is_err());
+        assert!(zadd(context(), request("ZADD", &["zset:z1", "GT", "LT", "1", "a"])).await.is_err());
+        assert!(zadd(context(), request("ZADD", &["zset:z1", "INCR", "1", "a", "2", "b"])).await.is_err());
+        assert!(zadd(context(), request("ZADD", &["zset:z1", "1", "a", "2"])).await.is_err());
+        assert!(zadd(context(), request("ZADD", &["zset:z1", "nan", "a"])).await.is_err());
+        zadd(context(), request("ZADD", &["zset:z1", "inf", "inf"])).await?;
+        assert!(zincrby(context(), request("ZINCRBY", &["zset:z1", "-inf", "inf"])).await.is_err());
+
+        assert_eq!(zcard(context(), request("ZCARD", &["zset:z1"])).await?, RespValue::Integer(4));
+        assert_eq!(zrank(context(), request("ZRANK", &["zset:z1", "a"])).await?, RespValue::Integer(2));
+        assert_eq!(zrevrank(context(), request("ZREVRANK", &["zset:z1", "a", "WITHSCORE"])).await?, RespValue::Array(vec![RespValue::Integer(1), RespValue::Double(7.0)]));
+        assert_eq!(zrank(context(), request("ZRANK", &["zset:z1", "x"])).await?, RespValue::BulkString(None));
+
+        assert_eq!(zadd(context(), request("ZADD", &["zset:z2", "XX", "1", "a"])).await?, RespValue::Integer(0));
+        assert!(DB.read().await.get(b"zset:z2").is_none());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn ranges() -> anyhow::Result<()> {
+        zadd(context(), request("ZADD", &["zset:r", "1", "a", "2", "b", "3", "c", "4", "d", "5", "e"])).await?;
+        assert_eq!(range("zset:r", &["1", "-2"]).await?, bulks(&["b", "c", "d"]));
+        assert_eq!(range("zset:r", &["0", "1", "REV"]).await?, bulks(&["e", "d"]));
+        assert_eq!(range("zset:r", &["0", "0", "WITHSCORES"]).await?, RespValue::Array(vec![bulk("a"), RespValue::Double(1.0)]));
+        assert_eq!(range("zset:r", &["(1", "3", "BYSCORE"]).await?, bulks(&["b", "c"]));
+        assert_eq!(range("zset:r", &["+inf", "(2", "BYSCORE", "REV", "LIMIT", "1", "2"]).await?, bulks(&["d", "c"]));
+        assert_eq!(range("zset:r", &["-inf", "+inf", "BYSCORE", "LIMIT", "3", "-1"]).await?, bulks(&["d", "e"]));
+        assert_eq!(range("zset:r", &["[b", "(d", "BYLEX"]).await?, bulks(&["b", "c"]));
+        assert_eq!(range("zset:r", &["+", "[d", "BYLEX", "REV"]).await?, bulks(&["e", "d"]));
+        assert!(range("zset:r", &["0", "1", "LIMIT", "0", "1"]).await.is_err());
+        assert!(range("zset:r", &["a", "b", "BYLEX"]).await.is_err());
+        assert!(range("zset:r", &["x", "1", "BYSCORE"]).await.is_err());
+
+        let reply = zrevrangebyscore(context(), request("ZREVRANGEBYSCORE", &["zset:r", "3", "-inf", "LIMIT", "0", "1"])).await?;
+        assert_eq!(reply, bulks(&["c"]));
+        assert_eq!(zcount(context(), request("ZCOUNT", &["zset:r", "(1", "5"])).await?, RespValue::Integer(4));
+        assert_eq!(zlexcount(context(), request("ZLEXCOUNT", &["zset:r", "-", "+"])).await?, RespValue::Integer(5));
+
+        let stored = zrangestore(context(), request("ZRANGESTORE", &["zset:r2", "zset:r", "4", "2", "BYSCORE", "REV"])).await?;
+        assert_eq!(stored, RespValue::Integer(3));
+        assert_eq!(range("zset:r2", &["0", "-1"]).await?, bulks(&["b", "c", "d"]));
+
+        assert_eq!(zremrangebyscore(context(), request("ZREMRANGEBYSCORE", &["zset:r", "-inf", "(2"])).await?, RespValue::Integer(1));
+        assert_eq!(zremrangebyrank(context(), request("ZREMRANGEBYRANK", &["zset:r", "-1", "-1"])).await?, RespValue::Integer(1));
+        assert_eq!(zremrangebylex(context(), request("ZREMRANGEBYLEX", &["zset:r", "[c", "+"])).await?, RespValue::Integer(2));
+        assert_eq!(zrem(context(), request("ZREM", &["zset:r", "b", "x"])).await?, RespValue::Integer(1));
+        assert!(DB.read().await.get(b"zset:r").is_none());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn pops() -> anyhow::Result<()> {
+        zadd(context(), request("ZADD", &["zset:p", "1", "a", "2", "b", "3", "c"])).await?;
+        assert_eq!(zpopmin(context(), request("ZPOPMIN", &["zset:p"])).await?, RespValue::Array(vec![bulk("a"), RespValue::Double(1.0)]));
+        let popped = zpopmax(context(), request("ZPOPMAX", &["zset:p", "5"])).await?;
+        assert_eq!(popped, RespValue::Array(vec![bulk("c"), RespValue::Double(3.0), bulk("b"), RespValue::Double(2.0)]));
+        assert!(DB.read().await.get(b"zset:p").is_none());
+        assert_eq!(zpopmin(context(), request("ZPOPMIN", &["zset:p"])).await?, RespValue::Array(vec![]));
+
+        let waiter = tokio::spawn(bzpopmin(context(), request("BZPOPMIN", &["zset:p0", "zset:p", "0"])));
+        tokio::time::sleep(std::time::Duration::from_millis(20)).await;
+        assert!(!waiter.is_finished());
+        zadd(context(), request("ZADD", &["zset:p", "7", "x", "8", "y"])).await?;
+        let reply = waiter.await??;
+        assert_eq!(reply, RespValue::Array(vec![bulk("zset:p"), bulk("x"), RespValue::Double(7.0)]));
+        assert_eq!(range("zset:p", &["0", "-1"]).await?, bulks(&["y"]));
+
+        assert_eq!(bzpopmax(context(), request("BZPOPMAX", &["zset:p0", "0.05"])).await?, RespValue::NullArray);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn union_and_intersection() -> anyhow::Result<()> {
+        zadd(context(), request("ZADD", &["zset:u1", "1", "a", "2", "b"])).await?;
+        zadd(context(), request("ZADD", &["zset:u2", "3", "b", "4", "c"])).await?;
+        DB.write().await.put("zset:plain".into(), Entry::new(Value::Set(["b", "c"].into_iter().map(Bytes::from).collect())));
+
+        let stored = zunionstore(context(), request("ZUNIONSTORE", &["zset:dst", "2", "zset:u1", "zset:u2", "WEIGHTS", "2", "1"])).await?;
+        assert_eq!(stored, RespValue::Integer(3));
+        assert_eq!(range("zset:dst", &["0", "-1", "WITHSCORES"]).await?, RespValue::Array(vec![
+            bulk("a"), RespValue::Double(2.0), bulk("c"), RespValue::Double(4.0), bulk("b"), RespValue::Double(7.0),
+        ]));
+
+        let args = ["zset:dst", "3", "zset:u1", "zset:u2", "zset:plain", "AGGREGATE", "MAX"];
+        assert_eq!(zinterstore(context(), request("ZINTERSTORE", &args)).await?, RespValue::Integer(1));
+        assert_eq!(range("zset:dst", &["0", "-1", "WITHSCORES"]).await?, RespValue::Array(vec![bulk("b"), RespValue::Double(3.0)]));
+
+        assert_eq!(zinterstore(context(), request("ZINTERSTORE", &["zset:dst", "2", "zset:u1", "zset:none"])).await?, RespValue::Integer(0));
+        assert!(DB.read().await.get(b"zset:dst").is_none());
+        assert!(zunionstore(context(), request("ZUNIONSTORE", &["zset:dst", "0", "zset:u1"])).await.is_err());
+        assert!(zunionstore(context(), request("ZUNIONSTORE", &["zset:dst", "1", "zset:u1", "WEIGHTS", "x"])).await.is_err());
+
+        let RespValue::Array(reply) = zscan(context(), request("ZSCAN", &["zset:u1", "0", "MATCH", "b"])).await? else { panic!() };
+        assert_eq!(reply[1], bulks(&["b", "2"]));
+        Ok(())
+    }
+}
@@ -0,0 +1,97 @@
+use std::sync::Arc;
+use anyhow::Result;
+use crate::{context::Context, engine::{aof, snapshot}, error::Error, parser::{RespRequest, RespValue, OK_RESP, RESP2, RESP3}};
+use crate::command_table::{RouteHandler, ROUTE_MAP};
+
+#[router_macro::route("SAVE")]
+async fn save(context : Arc<Context>, _request: RespRequest) -> Result<RespValue> {
+    context.clear_deadline();
+    snapshot::save().await?;
+    Ok(OK_RESP.clone())
+}
+
+#[router_macro::route("BGSAVE")]
+async fn bgsave(_context : Arc<Context>, _request: RespRequest) -> Result<RespValue> {
+    snapshot::bgsave().await?;
+    Ok(RespValue::SimpleString("Background saving started".into()))
+}
+
+#[router_macro::route("LASTSAVE")]
+async fn lastsave(_context : Arc<Context>, _request: RespRequest) -> Result<RespValue> {
+    Ok(RespValue::Integer(snapshot::last_save().timestamp()))
+}
+
+#[router_macro::route("BGREWRITEAOF")]
+async fn bgrewriteaof(_context : Arc<Context>, _request: RespRequest) -> Result<RespValue> {
+    aof::bgrewrite().await?;
+    Ok(RespValue::SimpleString("Background append only file rewriting started".into()))
+}
+
+/// HELLO [protover [AUTH username password] [SETNAME clientname]]
+#[router_macro::route("HELLO")]
+async fn hello(context : Arc<Context>, request: RespRequest) -> Result<RespValue> {
+    let mut args = request.args.iter();
+    let mut protocol = context.protocol();
+
+    if let Some(version) = args.next() {
+        protocol = match version.as_str()?.parse::<i64>() {
+            Ok(v) if v == RESP2 as i64 || v == RESP3 as i64 => v as u8,
+            Ok(_) => return Ok(RespValue::Error("NOPROTO unsupported protocol version".into())),
+            Err(_) => return Ok(RespValue::Error("ERR Protocol version is not an integer or out of range".into())),
+        };
+    }
+
+    while let Some(option) = args.next() {
+        match option.as_str()?.to_ascii_uppercase().as_str() {
+            // there are no users yet, every credential is accepted
+            "AUTH" => {
+                args.next().ok_or(Error::Syntax)?;
+                args.next().ok_or(Error::Syntax)?;
+            }
+            "SETNAME" => {
+                args.next().ok_or(Error::Syntax)?;
+            }
+            _ => return Err(Error::Syntax.into()),
+        }
+    }
+
+    context.set_protocol(protocol);
+    let field = |name: &'static str| RespValue::BulkString(Some(name.into()));
+    Ok(RespValue::Map(vec![
+        (field("server"), field("kv")),
+        (field("version"), field(env!("CARGO_PKG_VERSION"))),
+        (field("proto"), RespValue::Integer(protocol as i64)),
+        (field("id"), RespValue::Integer(context.client_id as i64)),
+        (field("mode"), field("standalone")),
+        (field("role"), field("master")),
+        (field("modules"), RespValue::Array(vec![])),
+    ]))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn hello_switches_protocol() -> Result<()> {
+        let context = Arc::new(Context::new(None, 3).with_client(7, RESP2));
+        let request = RespRequest {
+            command: "HELLO".into(),
+            args: vec![RespValue::BulkString(Some("3".into()))],
+        };
+        let response = hello(context.clone(), request).await?;
+        assert_eq!(context.protocol(), RESP3);
+        match response {
+            RespValue::Map(pairs) => assert!(pairs.contains(&(RespValue::BulkString(Some("id".into())), RespValue::Integer(7)))),
+            other => panic!("unexpected response {:?}", other),
+        }
+
+        let request = RespRequest {
+            command: "HELLO".into(),
+            args: vec![RespValue::BulkString(Some("4".into()))],
+        };
+        assert!(matches!(hello(context.clone(), request).await?, RespValue::Error(_)));
+        assert_eq!(context.protocol(), RESP3);
+        Ok(())
+    }
+}
@@ -1,3 +1,4 @@
+#[allow(clippy::module_inception)]
 pub mod built_info {
     include!(concat!(env!("OUT_DIR"), "/built.rs"));
 }
@@ -0,0 +1,142 @@
+// Glob-style matching of KEYS and the MATCH option of the SCAN family, a port of stringmatchlen of redis:
+// `*`, `?`, `[abc]`, `[^abc]`, `[a-z]` and `\` to escape the next character
+
+pub fn glob_match(pattern: &[u8], string: &[u8]) -> bool {
+    matches(pattern, string, &mut false, 0)
+}
+
+// bounds the recursion of a pattern with many stars, so that it cannot overflow the stack
+const MAX_NESTING: usize = 1000;
+
+/// `skip_longer` is set once the pattern after a star failed against every rest of the string, like
+/// skipLongerMatches of redis: the enclosing stars would only try shorter rests, which fail as well.
+/// Without it a few stars take exponential time (CVE-2022-36021).
+fn matches(mut pattern: &[u8], mut string: &[u8], skip_longer: &mut bool, nesting: usize) -> bool {
+    if nesting > MAX_NESTING {
+        return false;
+    }
+
+    while let Some(&p) = pattern.first() {
+        match p {
+            b'*' => {
+                while pattern.get(1) == Some(&b'*') {
+                    pattern = &pattern[1..];
+                }
+                if pattern.len() == 1 {
+                    return true;
+                }
+                for skip in 0..=string.len() {
+                    if matches(&pattern[1..], &string[skip..], skip_longer, nesting + 1) {
+                        return true;
+                    }
+                    if *skip_longer {
+                        return false;
+                    }
+                }
+                *skip_longer = true;
+                return false;
+            }
+            b'?' => {
+                if string.is_empty() {
+                    return false;
+                }
+                string = &string[1..];
+            }
+            b'[' => {
+                let Some(&c) = string.first() else {
+                    return false;
+                };
+                pattern = &pattern[1..];
+                let negate = pattern.first() == Some(&b'^');
+                if negate {
+                    pattern = &pattern[1..];
+                }
+
+                let mut matched = false;
+                loop {
+                    match pattern {
+                        // an unterminated class still matches what it saw, like redis
+                        [] => break,
+                        [b']', ..] => break,
+                        [b'\\', escaped, ..] => {
+                            matched |= *escaped == c;
+                            pattern = &pattern[2..];
+                        }
+                        [start, b'-', end, ..] if *end != b']' => {
+                            let (low, high) = if start <= end { (*start, *end) } else { (*end, *start) };
+                            matched |= (low..=high).contains(&c);
+                            pattern = &pattern[3..];
+                        }
+                        [other, ..] => {
+                            matched |= *other == c;
+                            pattern = &pattern[1..];
+                        }
+                    }
+                }
+                if matched == negate {
+                    return false;
+                }
+                string = &string[1..];
+                if pattern.is_empty() {
+                    return string.is_empty();
+                }
+            }
+            b'\\' if pattern.len() >= 2 => {
+                if string.first() != Some(&pattern[1]) {
+                    return false;
+                }
+                pattern = &pattern[1..];
+                string = &string[1..];
+            }
+            _ => {
+                if string.first() != Some(&p) {
+                    return false;
+                }
+                string = &string[1..];
+            }
+        }
+        pattern = &pattern[1..];
+    }
+    string.is_empty()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn glob_patterns() {
+        let cases: &[(&str, &str, bool)] = &[
+            ("*", "anything", true),
+            ("*", "", true),
+            ("h?llo", "hello", true),
+            ("h?llo", "hllo", false),
+            ("h*llo", "heeeello", true),
+            ("h[ae]llo", "hallo", true),
+            ("h[ae]llo", "hillo", false),
+            ("h[^e]llo", "hallo", true),
+            ("h[^e]llo", "hello", false),
+            ("h[a-b]llo", "hbllo", true),
+            ("h[b-a]llo", "hallo", true),
+            ("h[a-b]llo", "hcllo", false),
+            ("h\\*llo", "h*llo", true),
+            ("h\\*llo", "hello", false),
+            ("user:*:name", "user:42:name", true),
+            ("user:*:name", "user:42:mail", false),
+            ("a*b*c", "abxbc", true),
+            ("a*b*c", "abxbd", false),
+        ];
+        for (pattern, string, expected) in cases {
+            assert_eq!(glob_match(pattern.as_bytes(), string.as_bytes()), *expected, "{} ~ {}", pattern, string);
+        }
+    }
+
+    #[test]
+    fn many_stars_fail_fast() {
+        let start = std::time::Instant::now();
+        let key = "a".repeat(60);
+        assert!(!glob_match(b"*a*a*a*a*a*a*a*b", key.as_bytes()));
+        assert!(glob_match(b"*a*a*a*a*a*a*a*", key.as_bytes()));
+        assert!(start.elapsed() < std::time::Duration::from_millis(100));
+    }
+}
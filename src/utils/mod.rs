@@ -1,11 +1,31 @@
 mod built_info;
+mod crc64;
+mod glob;
+mod murmur;
 
 use anyhow::Result;
 
 pub use built_info::{print_built_info, get_built_info};
+pub use crc64::crc64;
+pub use glob::glob_match;
+pub use murmur::murmur_hash64a;
 
 pub async fn bind_port(port: u16) -> Result<tokio::net::TcpListener> {
     let addr = format!("[::]:{port}").parse::<std::net::SocketAddr>()?;
     let listener = tokio::net::TcpListener::bind(&addr).await?;
     Ok(listener)
-}
\ No newline at end of file
+}
+/// Parses sizes such as "1024", "64mb" or "1g"
+pub fn parse_size(s: &str) -> Result<u64> {
+    let lower = s.trim().to_ascii_lowercase();
+    let split = lower.find(|c: char| !c.is_ascii_digit()).unwrap_or(lower.len());
+    let (number, unit) = lower.split_at(split);
+    let unit = match unit {
+        "" | "b" => 1,
+        "k" | "kb" => 1024,
+        "m" | "mb" => 1024 * 1024,
+        "g" | "gb" => 1024 * 1024 * 1024,
+        _ => anyhow::bail!("invalid size {}", s),
+    };
+    Ok(number.parse::<u64>()? * unit)
+}